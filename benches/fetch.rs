@@ -0,0 +1,40 @@
+// What decrypting on fetch costs. The same compiled loop runs from plain code, from a
+// sealed module and from a sealed module opened straight out of an encrypted container.
+
+use biobox::compiler;
+use biobox::module::interface::Value;
+use biobox::module::Module;
+use biobox::vm::VM;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const SOURCE: &str = "uint main(uint n) {
+    uint total = 0;
+    for (uint i = 0; i < n; i++) { total = total ^ (i * i + (total >> 3)); }
+    return total;
+}";
+
+fn fetch(c: &mut Criterion) {
+    let key = [7; 32];
+    let module = compiler::compile(SOURCE).unwrap();
+    let args = [Value::U32(2000)];
+
+    let mut plain = VM::new();
+    plain.load_module(&module);
+    let mut sealed = VM::new();
+    sealed.load_sealed(&module.seal(&key));
+    let mut container = VM::new();
+    container.load_sealed(&Module::decrypt_sealed(&module.encrypt(&key).unwrap(), &key).unwrap());
+    assert_eq!(plain.call(&args), sealed.call(&args));
+
+    let mut group = c.benchmark_group("fetch");
+    group.bench_function("plain", |b| b.iter(|| plain.call(&args).unwrap()));
+    group.bench_function("sealed", |b| b.iter(|| sealed.call(&args).unwrap()));
+    group.bench_function("sealed container", |b| {
+        b.iter(|| container.call(&args).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, fetch);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+// The pre-decoded interpreter against the byte interpreter on three kernels: a bare
+// counting loop, a loop of arithmetic, and collatz step counting which branches on
+// every step. With the jit feature the same kernels also run as native code.
+
+use biobox::assembler::assemble;
+use biobox::module::interface::Value;
+use biobox::vm::VM;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const COUNTING: &str = ".input n i32\n.output count i32 $1\n\
+    load $1 #0\nload $3 @top\n\
+    top: inc $1\nlt $1 $0\njeq $3\nhlt\n";
+
+const ARITHMETIC: &str = ".input n i32\n.output hash i32 $1\n\
+    load $1 #0\nload $2 #0\nload $5 #31\nload $6 #7\nload $3 @top\n\
+    top: mul $1 $5 $1\nadd $1 $2 $1\nshl $1 $6 $7\nxor $1 $7 $1\n\
+    shru $1 $6 $7\nsub $1 $7 $1\ninc $2\nlt $2 $0\njeq $3\nhlt\n";
+
+// total collatz steps of every number from 1 to n
+const BRANCHES: &str = ".input n i32\n.output steps i32 $1\n\
+    load $1 #0\nload $2 #1\nload $10 #1\nload $11 #3\nload $12 #2\nload $13 #0\n\
+    load $20 @outer\nload $21 @inner\nload $22 @odd\nload $23 @next\n\
+    outer: mov $2 $4\n\
+    inner: eq $4 $10\njeq $23\nmodu $4 $12 $5\nneq $5 $13\njeq $22\n\
+    shr $4 $10 $4\ninc $1\njmp $21\n\
+    odd: mul $4 $11 $4\ninc $4\ninc $1\njmp $21\n\
+    next: inc $2\nlteq $2 $0\njeq $20\nhlt\n";
+
+fn interpreter(c: &mut Criterion) {
+    for (name, source, n) in [
+        ("counting", COUNTING, 10_000),
+        ("arithmetic", ARITHMETIC, 2_000),
+        ("branches", BRANCHES, 100),
+    ]
+    .iter()
+    {
+        let module = assemble(source).unwrap();
+        let args = [Value::I32(*n)];
+        let mut bytes = VM::new();
+        bytes.predecode(false);
+        bytes.load_module(&module);
+        let mut decoded = VM::new();
+        decoded.load_module(&module);
+        assert_eq!(bytes.call(&args), decoded.call(&args));
+
+        let mut group = c.benchmark_group(*name);
+        group.bench_function("bytes", |b| b.iter(|| bytes.call(&args).unwrap()));
+        group.bench_function("decoded", |b| b.iter(|| decoded.call(&args).unwrap()));
+        #[cfg(feature = "jit")]
+        {
+            let mut native = VM::new();
+            native.load_jit(&module).unwrap();
+            assert_eq!(native.call(&args), decoded.call(&args));
+            group.bench_function("native", |b| b.iter(|| native.call(&args).unwrap()));
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
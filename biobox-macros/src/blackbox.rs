@@ -0,0 +1,849 @@
+// #[blackbox] turns a Rust function into biobox bytecode at build time.
+//
+// The body is translated into the syntax tree of the biobox compiler, which type checks
+// and compiles it like a `main` written in the C-like language, and the function is
+// replaced by one that runs the module in a VM. Only a small part of Rust is accepted:
+//
+//   - i32, u32 and bool parameters, locals and return values
+//   - literals, arithmetic, bitwise and comparison operators, `as` casts between them
+//   - `let` (shadowing included), assignments and compound assignments
+//   - `if`/`else` (also as a value), `while`, `loop`, `break`, `continue`, `return`
+//
+// Arithmetic wraps like the wrapping_* methods, dividing by zero panics like it does
+// in Rust. Anything else is a compile error pointing at the construct.
+
+use biobox::compiler::ast::{self, BinaryOp, ExprKind, StmtKind, Type, UnaryOp};
+use biobox::compiler::{self, CompileError};
+use proc_macro2::{Literal, Span, TokenStream as TokenStream2};
+use quote::quote;
+use std::collections::HashMap;
+use syn::spanned::Spanned;
+use syn::visit::Visit;
+use syn::{BinOp, Expr, FnArg, ItemFn, Lit, Pat, ReturnType, Stmt, UnOp};
+
+/// Compiles the function and generates its replacement
+pub fn expand(item: ItemFn) -> syn::Result<TokenStream2> {
+    let mut translator = Translator::new();
+    let function = translator.function(&item)?;
+    let params = function.params.clone();
+    let return_type = function.return_type;
+    let program = ast::Program {
+        functions: vec![function],
+    };
+    let module = compiler::compile_ast(program).map_err(|e| translator.error(&item, &e))?;
+    let bytes = module
+        .to_bytes()
+        .map_err(|e| syn::Error::new(item.sig.ident.span(), e.to_string()))?;
+    let bytes = Literal::byte_string(&bytes);
+
+    let mut signature = item.sig.clone();
+    let mut values = vec![];
+    for (arg, (ty, _)) in signature.inputs.iter_mut().zip(&params) {
+        //the body is gone, so `mut` would only cause warnings
+        let ident = match arg {
+            FnArg::Typed(typed) => match &mut *typed.pat {
+                Pat::Ident(pat) => {
+                    pat.mutability = None;
+                    pat.ident.clone()
+                }
+                _ => unreachable!("checked by the translator"),
+            },
+            _ => unreachable!("checked by the translator"),
+        };
+        values.push(match ty {
+            Type::Int => quote!(::biobox::module::interface::Value::I32(#ident)),
+            Type::Bool => quote!(::biobox::module::interface::Value::U32(#ident as u32)),
+            _ => quote!(::biobox::module::interface::Value::U32(#ident)),
+        });
+    }
+    let result = match return_type {
+        Type::Void => quote!(()),
+        Type::Int => quote! {
+            match outputs.into_iter().next() {
+                ::std::option::Option::Some(::biobox::module::interface::Value::I32(v)) => v,
+                _ => unreachable!(),
+            }
+        },
+        ty => {
+            let convert = if ty == Type::Bool {
+                quote!(v != 0)
+            } else {
+                quote!(v)
+            };
+            quote! {
+                match outputs.into_iter().next() {
+                    ::std::option::Option::Some(::biobox::module::interface::Value::U32(v)) => #convert,
+                    _ => unreachable!(),
+                }
+            }
+        }
+    };
+
+    let attrs = &item.attrs;
+    let vis = &item.vis;
+    let message = format!("#[blackbox] function `{}` failed: {{}}", item.sig.ident);
+    Ok(quote! {
+        #(#attrs)*
+        #vis #signature {
+            const MODULE: &[u8] = #bytes;
+            thread_local! {
+                static VM: ::std::cell::RefCell<::biobox::vm::VM> = ::std::cell::RefCell::new({
+                    let mut vm = ::biobox::vm::VM::new();
+                    vm.load_module_bytes(MODULE)
+                        .expect("module was validated when it was compiled");
+                    vm
+                });
+            }
+            match VM.with(|vm| vm.borrow_mut().call(&[#(#values),*])) {
+                ::std::result::Result::Ok(outputs) => #result,
+                ::std::result::Result::Err(e) => panic!(#message, e),
+            }
+        }
+    })
+}
+
+/// Where the value of a block goes
+#[derive(Clone)]
+enum Sink {
+    Return,
+    Assign(String),
+    Discard,
+}
+
+struct Translator {
+    // the compiler only knows lines and columns, so every node's span is stored here
+    // and the node gets its index as the line
+    spans: Vec<Span>,
+    // Rust name to the unique name given to the compiler, and its type
+    scopes: Vec<HashMap<String, (String, Type)>>,
+    locals: usize,
+    return_type: Type,
+}
+
+impl Translator {
+    fn new() -> Translator {
+        Translator {
+            spans: vec![],
+            scopes: vec![],
+            locals: 0,
+            return_type: Type::Void,
+        }
+    }
+
+    fn function(&mut self, item: &ItemFn) -> syn::Result<ast::Function> {
+        let sig = &item.sig;
+        if let Some(asyncness) = &sig.asyncness {
+            return Err(unsupported(asyncness, "async functions"));
+        }
+        if !sig.generics.params.is_empty() {
+            return Err(unsupported(&sig.generics, "generic functions"));
+        }
+        if let Some(variadic) = &sig.variadic {
+            return Err(unsupported(variadic, "variadic functions"));
+        }
+        self.return_type = match &sig.output {
+            ReturnType::Default => Type::Void,
+            ReturnType::Type(_, ty) => value_type(ty, true)?,
+        };
+
+        self.scopes.push(HashMap::new());
+        let mut params = vec![];
+        for arg in &sig.inputs {
+            let typed = match arg {
+                FnArg::Typed(typed) => typed,
+                FnArg::Receiver(receiver) => return Err(unsupported(receiver, "methods")),
+            };
+            let name = binding(&typed.pat)?;
+            let ty = value_type(&typed.ty, false)?;
+            params.push((ty, self.declare(&name, ty)));
+        }
+
+        let body = &item.block;
+        if self.return_type != Type::Void && !ends_with_value(body) {
+            return Err(syn::Error::new(
+                sig.output.span(),
+                "#[blackbox] functions have to end with their return value",
+            ));
+        }
+        let body = self.block(body, Sink::Return)?;
+        Ok(ast::Function {
+            name: "main".to_string(),
+            params,
+            return_type: self.return_type,
+            body,
+            span: self.span(sig.ident.span()),
+        })
+    }
+
+    /// Translates a block, handing its tail expression to the sink
+    fn block(&mut self, block: &syn::Block, sink: Sink) -> syn::Result<Vec<ast::Stmt>> {
+        self.scopes.push(HashMap::new());
+        let mut statements = vec![];
+        for (index, statement) in block.stmts.iter().enumerate() {
+            let rest = &block.stmts[index + 1..];
+            match statement {
+                Stmt::Expr(expr, None) if rest.is_empty() => {
+                    statements.extend(self.value(expr, sink.clone())?)
+                }
+                Stmt::Local(local) => {
+                    //a value returned at the end of the function has the return type
+                    let returned = match (&sink, rest.last()) {
+                        (Sink::Return, Some(Stmt::Expr(tail, None))) => Some(tail),
+                        _ => None,
+                    };
+                    statements.extend(self.local(local, rest, returned)?)
+                }
+                statement => statements.extend(self.statement(statement)?),
+            }
+        }
+        self.scopes.pop();
+        Ok(statements)
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> syn::Result<Vec<ast::Stmt>> {
+        match statement {
+            Stmt::Local(local) => self.local(local, &[], None),
+            Stmt::Expr(expr, _) => self.expr_statement(expr),
+            Stmt::Item(item) => Err(unsupported(item, "items inside functions")),
+            Stmt::Macro(mac) => Err(unsupported(mac, "macros")),
+        }
+    }
+
+    /// `rest` are the statements after the `let`, they can tell the type when the value can't
+    fn local(
+        &mut self,
+        local: &syn::Local,
+        rest: &[Stmt],
+        returned: Option<&Expr>,
+    ) -> syn::Result<Vec<ast::Stmt>> {
+        let (pat, annotation) = match &local.pat {
+            Pat::Type(typed) => (&*typed.pat, Some(value_type(&typed.ty, false)?)),
+            pat => (pat, None),
+        };
+        let name = binding(pat)?;
+        let span = self.span(local.span());
+        let init = match &local.init {
+            Some(init) => {
+                if let Some((_, diverge)) = &init.diverge {
+                    return Err(unsupported(diverge, "let-else"));
+                }
+                Some(&*init.expr)
+            }
+            None => None,
+        };
+        //without an annotation the type comes from the value, then from how the variable
+        //is used, falling back to i32 like Rust
+        let ty = match annotation {
+            Some(ty) => ty,
+            None => init
+                .and_then(|init| self.infer(init))
+                .or_else(|| self.infer_from_uses(&name, rest, returned))
+                .unwrap_or(Type::Int),
+        };
+
+        //the value is translated before the name is declared, `let x = x + 1;` reads the old x
+        let unique = self.unique(&name);
+        let statements = match init {
+            Some(init) if needs_sink(init) => {
+                let mut statements = vec![stmt(StmtKind::Let(ty, unique.clone(), None), span)];
+                statements.extend(self.value(init, Sink::Assign(unique.clone()))?);
+                statements
+            }
+            Some(init) => {
+                let value = self.expr(init)?;
+                vec![stmt(StmtKind::Let(ty, unique.clone(), Some(value)), span)]
+            }
+            None => vec![stmt(StmtKind::Let(ty, unique.clone(), None), span)],
+        };
+        self.scopes
+            .last_mut()
+            .expect("there is always a scope")
+            .insert(name, (unique, ty));
+        Ok(statements)
+    }
+
+    /// An expression whose value isn't used
+    fn expr_statement(&mut self, expr: &Expr) -> syn::Result<Vec<ast::Stmt>> {
+        let span = self.span(expr.span());
+        let kind = match expr {
+            Expr::If(_) | Expr::Block(_) | Expr::Paren(_) | Expr::Group(_) => {
+                return self.value(expr, Sink::Discard)
+            }
+            Expr::While(expr_while) => {
+                if let Some(label) = &expr_while.label {
+                    return Err(unsupported(label, "loop labels"));
+                }
+                if let Expr::Let(expr_let) = &*expr_while.cond {
+                    return Err(unsupported(expr_let, "`while let`"));
+                }
+                let condition = self.expr(&expr_while.cond)?;
+                let body = self.block(&expr_while.body, Sink::Discard)?;
+                StmtKind::While(condition, Box::new(stmt(StmtKind::Block(body), span)))
+            }
+            Expr::Loop(expr_loop) => {
+                if let Some(label) = &expr_loop.label {
+                    return Err(unsupported(label, "loop labels"));
+                }
+                let condition = ast::Expr::new(ExprKind::Bool(true), span);
+                let body = self.block(&expr_loop.body, Sink::Discard)?;
+                StmtKind::While(condition, Box::new(stmt(StmtKind::Block(body), span)))
+            }
+            Expr::Return(expr_return) => match &expr_return.expr {
+                Some(value) => return self.value(value, Sink::Return),
+                None => StmtKind::Return(None),
+            },
+            Expr::Break(expr_break) => {
+                if let Some(label) = &expr_break.label {
+                    return Err(unsupported(label, "loop labels"));
+                }
+                if let Some(value) = &expr_break.expr {
+                    return Err(unsupported(value, "`break` with a value"));
+                }
+                StmtKind::Break
+            }
+            Expr::Continue(expr_continue) => {
+                if let Some(label) = &expr_continue.label {
+                    return Err(unsupported(label, "loop labels"));
+                }
+                StmtKind::Continue
+            }
+            Expr::Assign(assign) => {
+                let (name, _) = self.lookup(&assign.left)?;
+                return self.value(&assign.right, Sink::Assign(name));
+            }
+            Expr::Binary(binary) if compound_op(&binary.op).is_some() => {
+                let (name, _) = self.lookup(&binary.left)?;
+                let op = compound_op(&binary.op).expect("checked by the guard");
+                let current = ast::Expr::new(ExprKind::Var(name.clone()), span);
+                let value = self.expr(&binary.right)?;
+                let value = ExprKind::Binary(op, Box::new(current), Box::new(value));
+                StmtKind::Assign(name, ast::Expr::new(value, span))
+            }
+            expr => StmtKind::Expr(self.expr(expr)?),
+        };
+        Ok(vec![stmt(kind, span)])
+    }
+
+    /// An expression whose value goes to the sink. `if` and blocks pass the sink on to
+    /// their branches, the language doesn't have them as expressions.
+    fn value(&mut self, expr: &Expr, sink: Sink) -> syn::Result<Vec<ast::Stmt>> {
+        let span = self.span(expr.span());
+        match expr {
+            Expr::If(expr_if) => {
+                if let Expr::Let(expr_let) = &*expr_if.cond {
+                    return Err(unsupported(expr_let, "`if let`"));
+                }
+                let condition = self.expr(&expr_if.cond)?;
+                let then = self.block(&expr_if.then_branch, sink.clone())?;
+                let otherwise = match &expr_if.else_branch {
+                    Some((_, otherwise)) => Some(Box::new(stmt(
+                        StmtKind::Block(self.value(otherwise, sink)?),
+                        span,
+                    ))),
+                    None => match sink {
+                        Sink::Discard => None,
+                        _ => {
+                            return Err(syn::Error::new(
+                                expr.span(),
+                                "an `if` without an `else` has no value",
+                            ))
+                        }
+                    },
+                };
+                let then = Box::new(stmt(StmtKind::Block(then), span));
+                Ok(vec![stmt(StmtKind::If(condition, then, otherwise), span)])
+            }
+            Expr::Block(expr_block) => {
+                if let Some(label) = &expr_block.label {
+                    return Err(unsupported(label, "block labels"));
+                }
+                let body = self.block(&expr_block.block, sink)?;
+                Ok(vec![stmt(StmtKind::Block(body), span)])
+            }
+            Expr::Paren(paren) => self.value(&paren.expr, sink),
+            Expr::Group(group) => self.value(&group.expr, sink),
+            //statements evaluate to () or never finish, so there's nothing to hand over
+            Expr::While(_)
+            | Expr::Loop(_)
+            | Expr::Return(_)
+            | Expr::Break(_)
+            | Expr::Continue(_)
+            | Expr::Assign(_) => self.expr_statement(expr),
+            Expr::Binary(binary) if compound_op(&binary.op).is_some() => self.expr_statement(expr),
+            expr => {
+                let value = self.expr(expr)?;
+                let kind = match sink {
+                    Sink::Return if self.return_type != Type::Void => StmtKind::Return(Some(value)),
+                    Sink::Assign(name) => StmtKind::Assign(name, value),
+                    _ => StmtKind::Expr(value),
+                };
+                Ok(vec![stmt(kind, span)])
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> syn::Result<ast::Expr> {
+        let span = self.span(expr.span());
+        let kind = match expr {
+            Expr::Lit(lit) => match &lit.lit {
+                Lit::Int(int) => {
+                    if !matches!(int.suffix(), "" | "i32" | "u32") {
+                        return Err(unsupported(int, "integer types other than i32 and u32"));
+                    }
+                    let value = int.base10_parse::<u32>().map_err(|_| {
+                        syn::Error::new(int.span(), "literal doesn't fit in 32 bits")
+                    })?;
+                    ExprKind::Int(value)
+                }
+                Lit::Bool(value) => ExprKind::Bool(value.value),
+                lit => return Err(unsupported(lit, "this kind of literal")),
+            },
+            Expr::Path(_) => ExprKind::Var(self.lookup(expr)?.0),
+            Expr::Paren(paren) => return self.expr(&paren.expr),
+            Expr::Group(group) => return self.expr(&group.expr),
+            Expr::Unary(unary) => {
+                let op = match unary.op {
+                    UnOp::Neg(_) => UnaryOp::Neg,
+                    //`!` is the logical not on bools and the bitwise one on integers
+                    UnOp::Not(_) if self.infer(&unary.expr) == Some(Type::Bool) => UnaryOp::Not,
+                    UnOp::Not(_) => UnaryOp::BitNot,
+                    _ => return Err(unsupported(unary, "dereferencing")),
+                };
+                ExprKind::Unary(op, Box::new(self.expr(&unary.expr)?))
+            }
+            Expr::Binary(binary) => {
+                let op = binary_op(&binary.op).ok_or_else(|| {
+                    syn::Error::new(
+                        binary.op.span(),
+                        "#[blackbox] only supports compound assignments as statements",
+                    )
+                })?;
+                let left = self.expr(&binary.left)?;
+                let right = self.expr(&binary.right)?;
+                ExprKind::Binary(op, Box::new(left), Box::new(right))
+            }
+            Expr::Cast(cast) => {
+                let ty = value_type(&cast.ty, false)?;
+                if ty == Type::Bool {
+                    return Err(syn::Error::new(
+                        cast.ty.span(),
+                        "can only cast to i32 or u32",
+                    ));
+                }
+                ExprKind::Cast(ty, Box::new(self.expr(&cast.expr)?))
+            }
+            Expr::If(_) | Expr::Block(_) => {
+                return Err(syn::Error::new(
+                    expr.span(),
+                    "#[blackbox] only supports this as the value of a `let`, an assignment, \
+                     a `return` or a block",
+                ))
+            }
+            Expr::Call(call) => return Err(unsupported(call, "function calls")),
+            Expr::MethodCall(call) => return Err(unsupported(call, "method calls")),
+            Expr::Macro(mac) => return Err(unsupported(mac, "macros")),
+            expr => return Err(unsupported(expr, "this expression")),
+        };
+        Ok(ast::Expr::new(kind, span))
+    }
+
+    /// The type of an expression when it can be told without the type checker
+    fn infer(&self, expr: &Expr) -> Option<Type> {
+        match expr {
+            Expr::Lit(lit) => match &lit.lit {
+                Lit::Int(int) if int.suffix() == "i32" => Some(Type::Int),
+                Lit::Int(int) if int.suffix() == "u32" => Some(Type::Uint),
+                Lit::Bool(_) => Some(Type::Bool),
+                _ => None,
+            },
+            Expr::Path(_) => self.lookup(expr).ok().map(|(_, ty)| ty),
+            Expr::Paren(paren) => self.infer(&paren.expr),
+            Expr::Group(group) => self.infer(&group.expr),
+            Expr::Unary(unary) => self.infer(&unary.expr),
+            Expr::Cast(cast) => value_type(&cast.ty, false).ok(),
+            Expr::Binary(binary) => match binary_op(&binary.op) {
+                Some(op) if op.is_comparison() || op == BinaryOp::And || op == BinaryOp::Or => {
+                    Some(Type::Bool)
+                }
+                Some(BinaryOp::Shl) | Some(BinaryOp::Shr) => self.infer(&binary.left),
+                _ => self
+                    .infer(&binary.left)
+                    .or_else(|| self.infer(&binary.right)),
+            },
+            Expr::If(expr_if) => self.infer_block(&expr_if.then_branch).or_else(|| {
+                expr_if
+                    .else_branch
+                    .as_ref()
+                    .and_then(|(_, otherwise)| self.infer(otherwise))
+            }),
+            Expr::Block(expr_block) => self.infer_block(&expr_block.block),
+            _ => None,
+        }
+    }
+
+    /// The type a later use of the variable forces on it, like `v < n` with a u32 `n`
+    fn infer_from_uses(&self, name: &str, rest: &[Stmt], returned: Option<&Expr>) -> Option<Type> {
+        if let Some(returned) = returned {
+            if typed_by(returned, name) && self.return_type != Type::Void {
+                return Some(self.return_type);
+            }
+        }
+        let mut finder = UseFinder {
+            translator: self,
+            name,
+            found: None,
+        };
+        for statement in rest {
+            finder.visit_stmt(statement);
+        }
+        finder.found
+    }
+
+    fn infer_block(&self, block: &syn::Block) -> Option<Type> {
+        match block.stmts.last() {
+            Some(Stmt::Expr(expr, None)) => self.infer(expr),
+            _ => None,
+        }
+    }
+
+    //
+    // Names and spans
+    //
+
+    fn declare(&mut self, name: &str, ty: Type) -> String {
+        let unique = self.unique(name);
+        self.scopes
+            .last_mut()
+            .expect("there is always a scope")
+            .insert(name.to_string(), (unique.clone(), ty));
+        unique
+    }
+
+    /// Shadowing is fine in Rust but not in the language, so every binding gets its own name
+    fn unique(&mut self, name: &str) -> String {
+        self.locals += 1;
+        format!("{}#{}", name, self.locals)
+    }
+
+    fn lookup(&self, expr: &Expr) -> syn::Result<(String, Type)> {
+        let path = match expr {
+            Expr::Path(path) if path.qself.is_none() => &path.path,
+            Expr::Paren(paren) => return self.lookup(&paren.expr),
+            Expr::Group(group) => return self.lookup(&group.expr),
+            expr => {
+                return Err(syn::Error::new(
+                    expr.span(),
+                    "#[blackbox] can only assign to local variables",
+                ))
+            }
+        };
+        let name = path
+            .get_ident()
+            .ok_or_else(|| unsupported(path, "paths"))?
+            .to_string();
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name).cloned())
+            .ok_or_else(|| {
+                syn::Error::new(
+                    path.span(),
+                    format!(
+                        "unknown variable '{}', #[blackbox] functions can only use their \
+                         parameters and locals",
+                        name
+                    ),
+                )
+            })
+    }
+
+    fn span(&mut self, span: Span) -> ast::Span {
+        self.spans.push(span);
+        ast::Span {
+            line: self.spans.len(),
+            column: 0,
+        }
+    }
+
+    /// Points a compiler error back at the Rust code, the names it mentions lose their suffix
+    fn error(&self, item: &ItemFn, error: &CompileError) -> syn::Error {
+        let span = match error.column {
+            0 => self.spans.get(error.line - 1).cloned(),
+            _ => None,
+        };
+        let message = error
+            .message
+            .split('\'')
+            .enumerate()
+            .map(|(i, part)| match i % 2 {
+                1 => part.split('#').next().unwrap_or(part),
+                _ => part,
+            })
+            .collect::<Vec<&str>>()
+            .join("'");
+        syn::Error::new(
+            span.unwrap_or_else(|| item.sig.ident.span()),
+            format!("#[blackbox]: {}", rust_type_names(&message)),
+        )
+    }
+}
+
+/// Swaps the language's type names in a message for Rust's
+fn rust_type_names(message: &str) -> String {
+    message
+        .split(' ')
+        .map(|word| {
+            let name = word.trim_end_matches(',');
+            let rust = match name {
+                "int" => "i32",
+                "uint" => "u32",
+                _ => return word.to_string(),
+            };
+            format!("{}{}", rust, &word[name.len()..])
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+struct UseFinder<'a> {
+    translator: &'a Translator,
+    name: &'a str,
+    found: Option<Type>,
+}
+
+impl<'a, 'ast> Visit<'ast> for UseFinder<'a> {
+    fn visit_expr_binary(&mut self, binary: &'ast syn::ExprBinary) {
+        if self.found.is_some() {
+            return;
+        }
+        let logical = matches!(binary.op, BinOp::And(_) | BinOp::Or(_));
+        let shift = matches!(
+            binary.op,
+            BinOp::Shl(_) | BinOp::Shr(_) | BinOp::ShlAssign(_) | BinOp::ShrAssign(_)
+        );
+        if !logical {
+            let (left, right) = (&*binary.left, &*binary.right);
+            if typed_by(left, self.name) {
+                self.found = self.translator.infer(right);
+            } else if typed_by(right, self.name) && !shift {
+                self.found = self.translator.infer(left);
+            }
+        }
+        syn::visit::visit_expr_binary(self, binary);
+    }
+
+    fn visit_expr_assign(&mut self, assign: &'ast syn::ExprAssign) {
+        if self.found.is_some() {
+            return;
+        }
+        if typed_by(&assign.left, self.name) {
+            self.found = self.translator.infer(&assign.right);
+        } else if typed_by(&assign.right, self.name) {
+            self.found = self.translator.infer(&assign.left);
+        }
+        syn::visit::visit_expr_assign(self, assign);
+    }
+
+    fn visit_expr_return(&mut self, expr_return: &'ast syn::ExprReturn) {
+        if let Some(value) = &expr_return.expr {
+            let return_type = self.translator.return_type;
+            if self.found.is_none() && typed_by(value, self.name) && return_type != Type::Void {
+                self.found = Some(return_type);
+            }
+        }
+        syn::visit::visit_expr_return(self, expr_return);
+    }
+}
+
+/// Whether the expression has the type of the variable, going through arithmetic
+fn typed_by(expr: &Expr, name: &str) -> bool {
+    match expr {
+        Expr::Path(path) => path.path.is_ident(name),
+        Expr::Paren(paren) => typed_by(&paren.expr, name),
+        Expr::Group(group) => typed_by(&group.expr, name),
+        Expr::Unary(unary) => typed_by(&unary.expr, name),
+        Expr::Binary(binary) => match binary_op(&binary.op) {
+            Some(BinaryOp::Shl) | Some(BinaryOp::Shr) => typed_by(&binary.left, name),
+            Some(op) if !op.is_comparison() && op != BinaryOp::And && op != BinaryOp::Or => {
+                typed_by(&binary.left, name) || typed_by(&binary.right, name)
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn stmt(kind: StmtKind, span: ast::Span) -> ast::Stmt {
+    ast::Stmt { kind, span }
+}
+
+fn unsupported<T: Spanned>(node: &T, what: &str) -> syn::Error {
+    syn::Error::new(node.span(), format!("#[blackbox] doesn't support {}", what))
+}
+
+/// The variable a parameter or `let` binds
+fn binding(pat: &Pat) -> syn::Result<String> {
+    match pat {
+        Pat::Ident(ident) if ident.by_ref.is_none() && ident.subpat.is_none() => {
+            Ok(ident.ident.to_string())
+        }
+        pat => Err(unsupported(pat, "patterns other than plain names")),
+    }
+}
+
+fn value_type(ty: &syn::Type, allow_unit: bool) -> syn::Result<Type> {
+    match ty {
+        //types passed through macro_rules come wrapped in a group
+        syn::Type::Group(group) => return value_type(&group.elem, allow_unit),
+        syn::Type::Paren(paren) => return value_type(&paren.elem, allow_unit),
+        syn::Type::Path(path) if path.qself.is_none() => {
+            match path.path.get_ident().map(|i| i.to_string()).as_deref() {
+                Some("i32") => return Ok(Type::Int),
+                Some("u32") => return Ok(Type::Uint),
+                Some("bool") => return Ok(Type::Bool),
+                _ => {}
+            }
+        }
+        syn::Type::Tuple(tuple) if allow_unit && tuple.elems.is_empty() => return Ok(Type::Void),
+        _ => {}
+    }
+    Err(syn::Error::new(
+        ty.span(),
+        "#[blackbox] only supports the types i32, u32 and bool",
+    ))
+}
+
+/// Whether the last statement gives the function its value, or control never gets past it
+fn ends_with_value(block: &syn::Block) -> bool {
+    matches!(
+        block.stmts.last(),
+        Some(Stmt::Expr(_, None))
+            | Some(Stmt::Expr(Expr::Return(_), _))
+            | Some(Stmt::Expr(Expr::Loop(_), _))
+    )
+}
+
+/// Values that have to be assigned from inside their branches
+fn needs_sink(expr: &Expr) -> bool {
+    match expr {
+        Expr::If(_) | Expr::Block(_) => true,
+        Expr::Paren(paren) => needs_sink(&paren.expr),
+        Expr::Group(group) => needs_sink(&group.expr),
+        _ => false,
+    }
+}
+
+fn binary_op(op: &BinOp) -> Option<BinaryOp> {
+    Some(match op {
+        BinOp::Add(_) => BinaryOp::Add,
+        BinOp::Sub(_) => BinaryOp::Sub,
+        BinOp::Mul(_) => BinaryOp::Mul,
+        BinOp::Div(_) => BinaryOp::Div,
+        BinOp::Rem(_) => BinaryOp::Mod,
+        BinOp::And(_) => BinaryOp::And,
+        BinOp::Or(_) => BinaryOp::Or,
+        BinOp::BitXor(_) => BinaryOp::BitXor,
+        BinOp::BitAnd(_) => BinaryOp::BitAnd,
+        BinOp::BitOr(_) => BinaryOp::BitOr,
+        BinOp::Shl(_) => BinaryOp::Shl,
+        BinOp::Shr(_) => BinaryOp::Shr,
+        BinOp::Eq(_) => BinaryOp::Eq,
+        BinOp::Lt(_) => BinaryOp::Lt,
+        BinOp::Le(_) => BinaryOp::LtEq,
+        BinOp::Ne(_) => BinaryOp::Neq,
+        BinOp::Ge(_) => BinaryOp::GtEq,
+        BinOp::Gt(_) => BinaryOp::Gt,
+        _ => return None,
+    })
+}
+
+/// The operator of `+=` and friends
+fn compound_op(op: &BinOp) -> Option<BinaryOp> {
+    Some(match op {
+        BinOp::AddAssign(_) => BinaryOp::Add,
+        BinOp::SubAssign(_) => BinaryOp::Sub,
+        BinOp::MulAssign(_) => BinaryOp::Mul,
+        BinOp::DivAssign(_) => BinaryOp::Div,
+        BinOp::RemAssign(_) => BinaryOp::Mod,
+        BinOp::BitXorAssign(_) => BinaryOp::BitXor,
+        BinOp::BitAndAssign(_) => BinaryOp::BitAnd,
+        BinOp::BitOrAssign(_) => BinaryOp::BitOr,
+        BinOp::ShlAssign(_) => BinaryOp::Shl,
+        BinOp::ShrAssign(_) => BinaryOp::Shr,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(tokens: TokenStream2) -> String {
+        let item: ItemFn = syn::parse2(tokens).unwrap();
+        expand(item).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_expand() {
+        let item: ItemFn = syn::parse2(quote! {
+            /// docs
+            pub fn add(mut a: i32, b: bool) -> u32 { a += 1; if b { a as u32 } else { 0 } }
+        })
+        .unwrap();
+        let tokens = expand(item).unwrap().to_string();
+        assert!(tokens.contains("pub fn add (a : i32 , b : bool) -> u32"));
+        assert!(tokens.contains("b\"BBOX"));
+        assert!(tokens.contains("Value :: U32 (b as u32)"));
+        assert!(tokens.contains("doc"));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert_eq!(
+            error(quote! { fn f(a: i32) -> i32 { a.abs() } }),
+            "#[blackbox] doesn't support method calls"
+        );
+        assert_eq!(
+            error(quote! { fn f(a: i64) {} }),
+            "#[blackbox] only supports the types i32, u32 and bool"
+        );
+        assert_eq!(
+            error(quote! { fn f() { 'outer: loop {} } }),
+            "#[blackbox] doesn't support loop labels"
+        );
+        assert_eq!(
+            error(quote! { fn f() { println!("hi"); } }),
+            "#[blackbox] doesn't support macros"
+        );
+        assert_eq!(
+            error(quote! { fn f(a: i32) -> i32 { g(a) } }),
+            "#[blackbox] doesn't support function calls"
+        );
+        assert_eq!(
+            error(quote! { fn f<T>(a: i32) {} }),
+            "#[blackbox] doesn't support generic functions"
+        );
+        assert!(error(quote! { fn f() -> i32 { x } }).starts_with("unknown variable 'x'"));
+        assert_eq!(
+            error(quote! { fn f() -> i32 { let a = 1; } }),
+            "#[blackbox] functions have to end with their return value"
+        );
+        assert_eq!(
+            error(quote! { fn f(a: bool) -> i32 { let b = if a { 1 }; b } }),
+            "an `if` without an `else` has no value"
+        );
+    }
+
+    #[test]
+    fn test_type_errors() {
+        //errors from the compiler speak Rust's type names and the original variable names
+        assert_eq!(
+            error(quote! { fn f(a: i32, b: u32) -> i32 { a + b } }),
+            "#[blackbox]: type mismatch: expected i32, found u32"
+        );
+        assert_eq!(
+            error(quote! { fn f(a: i32) -> bool { a } }),
+            "#[blackbox]: type mismatch: expected bool, found i32"
+        );
+    }
+}
@@ -0,0 +1,406 @@
+// Compile time assembling of biobox modules.
+//
+// biobox_asm!("load $0 #1\nhlt") and include_biobox!("modules/thing.asm") run the biobox
+// assembler while the host crate is being built and expand to the module container as a
+// `&'static [u8]`, ready for VM::load_module_bytes. Add `key = "<64 hex chars>"` after the
+// source to get an encrypted module instead, which is loaded with VM::load_encrypted_bytes.
+// Assembly errors become compile errors pointing at the source.
+//
+// biobox_module! { pub struct Hasher = "modules/hash.asm"; } goes one step further and
+// generates a wrapper struct whose `compute` method takes the module's declared inputs as
+// typed arguments and returns its declared outputs, so changing the .asm signature breaks
+// the build of every caller instead of silently passing values in the wrong registers.
+//
+// #[blackbox] on a plain Rust function compiles its body to bytecode and replaces it with
+// a call into an embedded VM, see blackbox.rs for the subset of Rust it understands.
+
+extern crate proc_macro;
+
+mod blackbox;
+
+use biobox::assembler::{assemble, AssemblyError};
+use biobox::crypt;
+use biobox::module::interface::{Param, ParamType};
+use biobox::module::Module;
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, Span, TokenStream as TokenStream2};
+use quote::quote;
+use std::path::PathBuf;
+use syn::parse::{Parse, ParseStream};
+use syn::{Attribute, Ident, LitStr, Token, Visibility};
+
+/// Assembles biobox assembly written inline into a module container
+#[proc_macro]
+pub fn biobox_asm(input: TokenStream) -> TokenStream {
+    expand(syn::parse_macro_input!(input as AsmInput), Source::Inline).into()
+}
+
+/// Assembles a biobox assembly file (relative to the crate's Cargo.toml) into a module container
+#[proc_macro]
+pub fn include_biobox(input: TokenStream) -> TokenStream {
+    expand(syn::parse_macro_input!(input as AsmInput), Source::File).into()
+}
+
+/// Generates a typed wrapper struct around a biobox assembly file with a declared interface
+#[proc_macro]
+pub fn biobox_module(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as WrapperInput);
+    match expand_wrapper(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Compiles a restricted Rust function to biobox bytecode that runs in an embedded VM
+#[proc_macro_attribute]
+pub fn blackbox(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = TokenStream2::from(attr);
+        return syn::Error::new_spanned(attr, "#[blackbox] doesn't take arguments")
+            .to_compile_error()
+            .into();
+    }
+    let item = syn::parse_macro_input!(item as syn::ItemFn);
+    match blackbox::expand(item) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// `#[attrs] pub struct Name = "path/to/module.asm";`
+struct WrapperInput {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    name: Ident,
+    path: LitStr,
+}
+
+impl Parse for WrapperInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        input.parse::<Token![struct]>()?;
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let path = input.parse()?;
+        input.parse::<Option<Token![;]>>()?;
+        Ok(WrapperInput {
+            attrs,
+            vis,
+            name,
+            path,
+        })
+    }
+}
+
+fn expand_wrapper(input: &WrapperInput) -> syn::Result<TokenStream2> {
+    let path = manifest_path(&input.path.value());
+    let text = std::fs::read_to_string(&path).map_err(|e| {
+        syn::Error::new(
+            input.path.span(),
+            format!("unable to read {}: {}", path.display(), e),
+        )
+    })?;
+    let module =
+        assemble(&text).map_err(|e| assembly_error(&input.path, &e, &Some(path.clone())))?;
+    wrapper_tokens(input, &module, &path.to_string_lossy())
+}
+
+/// Builds the wrapper struct for an assembled module
+fn wrapper_tokens(input: &WrapperInput, module: &Module, path: &str) -> syn::Result<TokenStream2> {
+    let WrapperInput {
+        attrs, vis, name, ..
+    } = input;
+    let bytes = module
+        .to_bytes()
+        .map_err(|e| syn::Error::new(input.path.span(), e.to_string()))?;
+    let bytes = Literal::byte_string(&bytes);
+
+    let mut args = vec![];
+    let mut values = vec![];
+    for param in &module.interface.inputs {
+        let arg = param_ident(param, input.path.span())?;
+        let (ty, value) = match param.ty {
+            ParamType::I32 => (
+                quote!(i32),
+                quote!(::biobox::module::interface::Value::I32(#arg)),
+            ),
+            ParamType::U32 => (
+                quote!(u32),
+                quote!(::biobox::module::interface::Value::U32(#arg)),
+            ),
+            ParamType::F32 => (
+                quote!(f32),
+                quote!(::biobox::module::interface::Value::F32(#arg)),
+            ),
+            ParamType::Bytes => (
+                quote!(&[u8]),
+                quote!(::biobox::module::interface::Value::Bytes(#arg.to_vec())),
+            ),
+        };
+        args.push(quote!(#arg: #ty));
+        values.push(value);
+    }
+
+    let mut output_types = vec![];
+    let mut conversions = vec![];
+    for param in &module.interface.outputs {
+        let (ty, variant) = match param.ty {
+            ParamType::I32 => (quote!(i32), quote!(I32)),
+            ParamType::U32 => (quote!(u32), quote!(U32)),
+            ParamType::F32 => (quote!(f32), quote!(F32)),
+            ParamType::Bytes => (quote!(::std::vec::Vec<u8>), quote!(Bytes)),
+        };
+        let message = format!("output '{}' of the module changed type", param.name);
+        conversions.push(quote! {
+            match outputs.next() {
+                ::std::option::Option::Some(::biobox::module::interface::Value::#variant(v)) => v,
+                _ => unreachable!(#message),
+            }
+        });
+        output_types.push(ty);
+    }
+    //one output comes back as it is, none or several as a tuple
+    let (return_type, result) = if output_types.len() == 1 {
+        (quote!(#(#output_types)*), quote!(#(#conversions)*))
+    } else {
+        (quote!((#(#output_types),*)), quote!((#(#conversions),*)))
+    };
+    let body = if output_types.is_empty() {
+        quote! {
+            self.vm.call(&[#(#values),*])?;
+            ::std::result::Result::Ok(())
+        }
+    } else {
+        quote! {
+            let mut outputs = self.vm.call(&[#(#values),*])?.into_iter();
+            ::std::result::Result::Ok(#result)
+        }
+    };
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis struct #name {
+            vm: ::biobox::vm::VM,
+        }
+
+        impl #name {
+            /// The module container this wrapper runs
+            pub const MODULE: &'static [u8] = {
+                const _: &[u8] = include_bytes!(#path);
+                #bytes
+            };
+
+            pub fn new() -> #name {
+                let mut vm = ::biobox::vm::VM::new();
+                vm.load_module_bytes(#name::MODULE)
+                    .expect("module was validated when it was assembled");
+                #name { vm }
+            }
+
+            /// Runs the module with its declared inputs and returns its declared outputs
+            pub fn compute(&mut self, #(#args),*) -> ::std::result::Result<#return_type, ::biobox::vm::VmError> {
+                #body
+            }
+
+            /// The vm underneath, for registering syscalls or custom opcodes
+            pub fn vm(&mut self) -> &mut ::biobox::vm::VM {
+                &mut self.vm
+            }
+        }
+
+        impl ::std::default::Default for #name {
+            fn default() -> #name {
+                #name::new()
+            }
+        }
+    })
+}
+
+fn param_ident(param: &Param, span: Span) -> syn::Result<Ident> {
+    syn::parse_str::<Ident>(&param.name).map_err(|_| {
+        syn::Error::new(
+            span,
+            format!("'{}' can't be used as a Rust argument name", param.name),
+        )
+    })
+}
+
+/// The literal the macro was given, followed by an optional `key = "..."`
+struct AsmInput {
+    literal: LitStr,
+    key: Option<LitStr>,
+}
+
+impl Parse for AsmInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let literal = input.parse()?;
+        let mut key = None;
+        if input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let name: syn::Ident = input.parse()?;
+            if name != "key" {
+                return Err(syn::Error::new(name.span(), "expected `key = \"...\"`"));
+            }
+            input.parse::<Token![=]>()?;
+            key = Some(input.parse()?);
+            input.parse::<Option<Token![,]>>()?;
+        }
+        Ok(AsmInput { literal, key })
+    }
+}
+
+#[derive(PartialEq, Copy, Clone)]
+enum Source {
+    Inline,
+    File,
+}
+
+fn expand(input: AsmInput, source: Source) -> TokenStream2 {
+    match try_expand(&input, source) {
+        Ok(tokens) => tokens,
+        Err(e) => e.to_compile_error(),
+    }
+}
+
+fn try_expand(input: &AsmInput, source: Source) -> syn::Result<TokenStream2> {
+    let (text, path) = match source {
+        Source::Inline => (input.literal.value(), None),
+        Source::File => {
+            let path = manifest_path(&input.literal.value());
+            let text = std::fs::read_to_string(&path).map_err(|e| {
+                syn::Error::new(
+                    input.literal.span(),
+                    format!("unable to read {}: {}", path.display(), e),
+                )
+            })?;
+            (text, Some(path))
+        }
+    };
+
+    let module = assemble(&text).map_err(|e| assembly_error(&input.literal, &e, &path))?;
+    let bytes = match &input.key {
+        Some(key) => {
+            let parsed = crypt::key_from_hex(&key.value()).ok_or_else(|| {
+                syn::Error::new(key.span(), "key must be 64 hex characters (32 bytes)")
+            })?;
+            module.encrypt(&parsed)
+        }
+        None => module.to_bytes(),
+    }
+    .map_err(|e| syn::Error::new(input.literal.span(), e.to_string()))?;
+
+    let literal = Literal::byte_string(&bytes);
+    Ok(match path {
+        //include_bytes! makes cargo rebuild when the assembly file changes
+        Some(path) => {
+            let path = path.to_string_lossy().into_owned();
+            quote! {{
+                const _: &[u8] = include_bytes!(#path);
+                #literal as &'static [u8]
+            }}
+        }
+        None => quote! { (#literal as &'static [u8]) },
+    })
+}
+
+fn manifest_path(relative: &str) -> PathBuf {
+    let root = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(root).join(relative)
+}
+
+/// Turns an assembly error into a compile error on the offending part of the literal
+fn assembly_error(literal: &LitStr, error: &AssemblyError, path: &Option<PathBuf>) -> syn::Error {
+    let message = match path {
+        Some(path) => format!("biobox assembly error in {}, {}", path.display(), error),
+        None => format!("biobox assembly error at {}", error),
+    };
+    let span = match path {
+        Some(_) => literal.span(),
+        None => line_span(literal, error).unwrap_or_else(|| literal.span()),
+    };
+    syn::Error::new(span, message)
+}
+
+/// Narrows the span down to the failing line when the compiler supports sub spans,
+/// which is only possible for raw strings as escapes shift the offsets around
+fn line_span(literal: &LitStr, error: &AssemblyError) -> Option<Span> {
+    let token = literal.token();
+    let text = token.to_string();
+    if !text.starts_with('r') {
+        return None;
+    }
+    let prefix = text.find('"')? + 1;
+    let value = literal.value();
+    let line_start: usize = value
+        .split('\n')
+        .take(error.line - 1)
+        .map(|line| line.len() + 1)
+        .sum();
+    let line = value.split('\n').nth(error.line - 1)?;
+    let start = prefix + line_start + error.column - 1;
+    token.subspan(start..prefix + line_start + line.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_str(tokens: TokenStream2, source: Source) -> String {
+        let input: AsmInput = syn::parse2(tokens).unwrap();
+        expand(input, source).to_string()
+    }
+
+    #[test]
+    fn test_expand_inline() {
+        let expanded = expand_str(quote! { "load $0 #1\nhlt" }, Source::Inline);
+        assert!(expanded.contains("b\"BBOX"));
+        assert!(expanded.contains("& 'static [u8]"));
+    }
+
+    #[test]
+    fn test_expand_error() {
+        let expanded = expand_str(quote! { "load $0 #1\nbogus $1" }, Source::Inline);
+        assert!(expanded.contains("compile_error"));
+        assert!(expanded.contains("line 2, column 1"));
+
+        let expanded = expand_str(quote! { "hlt", key = "abc" }, Source::Inline);
+        assert!(expanded.contains("key must be 64 hex characters"));
+
+        let expanded = expand_str(quote! { "does/not/exist.asm" }, Source::File);
+        assert!(expanded.contains("unable to read"));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let input: AsmInput = syn::parse2(quote! { "hlt", key = "00", }).unwrap();
+        assert_eq!(input.key.unwrap().value(), "00");
+        assert!(syn::parse2::<AsmInput>(quote! { "hlt", nonce = "00" }).is_err());
+    }
+
+    #[test]
+    fn test_wrapper_signature() {
+        let input: WrapperInput = syn::parse2(quote! {
+            /// docs
+            pub struct Mixer = "mixer.asm";
+        })
+        .unwrap();
+        assert_eq!(input.name, "Mixer");
+        assert_eq!(input.attrs.len(), 1);
+        let module =
+            assemble(".input a i32\n.input key bytes\n.output hash u32\n.output tag bytes #8\nhlt")
+                .unwrap();
+        let tokens = wrapper_tokens(&input, &module, "mixer.asm")
+            .unwrap()
+            .to_string();
+        assert!(tokens.contains("pub fn compute (& mut self , a : i32 , key : & [u8])"));
+        assert!(tokens.contains("(u32 , :: std :: vec :: Vec < u8 >)"));
+    }
+
+    #[test]
+    fn test_wrapper_bad_name() {
+        let input: WrapperInput = syn::parse2(quote! { struct M = "m.asm"; }).unwrap();
+        let module = assemble(".input fn i32\nhlt").unwrap();
+        let error = wrapper_tokens(&input, &module, "m.asm").unwrap_err();
+        assert!(error.to_string().contains("'fn' can't be used"));
+    }
+}
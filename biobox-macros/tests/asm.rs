@@ -0,0 +1,41 @@
+use biobox::module::interface::Value;
+use biobox::vm::VM;
+use biobox_macros::{biobox_asm, include_biobox};
+
+const ADD_ONE: &[u8] = biobox_asm!(
+    r".input value i32
+    .output result i32 $0
+    load $1 #1
+    add $0 $1 $0
+    hlt"
+);
+
+const KEY: [u8; 32] = [7; 32];
+
+#[test]
+fn test_inline_module() {
+    let mut vm = VM::new();
+    vm.load_module_bytes(ADD_ONE).unwrap();
+    assert_eq!(vm.call(&[Value::I32(41)]), Ok(vec![Value::I32(42)]));
+}
+
+#[test]
+fn test_included_module() {
+    let mut vm = VM::new();
+    vm.load_module_bytes(include_biobox!("tests/modules/double.asm"))
+        .unwrap();
+    assert_eq!(vm.call(&[Value::I32(21)]), Ok(vec![Value::I32(42)]));
+}
+
+#[test]
+fn test_encrypted_module() {
+    let bytes = include_biobox!(
+        "tests/modules/double.asm",
+        key = "0707070707070707070707070707070707070707070707070707070707070707"
+    );
+    let mut vm = VM::new();
+    //encrypted modules can't be loaded without the key
+    assert!(vm.load_module_bytes(bytes).is_err());
+    vm.load_encrypted_bytes(bytes, &KEY).unwrap();
+    assert_eq!(vm.call(&[Value::I32(5)]), Ok(vec![Value::I32(10)]));
+}
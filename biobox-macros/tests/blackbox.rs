@@ -0,0 +1,122 @@
+use biobox_macros::blackbox;
+
+// defines the same function twice, once compiled to bytecode and once as plain Rust
+macro_rules! differential {
+    ($blackbox:ident, $native:ident, fn($($arg:ident: $ty:ty),*) -> $ret:ty $body:block) => {
+        #[blackbox]
+        fn $blackbox($($arg: $ty),*) -> $ret $body
+
+        #[allow(unused_mut, unused_assignments)]
+        fn $native($($arg: $ty),*) -> $ret $body
+    };
+}
+
+differential!(gcd, native_gcd, fn(a: u32, b: u32) -> u32 {
+    let mut a = a;
+    let mut b = b;
+    while b != 0 {
+        let t = b;
+        b = a % b;
+        a = t;
+    }
+    a
+});
+
+differential!(collatz, native_collatz, fn(n: u32) -> u32 {
+    let mut n = n;
+    let mut steps = 0u32;
+    loop {
+        if n <= 1 {
+            break;
+        }
+        n = if n & 1 == 0 { n / 2 } else { 3 * n + 1 };
+        steps += 1;
+    }
+    steps
+});
+
+differential!(bits, native_bits, fn(x: u32, signed: i32) -> i32 {
+    let mut count = 0;
+    let mut i = 0;
+    while i < 32 {
+        i += 1;
+        if (x >> (i - 1)) & 1 == 0 {
+            continue;
+        }
+        count += 1;
+    }
+    let shifted = signed >> 3;
+    let mixed = (!x ^ 0x5a5a_5a5a) as i32;
+    count * 1000 + (shifted & 0xff) - (mixed & 0x7f)
+});
+
+#[test]
+fn test_matches_native() {
+    for (a, b) in [(48, 18), (17, 5), (0, 9), (1_000_000, 250)].iter() {
+        assert_eq!(gcd(*a, *b), native_gcd(*a, *b));
+    }
+    for n in [1, 7, 27, 97].iter() {
+        assert_eq!(collatz(*n), native_collatz(*n));
+    }
+    for (x, signed) in [(0, 0), (0xdead_beef, -12345), (u32::MAX, i32::MIN)].iter() {
+        assert_eq!(bits(*x, *signed), native_bits(*x, *signed));
+    }
+}
+
+#[blackbox]
+fn classify(a: i32, b: i32) -> i32 {
+    //shadowing, blocks as values and early returns
+    let a = a * 2;
+    let a = a + 1;
+    if a < 0 && b < 0 {
+        return -1;
+    }
+    let bigger = {
+        let difference = a - b;
+        difference > 0
+    };
+    if bigger {
+        1
+    } else if a == b {
+        0
+    } else {
+        2
+    }
+}
+
+#[blackbox]
+fn is_prime(n: u32) -> bool {
+    if n < 2 {
+        return false;
+    }
+    let mut d = 2;
+    while d * d <= n {
+        if n % d == 0 {
+            return false;
+        }
+        d += 1;
+    }
+    true
+}
+
+#[blackbox]
+pub fn divide(a: i32, b: i32) -> i32 {
+    a / b
+}
+
+#[test]
+fn test_control_flow() {
+    assert_eq!(classify(-5, -1), -1);
+    assert_eq!(classify(10, 3), 1);
+    assert_eq!(classify(1, 3), 0);
+    assert_eq!(classify(1, 30), 2);
+    let primes: Vec<u32> = (0..30).filter(|n| is_prime(*n)).collect();
+    assert_eq!(primes, vec![2, 3, 5, 7, 11, 13, 17, 19, 23, 29]);
+}
+
+#[test]
+#[should_panic(expected = "#[blackbox] function `divide` failed")]
+fn test_divide_by_zero_panics() {
+    assert_eq!(divide(7, 2), 3);
+    divide(1, 0);
+}
@@ -0,0 +1,4 @@
+.input value i32
+.output doubled i32 $1
+add $0 $0 $1
+hlt
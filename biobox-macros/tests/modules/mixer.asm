@@ -0,0 +1,15 @@
+.input seed u32
+.input key bytes
+.input scale f32
+.output checksum u32 $21
+.output tagged bytes #4
+.output scaled f32 $1
+lui $3 #256
+mlen $3 $4
+lui $5 #4096
+add $0 $4 $21
+ldb $3 $6
+stb $6 $5
+inc $5
+stb $4 $5
+hlt
@@ -0,0 +1,27 @@
+use biobox_macros::biobox_module;
+
+biobox_module! {
+    /// Adds the key length to the seed and tags the output with the first key byte
+    pub struct Mixer = "tests/modules/mixer.asm";
+}
+
+biobox_module! {
+    struct Doubler = "tests/modules/double.asm";
+}
+
+#[test]
+fn test_typed_wrapper() {
+    let mut mixer = Mixer::new();
+    let (checksum, tagged, scale): (u32, Vec<u8>, f32) = mixer.compute(40, b"ab", 0.5).unwrap();
+    assert_eq!(checksum, 42);
+    assert_eq!(tagged, vec![b'a', 2]);
+    assert_eq!(scale, 0.5);
+}
+
+#[test]
+fn test_single_output_wrapper() {
+    let mut doubler = Doubler::default();
+    let doubled: i32 = doubler.compute(-4).unwrap();
+    assert_eq!(doubled, -8);
+    assert!(Doubler::MODULE.starts_with(b"BBOX"));
+}
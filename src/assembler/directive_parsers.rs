@@ -2,7 +2,10 @@ use super::instruction_parsers::AssemblerInstruction;
 use super::operand_parsers::operand;
 use super::Token;
 use nom::types::CompleteStr;
-use nom::{alpha1, multispace};
+use nom::{alpha1, multispace, space, Context, Err, ErrorKind, IResult};
+
+/// Directives the assembler knows what to do with
+pub const DIRECTIVES: &[&str] = &["input", "output", "data"];
 
 /// Directive format
 /// .directivename
@@ -17,13 +20,13 @@ named!(pub directive<CompleteStr, AssemblerInstruction>,
     )
 );
 
+// `..name`, the form directives were first written in, still parses
 named!(directive_format<CompleteStr, AssemblerInstruction>,
     do_parse!(
-        tag!(".") >>
-        name: directive_declaration >>
-        o1: opt!(operand) >>
-        o2: opt!(operand) >>
-        o3: opt!(operand) >>
+        name: alt!(preceded!(tag!("."), directive_declaration) | directive_declaration) >>
+        o1: opt!(directive_operand) >>
+        o2: opt!(directive_operand) >>
+        o3: opt!(directive_operand) >>
         opt!(multispace) >>
         (
             AssemblerInstruction{
@@ -48,6 +51,60 @@ named!(directive_declaration<CompleteStr, Token>,
     )
 );
 
+// Directives take bare words and strings as well as the usual operands:
+// .input key bytes
+// .output hash u32 $21
+// .data greeting "hello"
+named!(directive_operand<CompleteStr, Token>,
+    alt!(
+        operand |
+        string_operand |
+        identifier
+    )
+);
+
+// A quoted string with \" \\ \n \r \t and \0 escapes
+pub fn string_operand(input: CompleteStr) -> IResult<CompleteStr, Token> {
+    let error = || Err(Err::Error(Context::Code(input, ErrorKind::Custom(0))));
+    let start = input.0.trim_start_matches([' ', '\t']);
+    if !start.starts_with('"') {
+        return error();
+    }
+    let mut value = String::new();
+    let mut chars = start[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let rest = CompleteStr(&start[i + 2..]);
+                return Ok((rest, Token::StringOperand { value }));
+            }
+            '\\' => match chars.next() {
+                Some((_, '"')) => value.push('"'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 'r')) => value.push('\r'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, '0')) => value.push('\0'),
+                _ => return error(),
+            },
+            c => value.push(c),
+        }
+    }
+    //never closed
+    error()
+}
+
+// Bare words only count as operands when they are on the same line
+named!(pub identifier<CompleteStr, Token>,
+    do_parse!(
+        opt!(space) >>
+        name: take_while1!(|c: char| c.is_alphanumeric() || c == '_') >>
+        (
+            Token::Identifier{name: name.to_string()}
+        )
+    )
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -89,4 +146,51 @@ mod tests {
         let result = directive(CompleteStr("test"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_directive_identifiers() {
+        let (rest, token) = directive(CompleteStr(".input key bytes\nhlt\n")).unwrap();
+        assert_eq!(rest, CompleteStr("hlt\n"));
+        assert_eq!(
+            token.operand1,
+            Some(Token::Identifier {
+                name: "key".to_string()
+            })
+        );
+        assert_eq!(
+            token.operand2,
+            Some(Token::Identifier {
+                name: "bytes".to_string()
+            })
+        );
+        assert_eq!(token.operand3, None);
+
+        let (_, token) = directive(CompleteStr(".output hash u32 $21")).unwrap();
+        assert_eq!(token.operand3, Some(Token::Register { reg_num: 21 }));
+    }
+
+    #[test]
+    fn test_directive_strings() {
+        let (rest, token) = directive(CompleteStr(".data hi \"a \\\"b\\\" c\\n\"\nhlt")).unwrap();
+        assert_eq!(rest, CompleteStr("hlt"));
+        assert_eq!(
+            token.operand2,
+            Some(Token::StringOperand {
+                value: "a \"b\" c\n".to_string()
+            })
+        );
+        assert_eq!(token.to_string(), ".data hi \"a \\\"b\\\" c\\n\"");
+        //unterminated strings and unknown escapes don't parse
+        assert!(string_operand(CompleteStr("\"open")).is_err());
+        assert!(string_operand(CompleteStr("\"\\q\"")).is_err());
+        assert_eq!(
+            string_operand(CompleteStr(" \"\"")),
+            Ok((
+                CompleteStr(""),
+                Token::StringOperand {
+                    value: String::new()
+                }
+            ))
+        );
+    }
 }
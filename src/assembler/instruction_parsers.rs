@@ -1,14 +1,17 @@
-use super::directive_parsers::directive;
+use super::directive_parsers::{directive, DIRECTIVES};
 use super::label_parsers::*;
 use super::opcode_parsers::*;
 use super::operand_parsers::operand;
-use super::Token;
+use super::symbols::SymbolTable;
+use super::{OpcodeExtensions, Token};
 use crate::instructions::Opcode;
+use crate::verify::{layout, Operand};
 use nom::multispace;
 
 use nom::types::CompleteStr;
+use std::fmt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
     pub label: Option<Token>,
@@ -19,25 +22,36 @@ pub struct AssemblerInstruction {
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// The instruction's 4 bytes of bytecode, or why it has none. The operands have to
+    /// be the ones the opcode takes, registers below 32 and immediates in 16 bits.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
         let mut results = vec![];
-        match self.opcode {
-            Some(Token::Op { code }) => match code {
-                _ => {
-                    results.push(code as u8);
-                }
-            },
-            _ => {
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
+        match &self.opcode {
+            Some(Token::Op { code }) => {
+                self.check_operands(*code)?;
+                results.push(*code as u8);
+            }
+            Some(Token::CustomOp {
+                code: Some(code), ..
+            }) => {
+                results.push(*code);
             }
+            Some(Token::CustomOp { name, code: None }) => {
+                return Err(format!("unknown mnemonic '{}'", name));
+            }
+            _ => return Err("Non-opcode found in opcode field".to_string()),
         };
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            match operand {
-                Some(t) => AssemblerInstruction::extract_operand(t, &mut results),
-                None => {}
-            }
+        for t in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            AssemblerInstruction::extract_operand(t, &mut results)?;
+        }
+        //host opcodes take whatever operands fit
+        if results.len() > 4 {
+            return Err("operands don't fit in the instruction".to_string());
         }
 
         //pad any empty space out of the total 32 bits with 0
@@ -45,17 +59,23 @@ impl AssemblerInstruction {
             results.push(0);
         }
 
-        results
+        Ok(results)
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>) {
+    fn extract_operand(t: &Token, results: &mut Vec<u8>) -> Result<(), String> {
         match t {
             //Add a register token to the results if found
             Token::Register { reg_num } => {
+                if *reg_num >= 32 {
+                    return Err(format!("register ${} does not exist", reg_num));
+                }
                 results.push(*reg_num);
             }
             //Add an integer token to the results if found
             Token::IntegerOperand { value } => {
+                if *value < 0 || *value > i32::from(u16::MAX) {
+                    return Err(format!("#{} doesn't fit in 16 bits", value));
+                }
                 let converted = *value as u16;
                 let byte1 = converted;
                 let byte2 = converted >> 8;
@@ -64,17 +84,166 @@ impl AssemblerInstruction {
                 results.push(byte2 as u8);
                 results.push(byte1 as u8);
             }
-            _ => {
-                //opcodes (load, jmp, add, etc..) should not be in an operand field (after another opcode)
-                println!("Opcode found in operand field");
-                std::process::exit(1);
-            }
+            //opcodes (load, jmp, add, etc..) should not be in an operand field (after another opcode)
+            _ => return Err(format!("'{}' found in operand field", t)),
         };
+        Ok(())
+    }
+
+    // the operands have to be the registers and immediates the opcode takes, in order,
+    // so every instruction is 4 bytes and the label offsets stay right
+    fn check_operands(&self, code: Opcode) -> Result<(), String> {
+        let kinds = layout(code);
+        //the two bytes of an immediate are one operand
+        let expected: Vec<Operand> = kinds
+            .iter()
+            .enumerate()
+            .filter(|(i, kind)| match kind {
+                Operand::Register => true,
+                Operand::Immediate => *i == 0 || kinds[i - 1] != Operand::Immediate,
+                Operand::Unused => false,
+            })
+            .map(|(_, kind)| *kind)
+            .collect();
+        let operands: Vec<&Token> = [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+            .collect();
+        let matches = operands.len() == expected.len()
+            && operands.iter().zip(&expected).all(|(operand, kind)| {
+                matches!(
+                    (operand, kind),
+                    (Token::Register { .. }, Operand::Register)
+                        | (Token::IntegerOperand { .. }, Operand::Immediate)
+                        | (Token::LabelUsage { .. }, Operand::Immediate)
+                )
+            });
+        if matches {
+            return Ok(());
+        }
+        let shape: Vec<&str> = expected
+            .iter()
+            .map(|kind| match kind {
+                Operand::Register => "$register",
+                _ => "#immediate",
+            })
+            .collect();
+        let name = Token::Op { code }.to_string();
+        if shape.is_empty() {
+            Err(format!("{} takes no operands", name))
+        } else {
+            Err(format!("{} takes {}", name, shape.join(" ")))
+        }
     }
 
     pub fn is_valid(&self) -> bool {
-        //if there is no opcode then there has to be a directive TODO: CHECK FOR DIRECTIVES
-        self.opcode != Some(Token::Op { code: Opcode::IGL }) && self.opcode != None
+        //if there is no opcode then there has to be a directive
+        match (&self.opcode, &self.directive) {
+            (Some(Token::Op { code }), _) => *code != Opcode::IGL,
+            //custom mnemonics are only valid once an extension has given them a code
+            (Some(Token::CustomOp { code, .. }), _) => code.is_some(),
+            (None, Some(Token::Directive { name })) => DIRECTIVES.contains(&name.as_str()),
+            //a label on a line of its own points at the next instruction
+            (None, None) => self.label.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Whether the instruction takes up 4 bytes of bytecode, unlike directives and bare labels
+    pub fn has_code(&self) -> bool {
+        self.opcode.is_some()
+    }
+
+    /// The name of the label declared on this line, if any
+    pub fn label_name(&self) -> Option<&str> {
+        match &self.label {
+            Some(Token::LabelDeclaration { name }) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Swaps every @label operand for the 16 bit offset it points at
+    pub fn resolve_labels(&mut self, symbols: &SymbolTable) -> Result<(), String> {
+        for operand in [&mut self.operand1, &mut self.operand2, &mut self.operand3]
+            .iter_mut()
+            .filter_map(|o| o.as_mut())
+        {
+            if let Token::LabelUsage { name } = operand {
+                let offset = symbols
+                    .symbol_value(name)
+                    .ok_or_else(|| format!("unknown label '{}'", name))?;
+                if offset > u32::from(u16::MAX) {
+                    return Err(format!("label '{}' is out of the 16 bit range", name));
+                }
+                *operand = Token::IntegerOperand {
+                    value: offset as i32,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether an operand still refers to a label that hasn't been resolved
+    pub fn has_label_usage(&self) -> bool {
+        [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .any(|o| matches!(o, Some(Token::LabelUsage { .. })))
+    }
+
+    /// Whether this is a `.data` line
+    pub fn is_data(&self) -> bool {
+        match &self.directive {
+            Some(Token::Directive { name }) => name == "data",
+            _ => false,
+        }
+    }
+
+    /// The name and string of a `.data` line, None if it's missing either
+    pub fn data(&self) -> Option<(&str, &str)> {
+        if !self.is_data() {
+            return None;
+        }
+        match (&self.operand1, &self.operand2, &self.operand3) {
+            (Some(Token::Identifier { name }), Some(Token::StringOperand { value }), None) => {
+                Some((name, value))
+            }
+            _ => None,
+        }
+    }
+
+    /// Directive lines only describe the program and don't produce any bytecode
+    pub fn is_directive(&self) -> bool {
+        self.opcode.is_none() && self.directive.is_some()
+    }
+
+    /// Fills in the opcode byte of a custom mnemonic from the host's extensions
+    pub fn resolve_extensions(&mut self, extensions: &OpcodeExtensions) {
+        if let Some(Token::CustomOp { name, code }) = &mut self.opcode {
+            *code = extensions.lookup(name);
+        }
+    }
+}
+
+// Prints the instruction back out as a line of assembly
+impl fmt::Display for AssemblerInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        for token in [&self.label, &self.directive, &self.opcode]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            parts.push(token.to_string());
+        }
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            parts.push(token.to_string());
+        }
+        write!(f, "{}", parts.join(" "))
     }
 }
 
@@ -82,8 +251,8 @@ impl AssemblerInstruction {
 named!(pub instruction<CompleteStr, AssemblerInstruction>,
     do_parse!(
         ins: alt!(
-            instruction_format |
-            directive
+            directive |
+            instruction_format
         ) >>
         (
             ins
@@ -93,10 +262,11 @@ named!(pub instruction<CompleteStr, AssemblerInstruction>,
 
 /// Handles instructions in the following format:
 /// LOAD $0 #42
+/// loop: JMP $0
 named!(instruction_format<CompleteStr, AssemblerInstruction>,
     do_parse!(
-        o: opt!(opcode) >>
         l: opt!(label_declaration) >>
+        o: opt!(mnemonic) >>
         o1: opt!(operand) >>
         o2: opt!(operand) >>
         o3: opt!(operand) >>
@@ -138,4 +308,47 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn test_custom_instruction() {
+        let (_, mut instruction) = instruction_format(CompleteStr("myop $1 $2\n")).unwrap();
+        //unresolved custom mnemonics can't be assembled yet
+        assert!(!instruction.is_valid());
+        let mut extensions = OpcodeExtensions::new();
+        extensions.register("myop", 210).unwrap();
+        instruction.resolve_extensions(&extensions);
+        assert!(instruction.is_valid());
+        assert_eq!(instruction.to_bytes(), Ok(vec![210, 1, 2, 0]));
+
+        //unknown mnemonics are an error rather than an illegal opcode
+        let (_, instruction) = instruction_format(CompleteStr("nope $1\n")).unwrap();
+        assert_eq!(
+            instruction.to_bytes(),
+            Err("unknown mnemonic 'nope'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_labeled_instruction() {
+        let (rest, mut instruction) =
+            instruction_format(CompleteStr("top: load $1 @end\n")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(instruction.label_name(), Some("top"));
+        assert!(instruction.has_label_usage());
+        assert_eq!(instruction.to_string(), "top: load $1 @end");
+
+        let mut symbols = SymbolTable::new();
+        assert_eq!(
+            instruction.resolve_labels(&symbols),
+            Err("unknown label 'end'".to_string())
+        );
+        symbols.add_symbol("end", 260);
+        assert!(instruction.resolve_labels(&symbols).is_ok());
+        assert_eq!(instruction.to_bytes(), Ok(vec![1, 1, 1, 4]));
+
+        //a label on its own line is valid but takes no space
+        let (_, instruction) = instruction_format(CompleteStr("end:\n")).unwrap();
+        assert!(instruction.is_valid());
+        assert!(!instruction.has_code());
+    }
 }
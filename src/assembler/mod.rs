@@ -1,4 +1,15 @@
-use crate::instructions::Opcode;
+use self::program_parsers::program;
+use self::symbols::SymbolTable;
+use crate::crypt::Key;
+use crate::instructions::{Opcode, CUSTOM_OPCODES};
+use crate::module::interface::Interface;
+use crate::module::source_map::SourceMap;
+use crate::module::Module;
+
+use nom::types::CompleteStr;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 
 pub mod directive_parsers;
 pub mod instruction_parsers;
@@ -7,15 +18,204 @@ pub mod opcode_parsers;
 pub mod operand_parsers;
 pub mod program_parsers;
 pub mod register_parsers;
+pub mod symbols;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Op { code: Opcode },
+    // mnemonic that is not a builtin opcode, code is filled in from the OpcodeExtensions
+    CustomOp { name: String, code: Option<u8> },
     Register { reg_num: u8 },
     IntegerOperand { value: i32 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
+    // bare word operand used by directives, like the name and type in `.input key bytes`
+    Identifier { name: String },
+    // quoted string of a `.data` directive, escapes already resolved
+    StringOperand { value: String },
+}
+
+// Prints a token the way it is written in assembly
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Op { code } => write!(f, "{}", format!("{:?}", code).to_lowercase()),
+            Token::CustomOp { name, .. } => write!(f, "{}", name),
+            Token::Register { reg_num } => write!(f, "${}", reg_num),
+            Token::IntegerOperand { value } => write!(f, "#{}", value),
+            Token::LabelDeclaration { name } => write!(f, "{}:", name),
+            Token::LabelUsage { name } => write!(f, "@{}", name),
+            Token::Directive { name } => write!(f, ".{}", name),
+            Token::Identifier { name } => write!(f, "{}", name),
+            Token::StringOperand { value } => write!(f, "{:?}", value),
+        }
+    }
+}
+
+/// Mnemonics for host defined opcodes, so `myop $1 $2` can be assembled into the
+/// opcode byte the host registered with `VM::register_opcode`
+#[derive(Debug, Default, Clone)]
+pub struct OpcodeExtensions {
+    mnemonics: HashMap<String, u8>,
+}
+
+impl OpcodeExtensions {
+    pub fn new() -> OpcodeExtensions {
+        OpcodeExtensions {
+            mnemonics: HashMap::new(),
+        }
+    }
+
+    /// Adds a mnemonic (case insensitive) for a code in the 200-249 range
+    pub fn register(&mut self, name: &str, code: u8) -> Result<(), &'static str> {
+        if !CUSTOM_OPCODES.contains(&code) {
+            return Err("custom opcodes must be in the range 200-249");
+        }
+        if Opcode::from(CompleteStr(name)) != Opcode::IGL {
+            return Err("mnemonic is already taken by a builtin opcode");
+        }
+        self.mnemonics.insert(name.to_lowercase(), code);
+        Ok(())
+    }
+
+    /// Looks up the opcode byte for a custom mnemonic
+    pub fn lookup(&self, name: &str) -> Option<u8> {
+        self.mnemonics.get(&name.to_lowercase()).cloned()
+    }
+}
+
+/// Where and why assembling a source file failed. Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblyError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl Error for AssemblyError {}
+
+/// Assembles a whole source file into a module, stopping at the first line that fails
+pub fn assemble(source: &str) -> Result<Module, AssemblyError> {
+    assemble_with_extensions(source, &OpcodeExtensions::new())
+}
+
+/// Same as assemble, with custom mnemonics resolved against the host's extensions
+pub fn assemble_with_extensions(
+    source: &str,
+    extensions: &OpcodeExtensions,
+) -> Result<Module, AssemblyError> {
+    assemble_mapped(source, extensions).map(|(module, _)| module)
+}
+
+/// Same as assemble, along with the line and column every instruction came from
+pub fn assemble_with_source_map(source: &str) -> Result<(Module, SourceMap), AssemblyError> {
+    assemble_mapped(source, &OpcodeExtensions::new())
+}
+
+/// Same as assemble, with the source map kept in the module's debug section under the
+/// file's name. Module::strip takes it back out.
+pub fn assemble_with_debug_info(source: &str, file: &str) -> Result<Module, AssemblyError> {
+    let (module, mut source_map) = assemble_with_source_map(source)?;
+    source_map.set_file(file);
+    Ok(Module {
+        source_map: Some(source_map),
+        ..module
+    })
+}
+
+fn assemble_mapped(
+    source: &str,
+    extensions: &OpcodeExtensions,
+) -> Result<(Module, SourceMap), AssemblyError> {
+    let mut interface = Interface::new();
+    let mut symbols = SymbolTable::new();
+    let mut offset = 0;
+    let mut data_offset = 0;
+    let mut lines = vec![];
+    //every line is parsed on its own so errors can point at where they happened
+    for (index, line) in source.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let column = line.len() - line.trim_start().len() + 1;
+        let error = |column, message| AssemblyError {
+            line: index + 1,
+            column,
+            message,
+        };
+        let (rest, parsed) = program(CompleteStr(trimmed))
+            .map_err(|_| error(column, "unable to parse instruction".to_string()))?;
+        if !rest.is_empty() {
+            return Err(error(
+                column + trimmed.len() - rest.len(),
+                format!("unexpected '{}'", rest),
+            ));
+        }
+        //first pass, labels may be used before they are declared
+        offset = parsed
+            .declare_labels(&mut symbols, offset)
+            .map_err(|message| error(column, message))?;
+        data_offset = parsed
+            .declare_data(&mut symbols, data_offset)
+            .map_err(|message| error(column, message))?;
+        lines.push((index + 1, column, trimmed, parsed));
+    }
+
+    let mut code = vec![];
+    let mut data = vec![];
+    let mut source_map = SourceMap::new();
+    for (line, column, trimmed, mut parsed) in lines {
+        let error = |message| AssemblyError {
+            line,
+            column,
+            message,
+        };
+        parsed.resolve_labels(&symbols).map_err(error)?;
+        parsed.resolve_extensions(extensions);
+        let bytes = parsed.to_bytes().map_err(error)?;
+        if !parsed.is_valid() {
+            return Err(error(format!(
+                "invalid opcode or operands in '{}'",
+                trimmed
+            )));
+        }
+        parsed
+            .add_to_interface(&mut interface)
+            .map_err(|e| error(e.to_string()))?;
+        for pc in (code.len()..code.len() + bytes.len()).step_by(4) {
+            source_map.push(pc, line as u32, column as u32);
+        }
+        if !bytes.is_empty() {
+            source_map.set_text(line as u32, trimmed);
+        }
+        code.extend_from_slice(&bytes);
+        data.append(&mut parsed.data());
+    }
+    Ok((Module::with_data(code, interface, data), source_map))
+}
+
+/// Assembles with a random opcode encoding picked by the seed and the key, the table is
+/// stored encrypted with the key and the module has to be loaded through Module::decrypt
+pub fn assemble_permuted(source: &str, seed: u64, key: &Key) -> Result<Module, AssemblyError> {
+    Ok(assemble(source)?.permute(seed, key))
+}
+
+/// Assembles with every LOAD immediate and the data section masked by the seed, so
+/// neither shows up in the module as written. See module::masks.
+pub fn assemble_masked(source: &str, seed: u64) -> Result<Module, AssemblyError> {
+    Ok(assemble(source)?.mask(seed))
 }
 
 // #[derive(Debug)]
@@ -37,3 +237,156 @@ pub enum Token {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcode_extensions() {
+        let mut extensions = OpcodeExtensions::new();
+        assert!(extensions.register("myop", 200).is_ok());
+        assert!(extensions.register("other", 199).is_err());
+        assert!(extensions.register("ADD", 201).is_err());
+        assert_eq!(extensions.lookup("MYOP"), Some(200));
+        assert_eq!(extensions.lookup("other"), None);
+    }
+
+    #[test]
+    fn test_assemble() {
+        let module = assemble(".input a i32\n\n  inc $0\nhlt\n").unwrap();
+        assert_eq!(module.code, vec![19, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(module.interface.inputs.len(), 1);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble("load $0 #1\n  nope $1\n"),
+            Err(AssemblyError {
+                line: 2,
+                column: 3,
+                message: "unknown mnemonic 'nope'".to_string()
+            })
+        );
+        let error = assemble("hlt\nload $0 #1 %\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 12));
+        let error = assemble(".input a i32\n.input a u32\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 1));
+        assert_eq!(error.message, "'a' is declared twice");
+    }
+
+    #[test]
+    fn test_assemble_operand_shapes() {
+        let error = assemble("load $0 #1 #2\nhlt\n").unwrap_err();
+        assert_eq!(error.line, 1);
+        assert_eq!(error.message, "load takes $register #immediate");
+        let error = assemble("hlt\nadd $1 #5 $2\n").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.message, "add takes $register $register $register");
+        assert_eq!(
+            assemble("hlt $1\n").unwrap_err().message,
+            "hlt takes no operands"
+        );
+        assert_eq!(
+            assemble("inc $40\n").unwrap_err().message,
+            "register $40 does not exist"
+        );
+        assert_eq!(
+            assemble("load $0 #70000\n").unwrap_err().message,
+            "#70000 doesn't fit in 16 bits"
+        );
+        //numbers too big to parse at all are an error rather than a panic
+        assert_eq!(assemble("load $0 #99999999999\n").unwrap_err().line, 1);
+        assert_eq!(assemble("inc $300\n").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let module = assemble("load $0 @end\njmp $0\nload $1 #5\nend: hlt\n").unwrap();
+        assert_eq!(&module.code[..4], &[1, 0, 0, 12]);
+        let error = assemble("hlt\n  load $0 @nowhere\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 3));
+        assert_eq!(error.message, "unknown label 'nowhere'");
+        let error = assemble("x: hlt\nx: hlt\n").unwrap_err();
+        assert_eq!(error.message, "label 'x' is declared twice");
+    }
+
+    #[test]
+    fn test_assemble_data() {
+        let module =
+            assemble(".data a \"ab\"\n.data b \"\\0\\n\"\nload $0 @b\nload $1 @a\nhlt\n").unwrap();
+        assert_eq!(module.data, vec![b'a', b'b', 0, b'\n']);
+        //data labels are offsets into the data section
+        assert_eq!(&module.code[..8], &[1, 0, 0, 2, 1, 1, 0, 0]);
+
+        let error = assemble("a: hlt\n  .data a \"x\"\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 3));
+        assert_eq!(error.message, "label 'a' is declared twice");
+        let error = assemble(".data a\n").unwrap_err();
+        assert_eq!(error.message, "'.data' takes a name and a string");
+        assert!(assemble(".data a \"x\" $1\n").is_err());
+    }
+
+    #[test]
+    fn test_assemble_with_source_map() {
+        use crate::module::source_map::SourceLocation;
+
+        let source = ".input a i32\n\n  inc $0\nloop: jmp $1\n\n.data x \"y\"\n  hlt\n";
+        let (module, map) = assemble_with_source_map(source).unwrap();
+        assert_eq!(module, assemble(source).unwrap());
+        let lines: Vec<(usize, u32, u32)> = map
+            .entries()
+            .iter()
+            .map(|(pc, SourceLocation { line, column })| (*pc, *line, *column))
+            .collect();
+        assert_eq!(lines, vec![(0, 3, 3), (4, 4, 1), (8, 7, 3)]);
+        //only lines with code keep their text
+        assert_eq!(map.text(4), Some("loop: jmp $1"));
+        assert_eq!(map.text(1), None);
+
+        let module = assemble_with_debug_info(source, "loop.asm").unwrap();
+        let stored = module.source_map.as_ref().unwrap();
+        assert_eq!(stored.describe(5).unwrap(), "loop.asm:4:1: loop: jmp $1");
+        assert_eq!(module.strip(), assemble(source).unwrap());
+    }
+
+    #[test]
+    fn test_assemble_permuted() {
+        use crate::module::interface::Value;
+        use crate::vm::VM;
+
+        //sums 1..=n with a loop, so jumps and comparisons go through the table too
+        let source = ".input n i32\n.output sum i32 $1\n\
+                      load $1 #0\nload $2 #1\nload $3 @top\n\
+                      top: add $1 $2 $1\ninc $2\nlteq $2 $0\njeq $3\nhlt\n";
+        let key = [9; 32];
+        let plain = assemble(source).unwrap();
+        let first = assemble_permuted(source, 1, &key)
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        let second = assemble_permuted(source, 2, &key)
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        assert_ne!(first, second);
+        assert_ne!(first, plain.to_bytes().unwrap());
+        //seeds are reproducible, only the nonce of the sealed table changes
+        assert_eq!(
+            assemble_permuted(source, 1, &key).unwrap(),
+            assemble_permuted(source, 1, &key).unwrap()
+        );
+
+        let run = |module: &Module| {
+            let mut vm = VM::new();
+            vm.load_module(module);
+            vm.call(&[Value::I32(100)]).unwrap()
+        };
+        let expected = vec![Value::I32(5050)];
+        assert_eq!(run(&plain), expected);
+        for bytes in [first, second].iter() {
+            assert_eq!(run(&Module::decrypt(bytes, &key).unwrap()), expected);
+        }
+    }
+}
@@ -15,6 +15,23 @@ named!(pub opcode<CompleteStr, Token>,
         )
 );
 
+// Like opcode, but a mnemonic that isn't builtin is kept by name for the opcode extensions
+named!(pub mnemonic<CompleteStr, Token>,
+        do_parse!(
+            name: alpha1 >> ( mnemonic_token(name) )
+        )
+);
+
+fn mnemonic_token(name: CompleteStr) -> Token {
+    match Opcode::from(name) {
+        Opcode::IGL => Token::CustomOp {
+            name: name.to_lowercase(),
+            code: None,
+        },
+        code => Token::Op { code },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,4 +73,20 @@ mod tests {
         let (_, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::IGL });
     }
+
+    #[test]
+    fn test_mnemonic_parse() {
+        let (_, token) = mnemonic(CompleteStr("ADD")).unwrap();
+        assert_eq!(token, Token::Op { code: Opcode::ADD });
+
+        //unknown mnemonics are kept for the opcode extensions to resolve
+        let (_, token) = mnemonic(CompleteStr("MyOp")).unwrap();
+        assert_eq!(
+            token,
+            Token::CustomOp {
+                name: "myop".to_string(),
+                code: None
+            }
+        );
+    }
 }
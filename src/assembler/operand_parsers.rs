@@ -1,6 +1,7 @@
 use nom::digit;
 use nom::types::CompleteStr;
 
+use super::label_parsers::label_usage;
 use super::register_parsers::register;
 use super::Token;
 
@@ -10,9 +11,9 @@ named!(pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("#") >>
-            reg_num: digit >>
+            value: map_res!(digit, |digits: CompleteStr| digits.parse::<i32>()) >>
             (
-                Token::IntegerOperand{value: reg_num.parse::<i32>().unwrap()}
+                Token::IntegerOperand{value}
             )
         )
     )
@@ -21,7 +22,8 @@ named!(pub integer_operand<CompleteStr, Token>,
 named!(pub operand<CompleteStr, Token>,
     alt!(
         integer_operand |
-        register
+        register |
+        label_usage
     )
 );
 
@@ -42,4 +44,10 @@ mod tests {
         let result = integer_operand(CompleteStr("10"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_integer_operand_overflow() {
+        //too big for an i32 is a parse error rather than a panic
+        assert!(integer_operand(CompleteStr("#99999999999")).is_err());
+    }
 }
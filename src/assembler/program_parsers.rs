@@ -1,24 +1,150 @@
 use super::instruction_parsers::{instruction, AssemblerInstruction};
+use super::symbols::SymbolTable;
+use super::{OpcodeExtensions, Token};
+use crate::module::interface::{Interface, InterfaceError, ParamType};
+use crate::module::Module;
 
 use nom::types::CompleteStr;
+use std::fmt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program {
     instructions: Vec<AssemblerInstruction>,
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    pub fn new(instructions: Vec<AssemblerInstruction>) -> Program {
+        Program { instructions }
+    }
+
+    pub fn instructions(&self) -> &[AssemblerInstruction] {
+        &self.instructions
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
         let mut program = vec![];
+        for instruction in self.instructions.iter().filter(|i| i.has_code()) {
+            program.append(&mut instruction.to_bytes()?);
+        }
+        Ok(program)
+    }
+
+    /// Collects the `.input` and `.output` directives into the module's interface
+    /// .input <name> <type> [$register]
+    /// .output <name> <type> [$register | #buffer capacity]
+    pub fn interface(&self) -> Result<Interface, InterfaceError> {
+        let mut interface = Interface::new();
+        self.add_to_interface(&mut interface)?;
+        Ok(interface)
+    }
+
+    /// Adds this program's declarations to an interface that may already hold others
+    pub fn add_to_interface(&self, interface: &mut Interface) -> Result<(), InterfaceError> {
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes());
+            let directive = match &instruction.directive {
+                Some(Token::Directive { name }) if name == "input" || name == "output" => name,
+                _ => continue,
+            };
+            let (name, ty) = match (&instruction.operand1, &instruction.operand2) {
+                (Some(Token::Identifier { name }), Some(Token::Identifier { name: ty })) => {
+                    (name, ty)
+                }
+                _ => return Err(InterfaceError::MissingOperand(format!(".{}", directive))),
+            };
+            let ty =
+                ParamType::from_name(ty).ok_or_else(|| InterfaceError::UnknownType(ty.clone()))?;
+            let (register, capacity) = match &instruction.operand3 {
+                None => (None, 0),
+                Some(Token::Register { reg_num }) => (Some(*reg_num), 0),
+                Some(Token::IntegerOperand { value }) if directive == "output" && *value >= 0 => {
+                    (None, *value as u32)
+                }
+                Some(_) => return Err(InterfaceError::BadLocation(name.clone())),
+            };
+            if directive == "input" {
+                interface.add_input(name, ty, register)?;
+            } else {
+                interface.add_output(name, ty, register, capacity)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The bytes of every `.data` line, in order
+    pub fn data(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for (_, value) in self.instructions.iter().filter_map(|i| i.data()) {
+            data.extend_from_slice(value.as_bytes());
+        }
+        data
+    }
+
+    /// Assembles the program along with its declared interface into a module
+    pub fn to_module(&self) -> Result<Module, String> {
+        let interface = self.interface().map_err(|e| e.to_string())?;
+        Ok(Module::with_data(self.to_bytes()?, interface, self.data()))
+    }
+
+    /// Resolves every custom mnemonic in the program against the host's extensions
+    pub fn resolve_extensions(&mut self, extensions: &OpcodeExtensions) {
+        for instruction in &mut self.instructions {
+            instruction.resolve_extensions(extensions);
+        }
+    }
+
+    /// First pass: the byte offset of every declared label, starting from `offset`.
+    /// Returns the offset just past the program.
+    pub fn declare_labels(&self, symbols: &mut SymbolTable, offset: u32) -> Result<u32, String> {
+        let mut offset = offset;
+        for instruction in &self.instructions {
+            if let Some(name) = instruction.label_name() {
+                if !symbols.add_symbol(name, offset) {
+                    return Err(format!("label '{}' is declared twice", name));
+                }
+            }
+            if instruction.has_code() {
+                offset += 4;
+            }
+        }
+        Ok(offset)
+    }
+
+    /// First pass for `.data <name> "<string>"`, declares each name at the offset of its
+    /// string in the data section, starting from `offset`. Returns the offset just past
+    /// the data.
+    pub fn declare_data(&self, symbols: &mut SymbolTable, offset: u32) -> Result<u32, String> {
+        let mut offset = offset;
+        for instruction in self.instructions.iter().filter(|i| i.is_data()) {
+            let (name, value) = instruction
+                .data()
+                .ok_or_else(|| "'.data' takes a name and a string".to_string())?;
+            if !symbols.add_symbol(name, offset) {
+                return Err(format!("label '{}' is declared twice", name));
+            }
+            offset += value.len() as u32;
+        }
+        Ok(offset)
+    }
+
+    /// Second pass: replaces the label operands with their offsets
+    pub fn resolve_labels(&mut self, symbols: &SymbolTable) -> Result<(), String> {
+        for instruction in &mut self.instructions {
+            instruction.resolve_labels(symbols)?;
         }
-        program
+        Ok(())
+    }
+
+    /// Resolves the labels of a program that is assembled on its own
+    pub fn link(&mut self) -> Result<(), String> {
+        let mut symbols = SymbolTable::new();
+        self.declare_labels(&mut symbols, 0)?;
+        self.declare_data(&mut symbols, 0)?;
+        self.resolve_labels(&symbols)
     }
 
     pub fn is_valid(&self) -> bool {
         for instruction in &self.instructions {
-            if !instruction.is_valid() {
+            if !instruction.is_valid() || instruction.has_label_usage() {
                 return false;
             }
         }
@@ -26,12 +152,22 @@ impl Program {
     }
 }
 
+// Prints the program as assembly, one instruction per line
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instruction in &self.instructions {
+            writeln!(f, "{}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
 named!(pub program<CompleteStr, Program>,
     do_parse!(
         instructions: many1!(instruction) >>
         (
             Program {
-                instructions: instructions
+                instructions
             }
         )
     )
@@ -56,8 +192,111 @@ mod tests {
         let result = program(CompleteStr("load $0 #21\n"));
         assert_eq!(result.is_ok(), true);
         let (_, program) = result.unwrap();
-        let bytecode = program.to_bytes();
+        let bytecode = program.to_bytes().unwrap();
         assert_eq!(bytecode.len(), 4);
         println!("{:?}", bytecode);
     }
+
+    #[test]
+    fn test_program_with_extensions() {
+        use crate::vm::VM;
+
+        let mut extensions = OpcodeExtensions::new();
+        extensions.register("square", 200).unwrap();
+        let (_, mut program) = program(CompleteStr("load $1 #9\nsquare $1 $2\nhlt\n")).unwrap();
+        assert!(!program.is_valid());
+        program.resolve_extensions(&extensions);
+        assert!(program.is_valid());
+
+        let mut vm = VM::new();
+        vm.register_opcode(200, |ctx| {
+            let src = ctx.register_operand(0)?;
+            let dst = ctx.register_operand(1)?;
+            ctx.registers[dst] = ctx.registers[src] * ctx.registers[src];
+            Ok(())
+        })
+        .unwrap();
+        vm.append_program_bytes(program.to_bytes().unwrap());
+        assert!(vm.try_run().is_ok());
+        assert_eq!(vm.get_registers()[2], 81);
+    }
+
+    #[test]
+    fn test_program_syscall() {
+        let (_, program) = program(CompleteStr("syscall #3\n")).unwrap();
+        assert!(program.is_valid());
+        assert_eq!(program.to_bytes(), Ok(vec![250, 0, 3, 0]));
+    }
+
+    #[test]
+    fn test_program_interface() {
+        use crate::module::interface::Location;
+
+        let source = ".input key bytes\n\
+                      .input rounds u32\n\
+                      .output digest bytes #16\n\
+                      .output hash u32 $21\n\
+                      load $21 #7\n\
+                      hlt\n";
+        let (rest, program) = program(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert!(program.is_valid());
+        let module = program.to_module().unwrap();
+        //directives don't end up in the bytecode
+        assert_eq!(module.code.len(), 8);
+        assert_eq!(module.interface.inputs.len(), 2);
+        assert_eq!(module.interface.inputs[1].location, Location::Register(0));
+        assert_eq!(
+            module.interface.outputs[0].location,
+            Location::Buffer {
+                index: 0,
+                capacity: 16
+            }
+        );
+        assert_eq!(module.interface.outputs[1].location, Location::Register(21));
+    }
+
+    #[test]
+    fn test_program_bad_interface() {
+        let (_, p) = program(CompleteStr(".input key\nhlt\n")).unwrap();
+        assert_eq!(
+            p.interface(),
+            Err(InterfaceError::MissingOperand(".input".to_string()))
+        );
+        let (_, p) = program(CompleteStr(".input key string\nhlt\n")).unwrap();
+        assert_eq!(
+            p.interface(),
+            Err(InterfaceError::UnknownType("string".to_string()))
+        );
+        //unknown directives make the program invalid
+        let (_, p) = program(CompleteStr(".bogus\nhlt\n")).unwrap();
+        assert!(!p.is_valid());
+    }
+
+    #[test]
+    fn test_program_labels() {
+        let source = "load $0 #3\nload $1 @done\nloop: dec $0\nload $2 #0\neq $0 $2\njeq $1\nload $3 @loop\njmp $3\ndone:\nhlt\n";
+        let (rest, mut p) = program(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        //unresolved labels can't be turned into bytes yet
+        assert!(!p.is_valid());
+        assert!(p.link().is_ok());
+        assert!(p.is_valid());
+
+        let mut vm = crate::vm::VM::new();
+        vm.append_program_bytes(p.to_bytes().unwrap());
+        assert!(vm.try_run().is_ok());
+        assert_eq!(vm.get_registers()[0], 0);
+        assert_eq!(vm.get_registers()[1], 32);
+
+        let (_, mut p) = program(CompleteStr("a: hlt\na: hlt\n")).unwrap();
+        assert_eq!(p.link(), Err("label 'a' is declared twice".to_string()));
+    }
+
+    #[test]
+    fn test_program_display() {
+        let source = ".input a i32 $0\nstart: add $0 $1 $2\nload $3 @start\n";
+        let (_, p) = program(CompleteStr(source)).unwrap();
+        assert_eq!(p.to_string(), source);
+    }
 }
@@ -6,11 +6,9 @@ named!(pub register <CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("$") >>
-            reg_num: digit >>
+            reg_num: map_res!(digit, |digits: CompleteStr| digits.parse::<u8>()) >>
             (
-                Token::Register {
-                    reg_num: reg_num.parse::<u8>().unwrap()
-                }
+                Token::Register { reg_num }
             )
         )
     )
@@ -28,5 +26,7 @@ mod tests {
         assert_eq!(result.is_ok(), false);
         let result = register(CompleteStr("$a"));
         assert_eq!(result.is_ok(), false);
+        let result = register(CompleteStr("$256"));
+        assert!(result.is_err());
     }
 }
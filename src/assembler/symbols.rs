@@ -0,0 +1,53 @@
+/// A label and the byte offset of the instruction it points at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u32,
+}
+
+/// Every label declared in a program, filled in by the first assembler pass
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { symbols: vec![] }
+    }
+
+    /// Declares a label, returns false if the name is already taken
+    pub fn add_symbol(&mut self, name: &str, offset: u32) -> bool {
+        if self.symbol_value(name).is_some() {
+            return false;
+        }
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            offset,
+        });
+        true
+    }
+
+    /// The offset a label points at
+    pub fn symbol_value(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut symbols = SymbolTable::new();
+        assert!(symbols.add_symbol("start", 0));
+        assert!(symbols.add_symbol("end", 12));
+        assert!(!symbols.add_symbol("end", 16));
+        assert_eq!(symbols.symbol_value("end"), Some(12));
+        assert_eq!(symbols.symbol_value("middle"), None);
+    }
+}
@@ -0,0 +1,567 @@
+// Turns a type checked program into IR, allocates its registers and lowers it to
+// assembler instructions. Every local variable and intermediate value gets a virtual
+// register of its own, see convention.rs for how they end up in real ones.
+
+use super::ast::*;
+use super::convention::{ARGUMENT_REGISTERS, FP, RETURN_REGISTER, SCRATCH, SP, STACK_SIZE_UPPER};
+use super::ir::{Instr, VReg};
+use super::lower::{self, int, label, reg, Emitter};
+use super::regalloc;
+use super::CompileError;
+use crate::assembler::program_parsers::Program as AssemblyProgram;
+use crate::instructions::Opcode;
+
+use std::collections::HashMap;
+
+/// Generates the instructions for a whole program. The entry code sets up the stack,
+/// calls `main` and halts, with main's parameters and result declared as the module's
+/// inputs and output.
+pub fn generate(program: &Program) -> Result<AssemblyProgram, CompileError> {
+    let main = program
+        .functions
+        .iter()
+        .find(|f| f.name == "main")
+        .ok_or_else(|| CompileError::new(1, 1, "there is no main function".to_string()))?;
+    let mut generator = Generator::new(program);
+    let mut emitter = Emitter::new();
+    entry(&mut emitter, main, &generator.functions["main"])?;
+    for (index, function) in program.functions.iter().enumerate() {
+        let body = generator.function(function)?;
+        let allocation = regalloc::allocate(&body);
+        lower::lower_function(
+            &mut emitter,
+            &format!("f{}", index),
+            &format!("r{}", index),
+            &body,
+            &allocation,
+        )
+        .map_err(|message| CompileError::new(function.span.line, function.span.column, message))?;
+    }
+    Ok(AssemblyProgram::new(emitter.instructions))
+}
+
+/// Generates the IR of one function, before register allocation
+pub fn generate_ir(program: &Program, name: &str) -> Result<Vec<Instr>, CompileError> {
+    let mut generator = Generator::new(program);
+    let function = program
+        .functions
+        .iter()
+        .find(|f| f.name == name)
+        .ok_or_else(|| CompileError::new(1, 1, format!("unknown function '{}'", name)))?;
+    generator.function(function)
+}
+
+fn entry(emitter: &mut Emitter, main: &Function, target: &str) -> Result<(), CompileError> {
+    if main.params.len() > ARGUMENT_REGISTERS as usize {
+        return Err(too_many_params(main));
+    }
+    for (i, (ty, name)) in main.params.iter().enumerate() {
+        emitter.directive("input", name, param_type(*ty), i as u8);
+    }
+    if main.return_type != Type::Void {
+        emitter.directive(
+            "output",
+            "result",
+            param_type(main.return_type),
+            RETURN_REGISTER,
+        );
+    }
+    emitter.emit(Opcode::LUI, &[reg(SP), int(STACK_SIZE_UPPER)]);
+    emitter.emit(Opcode::ALOC, &[reg(SP)]);
+    emitter.emit(Opcode::MOV, &[reg(SP), reg(FP)]);
+    emitter.emit(Opcode::LOAD, &[reg(SCRATCH), label(target)]);
+    emitter.emit(Opcode::CALL, &[reg(SCRATCH)]);
+    emitter.emit(Opcode::HLT, &[]);
+    Ok(())
+}
+
+/// The module parameter type for a value of the language
+fn param_type(ty: Type) -> &'static str {
+    match ty {
+        Type::Int => "i32",
+        Type::Float => "f32",
+        _ => "u32",
+    }
+}
+
+struct Generator {
+    // function name to its label
+    functions: HashMap<String, String>,
+    labels: usize,
+    // per function state
+    instrs: Vec<Instr>,
+    vregs: VReg,
+    scopes: Vec<HashMap<String, VReg>>,
+    // continue and break targets of the enclosing loops
+    loops: Vec<(String, String)>,
+}
+
+impl Generator {
+    fn new(program: &Program) -> Generator {
+        let functions = program
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.name.clone(), format!("f{}", i)))
+            .collect();
+        Generator {
+            functions,
+            labels: 0,
+            instrs: vec![],
+            vregs: 0,
+            scopes: vec![],
+            loops: vec![],
+        }
+    }
+
+    fn function(&mut self, function: &Function) -> Result<Vec<Instr>, CompileError> {
+        if function.params.len() > ARGUMENT_REGISTERS as usize {
+            return Err(too_many_params(function));
+        }
+        self.instrs = vec![];
+        self.vregs = 0;
+        self.scopes = vec![HashMap::new()];
+        for (i, (_, name)) in function.params.iter().enumerate() {
+            let dst = self.declare(name);
+            self.instrs.push(Instr::Param {
+                index: i as u8,
+                dst,
+            });
+        }
+        for statement in &function.body {
+            self.statement(statement)?;
+        }
+        Ok(std::mem::take(&mut self.instrs))
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> Result<(), CompileError> {
+        match &statement.kind {
+            StmtKind::Let(_, name, value) => {
+                //the value is generated before the name exists so `int a = a;` can't see itself
+                let value = match value {
+                    Some(value) => Some(self.expr(value)?),
+                    None => None,
+                };
+                let var = self.declare(name);
+                match value {
+                    Some(value) => self.mov(value, var),
+                    None => self.instrs.push(Instr::Const { dst: var, bits: 0 }),
+                }
+            }
+            StmtKind::Assign(name, value) => {
+                let value = self.expr(value)?;
+                let var = self.lookup(name);
+                self.mov(value, var);
+            }
+            StmtKind::Expr(expr) => {
+                self.expr(expr)?;
+            }
+            StmtKind::If(condition, then, otherwise) => {
+                let else_label = self.new_label();
+                let end_label = self.new_label();
+                self.branch_if_false(condition, &else_label)?;
+                self.scoped(then)?;
+                self.instrs.push(Instr::Jump(end_label.clone()));
+                self.instrs.push(Instr::Label(else_label));
+                if let Some(otherwise) = otherwise {
+                    self.scoped(otherwise)?;
+                }
+                self.instrs.push(Instr::Label(end_label));
+            }
+            StmtKind::While(condition, body) => {
+                let top = self.new_label();
+                let end = self.new_label();
+                self.instrs.push(Instr::Label(top.clone()));
+                self.branch_if_false(condition, &end)?;
+                self.loop_body(body, &top, &end)?;
+                self.instrs.push(Instr::Jump(top));
+                self.instrs.push(Instr::Label(end));
+            }
+            StmtKind::For(init, condition, step, body) => {
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.statement(init)?;
+                }
+                let top = self.new_label();
+                let next = self.new_label();
+                let end = self.new_label();
+                self.instrs.push(Instr::Label(top.clone()));
+                if let Some(condition) = condition {
+                    self.branch_if_false(condition, &end)?;
+                }
+                self.loop_body(body, &next, &end)?;
+                self.instrs.push(Instr::Label(next));
+                if let Some(step) = step {
+                    self.statement(step)?;
+                }
+                self.instrs.push(Instr::Jump(top));
+                self.instrs.push(Instr::Label(end));
+                self.scopes.pop();
+            }
+            StmtKind::Return(value) => {
+                let value = match value {
+                    Some(value) => Some(self.expr(value)?),
+                    None => None,
+                };
+                self.instrs.push(Instr::Return(value));
+            }
+            StmtKind::Break => {
+                let (_, end) = self.loops.last().cloned().expect("checked by typeck");
+                self.instrs.push(Instr::Jump(end));
+            }
+            StmtKind::Continue => {
+                let (next, _) = self.loops.last().cloned().expect("checked by typeck");
+                self.instrs.push(Instr::Jump(next));
+            }
+            StmtKind::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                for statement in statements {
+                    self.statement(statement)?;
+                }
+                self.scopes.pop();
+            }
+        }
+        Ok(())
+    }
+
+    fn scoped(&mut self, statement: &Stmt) -> Result<(), CompileError> {
+        self.scopes.push(HashMap::new());
+        let result = self.statement(statement);
+        self.scopes.pop();
+        result
+    }
+
+    fn loop_body(&mut self, body: &Stmt, next: &str, end: &str) -> Result<(), CompileError> {
+        self.loops.push((next.to_string(), end.to_string()));
+        let result = self.scoped(body);
+        self.loops.pop();
+        result
+    }
+
+    /// Evaluates an expression. The returned register may be a variable, so it is
+    /// only ever read from.
+    fn expr(&mut self, expr: &Expr) -> Result<VReg, CompileError> {
+        let register = match &expr.kind {
+            ExprKind::Int(value) => self.constant(*value),
+            ExprKind::Float(value) => self.constant(value.to_bits()),
+            ExprKind::Bool(value) => self.constant(*value as u32),
+            ExprKind::Var(name) => self.lookup(name),
+            ExprKind::Unary(op, operand) => {
+                let value = self.expr(operand)?;
+                match (op, operand.ty()) {
+                    (UnaryOp::Neg, Type::Float) => self.op(Opcode::FNEG, &[value]),
+                    (UnaryOp::Neg, _) => self.op(Opcode::NEG, &[value]),
+                    (UnaryOp::BitNot, _) => self.op(Opcode::NOT, &[value]),
+                    (UnaryOp::Not, _) => {
+                        let one = self.constant(1);
+                        self.op(Opcode::XOR, &[value, one])
+                    }
+                }
+            }
+            ExprKind::Binary(op @ BinaryOp::And, left, right)
+            | ExprKind::Binary(op @ BinaryOp::Or, left, right) => {
+                //the right hand side only runs if the left doesn't decide the result
+                let result = self.new_vreg();
+                let value = self.expr(left)?;
+                self.mov(value, result);
+                let end = self.new_label();
+                let decided = self.constant(if *op == BinaryOp::And { 0 } else { 1 });
+                self.compare(Opcode::EQ, result, decided);
+                self.instrs.push(Instr::Branch {
+                    target: end.clone(),
+                    when: true,
+                });
+                let value = self.expr(right)?;
+                self.mov(value, result);
+                self.instrs.push(Instr::Label(end));
+                result
+            }
+            ExprKind::Binary(op, left, right) => {
+                let a = self.expr(left)?;
+                let b = self.expr(right)?;
+                let opcode = binary_opcode(*op, left.ty());
+                if op.is_comparison() {
+                    self.compare(opcode, a, b);
+                    self.flag()
+                } else {
+                    self.op(opcode, &[a, b])
+                }
+            }
+            ExprKind::Call(name, args) => {
+                if args.len() > ARGUMENT_REGISTERS as usize {
+                    return Err(CompileError::new(
+                        expr.span.line,
+                        expr.span.column,
+                        format!("calls can pass at most {} arguments", ARGUMENT_REGISTERS),
+                    ));
+                }
+                let mut values = vec![];
+                for arg in args {
+                    values.push(self.expr(arg)?);
+                }
+                let dst = self.new_vreg();
+                self.instrs.push(Instr::Call {
+                    target: self.functions[name].clone(),
+                    args: values,
+                    dst,
+                });
+                dst
+            }
+            ExprKind::Cast(ty, operand) => {
+                let value = self.expr(operand)?;
+                self.cast(value, operand.ty(), *ty)
+            }
+        };
+        Ok(register)
+    }
+
+    fn cast(&mut self, value: VReg, from: Type, to: Type) -> VReg {
+        let conversion = match (from, to) {
+            (Type::Int, Type::Float) => Some(Opcode::ITOF),
+            (Type::Uint, Type::Float) | (Type::Bool, Type::Float) => Some(Opcode::UTOF),
+            (Type::Float, Type::Int) => Some(Opcode::FTOI),
+            (Type::Float, Type::Uint) => Some(Opcode::FTOU),
+            _ => None,
+        };
+        if let Some(opcode) = conversion {
+            return self.op(opcode, &[value]);
+        }
+        if to == Type::Bool && from != Type::Bool {
+            let compare = if from == Type::Float {
+                Opcode::FNEQ
+            } else {
+                Opcode::NEQ
+            };
+            let zero = self.constant(0);
+            self.compare(compare, value, zero);
+            return self.flag();
+        }
+        //int, uint and bool share their bits otherwise
+        value
+    }
+
+    fn branch_if_false(&mut self, condition: &Expr, target: &str) -> Result<(), CompileError> {
+        match &condition.kind {
+            //comparisons branch on the flag straight away
+            ExprKind::Binary(op, left, right) if op.is_comparison() => {
+                let a = self.expr(left)?;
+                let b = self.expr(right)?;
+                self.compare(binary_opcode(*op, left.ty()), a, b);
+            }
+            _ => {
+                let value = self.expr(condition)?;
+                let one = self.constant(1);
+                self.compare(Opcode::EQ, value, one);
+            }
+        }
+        self.instrs.push(Instr::Branch {
+            target: target.to_string(),
+            when: false,
+        });
+        Ok(())
+    }
+
+    //
+    // Helpers
+    //
+
+    fn new_vreg(&mut self) -> VReg {
+        self.vregs += 1;
+        self.vregs - 1
+    }
+
+    fn constant(&mut self, bits: u32) -> VReg {
+        let dst = self.new_vreg();
+        self.instrs.push(Instr::Const { dst, bits });
+        dst
+    }
+
+    /// An instruction writing a new register
+    fn op(&mut self, opcode: Opcode, srcs: &[VReg]) -> VReg {
+        let dst = self.new_vreg();
+        self.instrs.push(Instr::Op {
+            opcode,
+            srcs: srcs.to_vec(),
+            dst: Some(dst),
+        });
+        dst
+    }
+
+    fn mov(&mut self, from: VReg, to: VReg) {
+        self.instrs.push(Instr::Op {
+            opcode: Opcode::MOV,
+            srcs: vec![from],
+            dst: Some(to),
+        });
+    }
+
+    fn compare(&mut self, opcode: Opcode, a: VReg, b: VReg) {
+        self.instrs.push(Instr::Op {
+            opcode,
+            srcs: vec![a, b],
+            dst: None,
+        });
+    }
+
+    /// The equal flag as a bool
+    fn flag(&mut self) -> VReg {
+        self.op(Opcode::FLAG, &[])
+    }
+
+    fn declare(&mut self, name: &str) -> VReg {
+        let vreg = self.new_vreg();
+        self.scopes
+            .last_mut()
+            .expect("there is always a scope")
+            .insert(name.to_string(), vreg);
+        vreg
+    }
+
+    fn lookup(&self, name: &str) -> VReg {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+            .expect("checked by typeck")
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("l{}", self.labels)
+    }
+}
+
+fn binary_opcode(op: BinaryOp, ty: Type) -> Opcode {
+    match (op, ty) {
+        (BinaryOp::Add, Type::Float) => Opcode::FADD,
+        (BinaryOp::Sub, Type::Float) => Opcode::FSUB,
+        (BinaryOp::Mul, Type::Float) => Opcode::FMUL,
+        (BinaryOp::Div, Type::Float) => Opcode::FDIV,
+        (BinaryOp::Eq, Type::Float) => Opcode::FEQ,
+        (BinaryOp::Neq, Type::Float) => Opcode::FNEQ,
+        (BinaryOp::Lt, Type::Float) => Opcode::FLT,
+        (BinaryOp::Gt, Type::Float) => Opcode::FGT,
+        (BinaryOp::LtEq, Type::Float) => Opcode::FLTEQ,
+        (BinaryOp::GtEq, Type::Float) => Opcode::FGTEQ,
+        (BinaryOp::Div, Type::Uint) => Opcode::DIVU,
+        (BinaryOp::Mod, Type::Uint) => Opcode::MODU,
+        (BinaryOp::Shr, Type::Uint) => Opcode::SHRU,
+        (BinaryOp::Lt, Type::Uint) => Opcode::LTU,
+        (BinaryOp::Gt, Type::Uint) => Opcode::GTU,
+        (BinaryOp::LtEq, Type::Uint) => Opcode::LTEQU,
+        (BinaryOp::GtEq, Type::Uint) => Opcode::GTEQU,
+        (BinaryOp::Add, _) => Opcode::ADD,
+        (BinaryOp::Sub, _) => Opcode::SUB,
+        (BinaryOp::Mul, _) => Opcode::MUL,
+        (BinaryOp::Div, _) => Opcode::DIV,
+        (BinaryOp::Mod, _) => Opcode::MOD,
+        (BinaryOp::BitAnd, _) => Opcode::AND,
+        (BinaryOp::BitOr, _) => Opcode::OR,
+        (BinaryOp::BitXor, _) => Opcode::XOR,
+        (BinaryOp::Shl, _) => Opcode::SHL,
+        (BinaryOp::Shr, _) => Opcode::SHR,
+        (BinaryOp::Eq, _) => Opcode::EQ,
+        (BinaryOp::Neq, _) => Opcode::NEQ,
+        (BinaryOp::Lt, _) => Opcode::LT,
+        (BinaryOp::Gt, _) => Opcode::GT,
+        (BinaryOp::LtEq, _) => Opcode::LTEQ,
+        (BinaryOp::GtEq, _) => Opcode::GTEQ,
+        (BinaryOp::And, _) | (BinaryOp::Or, _) => unreachable!("short circuit operators"),
+    }
+}
+
+fn too_many_params(function: &Function) -> CompileError {
+    CompileError::new(
+        function.span.line,
+        function.span.column,
+        format!(
+            "'{}' has more than {} parameters",
+            function.name, ARGUMENT_REGISTERS
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::convention::CALLEE_SAVED;
+    use super::super::{compile_to_program, parse, CompileError};
+    use super::*;
+    use crate::assembler::Token;
+
+    fn count(source: &str, mnemonic: &str) -> usize {
+        compile_to_program(source)
+            .unwrap()
+            .instructions()
+            .iter()
+            .filter(|i| match &i.opcode {
+                Some(token @ Token::Op { .. }) => token.to_string() == mnemonic,
+                _ => false,
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_entry_and_interface() {
+        let assembly = compile_to_program("float main(int a, bool b) { return 1.5; }")
+            .unwrap()
+            .to_string();
+        let lines: Vec<&str> = assembly.lines().collect();
+        assert_eq!(lines[0], ".input a i32 $0");
+        assert_eq!(lines[1], ".input b u32 $1");
+        assert_eq!(lines[2], ".output result f32 $0");
+        assert_eq!(lines[3], "lui $29 #1");
+        assert!(assembly.contains("f0:\n"));
+    }
+
+    #[test]
+    fn test_constants() {
+        //small constants take one load, big ones are built from both halves
+        assert_eq!(count("int main() { return 7; }", "lui"), 1);
+        assert_eq!(count("uint main() { return 0x12345678; }", "lui"), 2);
+        assert_eq!(count("uint main() { return 0x12340000; }", "or"), 0);
+    }
+
+    #[test]
+    fn test_ir_uses_virtual_registers() {
+        let program = parse("int main(int a) { int b = a * 2; return b + a; }").unwrap();
+        let ir = generate_ir(&program, "main").unwrap();
+        assert_eq!(ir[0], Instr::Param { index: 0, dst: 0 });
+        //variables are read in place, no copies
+        assert!(ir.contains(&Instr::Op {
+            opcode: Opcode::ADD,
+            srcs: vec![3, 0],
+            dst: Some(4),
+        }));
+        assert_eq!(ir.last(), Some(&Instr::Return(Some(4))));
+    }
+
+    #[test]
+    fn test_spills_when_out_of_registers() {
+        //thirty locals all live at once can't fit in twenty registers
+        let names: Vec<String> = (0..30).map(|i| format!("v{}", i)).collect();
+        let mut source = String::from("int main(int a) {");
+        for (i, name) in names.iter().enumerate() {
+            source.push_str(&format!("int {} = a + {};", name, i));
+        }
+        source.push_str(&format!("return {}; }}", names.join(" + ")));
+        let program = parse(&source).unwrap();
+        let allocation = regalloc::allocate(&generate_ir(&program, "main").unwrap());
+        assert!(allocation.spill_slots > 0);
+        assert_eq!(allocation.callee_saved.len(), CALLEE_SAVED.len());
+        assert!(count(&source, "stw") > allocation.spill_slots as usize);
+    }
+
+    #[test]
+    fn test_codegen_errors() {
+        assert_eq!(
+            compile_to_program("int f() { return 1; }").unwrap_err(),
+            CompileError::new(1, 1, "there is no main function".to_string())
+        );
+        let params: Vec<String> = (0..9).map(|i| format!("int a{}", i)).collect();
+        let source = format!("void main({}) {{}}", params.join(", "));
+        assert_eq!(
+            compile_to_program(&source).unwrap_err().message,
+            "'main' has more than 8 parameters"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+// The calling convention of compiled code.
+//
+// Registers:
+//   $0-$7    arguments in order, $0 also carries the return value. Not preserved.
+//   $8-$15   caller saved. The allocator never keeps a value in them across a call.
+//   $16-$27  callee saved. A function that uses one saves it in its frame first.
+//   $28      scratch for reloading spilled values
+//   $29      stack pointer
+//   $30      frame pointer
+//   $31      scratch for constants, spilled values and jump targets
+//
+// The stack lives at the top of the heap and grows down. CALL and RET keep the return
+// address on the vm's own call stack, so a frame only holds:
+//
+//   fp + 0              the caller's frame pointer
+//   fp - 4 * (k + 1)    callee saved register k, in the order they were saved
+//   below those         one word per spill slot
+//   sp                  the bottom of the frame
+//
+// At most 8 arguments can be passed, there are no stack arguments.
+
+/// How many arguments fit in registers
+pub const ARGUMENT_REGISTERS: u8 = 8;
+pub const RETURN_REGISTER: u8 = 0;
+pub const CALLER_SAVED: &[u8] = &[8, 9, 10, 11, 12, 13, 14, 15];
+pub const CALLEE_SAVED: &[u8] = &[16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
+pub const SPILL_SCRATCH: u8 = 28;
+pub const SP: u8 = 29;
+pub const FP: u8 = 30;
+pub const SCRATCH: u8 = 31;
+/// LUI immediate for the stack size, 1 << 16 bytes
+pub const STACK_SIZE_UPPER: i32 = 1;
+
+/// Byte offset below the frame pointer of the nth word in the frame
+pub fn frame_offset(word: u32) -> u32 {
+    4 * (word + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_classes_are_disjoint() {
+        let special = [SPILL_SCRATCH, SP, FP, SCRATCH];
+        let mut all: Vec<u8> = (0..ARGUMENT_REGISTERS).collect();
+        all.extend_from_slice(CALLER_SAVED);
+        all.extend_from_slice(CALLEE_SAVED);
+        all.extend_from_slice(&special);
+        all.sort_unstable();
+        assert_eq!(all, (0..32).collect::<Vec<u8>>());
+        assert_eq!(frame_offset(0), 4);
+    }
+}
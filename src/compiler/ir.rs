@@ -0,0 +1,106 @@
+// The instructions codegen produces before registers are allocated. They mirror the
+// vm's opcodes but name values with an unlimited supply of virtual registers, the
+// register allocator then maps those onto the 32 real ones.
+
+use crate::instructions::Opcode;
+
+/// A virtual register
+pub type VReg = u32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Label(String),
+    /// dst = a 32 bit constant
+    Const {
+        dst: VReg,
+        bits: u32,
+    },
+    /// A vm instruction. The operands are written in order with the destination last,
+    /// `add a b dst`, `ldw addr dst`, `stw src addr`, `lt a b`.
+    Op {
+        opcode: Opcode,
+        srcs: Vec<VReg>,
+        dst: Option<VReg>,
+    },
+    Jump(String),
+    /// jumps if the equal flag is `when`
+    Branch {
+        target: String,
+        when: bool,
+    },
+    /// calls the function at the label, passing the arguments in the argument registers
+    Call {
+        target: String,
+        args: Vec<VReg>,
+        dst: VReg,
+    },
+    /// copies argument register `index` into dst on function entry
+    Param {
+        index: u8,
+        dst: VReg,
+    },
+    /// puts the value in the return register and leaves the function
+    Return(Option<VReg>),
+}
+
+impl Instr {
+    /// The virtual registers the instruction reads
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Instr::Op { srcs, .. } => srcs.clone(),
+            Instr::Call { args, .. } => args.clone(),
+            Instr::Return(Some(value)) => vec![*value],
+            _ => vec![],
+        }
+    }
+
+    /// The virtual register the instruction writes
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Instr::Const { dst, .. } | Instr::Call { dst, .. } | Instr::Param { dst, .. } => {
+                Some(*dst)
+            }
+            Instr::Op { dst, .. } => *dst,
+            _ => None,
+        }
+    }
+
+    /// The label the instruction can jump to
+    pub fn jump_target(&self) -> Option<&str> {
+        match self {
+            Instr::Jump(target) | Instr::Branch { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uses_and_defs() {
+        let add = Instr::Op {
+            opcode: Opcode::ADD,
+            srcs: vec![1, 2],
+            dst: Some(3),
+        };
+        assert_eq!(add.uses(), vec![1, 2]);
+        assert_eq!(add.def(), Some(3));
+        let call = Instr::Call {
+            target: "f0".to_string(),
+            args: vec![4],
+            dst: 5,
+        };
+        assert_eq!((call.uses(), call.def()), (vec![4], Some(5)));
+        assert_eq!(Instr::Return(Some(7)).uses(), vec![7]);
+        assert_eq!(
+            Instr::Branch {
+                target: "l1".to_string(),
+                when: false
+            }
+            .jump_target(),
+            Some("l1")
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use super::CompileError;
+
+/// The kinds of tokens in a source file
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Int(u32),
+    Float(f32),
+    Ident(String),
+    // keywords
+    Type(String),
+    If,
+    Else,
+    While,
+    For,
+    Return,
+    Break,
+    Continue,
+    True,
+    False,
+    // punctuation and operators, kept as written
+    Symbol(&'static str),
+    Eof,
+}
+
+/// A token and where it starts in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+pub const TYPE_NAMES: &[&str] = &["int", "uint", "float", "bool", "void"];
+
+// longest first so `<<=` isn't read as `<` `<=`
+const SYMBOLS: &[&str] = &[
+    "<<=", ">>=", "&&", "||", "==", "!=", "<=", ">=", "<<", ">>", "+=", "-=", "*=", "/=", "%=",
+    "&=", "|=", "^=", "++", "--", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "<", ">", "=",
+    "(", ")", "{", "}", ",", ";",
+];
+
+/// Splits the source into tokens, ending with an Eof token
+pub fn tokenize(source: &str) -> Result<Vec<Token>, CompileError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    let mut line = 1;
+    let mut line_start = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i - line_start + 1;
+        let error = move |message: String| CompileError::new(line, column, message);
+        if c == '\n' {
+            i += 1;
+            line += 1;
+            line_start = i;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        //comments
+        if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+        if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            loop {
+                if i + 1 >= chars.len() {
+                    return Err(error("unterminated comment".to_string()));
+                }
+                if chars[i] == '\n' {
+                    line += 1;
+                    line_start = i + 1;
+                }
+                if chars[i] == '*' && chars[i + 1] == '/' {
+                    i += 2;
+                    break;
+                }
+                i += 1;
+            }
+            continue;
+        }
+
+        let start = i;
+        let kind = if c.is_ascii_digit() {
+            number(&chars, &mut i).ok_or_else(|| {
+                let text: String = chars[start..i].iter().collect();
+                error(format!("invalid number '{}'", text))
+            })?
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            keyword(word)
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| {
+                    s.chars()
+                        .enumerate()
+                        .all(|(k, c)| chars.get(i + k) == Some(&c))
+                })
+                .ok_or_else(|| error(format!("unexpected character '{}'", c)))?;
+            i += symbol.len();
+            TokenKind::Symbol(symbol)
+        };
+        tokens.push(Token { kind, line, column });
+    }
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        line,
+        column: chars.len() - line_start + 1,
+    });
+    Ok(tokens)
+}
+
+fn keyword(word: String) -> TokenKind {
+    match word.as_str() {
+        "if" => TokenKind::If,
+        "else" => TokenKind::Else,
+        "while" => TokenKind::While,
+        "for" => TokenKind::For,
+        "return" => TokenKind::Return,
+        "break" => TokenKind::Break,
+        "continue" => TokenKind::Continue,
+        "true" => TokenKind::True,
+        "false" => TokenKind::False,
+        _ if TYPE_NAMES.contains(&word.as_str()) => TokenKind::Type(word),
+        _ => TokenKind::Ident(word),
+    }
+}
+
+/// Reads a decimal, 0x hex or 0b binary integer, or a float with a `.` or exponent
+fn number(chars: &[char], i: &mut usize) -> Option<TokenKind> {
+    let start = *i;
+    while *i < chars.len() && (chars[*i].is_alphanumeric() || chars[*i] == '.' || chars[*i] == '_')
+    {
+        //exponent signs belong to the number
+        if (chars[*i] == 'e' || chars[*i] == 'E')
+            && matches!(chars.get(*i + 1), Some('+') | Some('-'))
+            && !chars[start..*i].contains(&'x')
+        {
+            *i += 1;
+        }
+        *i += 1;
+    }
+    let text: String = chars[start..*i].iter().filter(|c| **c != '_').collect();
+    let radix = |prefix: &str| text.strip_prefix(prefix);
+    if let Some(hex) = radix("0x") {
+        return u32::from_str_radix(hex, 16).ok().map(TokenKind::Int);
+    }
+    if let Some(binary) = radix("0b") {
+        return u32::from_str_radix(binary, 2).ok().map(TokenKind::Int);
+    }
+    if text.contains(['.', 'e', 'E']) {
+        return text.parse::<f32>().ok().map(TokenKind::Float);
+    }
+    text.parse::<u32>().ok().map(TokenKind::Int)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            kinds("uint x = 0xff << 2; // shift\nx >>= 1.5e1;"),
+            vec![
+                TokenKind::Type("uint".to_string()),
+                TokenKind::Ident("x".to_string()),
+                TokenKind::Symbol("="),
+                TokenKind::Int(255),
+                TokenKind::Symbol("<<"),
+                TokenKind::Int(2),
+                TokenKind::Symbol(";"),
+                TokenKind::Ident("x".to_string()),
+                TokenKind::Symbol(">>="),
+                TokenKind::Float(15.0),
+                TokenKind::Symbol(";"),
+                TokenKind::Eof,
+            ]
+        );
+        assert_eq!(
+            kinds("if (true) /* a\nb */ return;"),
+            vec![
+                TokenKind::If,
+                TokenKind::Symbol("("),
+                TokenKind::True,
+                TokenKind::Symbol(")"),
+                TokenKind::Return,
+                TokenKind::Symbol(";"),
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_positions() {
+        let tokens = tokenize("int a;\n  a = 1;").unwrap();
+        assert_eq!((tokens[3].line, tokens[3].column), (2, 3));
+        assert_eq!((tokens[5].line, tokens[5].column), (2, 7));
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert_eq!(
+            tokenize("int a = 1 @ 2;"),
+            Err(CompileError::new(
+                1,
+                11,
+                "unexpected character '@'".to_string()
+            ))
+        );
+        assert_eq!(
+            tokenize("\n 12ab").unwrap_err().message,
+            "invalid number '12ab'"
+        );
+        assert!(tokenize("/* never closed").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+// Turns allocated IR into assembler instructions: virtual registers are replaced by
+// their registers, spilled values are loaded into the scratch registers around the
+// instructions that use them, and the pseudo instructions become real sequences.
+
+use super::convention::*;
+use super::ir::{Instr, VReg};
+use super::regalloc::{Allocation, Location};
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::Token;
+use crate::instructions::Opcode;
+
+/// Collects the assembler instructions of a program
+#[derive(Debug, Default)]
+pub struct Emitter {
+    pub instructions: Vec<AssemblerInstruction>,
+}
+
+impl Emitter {
+    pub fn new() -> Emitter {
+        Emitter {
+            instructions: vec![],
+        }
+    }
+
+    pub fn emit(&mut self, code: Opcode, operands: &[Token]) {
+        let mut operands = operands.iter().cloned();
+        self.instructions.push(AssemblerInstruction {
+            opcode: Some(Token::Op { code }),
+            label: None,
+            directive: None,
+            operand1: operands.next(),
+            operand2: operands.next(),
+            operand3: operands.next(),
+        });
+    }
+
+    pub fn place_label(&mut self, name: &str) {
+        self.instructions.push(AssemblerInstruction {
+            opcode: None,
+            label: Some(Token::LabelDeclaration {
+                name: name.to_string(),
+            }),
+            directive: None,
+            operand1: None,
+            operand2: None,
+            operand3: None,
+        });
+    }
+
+    /// `.input name type $register` or `.output ...`
+    pub fn directive(&mut self, name: &str, param: &str, ty: &str, register: u8) {
+        let identifier = |name: &str| {
+            Some(Token::Identifier {
+                name: name.to_string(),
+            })
+        };
+        self.instructions.push(AssemblerInstruction {
+            opcode: None,
+            label: None,
+            directive: Some(Token::Directive {
+                name: name.to_string(),
+            }),
+            operand1: identifier(param),
+            operand2: identifier(ty),
+            operand3: Some(reg(register)),
+        });
+    }
+
+    /// Loads a 32 bit constant, LOAD only takes 16 bits so bigger ones are built with LUI.
+    /// Uses the scratch register for the lower half.
+    pub fn constant(&mut self, register: u8, bits: u32) {
+        let (upper, lower) = (bits >> 16, bits & 0xffff);
+        if upper == 0 {
+            self.emit(Opcode::LOAD, &[reg(register), int(lower as i32)]);
+        } else {
+            self.emit(Opcode::LUI, &[reg(register), int(upper as i32)]);
+            if lower != 0 {
+                self.emit(Opcode::LOAD, &[reg(SCRATCH), int(lower as i32)]);
+                self.emit(Opcode::OR, &[reg(register), reg(SCRATCH), reg(register)]);
+            }
+        }
+    }
+
+    /// Jumps through the scratch register, `jmp`, `jeq` or `jneq`
+    pub fn jump(&mut self, opcode: Opcode, target: &str) {
+        self.emit(Opcode::LOAD, &[reg(SCRATCH), label(target)]);
+        self.emit(opcode, &[reg(SCRATCH)]);
+    }
+
+    /// Puts fp - offset in a register
+    fn frame_address(&mut self, register: u8, word: u32) {
+        self.emit(
+            Opcode::LOAD,
+            &[reg(register), int(frame_offset(word) as i32)],
+        );
+        self.emit(Opcode::SUB, &[reg(FP), reg(register), reg(register)]);
+    }
+
+    fn load_frame(&mut self, register: u8, word: u32) {
+        self.frame_address(register, word);
+        self.emit(Opcode::LDW, &[reg(register), reg(register)]);
+    }
+
+    /// Stores a register in the frame, using `address` to hold the address
+    fn store_frame(&mut self, register: u8, word: u32, address: u8) {
+        self.frame_address(address, word);
+        self.emit(Opcode::STW, &[reg(register), reg(address)]);
+    }
+}
+
+/// Lowers one function, including its prologue and epilogue
+pub fn lower_function(
+    emitter: &mut Emitter,
+    name: &str,
+    return_label: &str,
+    body: &[Instr],
+    allocation: &Allocation,
+) -> Result<(), String> {
+    let saved = allocation.callee_saved.len() as u32;
+    let frame_size = frame_offset(saved + allocation.spill_slots) - 4;
+    if frame_size > u32::from(u16::MAX) {
+        return Err("function needs too much stack space".to_string());
+    }
+    let mut lowering = Lowering {
+        emitter,
+        allocation,
+        saved,
+    };
+    lowering.emitter.place_label(name);
+    lowering.prologue(frame_size);
+    for instr in body {
+        lowering.instr(instr, return_label);
+    }
+    //falling off the end returns 0
+    lowering
+        .emitter
+        .emit(Opcode::LOAD, &[reg(RETURN_REGISTER), int(0)]);
+    lowering.emitter.place_label(return_label);
+    lowering.epilogue();
+    Ok(())
+}
+
+struct Lowering<'a> {
+    emitter: &'a mut Emitter,
+    allocation: &'a Allocation,
+    // callee saved registers in the frame, the spill slots come after them
+    saved: u32,
+}
+
+impl<'a> Lowering<'a> {
+    fn prologue(&mut self, frame_size: u32) {
+        //push the caller's frame pointer, then make room for the rest of the frame
+        let e = &mut *self.emitter;
+        e.emit(Opcode::LOAD, &[reg(SCRATCH), int(4)]);
+        e.emit(Opcode::SUB, &[reg(SP), reg(SCRATCH), reg(SP)]);
+        e.emit(Opcode::STW, &[reg(FP), reg(SP)]);
+        e.emit(Opcode::MOV, &[reg(SP), reg(FP)]);
+        if frame_size > 0 {
+            e.emit(Opcode::LOAD, &[reg(SCRATCH), int(frame_size as i32)]);
+            e.emit(Opcode::SUB, &[reg(SP), reg(SCRATCH), reg(SP)]);
+        }
+        for (word, register) in self.allocation.callee_saved.iter().enumerate() {
+            e.store_frame(*register, word as u32, SCRATCH);
+        }
+    }
+
+    fn epilogue(&mut self) {
+        let e = &mut *self.emitter;
+        for (word, register) in self.allocation.callee_saved.iter().enumerate() {
+            e.load_frame(*register, word as u32);
+        }
+        e.emit(Opcode::MOV, &[reg(FP), reg(SP)]);
+        e.emit(Opcode::LDW, &[reg(SP), reg(FP)]);
+        e.emit(Opcode::LOAD, &[reg(SCRATCH), int(4)]);
+        e.emit(Opcode::ADD, &[reg(SP), reg(SCRATCH), reg(SP)]);
+        e.emit(Opcode::RET, &[]);
+    }
+
+    fn instr(&mut self, instr: &Instr, return_label: &str) {
+        match instr {
+            Instr::Label(name) => self.emitter.place_label(name),
+            Instr::Const { dst, bits } => {
+                let register = self.destination(*dst);
+                self.emitter.constant(register, *bits);
+                self.write_back(*dst, register);
+            }
+            Instr::Op { opcode, srcs, dst } => {
+                //two sources at most, each gets its own scratch register if spilled
+                let scratch = [SPILL_SCRATCH, SCRATCH];
+                let mut operands: Vec<Token> = srcs
+                    .iter()
+                    .zip(scratch.iter())
+                    .map(|(vreg, scratch)| reg(self.source(*vreg, *scratch)))
+                    .collect();
+                let register = dst.map(|dst| self.destination(dst));
+                operands.extend(register.map(reg));
+                self.emitter.emit(*opcode, &operands);
+                if let (Some(dst), Some(register)) = (dst, register) {
+                    self.write_back(*dst, register);
+                }
+            }
+            Instr::Jump(target) => self.emitter.jump(Opcode::JMP, target),
+            Instr::Branch { target, when } => {
+                let opcode = if *when { Opcode::JEQ } else { Opcode::JNEQ };
+                self.emitter.jump(opcode, target);
+            }
+            Instr::Call { target, args, dst } => {
+                for (index, vreg) in args.iter().enumerate() {
+                    self.copy_to(*vreg, index as u8);
+                }
+                self.emitter.jump(Opcode::CALL, target);
+                self.copy_from(RETURN_REGISTER, *dst);
+            }
+            Instr::Param { index, dst } => self.copy_from(*index, *dst),
+            Instr::Return(value) => {
+                if let Some(value) = value {
+                    self.copy_to(*value, RETURN_REGISTER);
+                }
+                self.emitter.jump(Opcode::JMP, return_label);
+            }
+        }
+    }
+
+    /// The register holding a value that is about to be read
+    fn source(&mut self, vreg: VReg, scratch: u8) -> u8 {
+        match self.allocation.location(vreg) {
+            Location::Register(register) => register,
+            Location::Spill(slot) => {
+                self.emitter.load_frame(scratch, self.saved + slot);
+                scratch
+            }
+        }
+    }
+
+    /// The register a value is written to, spilled values go through the spill scratch
+    fn destination(&self, vreg: VReg) -> u8 {
+        match self.allocation.location(vreg) {
+            Location::Register(register) => register,
+            Location::Spill(_) => SPILL_SCRATCH,
+        }
+    }
+
+    fn write_back(&mut self, vreg: VReg, register: u8) {
+        if let Location::Spill(slot) = self.allocation.location(vreg) {
+            self.emitter
+                .store_frame(register, self.saved + slot, SCRATCH);
+        }
+    }
+
+    /// Copies a value into a fixed register
+    fn copy_to(&mut self, vreg: VReg, register: u8) {
+        match self.allocation.location(vreg) {
+            Location::Register(from) => self.emitter.emit(Opcode::MOV, &[reg(from), reg(register)]),
+            Location::Spill(slot) => self.emitter.load_frame(register, self.saved + slot),
+        }
+    }
+
+    /// Copies a fixed register into a value
+    fn copy_from(&mut self, register: u8, vreg: VReg) {
+        match self.allocation.location(vreg) {
+            Location::Register(to) => self.emitter.emit(Opcode::MOV, &[reg(register), reg(to)]),
+            Location::Spill(slot) => self
+                .emitter
+                .store_frame(register, self.saved + slot, SCRATCH),
+        }
+    }
+}
+
+pub fn reg(reg_num: u8) -> Token {
+    Token::Register { reg_num }
+}
+
+pub fn int(value: i32) -> Token {
+    Token::IntegerOperand { value }
+}
+
+pub fn label(name: &str) -> Token {
+    Token::LabelUsage {
+        name: name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lowered(body: &[Instr], allocation: &Allocation) -> Vec<String> {
+        let mut emitter = Emitter::new();
+        lower_function(&mut emitter, "f", "r", body, allocation).unwrap();
+        emitter.instructions.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn test_spilled_operands() {
+        let body = vec![Instr::Op {
+            opcode: Opcode::ADD,
+            srcs: vec![0, 1],
+            dst: Some(2),
+        }];
+        let mut allocation = Allocation::default();
+        allocation.locations.insert(0, Location::Spill(0));
+        allocation.locations.insert(1, Location::Spill(1));
+        allocation.locations.insert(2, Location::Register(9));
+        allocation.spill_slots = 2;
+        let lines = lowered(&body, &allocation);
+        let add = lines.iter().position(|l| l.starts_with("add")).unwrap();
+        assert_eq!(
+            &lines[add - 6..=add],
+            &[
+                "load $28 #4",
+                "sub $30 $28 $28",
+                "ldw $28 $28",
+                "load $31 #8",
+                "sub $30 $31 $31",
+                "ldw $31 $31",
+                "add $28 $31 $9",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_callee_saved_frame() {
+        let body = vec![Instr::Const { dst: 0, bits: 1 }, Instr::Return(Some(0))];
+        let mut allocation = Allocation::default();
+        allocation.locations.insert(0, Location::Spill(0));
+        allocation.spill_slots = 1;
+        allocation.callee_saved = vec![16];
+        let lines = lowered(&body, &allocation);
+        //the frame holds the saved register and then the spill slot
+        assert!(lines.contains(&"load $31 #8".to_string()));
+        assert_eq!(&lines[7..9], &["load $31 #4", "sub $30 $31 $31"]);
+        assert_eq!(lines[9], "stw $16 $31");
+        //the spilled constant goes below the saved register
+        assert_eq!(
+            &lines[10..14],
+            &[
+                "load $28 #1",
+                "load $31 #8",
+                "sub $30 $31 $31",
+                "stw $28 $31"
+            ]
+        );
+        assert_eq!(lines.last().unwrap(), "ret");
+    }
+}
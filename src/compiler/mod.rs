@@ -0,0 +1,335 @@
+// Compiler for the C-like blackbox language. Source goes through the lexer, the parser
+// and the type checker, then codegen turns it into assembler instructions that are
+// assembled into a module like any hand written program.
+//
+//     int gcd(int a, int b) {
+//         while (b != 0) { int t = b; b = a % b; a = t; }
+//         return a;
+//     }
+//     int main(int a, int b) { return gcd(a, b); }
+//
+// The parameters of `main` become the module's inputs and its return value the
+// `result` output.
+
+pub mod ast;
+pub mod codegen;
+pub mod convention;
+pub mod ir;
+pub mod lexer;
+pub mod lower;
+pub mod parser;
+pub mod regalloc;
+pub mod typeck;
+
+use crate::assembler::program_parsers::Program as AssemblyProgram;
+use crate::module::Module;
+
+use std::error::Error;
+use std::fmt;
+
+/// Where and why compiling failed. Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl CompileError {
+    pub fn new(line: usize, column: usize, message: String) -> CompileError {
+        CompileError {
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl Error for CompileError {}
+
+/// Parses and type checks a source file
+pub fn parse(source: &str) -> Result<ast::Program, CompileError> {
+    let tokens = lexer::tokenize(source)?;
+    let mut program = parser::Parser::new(tokens).parse_program()?;
+    typeck::check_program(&mut program)?;
+    Ok(program)
+}
+
+/// Compiles to assembler instructions with the labels still unresolved.
+/// Printing the result gives assembly source that `assemble` accepts.
+pub fn compile_to_program(source: &str) -> Result<AssemblyProgram, CompileError> {
+    codegen::generate(&parse(source)?)
+}
+
+/// Compiles to assembly source
+pub fn compile_to_assembly(source: &str) -> Result<String, CompileError> {
+    Ok(compile_to_program(source)?.to_string())
+}
+
+/// Compiles straight to a module
+pub fn compile(source: &str) -> Result<Module, CompileError> {
+    link(compile_to_program(source)?)
+}
+
+/// Type checks and compiles a syntax tree that was built by something other than the
+/// parser, like the `#[blackbox]` attribute does with Rust functions
+pub fn compile_ast(mut program: ast::Program) -> Result<Module, CompileError> {
+    typeck::check_program(&mut program)?;
+    link(codegen::generate(&program)?)
+}
+
+fn link(mut program: AssemblyProgram) -> Result<Module, CompileError> {
+    let error = |message: String| CompileError::new(1, 1, message);
+    program.link().map_err(error)?;
+    program.to_module().map_err(|e| error(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::module::interface::Value;
+    use crate::vm::{VmError, VM};
+
+    fn run(source: &str, args: &[Value]) -> Result<Vec<Value>, VmError> {
+        let module = compile(source).unwrap();
+        let mut vm = VM::new();
+        vm.load_module(&module);
+        let result = vm.call(args);
+        //every program runs the same natively
+        #[cfg(feature = "jit")]
+        {
+            let mut native = VM::new();
+            native.load_jit(&module).unwrap();
+            let native = native.call(args);
+            assert_eq!(format!("{:?}", native), format!("{:?}", result));
+        }
+        result
+    }
+
+    fn run_i32(source: &str, args: &[i32]) -> i32 {
+        let args: Vec<Value> = args.iter().map(|a| Value::I32(*a)).collect();
+        match run(source, &args).unwrap()[0] {
+            Value::I32(v) => v,
+            ref other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let source = "int main(int a, int b) { return (a + b) * (a - b) / 3 % 7 - -a; }";
+        for (a, b) in [(10, 4), (-9, 2), (100, -3)].iter() {
+            assert_eq!(
+                run_i32(source, &[*a, *b]),
+                (a + b) * (a - b) / 3 % 7 - -a,
+                "{} {}",
+                a,
+                b
+            );
+        }
+        //big constants and overflow wrap like the vm does
+        assert_eq!(
+            run_i32("int main() { return 2147483647 + 0x7fff0001; }", &[]),
+            2147483647i32.wrapping_add(0x7fff_0001)
+        );
+    }
+
+    #[test]
+    fn test_functions_and_recursion() {
+        let source = "int fact(int n) { if (n <= 1) { return 1; } return n * fact(n - 1); }\n\
+                      int add3(int a, int b, int c) { return a + b + c; }\n\
+                      int main(int n) { return add3(fact(n), 1, fact(3)) + fact(2) * 100; }";
+        assert_eq!(run_i32(source, &[5]), 120 + 1 + 6 + 200);
+        assert_eq!(run_i32(source, &[10]), 3628800 + 1 + 6 + 200);
+    }
+
+    #[test]
+    fn test_loops() {
+        let source = "int main(int n) {\n\
+                          int total = 0;\n\
+                          for (int i = 0; i < n; i++) {\n\
+                              if (i % 3 == 0) continue;\n\
+                              if (i > 20) break;\n\
+                              total += i;\n\
+                          }\n\
+                          int j = n;\n\
+                          while (j > 0) { total = total + 1000; j -= 2; }\n\
+                          return total;\n\
+                      }";
+        let expected = |n: i32| {
+            let sum: i32 = (0..n).filter(|i| i % 3 != 0 && *i <= 20).sum();
+            sum + 1000 * ((n + 1) / 2)
+        };
+        for n in [0, 1, 7, 30].iter() {
+            assert_eq!(run_i32(source, &[*n]), expected(*n));
+        }
+    }
+
+    #[test]
+    fn test_bitwise_and_unsigned() {
+        let source = "uint main(uint a, uint b) {\n\
+                          uint mixed = (a ^ b) | (a & 0xff00) ;\n\
+                          mixed = mixed << 3 >> 1;\n\
+                          return ~mixed / b + a % b;\n\
+                      }";
+        let (a, b) = (0xdead_beefu32, 77u32);
+        let mixed = ((a ^ b) | (a & 0xff00)) << 3 >> 1;
+        let expected = !mixed / b + a % b;
+        assert_eq!(
+            run(source, &[Value::U32(a), Value::U32(b)]).unwrap(),
+            vec![Value::U32(expected)]
+        );
+        //signed shifts keep the sign
+        assert_eq!(run_i32("int main(int a) { return a >> 4; }", &[-256]), -16);
+    }
+
+    #[test]
+    fn test_floats_and_casts() {
+        let source = "float main(float x, int n) {\n\
+                          float total = 0;\n\
+                          for (int i = 0; i < n; i++) { total = total + x * (float)i; }\n\
+                          if (total > 100.0 && !(n == 0)) { total = -total; }\n\
+                          return total / 2;\n\
+                      }";
+        let result = run(source, &[Value::F32(1.5), Value::I32(20)]).unwrap();
+        assert_eq!(result, vec![Value::F32(-142.5)]);
+        assert_eq!(
+            run_i32(
+                "int main() { return (int)(7.9 * 2.0) + (int)(bool)3; }",
+                &[]
+            ),
+            16
+        );
+    }
+
+    // a + (b * (a - (b + ...))), every left operand stays live while the right one is
+    // evaluated, so deep nesting needs more values than there are registers
+    fn nested(depth: usize, a: i32, b: i32) -> (String, i32) {
+        if depth == 0 {
+            return ("a".to_string(), a);
+        }
+        let (inner, value) = nested(depth - 1, a, b);
+        let (left, left_value) = if depth.is_multiple_of(2) {
+            ("a", a)
+        } else {
+            ("b", b)
+        };
+        match depth % 3 {
+            0 => (
+                format!("({} + {})", left, inner),
+                left_value.wrapping_add(value),
+            ),
+            1 => (
+                format!("({} * {})", left, inner),
+                left_value.wrapping_mul(value),
+            ),
+            _ => (
+                format!("({} - {})", left, inner),
+                left_value.wrapping_sub(value),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_deeply_nested_expressions() {
+        for depth in [1, 10, 19, 20, 21, 40, 100].iter() {
+            let (expr, _) = nested(*depth, 0, 0);
+            let source = format!("int main(int a, int b) {{ return {}; }}", expr);
+            for (a, b) in [(3, -7), (123_456, 98_765)].iter() {
+                let (_, expected) = nested(*depth, *a, *b);
+                assert_eq!(run_i32(&source, &[*a, *b]), expected, "depth {}", depth);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nested_calls_keep_live_values() {
+        //each level holds a value across the call below it, past the callee saved registers
+        let mut expr = "n".to_string();
+        let mut expected = 5i32;
+        for i in 0..30 {
+            expr = format!("(n + {}) * 3 - twice({})", i, expr);
+            expected = (5 + i) * 3 - expected * 2;
+        }
+        let source = format!(
+            "int twice(int x) {{ int a = x; int b = a + x; return b; }}
+             int main(int n) {{ return {}; }}",
+            expr
+        );
+        assert_eq!(run_i32(&source, &[5]), expected);
+    }
+
+    #[test]
+    fn test_many_live_locals_in_loop() {
+        let mut source = String::from("int main(int n) {\n");
+        for i in 0..40 {
+            source.push_str(&format!("int v{} = n * {};\n", i, i));
+        }
+        source.push_str("for (int k = 0; k < n; k++) {\n");
+        for i in 0..40 {
+            source.push_str(&format!("v{} = v{} + v{};\n", i, i, (i + 1) % 40));
+        }
+        source.push_str("}\nint total = 0;\n");
+        for i in 0..40 {
+            source.push_str(&format!("total = total ^ v{};\n", i));
+        }
+        source.push_str("return total; }");
+
+        let n = 6;
+        let mut v: Vec<i32> = (0..40).map(|i| n * i).collect();
+        for _ in 0..n {
+            for i in 0..40 {
+                v[i] = v[i].wrapping_add(v[(i + 1) % 40]);
+            }
+        }
+        let expected = v.iter().fold(0, |total, v| total ^ v);
+        assert_eq!(run_i32(&source, &[n]), expected);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        //the right hand side would divide by zero if it ran
+        let source = "bool main(int a) { return a == 0 || 10 / a > 1; }";
+        assert_eq!(run(source, &[Value::I32(0)]).unwrap(), vec![Value::U32(1)]);
+        assert_eq!(run(source, &[Value::I32(20)]).unwrap(), vec![Value::U32(0)]);
+        let source = "int main(int a) { if (a != 0 && 10 / a == 5) { return 1; } return 2; }";
+        assert_eq!(run_i32(source, &[0]), 2);
+        assert_eq!(run_i32(source, &[2]), 1);
+    }
+
+    #[test]
+    fn test_runtime_faults() {
+        let source = "int main(int a) { return 10 / a; }";
+        assert_eq!(run(source, &[Value::I32(0)]), Err(VmError::DivideByZero));
+        //unbounded recursion runs off the bottom of the stack
+        let source = "int down(int n) { return down(n + 1); } int main() { return down(0); }";
+        assert!(run(source, &[]).is_err());
+    }
+
+    #[test]
+    fn test_assembly_round_trip() {
+        let source = "int twice(int a) { return a * 2; } int main(int a) { return twice(a) + 1; }";
+        let assembly = compile_to_assembly(source).unwrap();
+        assert_eq!(assemble(&assembly).unwrap(), compile(source).unwrap());
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let error = compile("int main() {\n  return 1.0;\n}").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 10));
+        assert_eq!(
+            error.to_string(),
+            "line 2, column 10: type mismatch: expected int, found float"
+        );
+    }
+}
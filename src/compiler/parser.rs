@@ -0,0 +1,502 @@
+use super::ast::*;
+use super::lexer::{Token, TokenKind};
+use super::CompileError;
+
+/// Recursive descent parser turning tokens into the syntax tree
+pub struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+// binary operators from loosest to tightest binding
+const PRECEDENCE: &[&[(&str, BinaryOp)]] = &[
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Neq)],
+    &[
+        ("<", BinaryOp::Lt),
+        (">", BinaryOp::Gt),
+        ("<=", BinaryOp::LtEq),
+        (">=", BinaryOp::GtEq),
+    ],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Mod),
+    ],
+];
+
+// `x op= y` is short for `x = x op y`
+const COMPOUND_ASSIGNMENTS: &[(&str, BinaryOp)] = &[
+    ("+=", BinaryOp::Add),
+    ("-=", BinaryOp::Sub),
+    ("*=", BinaryOp::Mul),
+    ("/=", BinaryOp::Div),
+    ("%=", BinaryOp::Mod),
+    ("&=", BinaryOp::BitAnd),
+    ("|=", BinaryOp::BitOr),
+    ("^=", BinaryOp::BitXor),
+    ("<<=", BinaryOp::Shl),
+    (">>=", BinaryOp::Shr),
+];
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        Parser {
+            tokens,
+            position: 0,
+        }
+    }
+
+    /// Parses every function in the file
+    pub fn parse_program(&mut self) -> Result<Program, CompileError> {
+        let mut functions = vec![];
+        while self.peek() != &TokenKind::Eof {
+            functions.push(self.function()?);
+        }
+        Ok(Program { functions })
+    }
+
+    fn function(&mut self) -> Result<Function, CompileError> {
+        let span = self.span();
+        let return_type = self.type_name()?;
+        let name = self.identifier()?;
+        self.expect("(")?;
+        let mut params = vec![];
+        if !self.check(")") {
+            loop {
+                let ty = self.type_name()?;
+                params.push((ty, self.identifier()?));
+                if !self.accept(",") {
+                    break;
+                }
+            }
+        }
+        self.expect(")")?;
+        let body = self.block()?;
+        Ok(Function {
+            name,
+            params,
+            return_type,
+            body,
+            span,
+        })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        self.expect("{")?;
+        let mut statements = vec![];
+        while !self.accept("}") {
+            if self.peek() == &TokenKind::Eof {
+                return Err(self.error("expected '}'"));
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CompileError> {
+        let span = self.span();
+        let kind = match self.peek().clone() {
+            TokenKind::Symbol("{") => StmtKind::Block(self.block()?),
+            TokenKind::If => {
+                self.advance();
+                let condition = self.condition()?;
+                let then = Box::new(self.statement()?);
+                let otherwise = if self.peek() == &TokenKind::Else {
+                    self.advance();
+                    Some(Box::new(self.statement()?))
+                } else {
+                    None
+                };
+                StmtKind::If(condition, then, otherwise)
+            }
+            TokenKind::While => {
+                self.advance();
+                let condition = self.condition()?;
+                StmtKind::While(condition, Box::new(self.statement()?))
+            }
+            TokenKind::For => {
+                self.advance();
+                self.expect("(")?;
+                let init = self.optional_simple(";")?;
+                self.expect(";")?;
+                let condition = if self.check(";") {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.expect(";")?;
+                let step = self.optional_simple(")")?;
+                self.expect(")")?;
+                StmtKind::For(init, condition, step, Box::new(self.statement()?))
+            }
+            TokenKind::Return => {
+                self.advance();
+                let value = if self.check(";") {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.expect(";")?;
+                StmtKind::Return(value)
+            }
+            TokenKind::Break => {
+                self.advance();
+                self.expect(";")?;
+                StmtKind::Break
+            }
+            TokenKind::Continue => {
+                self.advance();
+                self.expect(";")?;
+                StmtKind::Continue
+            }
+            _ => {
+                let statement = self.simple()?;
+                self.expect(";")?;
+                return Ok(statement);
+            }
+        };
+        Ok(Stmt { kind, span })
+    }
+
+    fn condition(&mut self) -> Result<Expr, CompileError> {
+        self.expect("(")?;
+        let condition = self.expression()?;
+        self.expect(")")?;
+        Ok(condition)
+    }
+
+    fn optional_simple(&mut self, end: &str) -> Result<Option<Box<Stmt>>, CompileError> {
+        if self.check(end) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(self.simple()?)))
+    }
+
+    /// Declarations, assignments and expressions, the statements that can go in a for header
+    fn simple(&mut self) -> Result<Stmt, CompileError> {
+        let span = self.span();
+        if let TokenKind::Type(_) = self.peek() {
+            let ty = self.type_name()?;
+            let name = self.identifier()?;
+            let value = if self.accept("=") {
+                Some(self.expression()?)
+            } else {
+                None
+            };
+            return Ok(Stmt {
+                kind: StmtKind::Let(ty, name, value),
+                span,
+            });
+        }
+        if let TokenKind::Ident(name) = self.peek().clone() {
+            let kind = match self.peek_at(1).clone() {
+                TokenKind::Symbol("=") => {
+                    self.position += 2;
+                    Some(StmtKind::Assign(name, self.expression()?))
+                }
+                TokenKind::Symbol(s @ "++") | TokenKind::Symbol(s @ "--") => {
+                    let op = if s == "++" {
+                        BinaryOp::Add
+                    } else {
+                        BinaryOp::Sub
+                    };
+                    self.position += 2;
+                    let one = Expr::new(ExprKind::Int(1), span);
+                    Some(StmtKind::Assign(
+                        name.clone(),
+                        compound(op, &name, one, span),
+                    ))
+                }
+                TokenKind::Symbol(s) => match COMPOUND_ASSIGNMENTS.iter().find(|(c, _)| *c == s) {
+                    Some((_, op)) => {
+                        let op = *op;
+                        self.position += 2;
+                        let value = self.expression()?;
+                        Some(StmtKind::Assign(
+                            name.clone(),
+                            compound(op, &name, value, span),
+                        ))
+                    }
+                    None => None,
+                },
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                return Ok(Stmt { kind, span });
+            }
+        }
+        Ok(Stmt {
+            kind: StmtKind::Expr(self.expression()?),
+            span,
+        })
+    }
+
+    pub fn expression(&mut self) -> Result<Expr, CompileError> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, CompileError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::Symbol(s) => PRECEDENCE[level].iter().find(|(o, _)| o == s),
+                _ => None,
+            };
+            let op = match op {
+                Some((_, op)) => *op,
+                None => return Ok(left),
+            };
+            let span = self.span();
+            self.advance();
+            let right = self.binary(level + 1)?;
+            left = Expr::new(ExprKind::Binary(op, Box::new(left), Box::new(right)), span);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        let span = self.span();
+        let op = match self.peek() {
+            TokenKind::Symbol("-") => Some(UnaryOp::Neg),
+            TokenKind::Symbol("!") => Some(UnaryOp::Not),
+            TokenKind::Symbol("~") => Some(UnaryOp::BitNot),
+            _ => None,
+        };
+        if let Some(op) = op {
+            self.advance();
+            let operand = self.unary()?;
+            return Ok(Expr::new(ExprKind::Unary(op, Box::new(operand)), span));
+        }
+        //(type) expr is a cast
+        if self.check("(") {
+            if let TokenKind::Type(_) = self.peek_at(1) {
+                self.advance();
+                let ty = self.type_name()?;
+                self.expect(")")?;
+                let operand = self.unary()?;
+                return Ok(Expr::new(ExprKind::Cast(ty, Box::new(operand)), span));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, CompileError> {
+        let span = self.span();
+        let kind = match self.peek().clone() {
+            TokenKind::Int(v) => ExprKind::Int(v),
+            TokenKind::Float(v) => ExprKind::Float(v),
+            TokenKind::True => ExprKind::Bool(true),
+            TokenKind::False => ExprKind::Bool(false),
+            TokenKind::Ident(name) => {
+                self.advance();
+                if !self.accept("(") {
+                    return Ok(Expr::new(ExprKind::Var(name), span));
+                }
+                let mut args = vec![];
+                if !self.check(")") {
+                    loop {
+                        args.push(self.expression()?);
+                        if !self.accept(",") {
+                            break;
+                        }
+                    }
+                }
+                self.expect(")")?;
+                return Ok(Expr::new(ExprKind::Call(name, args), span));
+            }
+            TokenKind::Symbol("(") => {
+                self.advance();
+                let inner = self.expression()?;
+                self.expect(")")?;
+                return Ok(inner);
+            }
+            _ => return Err(self.error("expected an expression")),
+        };
+        self.advance();
+        Ok(Expr::new(kind, span))
+    }
+
+    //
+    // Helpers
+    //
+
+    fn type_name(&mut self) -> Result<Type, CompileError> {
+        match self.peek().clone() {
+            TokenKind::Type(name) => {
+                self.advance();
+                Ok(Type::from_name(&name).expect("lexer only emits known types"))
+            }
+            _ => Err(self.error("expected a type")),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, CompileError> {
+        match self.peek().clone() {
+            TokenKind::Ident(name) => {
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn peek(&self) -> &TokenKind {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, ahead: usize) -> &TokenKind {
+        let last = self.tokens.len() - 1;
+        &self.tokens[(self.position + ahead).min(last)].kind
+    }
+
+    fn advance(&mut self) {
+        if self.position < self.tokens.len() - 1 {
+            self.position += 1;
+        }
+    }
+
+    fn check(&self, symbol: &str) -> bool {
+        matches!(self.peek(), TokenKind::Symbol(s) if *s == symbol)
+    }
+
+    fn accept(&mut self, symbol: &str) -> bool {
+        let found = self.check(symbol);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), CompileError> {
+        if self.accept(symbol) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", symbol)))
+        }
+    }
+
+    fn span(&self) -> Span {
+        let token = &self.tokens[self.position];
+        Span {
+            line: token.line,
+            column: token.column,
+        }
+    }
+
+    fn error(&self, message: &str) -> CompileError {
+        let span = self.span();
+        CompileError::new(span.line, span.column, message.to_string())
+    }
+}
+
+fn compound(op: BinaryOp, name: &str, value: Expr, span: Span) -> Expr {
+    let var = Expr::new(ExprKind::Var(name.to_string()), span);
+    Expr::new(ExprKind::Binary(op, Box::new(var), Box::new(value)), span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lexer::tokenize;
+    use super::*;
+
+    fn parse(source: &str) -> Result<Program, CompileError> {
+        Parser::new(tokenize(source)?).parse_program()
+    }
+
+    fn parse_expr(source: &str) -> Expr {
+        Parser::new(tokenize(source).unwrap()).expression().unwrap()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let expr = parse_expr("1 + 2 * 3 << 1 == x & 4");
+        //& binds looser than ==, which binds looser than <<
+        match expr.kind {
+            ExprKind::Binary(BinaryOp::BitAnd, left, _) => match left.kind {
+                ExprKind::Binary(BinaryOp::Eq, left, _) => match left.kind {
+                    ExprKind::Binary(BinaryOp::Shl, left, _) => match left.kind {
+                        ExprKind::Binary(BinaryOp::Add, _, right) => {
+                            assert!(matches!(right.kind, ExprKind::Binary(BinaryOp::Mul, _, _)))
+                        }
+                        other => panic!("{:?}", other),
+                    },
+                    other => panic!("{:?}", other),
+                },
+                other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unary_and_casts() {
+        let expr = parse_expr("-(float)~a");
+        match expr.kind {
+            ExprKind::Unary(UnaryOp::Neg, inner) => match inner.kind {
+                ExprKind::Cast(Type::Float, inner) => {
+                    assert!(matches!(inner.kind, ExprKind::Unary(UnaryOp::BitNot, _)))
+                }
+                other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        }
+        //a parenthesized expression isn't a cast
+        assert_eq!(parse_expr("(a)").kind, ExprKind::Var("a".to_string()));
+    }
+
+    #[test]
+    fn test_parse_function() {
+        let program = parse(
+            "int sum(int n) {\n\
+                 int total = 0;\n\
+                 for (int i = 0; i < n; i++) { total += i; }\n\
+                 while (true) { if (total > 10) break; else continue; }\n\
+                 return total;\n\
+             }\n\
+             void nothing() { sum(1); }",
+        )
+        .unwrap();
+        assert_eq!(program.functions.len(), 2);
+        let sum = &program.functions[0];
+        assert_eq!(sum.params, vec![(Type::Int, "n".to_string())]);
+        assert_eq!(sum.body.len(), 4);
+        match &sum.body[1].kind {
+            StmtKind::For(Some(init), Some(_), Some(step), _) => {
+                assert!(matches!(init.kind, StmtKind::Let(Type::Int, _, Some(_))));
+                assert!(matches!(step.kind, StmtKind::Assign(_, _)));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(sum.body[3].span, Span { line: 5, column: 1 });
+        assert_eq!(program.functions[1].return_type, Type::Void);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse("int f() { return 1 }"),
+            Err(CompileError::new(1, 20, "expected ';'".to_string()))
+        );
+        assert_eq!(
+            parse("int f() {\n  x = ;\n}").unwrap_err(),
+            CompileError::new(2, 7, "expected an expression".to_string())
+        );
+        assert_eq!(
+            parse("f() {}").unwrap_err().message,
+            "expected a type".to_string()
+        );
+        assert_eq!(
+            parse("int f() {").unwrap_err().message,
+            "expected '}'".to_string()
+        );
+    }
+}
@@ -0,0 +1,278 @@
+// Linear scan register allocation (Poletto and Sarkar). Every virtual register gets one
+// live interval over the instruction positions, the intervals are walked in order of
+// their start and given a free register, and when none is left the interval that
+// lives the longest is spilled to a slot in the frame.
+
+use super::convention::{CALLEE_SAVED, CALLER_SAVED};
+use super::ir::{Instr, VReg};
+
+use std::collections::HashMap;
+
+/// Where a virtual register lives for its whole lifetime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Register(u8),
+    /// word index in the function's spill area
+    Spill(u32),
+}
+
+/// The positions a virtual register is live between, inclusive
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interval {
+    pub vreg: VReg,
+    pub start: usize,
+    pub end: usize,
+    /// the value has to survive a call, so it can't sit in a caller saved register
+    pub crosses_call: bool,
+}
+
+/// The result of allocating one function
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Allocation {
+    pub locations: HashMap<VReg, Location>,
+    pub spill_slots: u32,
+    /// the callee saved registers the function has to preserve, in ascending order
+    pub callee_saved: Vec<u8>,
+}
+
+impl Allocation {
+    pub fn location(&self, vreg: VReg) -> Location {
+        self.locations[&vreg]
+    }
+
+    fn new_spill_slot(&mut self) -> u32 {
+        self.spill_slots += 1;
+        self.spill_slots - 1
+    }
+}
+
+/// Computes the live interval of every virtual register. Values that are live at the
+/// top of a loop are kept alive until its backward jump.
+pub fn live_intervals(instrs: &[Instr]) -> Vec<Interval> {
+    let mut ranges: HashMap<VReg, (usize, usize)> = HashMap::new();
+    for (position, instr) in instrs.iter().enumerate() {
+        for vreg in instr.uses().into_iter().chain(instr.def()) {
+            let range = ranges.entry(vreg).or_insert((position, position));
+            range.1 = position;
+        }
+    }
+
+    let labels: HashMap<&str, usize> = instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(position, instr)| match instr {
+            Instr::Label(name) => Some((name.as_str(), position)),
+            _ => None,
+        })
+        .collect();
+    let back_edges: Vec<(usize, usize)> = instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(position, instr)| {
+            let target = labels[instr.jump_target()?];
+            if target <= position {
+                Some((target, position))
+            } else {
+                None
+            }
+        })
+        .collect();
+    //extending one interval can make it live at the top of an enclosing loop
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (top, bottom) in &back_edges {
+            for range in ranges.values_mut() {
+                if range.0 < *top && range.1 >= *top && range.1 < *bottom {
+                    range.1 = *bottom;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let calls: Vec<usize> = instrs
+        .iter()
+        .enumerate()
+        .filter(|(_, instr)| matches!(instr, Instr::Call { .. }))
+        .map(|(position, _)| position)
+        .collect();
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(vreg, (start, end))| Interval {
+            vreg,
+            start,
+            end,
+            crosses_call: calls.iter().any(|c| start < *c && *c < end),
+        })
+        .collect();
+    intervals.sort_by_key(|i| (i.start, i.vreg));
+    intervals
+}
+
+/// Allocates the registers of one function using the calling convention's registers
+pub fn allocate(instrs: &[Instr]) -> Allocation {
+    allocate_with(instrs, CALLER_SAVED, CALLEE_SAVED)
+}
+
+/// Allocates with a custom set of registers, values crossing calls only go in `callee_saved`
+pub fn allocate_with(instrs: &[Instr], caller_saved: &[u8], callee_saved: &[u8]) -> Allocation {
+    let mut allocation = Allocation::default();
+    let mut free: Vec<u8> = caller_saved.iter().chain(callee_saved).cloned().collect();
+    // (end, vreg, register) of the intervals currently holding a register
+    let mut active: Vec<(usize, VReg, u8)> = vec![];
+
+    for interval in live_intervals(instrs) {
+        //a value read by the instruction that starts this one can give up its register,
+        //instructions read their operands before writing
+        active.retain(|(end, _, register)| {
+            if *end <= interval.start {
+                free.push(*register);
+                false
+            } else {
+                true
+            }
+        });
+
+        let allowed = |register: u8| !interval.crosses_call || callee_saved.contains(&register);
+        //caller saved registers first, they cost nothing to use
+        let register = caller_saved
+            .iter()
+            .chain(callee_saved)
+            .cloned()
+            .find(|r| allowed(*r) && free.contains(r));
+        let register = match register {
+            Some(register) => {
+                free.retain(|r| *r != register);
+                Some(register)
+            }
+            None => {
+                //spill whichever of this and the active intervals ends last
+                let victim = active
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (_, _, register))| allowed(*register))
+                    .max_by_key(|(_, (end, _, _))| *end)
+                    .map(|(index, entry)| (index, *entry));
+                match victim {
+                    Some((index, (end, vreg, register))) if end > interval.end => {
+                        active.remove(index);
+                        let slot = allocation.new_spill_slot();
+                        allocation.locations.insert(vreg, Location::Spill(slot));
+                        Some(register)
+                    }
+                    _ => None,
+                }
+            }
+        };
+        let location = match register {
+            Some(register) => {
+                active.push((interval.end, interval.vreg, register));
+                if callee_saved.contains(&register) && !allocation.callee_saved.contains(&register)
+                {
+                    allocation.callee_saved.push(register);
+                }
+                Location::Register(register)
+            }
+            None => Location::Spill(allocation.new_spill_slot()),
+        };
+        allocation.locations.insert(interval.vreg, location);
+    }
+    allocation.callee_saved.sort_unstable();
+    allocation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::Opcode;
+
+    fn op(opcode: Opcode, srcs: &[VReg], dst: Option<VReg>) -> Instr {
+        Instr::Op {
+            opcode,
+            srcs: srcs.to_vec(),
+            dst,
+        }
+    }
+
+    fn constant(dst: VReg) -> Instr {
+        Instr::Const { dst, bits: dst }
+    }
+
+    #[test]
+    fn test_intervals() {
+        let instrs = vec![
+            constant(0),
+            constant(1),
+            Instr::Label("top".to_string()),
+            op(Opcode::ADD, &[0, 1], Some(2)),
+            Instr::Call {
+                target: "f".to_string(),
+                args: vec![2],
+                dst: 3,
+            },
+            op(Opcode::MOV, &[3], Some(0)),
+            Instr::Jump("top".to_string()),
+            Instr::Return(Some(0)),
+        ];
+        let intervals = live_intervals(&instrs);
+        let find = |vreg| intervals.iter().find(|i| i.vreg == vreg).unwrap().clone();
+        //1 is live at the top of the loop so it lasts until the jump back
+        assert_eq!((find(1).start, find(1).end), (1, 6));
+        assert!(find(1).crosses_call);
+        assert_eq!((find(0).start, find(0).end), (0, 7));
+        //arguments and results end and start at the call, they don't cross it
+        assert!(!find(2).crosses_call);
+        assert!(!find(3).crosses_call);
+    }
+
+    #[test]
+    fn test_reuses_registers() {
+        //a chain of short lived values fits in one register
+        let mut instrs = vec![constant(0)];
+        for v in 1..20 {
+            instrs.push(op(Opcode::INC, &[v - 1], Some(v)));
+        }
+        let allocation = allocate_with(&instrs, &[8], &[]);
+        assert_eq!(allocation.spill_slots, 0);
+        assert!((0..20).all(|v| allocation.location(v) == Location::Register(8)));
+    }
+
+    #[test]
+    fn test_spills_longest_interval() {
+        //0 lives until the end, 1 and 2 are short, only two registers
+        let instrs = vec![
+            constant(0),
+            constant(1),
+            constant(2),
+            op(Opcode::ADD, &[1, 2], Some(3)),
+            op(Opcode::ADD, &[0, 3], Some(4)),
+        ];
+        let allocation = allocate_with(&instrs, &[8, 9], &[]);
+        assert_eq!(allocation.location(0), Location::Spill(0));
+        assert_eq!(allocation.spill_slots, 1);
+        assert!(matches!(allocation.location(1), Location::Register(_)));
+        assert!(matches!(allocation.location(2), Location::Register(_)));
+    }
+
+    #[test]
+    fn test_values_across_calls_are_callee_saved() {
+        let instrs = vec![
+            constant(0),
+            Instr::Call {
+                target: "f".to_string(),
+                args: vec![],
+                dst: 1,
+            },
+            op(Opcode::ADD, &[0, 1], Some(2)),
+            Instr::Return(Some(2)),
+        ];
+        let allocation = allocate(&instrs);
+        assert_eq!(allocation.location(0), Location::Register(16));
+        assert_eq!(allocation.location(1), Location::Register(8));
+        assert_eq!(allocation.callee_saved, vec![16]);
+        //without callee saved registers it has to go to memory
+        let allocation = allocate_with(&instrs, &[8, 9], &[]);
+        assert_eq!(allocation.location(0), Location::Spill(0));
+    }
+}
@@ -0,0 +1,401 @@
+use super::ast::*;
+use super::CompileError;
+
+use std::collections::HashMap;
+
+/// Parameter and return types of a function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub return_type: Type,
+}
+
+/// Checks the types of the whole program and records them on every expression.
+/// Integer literals take the type their context wants, so `uint x = 1;` and `f + 2`
+/// work without casts.
+pub fn check_program(program: &mut Program) -> Result<(), CompileError> {
+    let mut signatures = HashMap::new();
+    for function in &program.functions {
+        let signature = Signature {
+            params: function.params.iter().map(|(ty, _)| *ty).collect(),
+            return_type: function.return_type,
+        };
+        if signatures
+            .insert(function.name.clone(), signature)
+            .is_some()
+        {
+            return Err(error(
+                function.span,
+                format!("function '{}' is defined twice", function.name),
+            ));
+        }
+    }
+    for function in &mut program.functions {
+        let mut checker = Checker {
+            signatures: &signatures,
+            scopes: vec![HashMap::new()],
+            return_type: function.return_type,
+            loops: 0,
+        };
+        for (ty, name) in &function.params {
+            checker.declare(*ty, name, function.span)?;
+        }
+        for statement in &mut function.body {
+            checker.statement(statement)?;
+        }
+    }
+    Ok(())
+}
+
+struct Checker<'a> {
+    signatures: &'a HashMap<String, Signature>,
+    scopes: Vec<HashMap<String, Type>>,
+    return_type: Type,
+    // how many loops the current statement is inside, for break and continue
+    loops: usize,
+}
+
+impl<'a> Checker<'a> {
+    fn statement(&mut self, statement: &mut Stmt) -> Result<(), CompileError> {
+        let span = statement.span;
+        match &mut statement.kind {
+            StmtKind::Let(ty, name, value) => {
+                if let Some(value) = value {
+                    self.expect(value, *ty)?;
+                }
+                self.declare(*ty, name, span)?;
+            }
+            StmtKind::Assign(name, value) => {
+                let ty = self.lookup(name, span)?;
+                self.expect(value, ty)?;
+            }
+            StmtKind::Expr(expr) => {
+                self.expr(expr, None)?;
+            }
+            StmtKind::If(condition, then, otherwise) => {
+                self.expect(condition, Type::Bool)?;
+                self.scoped(then)?;
+                if let Some(otherwise) = otherwise {
+                    self.scoped(otherwise)?;
+                }
+            }
+            StmtKind::While(condition, body) => {
+                self.expect(condition, Type::Bool)?;
+                self.loops += 1;
+                self.scoped(body)?;
+                self.loops -= 1;
+            }
+            StmtKind::For(init, condition, step, body) => {
+                //the init declaration is only visible inside the loop
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.statement(init)?;
+                }
+                if let Some(condition) = condition {
+                    self.expect(condition, Type::Bool)?;
+                }
+                if let Some(step) = step {
+                    self.statement(step)?;
+                }
+                self.loops += 1;
+                self.scoped(body)?;
+                self.loops -= 1;
+                self.scopes.pop();
+            }
+            StmtKind::Return(value) => match (value, self.return_type) {
+                (None, Type::Void) => {}
+                (None, ty) => return Err(error(span, format!("missing {} return value", ty))),
+                (Some(value), Type::Void) => {
+                    return Err(error(value.span, "void functions can't return a value"))
+                }
+                (Some(value), ty) => self.expect(value, ty)?,
+            },
+            StmtKind::Break | StmtKind::Continue => {
+                if self.loops == 0 {
+                    return Err(error(span, "break and continue have to be inside a loop"));
+                }
+            }
+            StmtKind::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                for statement in statements {
+                    self.statement(statement)?;
+                }
+                self.scopes.pop();
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a statement in a scope of its own
+    fn scoped(&mut self, statement: &mut Stmt) -> Result<(), CompileError> {
+        self.scopes.push(HashMap::new());
+        let result = self.statement(statement);
+        self.scopes.pop();
+        result
+    }
+
+    /// Checks an expression that has to be of type `ty`
+    fn expect(&mut self, expr: &mut Expr, ty: Type) -> Result<(), CompileError> {
+        let found = self.expr(expr, Some(ty))?;
+        if found != ty {
+            return Err(mismatch(expr.span, ty, found));
+        }
+        Ok(())
+    }
+
+    /// Works out the type of an expression. `expected` is only a hint for literals.
+    fn expr(&mut self, expr: &mut Expr, expected: Option<Type>) -> Result<Type, CompileError> {
+        let span = expr.span;
+        let ty = match &mut expr.kind {
+            ExprKind::Int(value) => match expected {
+                Some(Type::Uint) => Type::Uint,
+                Some(Type::Float) => {
+                    expr.kind = ExprKind::Float(*value as f32);
+                    Type::Float
+                }
+                _ if *value > i32::MAX as u32 => {
+                    return Err(error(span, format!("{} doesn't fit in an int", value)))
+                }
+                _ => Type::Int,
+            },
+            ExprKind::Float(_) => Type::Float,
+            ExprKind::Bool(_) => Type::Bool,
+            ExprKind::Var(name) => self.lookup(name, span)?,
+            ExprKind::Unary(op, operand) => {
+                let ty = self.expr(operand, expected)?;
+                let allowed = match op {
+                    UnaryOp::Neg => ty.is_numeric(),
+                    UnaryOp::Not => ty == Type::Bool,
+                    UnaryOp::BitNot => ty.is_integer(),
+                };
+                if !allowed {
+                    return Err(error(span, format!("operator can't be used on {}", ty)));
+                }
+                ty
+            }
+            ExprKind::Binary(op, left, right) => self.binary(*op, left, right, expected, span)?,
+            ExprKind::Call(name, args) => {
+                let signature = self
+                    .signatures
+                    .get(name.as_str())
+                    .ok_or_else(|| error(span, format!("unknown function '{}'", name)))?;
+                if signature.params.len() != args.len() {
+                    return Err(error(
+                        span,
+                        format!(
+                            "'{}' takes {} arguments but {} were given",
+                            name,
+                            signature.params.len(),
+                            args.len()
+                        ),
+                    ));
+                }
+                for (arg, ty) in args.iter_mut().zip(&signature.params) {
+                    self.expect(arg, *ty)?;
+                }
+                signature.return_type
+            }
+            ExprKind::Cast(ty, operand) => {
+                let from = self.expr(operand, None)?;
+                if from == Type::Void || *ty == Type::Void {
+                    return Err(error(span, format!("can't cast {} to {}", from, ty)));
+                }
+                *ty
+            }
+        };
+        expr.ty = Some(ty);
+        Ok(ty)
+    }
+
+    fn binary(
+        &mut self,
+        op: BinaryOp,
+        left: &mut Expr,
+        right: &mut Expr,
+        expected: Option<Type>,
+        span: Span,
+    ) -> Result<Type, CompileError> {
+        let operand_hint = if op.is_comparison() || op == BinaryOp::And || op == BinaryOp::Or {
+            None
+        } else {
+            expected
+        };
+        //a literal on the left takes the type of the right hand side
+        let (left_ty, right_ty) = match (&left.kind, op) {
+            (ExprKind::Int(_), BinaryOp::Shl) | (ExprKind::Int(_), BinaryOp::Shr) => {
+                let left_ty = self.expr(left, operand_hint)?;
+                (left_ty, self.expr(right, None)?)
+            }
+            (ExprKind::Int(_), _) => {
+                let right_ty = self.expr(right, operand_hint)?;
+                (self.expr(left, Some(right_ty))?, right_ty)
+            }
+            (_, BinaryOp::Shl) | (_, BinaryOp::Shr) => {
+                let left_ty = self.expr(left, operand_hint)?;
+                (left_ty, self.expr(right, None)?)
+            }
+            _ => {
+                let left_ty = self.expr(left, operand_hint)?;
+                (left_ty, self.expr(right, Some(left_ty))?)
+            }
+        };
+        let bad_operands = || {
+            error(
+                span,
+                format!("operator can't be used on {} and {}", left_ty, right_ty),
+            )
+        };
+        match op {
+            //the shift amount can be any integer
+            BinaryOp::Shl | BinaryOp::Shr => {
+                if !left_ty.is_integer() || !right_ty.is_integer() {
+                    return Err(bad_operands());
+                }
+                return Ok(left_ty);
+            }
+            BinaryOp::And | BinaryOp::Or => {
+                if left_ty != Type::Bool || right_ty != Type::Bool {
+                    return Err(bad_operands());
+                }
+                return Ok(Type::Bool);
+            }
+            _ => {}
+        }
+        if left_ty != right_ty {
+            return Err(mismatch(right.span, left_ty, right_ty));
+        }
+        let allowed = match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => left_ty.is_numeric(),
+            BinaryOp::Mod | BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor => {
+                left_ty.is_integer()
+            }
+            BinaryOp::Eq | BinaryOp::Neq => left_ty != Type::Void,
+            _ => left_ty.is_numeric(),
+        };
+        if !allowed {
+            return Err(bad_operands());
+        }
+        Ok(if op.is_comparison() {
+            Type::Bool
+        } else {
+            left_ty
+        })
+    }
+
+    fn declare(&mut self, ty: Type, name: &str, span: Span) -> Result<(), CompileError> {
+        if ty == Type::Void {
+            return Err(error(span, format!("'{}' can't be void", name)));
+        }
+        let scope = self.scopes.last_mut().expect("there is always a scope");
+        if scope.insert(name.to_string(), ty).is_some() {
+            return Err(error(span, format!("'{}' is already declared", name)));
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str, span: Span) -> Result<Type, CompileError> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+            .ok_or_else(|| error(span, format!("unknown variable '{}'", name)))
+    }
+}
+
+fn error<S: Into<String>>(span: Span, message: S) -> CompileError {
+    CompileError::new(span.line, span.column, message.into())
+}
+
+fn mismatch(span: Span, expected: Type, found: Type) -> CompileError {
+    error(
+        span,
+        format!("type mismatch: expected {}, found {}", expected, found),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lexer::tokenize;
+    use super::super::parser::Parser;
+    use super::*;
+
+    fn check(source: &str) -> Result<Program, CompileError> {
+        let mut program = Parser::new(tokenize(source)?).parse_program()?;
+        check_program(&mut program)?;
+        Ok(program)
+    }
+
+    #[test]
+    fn test_literals_adapt() {
+        let program =
+            check("float f(uint a) { uint b = a + 1; float c = 2; return c * 3; }").unwrap();
+        match &program.functions[0].body[1].kind {
+            StmtKind::Let(Type::Float, _, Some(value)) => {
+                assert_eq!(value.kind, ExprKind::Float(2.0))
+            }
+            other => panic!("{:?}", other),
+        }
+        match &program.functions[0].body[0].kind {
+            StmtKind::Let(_, _, Some(value)) => match &value.kind {
+                ExprKind::Binary(_, _, right) => assert_eq!(right.ty, Some(Type::Uint)),
+                other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        }
+        //literals on the left follow the right hand side
+        assert!(check("bool f(uint a) { return 1 < a; }").is_ok());
+        assert!(check("uint f(uint a) { return a >> 2; }").is_ok());
+    }
+
+    #[test]
+    fn test_type_errors() {
+        let message = |source: &str| check(source).unwrap_err().message;
+        assert_eq!(
+            message("int f(float a) { return a; }"),
+            "type mismatch: expected int, found float"
+        );
+        assert_eq!(
+            message("int f(int a, uint b) { return a + b; }"),
+            "type mismatch: expected int, found uint"
+        );
+        assert_eq!(
+            message("void f(float a) { float b = a % 2.0; }"),
+            "operator can't be used on float and float"
+        );
+        assert_eq!(
+            message("void f() { if (1) {} }"),
+            "type mismatch: expected bool, found int"
+        );
+        assert_eq!(message("void f() { x = 1; }"), "unknown variable 'x'");
+        assert_eq!(message("void f() { g(); }"), "unknown function 'g'");
+        assert_eq!(
+            message("int g(int a) { return a; } void f() { g(); }"),
+            "'g' takes 1 arguments but 0 were given"
+        );
+        assert_eq!(
+            message("void f() { break; }"),
+            "break and continue have to be inside a loop"
+        );
+        assert_eq!(
+            message("void f() { int a; int a; }"),
+            "'a' is already declared"
+        );
+        assert_eq!(message("int f() { return; }"), "missing int return value");
+        assert_eq!(
+            message("void f() {} void f() {}"),
+            "function 'f' is defined twice"
+        );
+        assert_eq!(
+            message("void f() { int a = 3000000000; }"),
+            "3000000000 doesn't fit in an int"
+        );
+    }
+
+    #[test]
+    fn test_scopes() {
+        //blocks can shadow and their variables go away at the end
+        assert!(check("void f() { int a; { float a; } a = 1; }").is_ok());
+        assert!(check("void f() { for (int i = 0; i < 3; i++) {} i = 1; }").is_err());
+        assert!(check("void f() { for (int i = 0; i < 3; i++) {} int i; }").is_ok());
+    }
+}
@@ -0,0 +1,267 @@
+// ChaCha20 (RFC 8439) keystream used to encrypt modules, with an HMAC-SHA256 tag over
+// the ciphertext so a changed module or a wrong key is caught before anything is decrypted.
+// The keystream can be started at any byte offset, so the vm is able to decrypt
+// any part of a program without touching the rest of it.
+
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use std::fmt;
+
+/// 256 bit key used to encrypt and decrypt modules
+pub type Key = [u8; 32];
+/// 96 bit nonce stored next to the encrypted data
+pub type Nonce = [u8; 12];
+
+/// Length of the tag at the end of a sealed payload
+pub const TAG_LENGTH: usize = 32;
+
+/// XORs `data` with the keystream starting at byte `offset` of the stream.
+/// Running it twice with the same arguments gives back the original data.
+pub fn apply_keystream(key: &Key, nonce: &Nonce, offset: u64, data: &mut [u8]) {
+    let mut cipher = ChaCha20::new(key.into(), nonce.into());
+    cipher.seek(offset);
+    cipher.apply_keystream(data);
+}
+
+/// Random access to a keystream a byte at a time, for decrypting code as it's fetched.
+/// Only the 64 byte block of the last position asked for is kept, and it's wiped along
+/// with the key when the keystream is dropped.
+#[derive(Clone)]
+pub struct Keystream {
+    key: Key,
+    nonce: Nonce,
+    // stream offset of position 0
+    offset: u64,
+    block: Option<u64>,
+    bytes: [u8; 64],
+}
+
+impl Keystream {
+    pub fn new(key: &Key, nonce: &Nonce, offset: u64) -> Keystream {
+        Keystream {
+            key: *key,
+            nonce: *nonce,
+            offset,
+            block: None,
+            bytes: [0; 64],
+        }
+    }
+
+    /// The keystream byte that position is encrypted with
+    pub fn at(&mut self, position: usize) -> u8 {
+        let position = self.offset + position as u64;
+        let number = position / 64;
+        if self.block != Some(number) {
+            self.bytes = [0; 64];
+            apply_keystream(&self.key, &self.nonce, number * 64, &mut self.bytes);
+            self.block = Some(number);
+        }
+        self.bytes[(position % 64) as usize]
+    }
+}
+
+impl Zeroize for Keystream {
+    fn zeroize(&mut self) {
+        self.key.zeroize();
+        self.nonce.zeroize();
+        self.bytes.zeroize();
+        self.block = None;
+    }
+}
+
+impl Drop for Keystream {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+// the key stays out of debug output
+impl fmt::Debug for Keystream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keystream")
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+/// A fresh random nonce
+pub fn random_nonce() -> Nonce {
+    let mut nonce = [0; 12];
+    getrandom::getrandom(&mut nonce).expect("the os has no random numbers");
+    nonce
+}
+
+/// A key of its own for each use of a module key, HMAC-SHA256 of the label under the key.
+/// Without the module key the derived one can't be worked out.
+pub fn derive_key(key: &Key, label: &[u8]) -> Key {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("hmac takes any key size");
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+/// The key the ciphertext of `seal` is encrypted with
+pub fn cipher_key(key: &Key) -> Key {
+    derive_key(key, b"biobox cipher")
+}
+
+fn tag(key: &Key, nonce: &Nonce, ciphertext: &[u8]) -> Hmac<Sha256> {
+    let mut mac_key = derive_key(key, b"biobox tag");
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&mac_key).expect("hmac takes any key size");
+    mac_key.zeroize();
+    mac.update(nonce);
+    mac.update(ciphertext);
+    mac
+}
+
+/// Encrypts data under a random nonce and authenticates it with HMAC-SHA256.
+/// The payload is the nonce, the ciphertext and the tag.
+pub fn seal(key: &Key, data: &[u8]) -> Vec<u8> {
+    let nonce = random_nonce();
+    let mut ciphertext = data.to_vec();
+    let mut cipher = cipher_key(key);
+    apply_keystream(&cipher, &nonce, 0, &mut ciphertext);
+    cipher.zeroize();
+    let tag = tag(key, &nonce, &ciphertext).finalize().into_bytes();
+
+    let mut payload = nonce.to_vec();
+    payload.append(&mut ciphertext);
+    payload.extend_from_slice(&tag);
+    payload
+}
+
+/// Checks the tag of a sealed payload without decrypting it, giving back the nonce and
+/// the ciphertext. None if the key is wrong or the payload was changed.
+pub fn authenticate<'a>(key: &Key, payload: &'a [u8]) -> Option<(Nonce, &'a [u8])> {
+    if payload.len() < 12 + TAG_LENGTH {
+        return None;
+    }
+    let (nonce, rest) = payload.split_at(12);
+    let (ciphertext, expected) = rest.split_at(rest.len() - TAG_LENGTH);
+    let mut nonce_bytes = [0; 12];
+    nonce_bytes.copy_from_slice(nonce);
+    tag(key, &nonce_bytes, ciphertext)
+        .verify_slice(expected)
+        .ok()?;
+    Some((nonce_bytes, ciphertext))
+}
+
+/// Authenticates and decrypts a payload made by `seal`
+pub fn open(key: &Key, payload: &[u8]) -> Option<Vec<u8>> {
+    let (nonce, ciphertext) = authenticate(key, payload)?;
+    let mut data = ciphertext.to_vec();
+    let mut cipher = cipher_key(key);
+    apply_keystream(&cipher, &nonce, 0, &mut data);
+    cipher.zeroize();
+    Some(data)
+}
+
+/// Parses a key written as 64 hex characters
+pub fn key_from_hex(hex: &str) -> Option<Key> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> Key {
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        key
+    }
+
+    #[test]
+    fn test_rfc8439_block() {
+        //test vector from section 2.3.2 of RFC 8439, block 1 starts at byte 64
+        let nonce = [0, 0, 0, 9, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let mut output = [0; 64];
+        apply_keystream(&test_key(), &nonce, 64, &mut output);
+        assert_eq!(
+            &output[..16],
+            &[
+                0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20,
+                0x71, 0xc4
+            ]
+        );
+        assert_eq!(&output[60..], &[0xa2, 0x50, 0x3c, 0x4e]);
+    }
+
+    #[test]
+    fn test_keystream_offsets() {
+        let key = test_key();
+        let nonce = [7; 12];
+        let mut whole = vec![0u8; 200];
+        apply_keystream(&key, &nonce, 0, &mut whole);
+        //starting part way through the stream lines up with the full run
+        let mut part = vec![0u8; 100];
+        apply_keystream(&key, &nonce, 77, &mut part);
+        assert_eq!(&part[..], &whole[77..177]);
+        //and applying it again undoes it
+        apply_keystream(&key, &nonce, 0, &mut whole);
+        assert_eq!(whole, vec![0u8; 200]);
+    }
+
+    #[test]
+    fn test_random_access_keystream() {
+        let key = test_key();
+        let nonce = [3; 12];
+        let mut whole = vec![0u8; 300];
+        apply_keystream(&key, &nonce, 0, &mut whole);
+        let mut keystream = Keystream::new(&key, &nonce, 10);
+        //backwards, across block boundaries
+        for position in (0..290).rev() {
+            assert_eq!(keystream.at(position), whole[position + 10]);
+        }
+        keystream.zeroize();
+        assert_eq!(keystream.key, [0; 32]);
+        assert_eq!(keystream.bytes, [0; 64]);
+    }
+
+    #[test]
+    fn test_key_from_hex() {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        assert_eq!(key_from_hex(hex), Some(test_key()));
+        assert_eq!(key_from_hex("0011"), None);
+        assert_eq!(key_from_hex(&hex.replace("0a", "zz")), None);
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = test_key();
+        let payload = seal(&key, b"attack at dawn");
+        assert_eq!(payload.len(), 12 + 14 + TAG_LENGTH);
+        assert_eq!(open(&key, &payload), Some(b"attack at dawn".to_vec()));
+        //the nonce is random, so the same data never encrypts the same way twice
+        assert_ne!(seal(&key, b"attack at dawn"), payload);
+        //a wrong key or any changed byte fails the tag
+        assert_eq!(open(&[1; 32], &payload), None);
+        for i in 0..payload.len() {
+            let mut changed = payload.clone();
+            changed[i] ^= 1;
+            assert_eq!(open(&key, &changed), None);
+        }
+        assert_eq!(open(&key, &payload[..40]), None);
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let key = test_key();
+        assert_eq!(derive_key(&key, b"a"), derive_key(&key, b"a"));
+        assert_ne!(derive_key(&key, b"a"), derive_key(&key, b"b"));
+        assert_ne!(derive_key(&key, b"a"), derive_key(&[1; 32], b"a"));
+    }
+}
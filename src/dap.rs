@@ -0,0 +1,736 @@
+// Debug adapter protocol server, so VS Code and other editors can debug assembly files.
+// Messages are JSON behind a Content-Length header, `biobox dap` reads them from stdin
+// and answers on stdout. Launching assembles the file along with its source map, which
+// is how line breakpoints and stack frames get between lines and offsets.
+//
+// Runs are synchronous: a continue is answered, runs until something stops it and then
+// sends the stop event, so there is no pause. Everything happens on the one thread.
+
+use crate::assembler::assemble_with_source_map;
+use crate::module::source_map::SourceMap;
+use crate::vm::debugger::{Debugger, StopReason};
+use crate::vm::memory::output_address;
+use crate::vm::{VmError, VM};
+
+use serde_json::{json, Value};
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+// id of the only thread
+const THREAD: u64 = 1;
+// variablesReference of the two scopes
+const REGISTERS: u64 = 1;
+const MEMORY: u64 = 2;
+// the most one readMemory hands back
+const MAX_READ: u64 = 1 << 20;
+
+/// Answers one client's requests, from initialize to disconnect
+pub struct DapServer {
+    debugger: Option<Debugger>,
+    source_map: SourceMap,
+    // the launched file, as the client named it
+    path: String,
+    stop_on_entry: bool,
+    // the exception filter, off means an error ends the session
+    stop_on_errors: bool,
+    // the error the program stopped on, it can't run any further after one
+    error: Option<VmError>,
+    // pcs of the line breakpoints
+    breakpoints: Vec<usize>,
+    seq: u64,
+    outgoing: Vec<Value>,
+    done: bool,
+}
+
+impl Default for DapServer {
+    fn default() -> DapServer {
+        DapServer::new()
+    }
+}
+
+impl DapServer {
+    pub fn new() -> DapServer {
+        DapServer {
+            debugger: None,
+            source_map: SourceMap::new(),
+            path: String::new(),
+            stop_on_entry: false,
+            stop_on_errors: true,
+            error: None,
+            breakpoints: vec![],
+            seq: 0,
+            outgoing: vec![],
+            done: false,
+        }
+    }
+
+    /// Handles requests from the input until the client disconnects or hangs up
+    pub fn serve<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        while !self.done {
+            let request = match read_message(&mut input)? {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            self.handle(&request);
+            for message in self.outgoing.drain(..) {
+                write_message(&mut output, &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: &Value) {
+        let command = request["command"].as_str().unwrap_or("");
+        let arguments = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setExceptionBreakpoints" => {
+                let filters = arguments["filters"].as_array();
+                self.stop_on_errors =
+                    filters.is_some_and(|filters| filters.iter().any(|filter| filter == "vmError"));
+                Ok(Value::Null)
+            }
+            "configurationDone" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "biobox" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    {
+                        "name": "Registers",
+                        "presentationHint": "registers",
+                        "variablesReference": REGISTERS,
+                        "expensive": false
+                    },
+                    { "name": "Memory", "variablesReference": MEMORY, "expensive": false }
+                ]
+            })),
+            "variables" => self.variables(arguments),
+            "readMemory" => self.read_memory(arguments),
+            "exceptionInfo" => self.exception_info(),
+            "continue" => Ok(json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" => Ok(Value::Null),
+            "disconnect" | "terminate" => {
+                self.done = true;
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        };
+        let succeeded = result.is_ok();
+        self.respond(request, result);
+        if !succeeded {
+            return;
+        }
+        //what follows the response
+        match command {
+            //breakpoints need the source map, so they are asked for after launching
+            "launch" => self.event("initialized", Value::Null),
+            "configurationDone" if self.stop_on_entry => self.stopped("entry", None),
+            "configurationDone" | "continue" => self.run(Debugger::resume_all),
+            "next" => self.run(Debugger::step_over),
+            "stepIn" => self.run(Debugger::step),
+            "stepOut" => self.run(Debugger::step_out),
+            _ => {}
+        }
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or("launch needs the program to debug")?;
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let (module, source_map) =
+            assemble_with_source_map(&source).map_err(|e| format!("{}: {}", path, e))?;
+        let mut vm = VM::new();
+        vm.load_module(&module);
+        let mut debugger = Debugger::new(vm);
+        //starting values of $0, $1 and on
+        if let Some(registers) = arguments["registers"].as_array() {
+            for (register, value) in registers.iter().enumerate() {
+                let value = value
+                    .as_i64()
+                    .ok_or_else(|| format!("register ${} has to be a number", register))?;
+                if !debugger.set_register(register, value as i32) {
+                    return Err("there are only 32 registers".to_string());
+                }
+            }
+        }
+        self.debugger = Some(debugger);
+        self.source_map = source_map;
+        self.path = path.to_string();
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.error = None;
+        self.breakpoints.clear();
+        Ok(Value::Null)
+    }
+
+    // replaces the breakpoints with the lines asked for, moved down to lines with code
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let debugger = self.debugger.as_mut().ok_or("nothing has been launched")?;
+        for pc in self.breakpoints.drain(..) {
+            debugger.remove_breakpoint(pc);
+        }
+        let ours = same_file(
+            arguments["source"]["path"].as_str().unwrap_or(""),
+            &self.path,
+        );
+        let source = source(&self.path);
+        let mut results = vec![];
+        for requested in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = requested["line"].as_u64().unwrap_or(0) as u32;
+            let found = self.source_map.pc_for_line(line).filter(|_| ours);
+            results.push(match found {
+                Some((pc, location)) => {
+                    debugger.add_breakpoint(pc);
+                    self.breakpoints.push(pc);
+                    json!({ "verified": true, "line": location.line, "source": source })
+                }
+                None => json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no code on or after this line"
+                }),
+            });
+        }
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    // the pc, then the CALL of every call in progress going outwards
+    fn stack_trace(&self) -> Result<Value, String> {
+        let debugger = self.debugger.as_ref().ok_or("nothing has been launched")?;
+        let mut pcs = vec![debugger.pc()];
+        pcs.extend(
+            debugger
+                .call_stack()
+                .iter()
+                .rev()
+                .map(|pc| pc.saturating_sub(4)),
+        );
+        let frames: Vec<Value> = pcs
+            .iter()
+            .enumerate()
+            .map(|(id, pc)| {
+                let mut frame = json!({
+                    "id": id,
+                    "name": format!("{:#06x}", pc),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("{:#x}", pc)
+                });
+                if let Some(location) = self.source_map.lookup(*pc) {
+                    frame["line"] = json!(location.line);
+                    frame["column"] = json!(location.column);
+                    frame["source"] = source(&self.path);
+                }
+                frame
+            })
+            .collect();
+        Ok(json!({ "stackFrames": frames, "totalFrames": pcs.len() }))
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Value, String> {
+        let debugger = self.debugger.as_ref().ok_or("nothing has been launched")?;
+        let mut variables = vec![];
+        match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS) => {
+                for (register, value) in debugger.registers().iter().enumerate() {
+                    variables.push(variable(&format!("${}", register), value.to_string()));
+                }
+                variables.push(variable("pc", debugger.pc().to_string()));
+                variables.push(variable("flag", debugger.equal_flag().to_string()));
+            }
+            //opened in the editor's memory view through their memoryReference
+            Some(MEMORY) => {
+                let mut heap = variable("heap", format!("{} bytes", debugger.heap().len()));
+                heap["memoryReference"] = json!(format!("{:#010x}", 0));
+                variables.push(heap);
+                let outputs = (0..).map_while(|index| debugger.vm().output(index));
+                for (index, written) in outputs.enumerate() {
+                    let name = format!("output {}", index);
+                    let mut output = variable(&name, format!("{} bytes", written.len()));
+                    output["memoryReference"] = json!(format!("{:#010x}", output_address(index)));
+                    variables.push(output);
+                }
+            }
+            _ => return Err("no such variables".to_string()),
+        }
+        Ok(json!({ "variables": variables }))
+    }
+
+    // bytes of the vm's address space, from the reference up to the first that isn't mapped
+    fn read_memory(&mut self, arguments: &Value) -> Result<Value, String> {
+        let debugger = self.debugger.as_mut().ok_or("nothing has been launched")?;
+        let reference = arguments["memoryReference"].as_str().unwrap_or("");
+        let base = u32::from_str_radix(reference.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("'{}' is not a memory reference", reference))?;
+        let start = i64::from(base) + arguments["offset"].as_i64().unwrap_or(0);
+        let count = arguments["count"].as_u64().unwrap_or(0).min(MAX_READ);
+        let mut bytes = vec![];
+        for i in 0..count as i64 {
+            let byte = u32::try_from(start + i)
+                .ok()
+                .and_then(|address| debugger.read_memory(address).ok());
+            match byte {
+                Some(byte) => bytes.push(byte),
+                None => break,
+            }
+        }
+        Ok(json!({
+            "address": format!("{:#010x}", start),
+            "data": base64(&bytes),
+            "unreadableBytes": count - bytes.len() as u64
+        }))
+    }
+
+    fn exception_info(&self) -> Result<Value, String> {
+        let error = self
+            .error
+            .as_ref()
+            .ok_or("the program didn't stop on an error")?;
+        //the variant's name, without whatever it holds
+        let id: String = format!("{:?}", error)
+            .chars()
+            .take_while(|c| c.is_alphanumeric())
+            .collect();
+        Ok(json!({
+            "exceptionId": id,
+            "description": error.to_string(),
+            "breakMode": "always"
+        }))
+    }
+
+    // resumes the program one way or another, then tells the client where it stopped
+    fn run(&mut self, resume: fn(&mut Debugger) -> StopReason) {
+        //nothing runs after an error, the pc was left inside the failed instruction
+        if self.error.take().is_some() {
+            return self.exited(1);
+        }
+        let stop = match &mut self.debugger {
+            Some(debugger) => resume(debugger),
+            None => return,
+        };
+        match stop {
+            StopReason::Step | StopReason::Reached(_) => self.stopped("step", None),
+            StopReason::Breakpoint(_) => self.stopped("breakpoint", None),
+            StopReason::Watchpoint { .. } => self.stopped("data breakpoint", None),
+            StopReason::Halted => self.exited(0),
+            StopReason::Error(e) if self.stop_on_errors => {
+                self.stopped("exception", Some(e.to_string()));
+                self.error = Some(e);
+            }
+            StopReason::Error(e) => {
+                let output = format!("VM error: {}\n", e);
+                self.event("output", json!({ "category": "stderr", "output": output }));
+                self.exited(1);
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        let mut body = json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+            body["text"] = json!(description);
+        }
+        self.event("stopped", body);
+    }
+
+    fn exited(&mut self, code: i32) {
+        self.event("exited", json!({ "exitCode": code }));
+        self.event("terminated", Value::Null);
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok()
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(message);
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        self.outgoing.push(message);
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsReadMemoryRequest": true,
+        "supportsExceptionInfoRequest": true,
+        "supportsTerminateRequest": true,
+        "exceptionBreakpointFilters": [
+            { "filter": "vmError", "label": "VM errors", "default": true }
+        ]
+    })
+}
+
+fn source(path: &str) -> Value {
+    let name = Path::new(path)
+        .file_name()
+        .map_or(path.into(), |name| name.to_string_lossy());
+    json!({ "name": name, "path": path })
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+// the same file even when written two different ways
+fn same_file(first: &str, second: &str) -> bool {
+    match (fs::canonicalize(first), fs::canonicalize(second)) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => first == second,
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(group >> (18 - 6 * i) & 63) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// the next message, None once the input is closed
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| invalid("message without a Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid(&e.to_string()))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::io::Cursor;
+
+    // calls a function doubling $0 into $4 and adds that to $1, counting $0 down to 0
+    const LOOP: &str = ".output total i32 $1\n\
+                        load $0 #3\n\
+                        load $5 @double\n\
+                        load $3 @top\n\
+                        top: call $5\n\
+                        add $1 $4 $1\n\
+                        dec $0\n\
+                        load $6 #0\n\
+                        gt $0 $6\n\
+                        jeq $3\n\
+                        hlt\n\
+                        double: add $0 $0 $4\n\
+                        ret\n";
+
+    // stores 7 on the heap, then divides it by $3
+    const DIVIDE: &str = "load $0 #4\naloc $0\nload $1 #7\nstb $1 $2\ndiv $1 $3 $4\nhlt\n";
+
+    fn write_source(name: &str, source: &str) -> String {
+        let path = env::temp_dir().join(format!("biobox-dap-{}-{}.asm", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    // runs the requests through a server and hands back everything it sent
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let mut input = vec![];
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            write_message(&mut input, &request).unwrap();
+        }
+        let mut output = vec![];
+        DapServer::new()
+            .serve(Cursor::new(input), &mut output)
+            .unwrap();
+        let mut output = Cursor::new(output);
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    // everything in the recording has to be in what was sent, arrays match as prefixes
+    fn matches(recorded: &Value, sent: &Value) -> bool {
+        match (recorded, sent) {
+            (Value::Object(recorded), Value::Object(sent)) => recorded
+                .iter()
+                .all(|(key, value)| sent.get(key).is_some_and(|sent| matches(value, sent))),
+            (Value::Array(recorded), Value::Array(sent)) => {
+                recorded.len() <= sent.len()
+                    && recorded.iter().zip(sent).all(|(r, s)| matches(r, s))
+            }
+            (recorded, sent) => recorded == sent,
+        }
+    }
+
+    fn check(recording: &[Value], sent: &[Value]) {
+        assert_eq!(recording.len(), sent.len(), "{:#?}", sent);
+        for (recorded, sent) in recording.iter().zip(sent) {
+            assert!(matches(recorded, sent), "{:#} in {:#}", recorded, sent);
+        }
+    }
+
+    fn response(command: &str) -> Value {
+        json!({ "type": "response", "command": command, "success": true })
+    }
+
+    fn event(event: &str, body: Value) -> Value {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        message
+    }
+
+    fn stopped(reason: &str) -> Value {
+        event("stopped", json!({ "reason": reason, "threadId": 1 }))
+    }
+
+    fn at_line(line: u32) -> Value {
+        json!({ "type": "response", "body": { "stackFrames": [{ "line": line }] } })
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let path = write_source("loop", LOOP);
+        let sent = session(&[
+            json!({ "command": "initialize", "arguments": { "adapterID": "biobox" } }),
+            json!({ "command": "launch", "arguments": { "program": path, "stopOnEntry": true } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": path },
+                "breakpoints": [{ "line": 7 }, { "line": 1 }, { "line": 20 }]
+            } }),
+            json!({ "command": "setExceptionBreakpoints", "arguments": { "filters": ["vmError"] } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "threads" }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "scopes", "arguments": { "frameId": 0 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            //only the call, then into it and back out
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": path },
+                "breakpoints": [{ "line": 5 }]
+            } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stepIn", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stepOut", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": path },
+                "breakpoints": []
+            } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "disconnect" }),
+            json!({ "command": "threads" }),
+        ]);
+        fs::remove_file(&path).unwrap();
+        let name = Path::new(&path).file_name().unwrap().to_string_lossy();
+        let mut capabilities = response("initialize");
+        capabilities["body"] = json!({ "supportsConfigurationDoneRequest": true });
+        check(
+            &[
+                capabilities,
+                response("launch"),
+                event("initialized", Value::Null),
+                json!({ "command": "setBreakpoints", "body": { "breakpoints": [
+                    { "verified": true, "line": 7, "source": { "name": name } },
+                    { "verified": true, "line": 2 },
+                    { "verified": false, "line": 20 }
+                ] } }),
+                response("setExceptionBreakpoints"),
+                response("configurationDone"),
+                stopped("entry"),
+                json!({ "body": { "threads": [{ "id": 1 }] } }),
+                response("continue"),
+                stopped("breakpoint"),
+                json!({ "body": {
+                    "stackFrames": [{ "id": 0, "line": 7, "column": 1, "source": { "path": path } }],
+                    "totalFrames": 1
+                } }),
+                json!({ "body": { "scopes": [{ "name": "Registers" }, { "name": "Memory" }] } }),
+                json!({ "body": { "variables": [
+                    { "name": "$0", "value": "3" },
+                    { "name": "$1", "value": "6" },
+                    { "name": "$2", "value": "0" },
+                    { "name": "$3", "value": "12" },
+                    { "name": "$4", "value": "6" }
+                ] } }),
+                response("setBreakpoints"),
+                response("continue"),
+                stopped("breakpoint"),
+                response("stepIn"),
+                stopped("step"),
+                json!({ "body": { "stackFrames": [{ "line": 12 }, { "line": 5 }], "totalFrames": 2 } }),
+                response("stepOut"),
+                stopped("step"),
+                at_line(6),
+                response("next"),
+                stopped("step"),
+                response("next"),
+                stopped("step"),
+                at_line(8),
+                response("setBreakpoints"),
+                response("continue"),
+                event("exited", json!({ "exitCode": 0 })),
+                json!({ "type": "event", "event": "terminated" }),
+                json!({ "body": { "variables": [
+                    { "name": "$0", "value": "0" },
+                    { "name": "$1", "value": "12" }
+                ] } }),
+                response("disconnect"),
+            ],
+            &sent,
+        );
+        //everything is numbered in the order it was sent
+        let numbers: Vec<u64> = sent.iter().map(|m| m["seq"].as_u64().unwrap()).collect();
+        assert_eq!(numbers, (1..=sent.len() as u64).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_errors_and_memory() {
+        let path = write_source("divide", DIVIDE);
+        let launch = json!({ "command": "launch", "arguments": { "program": path } });
+        let no_filters =
+            json!({ "command": "setExceptionBreakpoints", "arguments": { "filters": [] } });
+        let sent = session(&[
+            json!({ "command": "initialize" }),
+            launch.clone(),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "exceptionInfo", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 2 } }),
+            json!({ "command": "readMemory", "arguments": { "memoryReference": "0x00000000", "count": 8 } }),
+            json!({ "command": "readMemory", "arguments": {
+                "memoryReference": "0x00000000", "offset": 3, "count": 2
+            } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            //without the filter an error ends it
+            launch.clone(),
+            no_filters,
+            json!({ "command": "configurationDone" }),
+            //and with a divisor it gets through
+            json!({ "command": "launch", "arguments": { "program": path, "registers": [0, 0, 0, 7] } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "launch", "arguments": { "program": "/nonexistent.asm" } }),
+            json!({ "command": "pause", "arguments": { "threadId": 1 } }),
+            json!({ "command": "terminate" }),
+        ]);
+        fs::remove_file(&path).unwrap();
+        let failed = |command: &str| json!({ "command": command, "success": false });
+        check(
+            &[
+                response("initialize"),
+                response("launch"),
+                event("initialized", Value::Null),
+                response("configurationDone"),
+                event(
+                    "stopped",
+                    json!({ "reason": "exception", "description": "division by zero" }),
+                ),
+                at_line(5),
+                json!({ "body": { "exceptionId": "DivideByZero", "breakMode": "always" } }),
+                json!({ "body": { "variables": [
+                    { "name": "heap", "value": "4 bytes", "memoryReference": "0x00000000" }
+                ] } }),
+                json!({ "body": { "address": "0x00000000", "data": "BwAAAA==", "unreadableBytes": 4 } }),
+                json!({ "body": { "address": "0x00000003", "data": "AA==", "unreadableBytes": 1 } }),
+                response("continue"),
+                event("exited", json!({ "exitCode": 1 })),
+                json!({ "event": "terminated" }),
+                response("launch"),
+                event("initialized", Value::Null),
+                response("setExceptionBreakpoints"),
+                response("configurationDone"),
+                event(
+                    "output",
+                    json!({ "category": "stderr", "output": "VM error: division by zero\n" }),
+                ),
+                event("exited", json!({ "exitCode": 1 })),
+                json!({ "event": "terminated" }),
+                response("launch"),
+                event("initialized", Value::Null),
+                response("configurationDone"),
+                event("exited", json!({ "exitCode": 0 })),
+                json!({ "event": "terminated" }),
+                failed("launch"),
+                json!({ "success": false, "message": "unsupported request 'pause'" }),
+                response("terminate"),
+            ],
+            &sent,
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+}
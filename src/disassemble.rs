@@ -0,0 +1,133 @@
+// Modules back to assembly, one line per instruction with its offset. Labels and
+// directives are gone once assembled, so jump targets show as the immediates loaded
+// for them. When the module kept its source map, every line ends with a comment
+// giving the source line the instruction was assembled from.
+//
+// Permuted and masked code is shown plain. Host opcodes and bytes that aren't an
+// opcode are shown as their bytes.
+
+use crate::instructions::{Opcode, CUSTOM_OPCODES};
+use crate::module::Module;
+use crate::verify::{layout, Operand};
+
+use std::fmt::Write;
+
+// width the instructions are padded to before a source comment
+const COLUMN: usize = 24;
+
+/// The module's code as assembly, with source lines when it has a source map
+pub fn disassemble(module: &Module) -> String {
+    let mut code = module.unmask().code;
+    if let Some(permutation) = &module.permutation {
+        for instruction in code.chunks_mut(4) {
+            instruction[0] = permutation.decode(instruction[0]);
+        }
+    }
+    let mut results = String::new();
+    for (i, bytes) in code.chunks(4).enumerate() {
+        let pc = i * 4;
+        let text = instruction(bytes);
+        let source = module
+            .source_map
+            .as_ref()
+            .and_then(|source_map| source_map.describe(pc));
+        match source {
+            Some(source) => writeln!(
+                results,
+                "{:#06x}  {:<width$} ; {}",
+                pc,
+                text,
+                source,
+                width = COLUMN
+            ),
+            None => writeln!(results, "{:#06x}  {}", pc, text),
+        }
+        .unwrap();
+    }
+    results
+}
+
+/// One plain instruction as assembly
+pub fn instruction(bytes: &[u8]) -> String {
+    let hex = || {
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        bytes.join(" ")
+    };
+    if bytes.len() < 4 {
+        return format!("truncated {}", hex());
+    }
+    let opcode = Opcode::from(bytes[0]);
+    if CUSTOM_OPCODES.contains(&bytes[0]) {
+        return format!("host opcode {}", hex());
+    }
+    if opcode == Opcode::IGL {
+        return format!("illegal {}", hex());
+    }
+    let mut parts = vec![format!("{:?}", opcode).to_lowercase()];
+    let kinds = layout(opcode);
+    for (i, kind) in kinds.iter().enumerate() {
+        match kind {
+            Operand::Register => parts.push(format!("${}", bytes[i + 1])),
+            //the first byte of an immediate prints both
+            Operand::Immediate if i == 0 || kinds[i - 1] != Operand::Immediate => {
+                let immediate = u16::from_be_bytes([bytes[i + 1], bytes[i + 2]]);
+                parts.push(format!("#{}", immediate));
+            }
+            Operand::Immediate | Operand::Unused => {}
+        }
+    }
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, assemble_masked, assemble_with_debug_info};
+
+    const SOURCE: &str =
+        "load $0 #300\nlui $0 #2\n\ntop: add $0 $1 $2\n  syscall #7\njeq $3\nhlt\n";
+
+    #[test]
+    fn test_disassemble() {
+        let plain = "0x0000  load $0 #300\n\
+                     0x0004  lui $0 #2\n\
+                     0x0008  add $0 $1 $2\n\
+                     0x000c  syscall #7\n\
+                     0x0010  jeq $3\n\
+                     0x0014  hlt\n";
+        assert_eq!(disassemble(&assemble(SOURCE).unwrap()), plain);
+        //masks and permutations are taken off first
+        assert_eq!(disassemble(&assemble_masked(SOURCE, 3).unwrap()), plain);
+        let key = [1; 32];
+        assert_eq!(
+            disassemble(&assemble(SOURCE).unwrap().permute(5, &key)),
+            plain
+        );
+
+        let module = assemble_with_debug_info(SOURCE, "main.asm").unwrap();
+        let lines: Vec<String> = disassemble(&module).lines().map(String::from).collect();
+        assert_eq!(
+            lines[2],
+            "0x0008  add $0 $1 $2             ; main.asm:4:1: top: add $0 $1 $2"
+        );
+        assert_eq!(
+            lines[3],
+            "0x000c  syscall #7               ; main.asm:5:3: syscall #7"
+        );
+
+        assert_eq!(instruction(&[210, 1, 2, 3]), "host opcode d2 01 02 03");
+        assert_eq!(instruction(&[253, 0, 0, 0]), "illegal fd 00 00 00");
+        assert_eq!(instruction(&[1, 2]), "truncated 01 02");
+    }
+
+    #[test]
+    fn test_disassembly_assembles_back() {
+        //without labels the output is valid assembly for the same code
+        let module = assemble(SOURCE).unwrap();
+        let text: String = disassemble(&module)
+            .lines()
+            .map(|line| format!("{}\n", &line[8..]))
+            .collect();
+        assert_eq!(assemble(&text).unwrap().code, module.code);
+    }
+}
@@ -0,0 +1,584 @@
+// GDB remote serial protocol server, so gdb or lldb can attach to a vm through the
+// debugger. It speaks just enough of the protocol for registers, memory, software
+// breakpoints, stepping and continuing, plus the target description gdb asks for.
+//
+// gdb sees one flat 32 bit address space: the vm's own (heap, buffers and data, see
+// vm::memory) with the program bank mapped in at PROGRAM_BASE, where nothing else lives.
+// The pc reads as an address in there too. The registers go r0-r31, pc, then the equal
+// flag as a 0 or 1.
+//
+// Continuing runs until the program stops by itself, a ^C from gdb isn't seen until then.
+
+use crate::vm::debugger::{Debugger, StopReason, Watch};
+use crate::vm::VmError;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+
+/// Where the program bank shows up in gdb's address space
+pub const PROGRAM_BASE: u32 = 0x3000_0000;
+
+// where the bank ends, the region is as big as any other
+const PROGRAM_END: u32 = PROGRAM_BASE + 0x0100_0000;
+
+// r0-r31, the pc and the flag
+const REGISTERS: usize = 34;
+
+/// Serves one gdb connection at a time over a debugger
+pub struct GdbServer {
+    debugger: Debugger,
+    // the reply to `?`
+    last_stop: String,
+    acks: bool,
+}
+
+impl GdbServer {
+    pub fn new(debugger: Debugger) -> GdbServer {
+        GdbServer {
+            debugger,
+            last_stop: "S05".to_string(),
+            acks: true,
+        }
+    }
+
+    pub fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    pub fn into_debugger(self) -> Debugger {
+        self.debugger
+    }
+
+    /// Waits for gdb to connect on the address, then serves it until it goes away
+    pub fn listen_tcp<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<()> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(stream)
+    }
+
+    /// Same as listen_tcp on a unix socket at the path
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        self.serve(stream)
+    }
+
+    /// Answers packets on the stream until gdb detaches, kills the target or hangs up
+    pub fn serve<S: Read + Write>(&mut self, mut stream: S) -> io::Result<()> {
+        self.acks = true;
+        let mut last_reply = String::new();
+        loop {
+            let packet = match read_packet(&mut stream)? {
+                Incoming::Packet(packet) => packet,
+                Incoming::Resend => {
+                    send(&mut stream, &last_reply)?;
+                    continue;
+                }
+                Incoming::Closed => return Ok(()),
+            };
+            if self.acks {
+                stream.write_all(b"+")?;
+            }
+            let reply = match packet.as_str() {
+                "k" => return Ok(()),
+                detach if detach.starts_with('D') => {
+                    send(&mut stream, "OK")?;
+                    return Ok(());
+                }
+                "QStartNoAckMode" => {
+                    send(&mut stream, "OK")?;
+                    self.acks = false;
+                    continue;
+                }
+                packet => self.handle(packet),
+            };
+            send(&mut stream, &reply)?;
+            last_reply = reply;
+        }
+    }
+
+    // the reply to one packet, empty for the ones that aren't supported
+    fn handle(&mut self, packet: &str) -> String {
+        if !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (command, arguments) = packet.split_at(1);
+        let reply = match command {
+            "?" => Some(self.last_stop.clone()),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(arguments),
+            "p" => usize::from_str_radix(arguments, 16)
+                .ok()
+                .and_then(|register| self.read_register(register))
+                .map(|value| hex(&value.to_le_bytes())),
+            "P" => self.write_register(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "Z" | "z" => return self.breakpoint(command == "Z", arguments),
+            "s" | "c" => {
+                if !arguments.is_empty() {
+                    match code_offset(arguments) {
+                        Some(pc) => self.debugger.set_pc(pc),
+                        None => return error(),
+                    }
+                }
+                let stop = if command == "s" {
+                    self.debugger.step()
+                } else {
+                    self.debugger.resume_all()
+                };
+                self.last_stop = stop_reply(&stop);
+                Some(self.last_stop.clone())
+            }
+            "H" => Some("OK".to_string()),
+            "q" => return self.query(packet),
+            _ => return String::new(),
+        };
+        reply.unwrap_or_else(error)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=1000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, length)) => {
+                    let description = target_description();
+                    let start = (offset as usize).min(description.len());
+                    let end = (start + length).min(description.len());
+                    let more = if end < description.len() { "m" } else { "l" };
+                    format!("{}{}", more, &description[start..end])
+                }
+                None => error(),
+            };
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            //a single thread, so gdb doesn't go looking for more
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qC" => "QC1".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn read_register(&self, register: usize) -> Option<u32> {
+        match register {
+            0..=31 => Some(self.debugger.registers()[register] as u32),
+            32 => Some(PROGRAM_BASE.wrapping_add(self.debugger.pc() as u32)),
+            33 => Some(self.debugger.equal_flag() as u32),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, register: usize, value: u32) -> bool {
+        match register {
+            0..=31 => self.debugger.set_register(register, value as i32),
+            32 => match value.checked_sub(PROGRAM_BASE) {
+                Some(pc) => {
+                    self.debugger.set_pc(pc as usize);
+                    true
+                }
+                None => false,
+            },
+            33 => {
+                self.debugger.set_equal_flag(value != 0);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTERS)
+            .filter_map(|register| self.read_register(register))
+            .map(|value| hex(&value.to_le_bytes()))
+            .collect()
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> Option<String> {
+        let bytes = unhex(arguments).filter(|bytes| bytes.len() == REGISTERS * 4)?;
+        for (register, value) in bytes.chunks(4).enumerate() {
+            let value = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+            if !self.set_register(register, value) {
+                return None;
+            }
+        }
+        Some("OK".to_string())
+    }
+
+    fn write_register(&mut self, arguments: &str) -> Option<String> {
+        let mut parts = arguments.splitn(2, '=');
+        let register = usize::from_str_radix(parts.next()?, 16).ok()?;
+        let bytes = unhex(parts.next()?).filter(|bytes| bytes.len() == 4)?;
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if self.set_register(register, value) {
+            Some("OK".to_string())
+        } else {
+            None
+        }
+    }
+
+    fn read_byte(&mut self, address: u32) -> Option<u8> {
+        if (PROGRAM_BASE..PROGRAM_END).contains(&address) {
+            self.debugger.read_code((address - PROGRAM_BASE) as usize)
+        } else {
+            self.debugger.read_memory(address).ok()
+        }
+    }
+
+    // as many of the bytes as can be read, an error if not even the first one can
+    fn read_memory(&mut self, arguments: &str) -> Option<String> {
+        let (address, length) = parse_range(arguments)?;
+        let mut bytes = vec![];
+        for i in 0..length as u64 {
+            let address = u32::try_from(address + i).ok();
+            match address.and_then(|address| self.read_byte(address)) {
+                Some(byte) => bytes.push(byte),
+                None => break,
+            }
+        }
+        if bytes.is_empty() && length > 0 {
+            return None;
+        }
+        Some(hex(&bytes))
+    }
+
+    // the program bank isn't writable from here, only what STB could store to
+    fn write_memory(&mut self, arguments: &str) -> Option<String> {
+        let mut parts = arguments.splitn(2, ':');
+        let (address, length) = parse_range(parts.next()?)?;
+        let bytes = unhex(parts.next()?).filter(|bytes| bytes.len() == length)?;
+        for (i, byte) in bytes.iter().enumerate() {
+            let address = u32::try_from(address + i as u64).ok()?;
+            if (PROGRAM_BASE..PROGRAM_END).contains(&address) {
+                return None;
+            }
+            self.debugger.write_memory(address, *byte).ok()?;
+        }
+        Some("OK".to_string())
+    }
+
+    // Z0 and z0, software breakpoints in the program bank
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let mut parts = arguments.splitn(3, ',');
+        if parts.next() != Some("0") {
+            return String::new();
+        }
+        let pc = match parts.next().and_then(code_offset) {
+            Some(pc) => pc,
+            None => return error(),
+        };
+        if insert {
+            self.debugger.add_breakpoint(pc);
+        } else {
+            self.debugger.remove_breakpoint(pc);
+        }
+        "OK".to_string()
+    }
+}
+
+/// The target description gdb reads through qXfer, laying out the registers
+pub fn target_description() -> String {
+    let mut registers = String::new();
+    for r in 0..32 {
+        registers.push_str(&format!(
+            "    <reg name=\"r{}\" bitsize=\"32\" type=\"int32\" regnum=\"{}\"/>\n",
+            r, r
+        ));
+    }
+    format!(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         \x20 <feature name=\"org.biobox.core\">\n\
+         {}\
+         \x20   <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"32\"/>\n\
+         \x20   <reg name=\"flag\" bitsize=\"32\" type=\"int32\" regnum=\"33\"/>\n\
+         \x20 </feature>\n\
+         </target>\n",
+        registers
+    )
+}
+
+// what gdb is told when the target stops, as a signal number
+fn stop_reply(stop: &StopReason) -> String {
+    match stop {
+        StopReason::Breakpoint(_) => "T05swbreak:;".to_string(),
+        StopReason::Watchpoint {
+            watch: Watch::Heap(offset),
+            ..
+        } => format!("T05watch:{:x};", offset),
+        StopReason::Step | StopReason::Reached(_) | StopReason::Watchpoint { .. } => {
+            "S05".to_string()
+        }
+        StopReason::Halted => "W00".to_string(),
+        //SIGFPE, SIGSEGV and SIGILL for the rest
+        StopReason::Error(VmError::DivideByZero) => "S08".to_string(),
+        StopReason::Error(VmError::InvalidAddress(_))
+        | StopReason::Error(VmError::ReadOnlyAddress(_)) => "S0b".to_string(),
+        StopReason::Error(_) => "S04".to_string(),
+    }
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+// a pc from an address in the program bank
+fn code_offset(address: &str) -> Option<usize> {
+    let address = u32::from_str_radix(address, 16).ok()?;
+    if (PROGRAM_BASE..PROGRAM_END).contains(&address) {
+        Some((address - PROGRAM_BASE) as usize)
+    } else {
+        None
+    }
+}
+
+// `address,length` in hex
+fn parse_range(range: &str) -> Option<(u64, usize)> {
+    let mut parts = range.splitn(2, ',');
+    let address = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let length = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, length))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+enum Incoming {
+    Packet(String),
+    /// gdb got the last reply garbled
+    Resend,
+    Closed,
+}
+
+fn read_byte<S: Read>(stream: &mut S) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// the next packet, skipping acks and interrupts that come while the target is stopped
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Incoming> {
+    loop {
+        match read_byte(stream)? {
+            None => return Ok(Incoming::Closed),
+            Some(b'$') => break,
+            Some(b'-') => return Ok(Incoming::Resend),
+            Some(_) => {}
+        }
+    }
+    let mut data = vec![];
+    loop {
+        match read_byte(stream)? {
+            None => return Ok(Incoming::Closed),
+            Some(b'#') => break,
+            Some(byte) => data.push(byte),
+        }
+    }
+    let mut checksum = [0; 2];
+    stream.read_exact(&mut checksum)?;
+    let expected = std::str::from_utf8(&checksum)
+        .ok()
+        .and_then(|text| u8::from_str_radix(text, 16).ok());
+    if expected != Some(sum(&data)) {
+        stream.write_all(b"-")?;
+        return read_packet(stream);
+    }
+    Ok(Incoming::Packet(
+        String::from_utf8_lossy(&data).into_owned(),
+    ))
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// `$`, the data with the characters the framing uses escaped, `#` and the checksum
+fn send<S: Write>(stream: &mut S, reply: &str) -> io::Result<()> {
+    let mut data = vec![];
+    for byte in reply.bytes() {
+        match byte {
+            b'$' | b'#' | b'}' | b'*' => data.extend_from_slice(&[b'}', byte ^ 0x20]),
+            byte => data.push(byte),
+        }
+    }
+    let packet = format!("${}#{:02x}", String::from_utf8_lossy(&data), sum(&data));
+    stream.write_all(packet.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::VM;
+
+    use std::net::TcpStream;
+    use std::thread;
+
+    // talks to the server the way gdb does, one packet and its reply at a time
+    struct Client<S: Read + Write> {
+        stream: S,
+        acks: bool,
+    }
+
+    impl<S: Read + Write> Client<S> {
+        fn send(&mut self, packet: &str) -> String {
+            let framed = format!("${}#{:02x}", packet, sum(packet.as_bytes()));
+            self.stream.write_all(framed.as_bytes()).unwrap();
+            if self.acks {
+                assert_eq!(read_byte(&mut self.stream).unwrap(), Some(b'+'));
+            }
+            let reply = match read_packet(&mut self.stream).unwrap() {
+                Incoming::Packet(reply) => reply,
+                _ => panic!("no reply to {}", packet),
+            };
+            if self.acks {
+                self.stream.write_all(b"+").unwrap();
+            }
+            reply
+        }
+    }
+
+    // serves the assembled program on a fresh vm in its own thread
+    fn start(source: &'static str, listener: TcpListener) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut vm = VM::new();
+            vm.load_module(&assemble(source).unwrap());
+            let (stream, _) = listener.accept().unwrap();
+            GdbServer::new(Debugger::new(vm)).serve(stream).unwrap();
+        })
+    }
+
+    fn connect(source: &'static str) -> (Client<TcpStream>, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = start(source, listener);
+        let stream = TcpStream::connect(address).unwrap();
+        (Client { stream, acks: true }, server)
+    }
+
+    fn register(value: u32) -> String {
+        hex(&value.to_le_bytes())
+    }
+
+    #[test]
+    fn test_session() {
+        let source = "load $0 #8\naloc $0\nload $1 #3\nload $2 #0\n\
+                      stb $1 $2\nldb $2 $3\nadd $3 $1 $4\nhlt\n";
+        let (mut gdb, server) = connect(source);
+        assert!(gdb
+            .send("qSupported:multiprocess+;swbreak+")
+            .contains("qXfer:features:read+"));
+        let description = target_description();
+        let reply = gdb.send("qXfer:features:read:target.xml:0,40");
+        assert_eq!(reply, format!("m{}", &description[..0x40]));
+        let reply = gdb.send(&format!("qXfer:features:read:target.xml:0,{:x}", 0x10000));
+        assert_eq!(reply, format!("l{}", description));
+        assert!(description.contains("<reg name=\"r31\""));
+        assert_eq!(gdb.send("?"), "S05");
+        assert_eq!(gdb.send("Hg0"), "OK");
+        assert_eq!(gdb.send(""), "");
+
+        //all zero, the pc at the start of the bank
+        let registers = gdb.send("g");
+        assert_eq!(registers.len(), REGISTERS * 8);
+        assert_eq!(&registers[32 * 8..33 * 8], register(PROGRAM_BASE));
+        assert_eq!(gdb.send("p20"), register(PROGRAM_BASE));
+        assert_eq!(gdb.send("P9=2a000000"), "OK");
+        assert_eq!(gdb.send("p9"), register(42));
+        assert_eq!(gdb.send("p22"), "E01");
+
+        //the program bank reads back as assembled
+        let code = assemble(source).unwrap().code;
+        assert_eq!(gdb.send("m30000000,8"), hex(&code[..8]));
+        //short reads stop at the end
+        assert_eq!(gdb.send("m3000001c,10"), hex(&code[28..]));
+        assert_eq!(gdb.send("m40000000,4"), "E01");
+        assert_eq!(gdb.send("M30000000,1:00"), "E01");
+
+        assert_eq!(gdb.send("Z0,30000014,4"), "OK");
+        assert_eq!(gdb.send("Z2,0,1"), "");
+        assert_eq!(gdb.send("c"), "T05swbreak:;");
+        assert_eq!(gdb.send("p20"), register(PROGRAM_BASE + 0x14));
+        //the heap is there now, and gdb can change it under the program
+        assert_eq!(gdb.send("m0,8"), "0300000000000000");
+        assert_eq!(gdb.send("M0,1:07"), "OK");
+        assert_eq!(gdb.send("M1000000,1:07"), "E01");
+        assert_eq!(gdb.send("s"), "S05");
+        assert_eq!(gdb.send("p3"), register(7));
+        assert_eq!(gdb.send("z0,30000014,4"), "OK");
+        assert_eq!(gdb.send("c"), "W00");
+        assert_eq!(gdb.send("p4"), register(10));
+        assert_eq!(gdb.send("?"), "W00");
+        gdb.stream.write_all(b"$k#6b").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_faults_and_no_ack_mode() {
+        let source = "load $0 #1\ndiv $0 $1 $2\nhlt\n";
+        let (mut gdb, server) = connect(source);
+        assert_eq!(gdb.send("QStartNoAckMode"), "OK");
+        gdb.acks = false;
+        assert_eq!(gdb.send("c"), "S08");
+        assert_eq!(gdb.send("p20"), register(PROGRAM_BASE + 7));
+        //moving the pc back and giving the divisor a value gets it through
+        assert_eq!(gdb.send("P1=02000000"), "OK");
+        assert_eq!(gdb.send("c30000004"), "W00");
+        assert_eq!(gdb.send("p2"), register(0));
+        assert_eq!(gdb.send("D"), "OK");
+        server.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut vm = VM::new();
+            vm.load_module(&assemble("load $5 #9\nhlt\n").unwrap());
+            let mut gdb = GdbServer::new(Debugger::new(vm));
+            gdb.serve(server).unwrap();
+            assert_eq!(gdb.into_debugger().registers()[5], 9);
+        });
+        let mut gdb = Client {
+            stream: client,
+            acks: true,
+        };
+        assert_eq!(gdb.send("s"), "S05");
+        //a garbled reply is sent again
+        gdb.stream.write_all(b"-").unwrap();
+        match read_packet(&mut gdb.stream).unwrap() {
+            Incoming::Packet(reply) => assert_eq!(reply, "S05"),
+            _ => panic!("the reply wasn't sent again"),
+        }
+        gdb.stream.write_all(b"+").unwrap();
+        assert_eq!(gdb.send("p5"), register(9));
+        drop(gdb);
+        server.join().unwrap();
+    }
+}
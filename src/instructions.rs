@@ -3,10 +3,15 @@
 use nom::types::CompleteStr;
 
 use self::Opcode::*;
+use std::ops::RangeInclusive;
 use std::slice::Iter;
 
+/// opcode bytes a host may bind to its own handlers with `VM::register_opcode`
+pub const CUSTOM_OPCODES: RangeInclusive<u8> = 200..=249;
+
 //opcodes
 #[derive(Debug, PartialEq, Copy, Clone)]
+#[rustfmt::skip]
 pub enum Opcode {
 
     /* 0 - 15 similar opcodes to the LC-3 16 bit instruction set but in 32-bit*/
@@ -32,8 +37,20 @@ pub enum Opcode {
 
     //system
     LOAD = 1,
+    // LOAD with the immediate masked by the module, see module::masks
+    LOADM = 80,
+    LUI = 21,
+    MOV = 25,
+    FLAG = 31,
     ALOC = 18,
 
+    //memory (see vm::memory for the address layout)
+    LDB = 22,
+    STB = 23,
+    MLEN = 24,
+    LDW = 26,
+    STW = 27,
+
     //math
     ADD = 2,
     SUB = 3,
@@ -56,6 +73,9 @@ pub enum Opcode {
     JMPF = 7,
     JMPB = 8,
     JEQ = 16,
+    JNEQ = 28,
+    CALL = 29,
+    RET = 30,
 
     //defaults
     HLT = 0,
@@ -64,17 +84,56 @@ pub enum Opcode {
 
 
     /* 50 - 99 special math operators */
-
+    //bitwise
+    AND = 50,
+    OR = 51,
+    XOR = 52,
+    NOT = 53,
+    SHL = 54,
+    SHR = 55,
+    SHRU = 56,
+
+    //integer math
+    MOD = 57,
+    DIVU = 58,
+    MODU = 59,
+    NEG = 78,
+
+    //unsigned comparison
+    LTU = 60,
+    GTU = 61,
+    LTEQU = 62,
+    GTEQU = 63,
+
+    //floating point math on the f32 bits stored in the registers
+    FADD = 64,
+    FSUB = 65,
+    FMUL = 66,
+    FDIV = 67,
+    FNEG = 79,
+    FLT = 68,
+    FGT = 69,
+    FLTEQ = 70,
+    FGTEQ = 71,
+    FEQ = 72,
+    FNEQ = 73,
+
+    //conversions
+    ITOF = 74,
+    FTOI = 75,
+    UTOF = 76,
+    FTOU = 77,
 
     /* 100 to 199 */
 
 
 
 
-    /* 200 - 249 */
+    /* 200 - 249 host extension opcodes (see CUSTOM_OPCODES and VM::register_opcode) */
 
     
     /* 250 - 254 special sys codes */
+    SYSCALL = 250,
 
 
 
@@ -105,11 +164,15 @@ pub enum Opcode {
 
 impl Opcode {
     pub fn iterator() -> Iter<'static, Opcode> {
-        static OPCODES: [Opcode; 22] = [
-            LOAD, ALOC, //system
-            ADD, SUB, INC, DEC, MUL, DIV, //math
-            EQ, NEQ, GT, LT, GTEQ, LTEQ, BETW, //comparison
-            JMP, JMPF, JMPB, JEQ, //jumps
+        static OPCODES: [Opcode; 65] = [
+            LOAD, LOADM, LUI, MOV, FLAG, ALOC, SYSCALL, //system
+            LDB, STB, MLEN, LDW, STW, //memory
+            ADD, SUB, INC, DEC, MUL, DIV, MOD, DIVU, MODU, NEG, //math
+            AND, OR, XOR, NOT, SHL, SHR, SHRU, //bitwise
+            EQ, NEQ, GT, LT, GTEQ, LTEQ, BETW, LTU, GTU, LTEQU, GTEQU, //comparison
+            FADD, FSUB, FMUL, FDIV, FNEG, FLT, FGT, FLTEQ, FGTEQ, FEQ, FNEQ, //floats
+            ITOF, FTOI, UTOF, FTOU, //conversions
+            JMP, JMPF, JMPB, JEQ, JNEQ, CALL, RET, //jumps
             HLT, NOP, IGL, //defaults
         ];
         OPCODES.iter()
@@ -149,6 +212,49 @@ impl From<u8> for Opcode {
             18 => Opcode::ALOC,
             19 => Opcode::INC,
             20 => Opcode::DEC,
+            21 => Opcode::LUI,
+            22 => Opcode::LDB,
+            23 => Opcode::STB,
+            24 => Opcode::MLEN,
+            25 => Opcode::MOV,
+            26 => Opcode::LDW,
+            27 => Opcode::STW,
+            28 => Opcode::JNEQ,
+            29 => Opcode::CALL,
+            30 => Opcode::RET,
+            31 => Opcode::FLAG,
+            50 => Opcode::AND,
+            51 => Opcode::OR,
+            52 => Opcode::XOR,
+            53 => Opcode::NOT,
+            54 => Opcode::SHL,
+            55 => Opcode::SHR,
+            56 => Opcode::SHRU,
+            57 => Opcode::MOD,
+            58 => Opcode::DIVU,
+            59 => Opcode::MODU,
+            60 => Opcode::LTU,
+            61 => Opcode::GTU,
+            62 => Opcode::LTEQU,
+            63 => Opcode::GTEQU,
+            64 => Opcode::FADD,
+            65 => Opcode::FSUB,
+            66 => Opcode::FMUL,
+            67 => Opcode::FDIV,
+            68 => Opcode::FLT,
+            69 => Opcode::FGT,
+            70 => Opcode::FLTEQ,
+            71 => Opcode::FGTEQ,
+            72 => Opcode::FEQ,
+            73 => Opcode::FNEQ,
+            74 => Opcode::ITOF,
+            75 => Opcode::FTOI,
+            76 => Opcode::UTOF,
+            77 => Opcode::FTOU,
+            78 => Opcode::NEG,
+            79 => Opcode::FNEG,
+            80 => Opcode::LOADM,
+            250 => Opcode::SYSCALL,
             _ => Opcode::IGL,
         }
     }
@@ -178,6 +284,49 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             "aloc" => Opcode::ALOC,
             "inc" => Opcode::INC,
             "dec" => Opcode::DEC,
+            "lui" => Opcode::LUI,
+            "ldb" => Opcode::LDB,
+            "stb" => Opcode::STB,
+            "mlen" => Opcode::MLEN,
+            "mov" => Opcode::MOV,
+            "ldw" => Opcode::LDW,
+            "stw" => Opcode::STW,
+            "jneq" => Opcode::JNEQ,
+            "call" => Opcode::CALL,
+            "ret" => Opcode::RET,
+            "flag" => Opcode::FLAG,
+            "and" => Opcode::AND,
+            "or" => Opcode::OR,
+            "xor" => Opcode::XOR,
+            "not" => Opcode::NOT,
+            "shl" => Opcode::SHL,
+            "shr" => Opcode::SHR,
+            "shru" => Opcode::SHRU,
+            "mod" => Opcode::MOD,
+            "divu" => Opcode::DIVU,
+            "modu" => Opcode::MODU,
+            "ltu" => Opcode::LTU,
+            "gtu" => Opcode::GTU,
+            "ltequ" => Opcode::LTEQU,
+            "gtequ" => Opcode::GTEQU,
+            "fadd" => Opcode::FADD,
+            "fsub" => Opcode::FSUB,
+            "fmul" => Opcode::FMUL,
+            "fdiv" => Opcode::FDIV,
+            "flt" => Opcode::FLT,
+            "fgt" => Opcode::FGT,
+            "flteq" => Opcode::FLTEQ,
+            "fgteq" => Opcode::FGTEQ,
+            "feq" => Opcode::FEQ,
+            "fneq" => Opcode::FNEQ,
+            "itof" => Opcode::ITOF,
+            "ftoi" => Opcode::FTOI,
+            "utof" => Opcode::UTOF,
+            "ftou" => Opcode::FTOU,
+            "neg" => Opcode::NEG,
+            "fneg" => Opcode::FNEG,
+            "loadm" => Opcode::LOADM,
+            "syscall" => Opcode::SYSCALL,
             _ => Opcode::IGL,
         }
     }
@@ -218,4 +367,14 @@ mod tests {
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_opcode_round_trip() {
+        //every listed opcode decodes back from its byte and its lowercase name
+        for opcode in Opcode::iterator().filter(|o| **o != Opcode::IGL) {
+            assert_eq!(Opcode::from(*opcode as u8), *opcode);
+            let name = format!("{:?}", opcode).to_lowercase();
+            assert_eq!(Opcode::from(CompleteStr(&name)), *opcode);
+        }
+    }
 }
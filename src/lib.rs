@@ -0,0 +1,38 @@
+// BIOBox library crate. Hosts embed the VM (and optionally the assembler) from here,
+// while main.rs only wraps it in the REPL terminal.
+
+//the nom parsers are documented with doc comments on their macros, and the tests
+//compare flags against true and false
+#![allow(unused_doc_comments)]
+#![allow(clippy::empty_line_after_doc_comments, clippy::bool_assert_comparison)]
+
+#[macro_use]
+extern crate nom;
+
+//import the assembler
+pub mod assembler;
+//and back from modules to assembly
+pub mod disassemble;
+
+//import the modules
+pub mod instructions;
+//the container format assembled programs are shipped in
+pub mod module;
+//keystream cipher for encrypted modules
+pub mod compiler;
+pub mod crypt;
+//bytecode to bytecode obfuscation passes
+pub mod obfuscate;
+//static checks of bytecode before it runs
+pub mod verify;
+//verified modules translated to standalone Rust source
+pub mod transpile;
+//gdb remote serial protocol server on top of the debugger
+pub mod gdb;
+//debug adapter protocol server for editors, over stdio
+#[cfg(feature = "dap")]
+pub mod dap;
+//vm after instructions because it uses instructions in the vm :)
+pub mod vm;
+//now bring in the REPL terminal (Read, Evaluate, and Print Loop)
+pub mod repl;
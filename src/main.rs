@@ -10,22 +10,344 @@
 // features for obfuscating the binary with either built in or provided xor or similar functions should be worked in somehow (for protecting proprietary tech)
 // optomizations on the engine to make sure primative math and binary functions run as close to the metal as they can would also be nice
 
-#[macro_use]
-extern crate nom;
+use biobox::assembler;
+use biobox::compiler;
+use biobox::crypt;
+#[cfg(feature = "dap")]
+use biobox::dap::DapServer;
+use biobox::disassemble::disassemble;
+use biobox::gdb::GdbServer;
+use biobox::module::signature::{self, SigningKey};
+use biobox::module::Module;
+use biobox::repl;
+use biobox::transpile;
+use biobox::vm::debugger::Debugger;
+use biobox::vm::VM;
 
-//import the assembler
-pub mod assembler;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
 
-//import the modules
-pub mod instructions;
-//vm after instructions because it uses instructions in the vm :)
-pub mod vm;
-//now bring in the REPL terminal (Read, Evaluate, and Print Loop)
-pub mod repl;
+const USAGE: &str = "usage:
+    biobox                                   start the REPL
+    biobox compile <source> [-o <output>] [--asm]
+                                             compile to a module, or to assembly with --asm
+    biobox assemble <source> [-o <output>] [--debug]
+                                             assemble to a module, keeping the source map
+                                             in its debug section with --debug
+    biobox disasm <module>                   print a module as assembly, with the source
+                                             lines of its debug section
+    biobox strip <module> [-o <output>] [--force]
+                                             remove the debug section, in place by default.
+                                             Signed modules are refused unless forced, the
+                                             signature covers the debug section
+    biobox sign <module> --key <keyfile> [-o <output>]
+                                             sign a module with the ed25519 key stored as
+                                             64 hex characters, in place by default
+    biobox transpile <module> [-o <output>]
+                                             translate a module to a standalone Rust
+                                             function, for hosts that can't use the jit
+    biobox gdb <module> [--tcp <address> | --unix <path>]
+                                             wait for gdb to attach over the remote
+                                             protocol, on 127.0.0.1:1234 by default
+    biobox dap                               serve the debug adapter protocol on stdin
+                                             and stdout, for editors debugging assembly";
 
 fn main() {
-    let mut repl_term = repl::REPL::new();
-    repl_term.run();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {
+            let mut repl_term = repl::REPL::new();
+            repl_term.run();
 
-    println!("Reached end of main.");
+            println!("Reached end of main.");
+        }
+        Some("compile") => {
+            if let Err(message) = compile(&args[1..]) {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+        }
+        Some("assemble") => {
+            if let Err(message) = assemble(&args[1..]) {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+        }
+        Some("disasm") => {
+            if let Err(message) = disasm(&args[1..]) {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+        }
+        Some("strip") => {
+            if let Err(message) = strip(&args[1..]) {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+        }
+        Some("sign") => {
+            if let Err(message) = sign(&args[1..]) {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+        }
+        Some("transpile") => {
+            if let Err(message) = transpile(&args[1..]) {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+        }
+        Some("gdb") => {
+            if let Err(message) = gdb(&args[1..]) {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+        }
+        #[cfg(feature = "dap")]
+        Some("dap") => {
+            let stdin = std::io::stdin();
+            let stdout = std::io::stdout();
+            if let Err(e) = DapServer::new().serve(stdin.lock(), stdout.lock()) {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+/// biobox compile <source> [-o <output>] [--asm]
+fn compile(args: &[String]) -> Result<(), String> {
+    let mut source = None;
+    let mut output = None;
+    let mut assembly = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or("-o needs a file name")?.clone()),
+            "--asm" => assembly = true,
+            _ if source.is_none() => source = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    let source = source.ok_or_else(|| USAGE.to_string())?;
+    //defaults to the source name with a .bbx or .asm extension
+    let output = output.unwrap_or_else(|| {
+        let extension = if assembly { "asm" } else { "bbx" };
+        Path::new(&source)
+            .with_extension(extension)
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let text = fs::read_to_string(&source).map_err(|e| format!("{}: {}", source, e))?;
+    let bytes = if assembly {
+        compiler::compile_to_assembly(&text)
+            .map(String::into_bytes)
+            .map_err(|e| e.to_string())
+    } else {
+        compiler::compile(&text)
+            .map_err(|e| e.to_string())
+            .and_then(|module| module.to_bytes().map_err(|e| e.to_string()))
+    }
+    .map_err(|e| format!("{}: {}", source, e))?;
+    fs::write(&output, bytes).map_err(|e| format!("{}: {}", output, e))
+}
+
+/// biobox assemble <source> [-o <output>] [--debug]
+fn assemble(args: &[String]) -> Result<(), String> {
+    let mut source = None;
+    let mut output = None;
+    let mut debug = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or("-o needs a file name")?.clone()),
+            "--debug" => debug = true,
+            _ if source.is_none() => source = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    let source = source.ok_or_else(|| USAGE.to_string())?;
+    let output = output.unwrap_or_else(|| {
+        Path::new(&source)
+            .with_extension("bbx")
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let text = fs::read_to_string(&source).map_err(|e| format!("{}: {}", source, e))?;
+    let module = if debug {
+        assembler::assemble_with_debug_info(&text, &source)
+    } else {
+        assembler::assemble(&text)
+    }
+    .map_err(|e| format!("{}: {}", source, e))?;
+    let bytes = module
+        .to_bytes()
+        .map_err(|e| format!("{}: {}", source, e))?;
+    fs::write(&output, bytes).map_err(|e| format!("{}: {}", output, e))
+}
+
+/// biobox disasm <module>
+fn disasm(args: &[String]) -> Result<(), String> {
+    let module = match args {
+        [module] => module,
+        _ => return Err(USAGE.to_string()),
+    };
+    let bytes = fs::read(module).map_err(|e| format!("{}: {}", module, e))?;
+    let parsed = Module::from_bytes(&bytes).map_err(|e| format!("{}: {}", module, e))?;
+    print!("{}", disassemble(&parsed));
+    Ok(())
+}
+
+/// biobox strip <module> [-o <output>] [--force]
+fn strip(args: &[String]) -> Result<(), String> {
+    let mut module = None;
+    let mut output = None;
+    let mut force = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or("-o needs a file name")?.clone()),
+            "--force" => force = true,
+            _ if module.is_none() => module = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    let module = module.ok_or_else(|| USAGE.to_string())?;
+    let output = output.unwrap_or_else(|| module.clone());
+
+    let bytes = fs::read(&module).map_err(|e| format!("{}: {}", module, e))?;
+    let parsed = Module::from_bytes(&bytes).map_err(|e| format!("{}: {}", module, e))?;
+    let signed = signature::is_signed(&bytes).map_err(|e| format!("{}: {}", module, e))?;
+    if signed {
+        //without a debug section there's nothing to strip and the signature stays valid
+        if parsed.source_map.is_none() {
+            return fs::write(&output, bytes).map_err(|e| format!("{}: {}", output, e));
+        }
+        if !force {
+            return Err(format!(
+                "{}: module is signed, stripping it drops the signature (--force to strip \
+                 anyway and sign it again)",
+                module
+            ));
+        }
+        eprintln!("{}: warning: dropping the signature", module);
+    }
+    let stripped = parsed
+        .strip()
+        .to_bytes()
+        .map_err(|e| format!("{}: {}", module, e))?;
+    fs::write(&output, stripped).map_err(|e| format!("{}: {}", output, e))
+}
+
+/// biobox sign <module> --key <keyfile> [-o <output>]
+fn sign(args: &[String]) -> Result<(), String> {
+    let mut module = None;
+    let mut key = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => key = Some(args.next().ok_or("--key needs a file name")?.clone()),
+            "-o" => output = Some(args.next().ok_or("-o needs a file name")?.clone()),
+            _ if module.is_none() => module = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    let module = module.ok_or_else(|| USAGE.to_string())?;
+    let key = key.ok_or("sign needs a --key")?;
+    let output = output.unwrap_or_else(|| module.clone());
+
+    let text = fs::read_to_string(&key).map_err(|e| format!("{}: {}", key, e))?;
+    let key = crypt::key_from_hex(&text)
+        .map(|seed| SigningKey::from_bytes(&seed))
+        .ok_or_else(|| format!("{}: key has to be 64 hex characters", key))?;
+    let bytes = fs::read(&module).map_err(|e| format!("{}: {}", module, e))?;
+    let signed = signature::sign(&bytes, &key).map_err(|e| format!("{}: {}", module, e))?;
+    fs::write(&output, signed).map_err(|e| format!("{}: {}", output, e))?;
+    //the key to trust when loading
+    let public_key: String = signature::public_key(&key)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    println!("{}", public_key);
+    Ok(())
+}
+
+/// biobox transpile <module> [-o <output>]
+fn transpile(args: &[String]) -> Result<(), String> {
+    let mut module = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or("-o needs a file name")?.clone()),
+            _ if module.is_none() => module = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    let module = module.ok_or_else(|| USAGE.to_string())?;
+    //defaults to the module name with a .rs extension
+    let output = output.unwrap_or_else(|| {
+        Path::new(&module)
+            .with_extension("rs")
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let bytes = fs::read(&module).map_err(|e| format!("{}: {}", module, e))?;
+    let source = Module::from_bytes(&bytes)
+        .map_err(|e| e.to_string())
+        .and_then(|parsed| transpile::transpile(&parsed).map_err(|e| e.to_string()))
+        .map_err(|e| format!("{}: {}", module, e))?;
+    fs::write(&output, source).map_err(|e| format!("{}: {}", output, e))
+}
+
+/// biobox gdb <module> [--tcp <address> | --unix <path>]
+fn gdb(args: &[String]) -> Result<(), String> {
+    let mut module = None;
+    let mut tcp = None;
+    let mut unix = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tcp" => tcp = Some(args.next().ok_or("--tcp needs an address")?.clone()),
+            "--unix" => unix = Some(args.next().ok_or("--unix needs a path")?.clone()),
+            _ if module.is_none() => module = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    let module = module.ok_or_else(|| USAGE.to_string())?;
+
+    let bytes = fs::read(&module).map_err(|e| format!("{}: {}", module, e))?;
+    let mut vm = VM::new();
+    vm.load_module_bytes(&bytes)
+        .map_err(|e| format!("{}: {}", module, e))?;
+    let mut server = GdbServer::new(Debugger::new(vm));
+    match (tcp, unix) {
+        (Some(_), Some(_)) => Err("give either --tcp or --unix, not both".to_string()),
+        #[cfg(unix)]
+        (None, Some(path)) => {
+            println!("waiting for gdb on {}", path);
+            server
+                .listen_unix(&path)
+                .map_err(|e| format!("{}: {}", path, e))
+        }
+        #[cfg(not(unix))]
+        (None, Some(_)) => Err("unix sockets aren't available here".to_string()),
+        (tcp, None) => {
+            let address = tcp.unwrap_or_else(|| "127.0.0.1:1234".to_string());
+            println!("waiting for gdb on {}", address);
+            server
+                .listen_tcp(&address)
+                .map_err(|e| format!("{}: {}", address, e))
+        }
+    }
 }
@@ -0,0 +1,520 @@
+use crate::vm::memory::MAX_BUFFER;
+
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The types a module input or output can be declared as
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ParamType {
+    I32,
+    U32,
+    F32,
+    Bytes,
+}
+
+impl ParamType {
+    /// Parses the type name used in the `.input` / `.output` directives
+    pub fn from_name(name: &str) -> Option<ParamType> {
+        match name.to_lowercase().as_str() {
+            "i32" => Some(ParamType::I32),
+            "u32" => Some(ParamType::U32),
+            "f32" => Some(ParamType::F32),
+            "bytes" => Some(ParamType::Bytes),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ParamType::I32 => "i32",
+            ParamType::U32 => "u32",
+            ParamType::F32 => "f32",
+            ParamType::Bytes => "bytes",
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<ParamType> {
+        match v {
+            0 => Some(ParamType::I32),
+            1 => Some(ParamType::U32),
+            2 => Some(ParamType::F32),
+            3 => Some(ParamType::Bytes),
+            _ => None,
+        }
+    }
+}
+
+/// Where a parameter lives while the module runs
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Location {
+    /// scalars sit in one of the 32 registers
+    Register(u8),
+    /// bytes are mapped in as the nth input or output buffer
+    Buffer { index: u8, capacity: u32 },
+}
+
+/// One named input or output of a module
+#[derive(Debug, PartialEq, Clone)]
+pub struct Param {
+    pub name: String,
+    pub ty: ParamType,
+    pub location: Location,
+}
+
+/// A value passed into or read back out of a module call
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    I32(i32),
+    U32(u32),
+    F32(f32),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    pub fn ty(&self) -> ParamType {
+        match self {
+            Value::I32(_) => ParamType::I32,
+            Value::U32(_) => ParamType::U32,
+            Value::F32(_) => ParamType::F32,
+            Value::Bytes(_) => ParamType::Bytes,
+        }
+    }
+
+    /// The register contents for a scalar value, None for bytes
+    pub fn to_register(&self) -> Option<i32> {
+        match self {
+            Value::I32(v) => Some(*v),
+            Value::U32(v) => Some(*v as i32),
+            Value::F32(v) => Some(v.to_bits() as i32),
+            Value::Bytes(_) => None,
+        }
+    }
+
+    /// Reads a scalar of type `ty` back out of a register
+    pub fn from_register(ty: ParamType, register: i32) -> Value {
+        match ty {
+            ParamType::I32 => Value::I32(register),
+            ParamType::U32 => Value::U32(register as u32),
+            ParamType::F32 => Value::F32(f32::from_bits(register as u32)),
+            ParamType::Bytes => Value::Bytes(vec![]),
+        }
+    }
+}
+
+/// Why a set of `.input` / `.output` directives could not be turned into an interface
+#[derive(Debug, PartialEq, Clone)]
+pub enum InterfaceError {
+    MissingOperand(String),
+    UnknownType(String),
+    DuplicateName(String),
+    BadLocation(String),
+    /// two inputs are passed in the same register
+    DuplicateRegister(String),
+    /// names are stored with a one byte length
+    NameTooLong(String),
+    /// there can be at most 255 inputs and 255 outputs
+    TooManyParams,
+    /// an output buffer bigger than memory::MAX_BUFFER
+    BufferTooLarge(String),
+}
+
+impl fmt::Display for InterfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterfaceError::MissingOperand(d) => write!(f, "{} needs a name and a type", d),
+            InterfaceError::UnknownType(t) => write!(f, "unknown parameter type '{}'", t),
+            InterfaceError::DuplicateName(n) => write!(f, "'{}' is declared twice", n),
+            InterfaceError::BadLocation(n) => write!(f, "'{}' has an invalid location", n),
+            InterfaceError::DuplicateRegister(n) => {
+                write!(f, "'{}' is in the same register as another input", n)
+            }
+            InterfaceError::NameTooLong(n) => write!(f, "'{}' is longer than 255 bytes", n),
+            InterfaceError::TooManyParams => {
+                write!(f, "there can be at most 255 inputs and 255 outputs")
+            }
+            InterfaceError::BufferTooLarge(n) => {
+                write!(f, "'{}' is bigger than {} bytes", n, MAX_BUFFER)
+            }
+        }
+    }
+}
+
+/// The declared signature of a module: its named and typed inputs and outputs
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Interface {
+    pub inputs: Vec<Param>,
+    pub outputs: Vec<Param>,
+}
+
+impl Interface {
+    pub fn new() -> Interface {
+        Interface {
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+
+    /// Adds an input. Scalars without an explicit register take the next free one
+    /// from $0 up and byte inputs take the next input buffer.
+    pub fn add_input(
+        &mut self,
+        name: &str,
+        ty: ParamType,
+        register: Option<u8>,
+    ) -> Result<(), InterfaceError> {
+        let location = Interface::place(&self.inputs, name, ty, register, 0)?;
+        self.check_name(name)?;
+        let param = Param {
+            name: name.to_string(),
+            ty,
+            location,
+        };
+        Interface::check(&self.inputs, &param, true)?;
+        self.inputs.push(param);
+        Ok(())
+    }
+
+    /// Adds an output. Byte outputs need the capacity of the buffer the host maps in.
+    pub fn add_output(
+        &mut self,
+        name: &str,
+        ty: ParamType,
+        register: Option<u8>,
+        capacity: u32,
+    ) -> Result<(), InterfaceError> {
+        let location = Interface::place(&self.outputs, name, ty, register, capacity)?;
+        self.check_name(name)?;
+        let param = Param {
+            name: name.to_string(),
+            ty,
+            location,
+        };
+        Interface::check(&self.outputs, &param, false)?;
+        self.outputs.push(param);
+        Ok(())
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), InterfaceError> {
+        if self
+            .inputs
+            .iter()
+            .chain(&self.outputs)
+            .any(|p| p.name == name)
+        {
+            return Err(InterfaceError::DuplicateName(name.to_string()));
+        }
+        Ok(())
+    }
+
+    // what the vm relies on when it calls the module: the param fits the container,
+    // scalars are in one of the 32 registers and no two inputs share one, and byte
+    // buffers are numbered in the order they are mapped and small enough to address.
+    // The vm allocates output buffers up front, so the capacity can't be trusted.
+    fn check(params: &[Param], param: &Param, input: bool) -> Result<(), InterfaceError> {
+        if params.len() >= usize::from(u8::MAX) {
+            return Err(InterfaceError::TooManyParams);
+        }
+        if param.name.len() > usize::from(u8::MAX) {
+            return Err(InterfaceError::NameTooLong(param.name.clone()));
+        }
+        let bad_location = || InterfaceError::BadLocation(param.name.clone());
+        match (param.ty, param.location) {
+            (ParamType::Bytes, Location::Buffer { index, capacity }) => {
+                let buffers = params.iter().filter(|p| p.ty == ParamType::Bytes).count();
+                if usize::from(index) != buffers {
+                    return Err(bad_location());
+                }
+                if capacity as usize > MAX_BUFFER {
+                    return Err(InterfaceError::BufferTooLarge(param.name.clone()));
+                }
+            }
+            (ParamType::Bytes, _) | (_, Location::Buffer { .. }) => return Err(bad_location()),
+            (_, Location::Register(r)) => {
+                if r >= 32 {
+                    return Err(bad_location());
+                }
+                if input && params.iter().any(|p| p.location == param.location) {
+                    return Err(InterfaceError::DuplicateRegister(param.name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn place(
+        params: &[Param],
+        name: &str,
+        ty: ParamType,
+        register: Option<u8>,
+        capacity: u32,
+    ) -> Result<Location, InterfaceError> {
+        let bad_location = || InterfaceError::BadLocation(name.to_string());
+        if ty == ParamType::Bytes {
+            if register.is_some() {
+                return Err(bad_location());
+            }
+            let index = params.iter().filter(|p| p.ty == ParamType::Bytes).count();
+            return Ok(Location::Buffer {
+                index: index as u8,
+                capacity,
+            });
+        }
+        let register = match register {
+            Some(r) => r,
+            None => params
+                .iter()
+                .filter_map(|p| match p.location {
+                    Location::Register(r) => Some(r + 1),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0),
+        };
+        if register >= 32 {
+            return Err(bad_location());
+        }
+        Ok(Location::Register(register))
+    }
+
+    /// Serializes the interface for the module container, counts and name lengths have
+    /// to fit in a byte
+    pub fn to_bytes(&self) -> Result<Vec<u8>, InterfaceError> {
+        let count = |params: &[Param]| u8::try_from(params.len()).ok();
+        let (inputs, outputs) = match (count(&self.inputs), count(&self.outputs)) {
+            (Some(inputs), Some(outputs)) => (inputs, outputs),
+            _ => return Err(InterfaceError::TooManyParams),
+        };
+        let mut results = vec![inputs, outputs];
+        for param in self.inputs.iter().chain(&self.outputs) {
+            let len = u8::try_from(param.name.len())
+                .map_err(|_| InterfaceError::NameTooLong(param.name.clone()))?;
+            results.push(len);
+            results.extend_from_slice(param.name.as_bytes());
+            results.push(param.ty as u8);
+            match param.location {
+                Location::Register(r) => {
+                    results.push(0);
+                    results.push(r);
+                }
+                Location::Buffer { index, capacity } => {
+                    results.push(1);
+                    results.push(index);
+                    results.extend_from_slice(&capacity.to_le_bytes());
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Reads an interface back out of its serialized form, None if it is malformed or
+    /// declares locations the vm can't call it with
+    pub fn from_bytes(bytes: &[u8]) -> Option<Interface> {
+        let mut reader = bytes.iter().cloned();
+        let input_count = reader.next()?;
+        let output_count = reader.next()?;
+        let mut interface = Interface::new();
+        for i in 0..(u16::from(input_count) + u16::from(output_count)) {
+            let name_len = reader.next()? as usize;
+            let name: Vec<u8> = reader.by_ref().take(name_len).collect();
+            if name.len() != name_len {
+                return None;
+            }
+            let name = String::from_utf8(name).ok()?;
+            let ty = ParamType::from_u8(reader.next()?)?;
+            let location = match reader.next()? {
+                0 => Location::Register(reader.next()?),
+                1 => {
+                    let index = reader.next()?;
+                    let capacity = [
+                        reader.next()?,
+                        reader.next()?,
+                        reader.next()?,
+                        reader.next()?,
+                    ];
+                    Location::Buffer {
+                        index,
+                        capacity: u32::from_le_bytes(capacity),
+                    }
+                }
+                _ => return None,
+            };
+            let param = Param { name, ty, location };
+            interface.check_name(&param.name).ok()?;
+            let input = i < u16::from(input_count);
+            let params = if input {
+                &mut interface.inputs
+            } else {
+                &mut interface.outputs
+            };
+            Interface::check(params, &param, input).ok()?;
+            params.push(param);
+        }
+        Some(interface)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign_locations() {
+        let mut interface = Interface::new();
+        interface.add_input("a", ParamType::I32, None).unwrap();
+        interface.add_input("key", ParamType::Bytes, None).unwrap();
+        interface.add_input("b", ParamType::F32, Some(5)).unwrap();
+        interface.add_input("c", ParamType::U32, None).unwrap();
+        interface
+            .add_output("digest", ParamType::Bytes, None, 32)
+            .unwrap();
+        interface
+            .add_output("hash", ParamType::U32, Some(21), 0)
+            .unwrap();
+        assert_eq!(interface.inputs[0].location, Location::Register(0));
+        assert_eq!(
+            interface.inputs[1].location,
+            Location::Buffer {
+                index: 0,
+                capacity: 0
+            }
+        );
+        //registers continue after the highest one used so far
+        assert_eq!(interface.inputs[3].location, Location::Register(6));
+        assert_eq!(
+            interface.outputs[0].location,
+            Location::Buffer {
+                index: 0,
+                capacity: 32
+            }
+        );
+        assert_eq!(interface.outputs[1].location, Location::Register(21));
+    }
+
+    #[test]
+    fn test_bad_declarations() {
+        let mut interface = Interface::new();
+        interface.add_input("a", ParamType::I32, None).unwrap();
+        assert_eq!(
+            interface.add_output("a", ParamType::I32, None, 0),
+            Err(InterfaceError::DuplicateName("a".to_string()))
+        );
+        assert_eq!(
+            interface.add_input("b", ParamType::I32, Some(32)),
+            Err(InterfaceError::BadLocation("b".to_string()))
+        );
+        assert_eq!(
+            interface.add_input("c", ParamType::Bytes, Some(1)),
+            Err(InterfaceError::BadLocation("c".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_interface_round_trip() {
+        let mut interface = Interface::new();
+        interface.add_input("key", ParamType::Bytes, None).unwrap();
+        interface.add_input("rounds", ParamType::U32, None).unwrap();
+        interface
+            .add_output("out", ParamType::Bytes, None, 64)
+            .unwrap();
+        interface
+            .add_output("ratio", ParamType::F32, Some(3), 0)
+            .unwrap();
+        let bytes = interface.to_bytes().unwrap();
+        assert_eq!(Interface::from_bytes(&bytes), Some(interface));
+        //a truncated interface is rejected
+        assert_eq!(Interface::from_bytes(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_from_bytes_checks_locations() {
+        let mut interface = Interface::new();
+        interface.add_input("a", ParamType::I32, Some(4)).unwrap();
+        interface.add_input("key", ParamType::Bytes, None).unwrap();
+        let bytes = interface.to_bytes().unwrap();
+        //a: count bytes, name length and name, type, location kind, then the register
+        let register = 2 + 1 + 1 + 1 + 1;
+        assert_eq!(bytes[register], 4);
+        let with = |offset: usize, value: u8| {
+            let mut bytes = bytes.clone();
+            bytes[offset] = value;
+            Interface::from_bytes(&bytes)
+        };
+        assert!(with(register, 31).is_some());
+        assert_eq!(with(register, 32), None);
+        //the buffer index of key, it has to be the first buffer
+        assert_eq!(with(bytes.len() - 5, 1), None);
+        //a byte input can't sit in a register, nor a scalar in a buffer
+        assert_eq!(with(register - 2, ParamType::Bytes as u8), None);
+        assert_eq!(with(bytes.len() - 7, ParamType::I32 as u8), None);
+
+        let mut shared = Interface::new();
+        shared.inputs = interface.inputs.clone();
+        shared.inputs[1] = shared.inputs[0].clone();
+        shared.inputs[1].name = "b".to_string();
+        assert_eq!(Interface::from_bytes(&shared.to_bytes().unwrap()), None);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut interface = Interface::new();
+        interface.add_input("a", ParamType::I32, Some(3)).unwrap();
+        assert_eq!(
+            interface.add_input("b", ParamType::U32, Some(3)),
+            Err(InterfaceError::DuplicateRegister("b".to_string()))
+        );
+        //outputs may read the same register
+        interface
+            .add_output("x", ParamType::I32, Some(3), 0)
+            .unwrap();
+        interface
+            .add_output("y", ParamType::I32, Some(3), 0)
+            .unwrap();
+
+        let long = "n".repeat(256);
+        assert_eq!(
+            interface.add_input(&long, ParamType::I32, None),
+            Err(InterfaceError::NameTooLong(long.clone()))
+        );
+        interface.inputs[0].name = long.clone();
+        assert_eq!(interface.to_bytes(), Err(InterfaceError::NameTooLong(long)));
+
+        //output buffers are allocated when the module is called, so their size is capped
+        let mut interface = Interface::new();
+        let largest = MAX_BUFFER as u32;
+        assert_eq!(
+            interface.add_output("big", ParamType::Bytes, None, largest + 1),
+            Err(InterfaceError::BufferTooLarge("big".to_string()))
+        );
+        interface
+            .add_output("big", ParamType::Bytes, None, largest)
+            .unwrap();
+        let mut bytes = interface.to_bytes().unwrap();
+        let end = bytes.len();
+        bytes[end - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Interface::from_bytes(&bytes), None);
+
+        let mut interface = Interface::new();
+        for i in 0..255 {
+            interface
+                .add_output(&i.to_string(), ParamType::Bytes, None, 1)
+                .unwrap();
+        }
+        assert!(interface.to_bytes().is_ok());
+        assert_eq!(
+            interface.add_output("more", ParamType::Bytes, None, 1),
+            Err(InterfaceError::TooManyParams)
+        );
+        interface.outputs.push(interface.outputs[0].clone());
+        assert_eq!(interface.to_bytes(), Err(InterfaceError::TooManyParams));
+    }
+
+    #[test]
+    fn test_value_registers() {
+        assert_eq!(Value::U32(u32::MAX).to_register(), Some(-1));
+        assert_eq!(
+            Value::from_register(ParamType::F32, Value::F32(1.5).to_register().unwrap()),
+            Value::F32(1.5)
+        );
+        assert_eq!(Value::Bytes(vec![1]).to_register(), None);
+    }
+}
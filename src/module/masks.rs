@@ -0,0 +1,95 @@
+// Masked constants. Without masks every LOAD immediate and every string in the data
+// section sits in the module as written, so a hex dump shows the magic numbers and
+// messages a program uses. Masking XORs each LOADM immediate with a key of its own and
+// each data byte with a keystream, both drawn from the module's mask seed.
+//
+// The seed is stored in the clear, masks keep constants from being grepped for, they
+// aren't encryption. Encrypt the module as well to keep the seed away from readers.
+
+use crate::crypt::{Key, Keystream, Nonce};
+
+use std::fmt;
+
+// streams the masks are drawn from, the seed is the key
+const IMMEDIATE_NONCE: &Nonce = b"immediates\0\0";
+const DATA_NONCE: &Nonce = b"data-section";
+
+/// The masks of a module's immediates and data
+#[derive(Clone)]
+pub struct Masks {
+    seed: u64,
+    immediates: Keystream,
+    data: Keystream,
+}
+
+impl Masks {
+    /// The same seed always gives the same masks
+    pub fn new(seed: u64) -> Masks {
+        let mut key: Key = [0; 32];
+        key[..8].copy_from_slice(&seed.to_le_bytes());
+        Masks {
+            seed,
+            immediates: Keystream::new(&key, IMMEDIATE_NONCE, 0),
+            data: Keystream::new(&key, DATA_NONCE, 0),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The key of the LOADM at byte offset `pc` of the code, every instruction gets its own
+    pub fn immediate(&mut self, pc: usize) -> u16 {
+        let index = pc / 4 * 2;
+        u16::from_be_bytes([self.immediates.at(index), self.immediates.at(index + 1)])
+    }
+
+    /// The mask of the data byte at `offset`
+    pub fn data(&mut self, offset: usize) -> u8 {
+        self.data.at(offset)
+    }
+
+    /// Masks or unmasks a whole data section
+    pub fn apply_data(&mut self, data: &mut [u8]) {
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte ^= self.data(offset);
+        }
+    }
+}
+
+impl PartialEq for Masks {
+    fn eq(&self, other: &Masks) -> bool {
+        self.seed == other.seed
+    }
+}
+
+// the seed stays out of debug output
+impl fmt::Debug for Masks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Masks").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masks() {
+        let mut masks = Masks::new(77);
+        let mut again = Masks::new(77);
+        assert_eq!(masks.immediate(4), again.immediate(4));
+        assert_eq!(masks, again);
+        assert_ne!(masks, Masks::new(78));
+        //every byte of an instruction shares its key, instructions don't
+        assert_eq!(masks.immediate(8), masks.immediate(11));
+        let keys: Vec<u16> = (0..16).map(|i| masks.immediate(i * 4)).collect();
+        assert!(keys.windows(2).all(|w| w[0] != w[1]));
+
+        let mut data = b"hello, world".to_vec();
+        masks.apply_data(&mut data);
+        assert_ne!(&data[..], b"hello, world");
+        again.apply_data(&mut data);
+        assert_eq!(&data[..], b"hello, world");
+    }
+}
@@ -0,0 +1,657 @@
+// The module container a blackbox ships in. Layout:
+//   "BBOX" magic, 1 byte format version
+//   then any number of sections: 1 byte section id, 4 byte little endian length, payload
+// Every module has a code section, the other sections are optional.
+
+pub mod interface;
+pub mod masks;
+pub mod permutation;
+pub mod signature;
+pub mod source_map;
+
+use self::interface::{Interface, InterfaceError};
+use self::masks::Masks;
+use self::permutation::Permutation;
+use self::source_map::SourceMap;
+use crate::crypt::{self, Key, Keystream};
+use crate::instructions::Opcode;
+use zeroize::Zeroizing;
+
+use std::error::Error;
+use std::fmt;
+
+pub const MAGIC: &[u8; 4] = b"BBOX";
+pub const VERSION: u8 = 1;
+
+/// Section ids of the container
+pub const SECTION_CODE: u8 = 1;
+pub const SECTION_INTERFACE: u8 = 2;
+/// nonce, a whole encrypted module container and the tag over them, see crypt::seal
+pub const SECTION_ENCRYPTED: u8 = 3;
+/// the encrypted opcode permutation the code is written with
+pub const SECTION_OPCODES: u8 = 4;
+/// 8 byte little endian seed of the immediate and data masks
+pub const SECTION_MASKS: u8 = 5;
+/// read only bytes mapped in at vm::memory::DATA_REGION
+pub const SECTION_DATA: u8 = 6;
+/// signer public key and signature over the rest of the container, always last
+pub const SECTION_SIGNATURE: u8 = 7;
+/// the source map of the code, see source_map. Module::strip takes it out.
+pub const SECTION_DEBUG: u8 = 8;
+
+/// Reasons a byte string isn't a usable module
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModuleError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    MissingCode,
+    BadSection(u8),
+    /// the module or its opcode table is encrypted and has to be opened with Module::decrypt
+    Encrypted,
+    /// decrypting didn't produce a module, most likely the key is wrong
+    DecryptionFailed,
+    /// a signature is required and the module has none
+    Unsigned,
+    /// the signature doesn't match the container, it was changed after signing
+    BadSignature,
+    /// the signature is valid but the key it was made with isn't trusted
+    UntrustedSigner,
+    /// the interface doesn't fit the container format
+    BadInterface(InterfaceError),
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModuleError::BadMagic => write!(f, "not a biobox module"),
+            ModuleError::UnsupportedVersion(v) => write!(f, "unsupported module version {}", v),
+            ModuleError::Truncated => write!(f, "module ends in the middle of a section"),
+            ModuleError::MissingCode => write!(f, "module has no code section"),
+            ModuleError::BadSection(id) => write!(f, "section {} is malformed", id),
+            ModuleError::Encrypted => write!(f, "module is encrypted"),
+            ModuleError::DecryptionFailed => write!(f, "unable to decrypt module"),
+            ModuleError::Unsigned => write!(f, "module isn't signed"),
+            ModuleError::BadSignature => write!(f, "module signature doesn't match its contents"),
+            ModuleError::UntrustedSigner => write!(f, "module is signed with an untrusted key"),
+            ModuleError::BadInterface(e) => write!(f, "interface can't be stored: {}", e),
+        }
+    }
+}
+
+impl Error for ModuleError {}
+
+/// An assembled program together with everything the vm needs to know to call it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Module {
+    pub code: Vec<u8>,
+    pub interface: Interface,
+    /// the opcode encoding of the code, None for the standard one
+    pub permutation: Option<Permutation>,
+    /// the data section as stored, masked if the module has masks
+    pub data: Vec<u8>,
+    /// what the LOADM immediates and the data are masked with
+    pub masks: Option<Masks>,
+    /// where each instruction came from in the assembly source, if it was kept
+    pub source_map: Option<SourceMap>,
+}
+
+impl Module {
+    pub fn new(code: Vec<u8>, interface: Interface) -> Module {
+        Module {
+            code,
+            interface,
+            permutation: None,
+            data: vec![],
+            masks: None,
+            source_map: None,
+        }
+    }
+
+    /// Same as new, with a data section
+    pub fn with_data(code: Vec<u8>, interface: Interface, data: Vec<u8>) -> Module {
+        Module {
+            data,
+            ..Module::new(code, interface)
+        }
+    }
+
+    /// The module without its debug section, so it doesn't give away the source
+    pub fn strip(&self) -> Module {
+        Module {
+            source_map: None,
+            ..self.clone()
+        }
+    }
+
+    /// Rewrites the code with the opcode permutation of the seed. Saving the module
+    /// stores the table encrypted with the key, so only Module::decrypt can load it.
+    pub fn permute(&self, seed: u64, key: &Key) -> Module {
+        let permutation = Permutation::new(seed, key);
+        let mut code = self.code.clone();
+        for instruction in code.chunks_mut(4) {
+            instruction[0] = permutation.encode(decode(&self.permutation, instruction[0]));
+        }
+        Module {
+            code,
+            permutation: Some(permutation),
+            ..self.clone()
+        }
+    }
+
+    /// Rewrites every LOAD as a LOADM with its immediate masked, and masks the data,
+    /// with the masks of the seed. Remasking starts from the plain module.
+    pub fn mask(&self, seed: u64) -> Module {
+        let mut module = self.unmask();
+        let mut masks = Masks::new(seed);
+        let permutation = &module.permutation;
+        for (i, instruction) in module.code.chunks_mut(4).enumerate() {
+            if decode(permutation, instruction[0]) != Opcode::LOAD as u8 || instruction.len() < 4 {
+                continue;
+            }
+            instruction[0] = encode(permutation, Opcode::LOADM as u8);
+            let immediate = u16::from_be_bytes([instruction[2], instruction[3]]);
+            let masked = immediate ^ masks.immediate(i * 4);
+            instruction[2..4].copy_from_slice(&masked.to_be_bytes());
+        }
+        masks.apply_data(&mut module.data);
+        module.masks = Some(masks);
+        module
+    }
+
+    /// Turns every LOADM back into a LOAD with the plain immediate and unmasks the data
+    pub fn unmask(&self) -> Module {
+        let mut module = self.clone();
+        let mut masks = match module.masks.take() {
+            Some(masks) => masks,
+            //LOADM without masks loads its immediate as it is
+            None => Masks::new(0),
+        };
+        let masked = self.masks.is_some();
+        let permutation = &module.permutation;
+        for (i, instruction) in module.code.chunks_mut(4).enumerate() {
+            if decode(permutation, instruction[0]) != Opcode::LOADM as u8 || instruction.len() < 4 {
+                continue;
+            }
+            instruction[0] = encode(permutation, Opcode::LOAD as u8);
+            if masked {
+                let immediate = u16::from_be_bytes([instruction[2], instruction[3]]);
+                let plain = immediate ^ masks.immediate(i * 4);
+                instruction[2..4].copy_from_slice(&plain.to_be_bytes());
+            }
+        }
+        if masked {
+            masks.apply_data(&mut module.data);
+        }
+        module
+    }
+
+    /// Serializes the module into the container format
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ModuleError> {
+        let mut results = MAGIC.to_vec();
+        results.push(VERSION);
+        Module::push_section(&mut results, SECTION_CODE, &self.code);
+        if self.interface != Interface::default() {
+            let interface = self
+                .interface
+                .to_bytes()
+                .map_err(ModuleError::BadInterface)?;
+            Module::push_section(&mut results, SECTION_INTERFACE, &interface);
+        }
+        if let Some(permutation) = &self.permutation {
+            Module::push_section(&mut results, SECTION_OPCODES, &permutation.seal());
+        }
+        if let Some(masks) = &self.masks {
+            Module::push_section(&mut results, SECTION_MASKS, &masks.seed().to_le_bytes());
+        }
+        if !self.data.is_empty() {
+            Module::push_section(&mut results, SECTION_DATA, &self.data);
+        }
+        if let Some(source_map) = &self.source_map {
+            Module::push_section(&mut results, SECTION_DEBUG, &source_map.to_bytes());
+        }
+        Ok(results)
+    }
+
+    /// Parses a module container, unknown sections are skipped
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, ModuleError> {
+        Module::parse(bytes, None)
+    }
+
+    /// Parses a container, opening encrypted sections if there is a key
+    fn parse(bytes: &[u8], key: Option<&Key>) -> Result<Module, ModuleError> {
+        if bytes.len() < 5 || &bytes[..4] != MAGIC {
+            return Err(ModuleError::BadMagic);
+        }
+        if bytes[4] != VERSION {
+            return Err(ModuleError::UnsupportedVersion(bytes[4]));
+        }
+        let mut code = None;
+        let mut interface = Interface::new();
+        let mut permutation = None;
+        let mut data = vec![];
+        let mut masks = None;
+        let mut source_map = None;
+        for (id, payload) in Sections::new(&bytes[5..]) {
+            let payload = payload?;
+            match (id, key) {
+                (SECTION_CODE, _) => code = Some(payload.to_vec()),
+                (SECTION_ENCRYPTED, Some(key)) => return Module::open(payload, key),
+                (SECTION_OPCODES, Some(key)) => {
+                    permutation =
+                        Some(Permutation::open(payload, key).ok_or(ModuleError::DecryptionFailed)?)
+                }
+                (SECTION_ENCRYPTED, None) | (SECTION_OPCODES, None) => {
+                    return Err(ModuleError::Encrypted)
+                }
+                (SECTION_INTERFACE, _) => {
+                    interface = Interface::from_bytes(payload).ok_or(ModuleError::BadSection(id))?
+                }
+                (SECTION_MASKS, _) => {
+                    if payload.len() != 8 {
+                        return Err(ModuleError::BadSection(id));
+                    }
+                    let mut seed = [0; 8];
+                    seed.copy_from_slice(payload);
+                    masks = Some(Masks::new(u64::from_le_bytes(seed)));
+                }
+                (SECTION_DATA, _) => data = payload.to_vec(),
+                (SECTION_DEBUG, _) => {
+                    source_map =
+                        Some(SourceMap::from_bytes(payload).ok_or(ModuleError::BadSection(id))?)
+                }
+                _ => {}
+            }
+        }
+        match code {
+            Some(code) => Ok(Module {
+                code,
+                interface,
+                permutation,
+                data,
+                masks,
+                source_map,
+            }),
+            None => Err(ModuleError::MissingCode),
+        }
+    }
+
+    /// Serializes the module and encrypts the whole container with the key
+    pub fn encrypt(&self, key: &Key) -> Result<Vec<u8>, ModuleError> {
+        let payload = crypt::seal(key, &self.to_bytes()?);
+
+        let mut results = MAGIC.to_vec();
+        results.push(VERSION);
+        Module::push_section(&mut results, SECTION_ENCRYPTED, &payload);
+        Ok(results)
+    }
+
+    /// Opens a module made with `encrypt` or with a permutation. Plain modules are
+    /// loaded as they are.
+    pub fn decrypt(bytes: &[u8], key: &Key) -> Result<Module, ModuleError> {
+        Module::parse(bytes, Some(key))
+    }
+
+    /// Encrypts the code for running with VM::load_sealed
+    pub fn seal(&self, key: &Key) -> SealedModule {
+        let nonce = crypt::random_nonce();
+        let mut code = self.code.clone();
+        crypt::apply_keystream(key, &nonce, 0, &mut code);
+        SealedModule {
+            code,
+            interface: self.interface.clone(),
+            permutation: self.permutation.clone(),
+            data: self.data.clone(),
+            masks: self.masks.clone(),
+            keystream: Keystream::new(key, &nonce, 0),
+        }
+    }
+
+    /// Opens a module made with `encrypt` without ever decrypting its code, which is run
+    /// straight from the ciphertext. Other containers are loaded and then sealed.
+    pub fn decrypt_sealed(bytes: &[u8], key: &Key) -> Result<SealedModule, ModuleError> {
+        if bytes.len() < 5 || &bytes[..4] != MAGIC {
+            return Err(ModuleError::BadMagic);
+        }
+        if bytes[4] != VERSION {
+            return Err(ModuleError::UnsupportedVersion(bytes[4]));
+        }
+        let mut encrypted = None;
+        for (id, payload) in Sections::new(&bytes[5..]) {
+            if id == SECTION_ENCRYPTED {
+                encrypted = Some(payload?);
+            }
+        }
+        let payload = match encrypted {
+            Some(payload) => payload,
+            None => return Ok(Module::decrypt(bytes, key)?.seal(key)),
+        };
+        //the whole container is authenticated before any of it is decrypted
+        let (nonce, ciphertext) =
+            crypt::authenticate(key, payload).ok_or(ModuleError::DecryptionFailed)?;
+        let cipher = Zeroizing::new(crypt::cipher_key(key));
+
+        //decrypts the headers and every section but the code, so the inner container
+        //parses with the code section still encrypted
+        let mut inner = ciphertext.to_vec();
+        let header = inner.len().min(5);
+        crypt::apply_keystream(&cipher, &nonce, 0, &mut inner[..header]);
+        let mut position = header;
+        let mut code_offset = 0;
+        while position + 5 <= inner.len() {
+            crypt::apply_keystream(
+                &cipher,
+                &nonce,
+                position as u64,
+                &mut inner[position..position + 5],
+            );
+            let id = inner[position];
+            let length = u32::from_le_bytes([
+                inner[position + 1],
+                inner[position + 2],
+                inner[position + 3],
+                inner[position + 4],
+            ]) as usize;
+            let start = position + 5;
+            let end = match start.checked_add(length) {
+                Some(end) if end <= inner.len() => end,
+                _ => return Err(ModuleError::DecryptionFailed),
+            };
+            match id {
+                SECTION_CODE => code_offset = start,
+                SECTION_ENCRYPTED => return Err(ModuleError::BadSection(id)),
+                _ => crypt::apply_keystream(&cipher, &nonce, start as u64, &mut inner[start..end]),
+            }
+            position = end;
+        }
+        let module = Module::parse(&inner, Some(key)).map_err(|_| ModuleError::DecryptionFailed)?;
+        Ok(SealedModule {
+            code: module.code.clone(),
+            interface: module.interface.clone(),
+            permutation: module.permutation.clone(),
+            data: module.data.clone(),
+            masks: module.masks.clone(),
+            keystream: Keystream::new(&cipher, &nonce, code_offset as u64),
+        })
+    }
+
+    /// Decrypts the payload of an encrypted section
+    fn open(payload: &[u8], key: &Key) -> Result<Module, ModuleError> {
+        let inner = crypt::open(key, payload).ok_or(ModuleError::DecryptionFailed)?;
+        Module::parse(&inner, Some(key)).map_err(|_| ModuleError::DecryptionFailed)
+    }
+
+    fn push_section(results: &mut Vec<u8>, id: u8, payload: &[u8]) {
+        results.push(id);
+        results.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        results.extend_from_slice(payload);
+    }
+}
+
+// the opcode a byte of the code stands for
+fn decode(permutation: &Option<Permutation>, byte: u8) -> u8 {
+    match permutation {
+        Some(permutation) => permutation.decode(byte),
+        None => byte,
+    }
+}
+
+// the byte an opcode is written as
+fn encode(permutation: &Option<Permutation>, opcode: u8) -> u8 {
+    match permutation {
+        Some(permutation) => permutation.encode(opcode),
+        None => opcode,
+    }
+}
+
+/// A module whose code stays encrypted in memory. The vm decrypts each byte as it
+/// fetches it, see VM::load_sealed.
+#[derive(Debug, Clone)]
+pub struct SealedModule {
+    /// the encrypted code
+    pub code: Vec<u8>,
+    pub interface: Interface,
+    pub permutation: Option<Permutation>,
+    pub data: Vec<u8>,
+    pub masks: Option<Masks>,
+    keystream: Keystream,
+}
+
+impl SealedModule {
+    /// The keystream the code is encrypted with, position 0 being the first code byte
+    pub fn keystream(&self) -> &Keystream {
+        &self.keystream
+    }
+}
+
+/// Walks the sections following the container header
+struct Sections<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Sections<'a> {
+    fn new(rest: &'a [u8]) -> Sections<'a> {
+        Sections { rest }
+    }
+}
+
+impl<'a> Iterator for Sections<'a> {
+    type Item = (u8, Result<&'a [u8], ModuleError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let id = self.rest[0];
+        if self.rest.len() < 5 {
+            self.rest = &[];
+            return Some((id, Err(ModuleError::Truncated)));
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&self.rest[1..5]);
+        let len = u32::from_le_bytes(len) as usize;
+        if self.rest.len() - 5 < len {
+            self.rest = &[];
+            return Some((id, Err(ModuleError::Truncated)));
+        }
+        let payload = &self.rest[5..5 + len];
+        self.rest = &self.rest[5 + len..];
+        Some((id, Ok(payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::interface::ParamType;
+    use super::*;
+
+    #[test]
+    fn test_module_round_trip() {
+        let mut interface = Interface::new();
+        interface.add_input("a", ParamType::I32, None).unwrap();
+        let module = Module::new(vec![1, 0, 0, 42, 0, 0, 0, 0], interface);
+        let bytes = module.to_bytes().unwrap();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(Module::from_bytes(&bytes), Ok(module));
+    }
+
+    #[test]
+    fn test_debug_section() {
+        let mut source_map = SourceMap::new();
+        source_map.push(0, 3, 1);
+        source_map.set_text(3, "hlt");
+        let module = Module {
+            source_map: Some(source_map),
+            ..Module::new(vec![0, 0, 0, 0], Interface::new())
+        };
+        let bytes = module.to_bytes().unwrap();
+        assert_eq!(Module::from_bytes(&bytes), Ok(module.clone()));
+        //encrypting keeps it, stripping drops the whole section
+        let key = [42; 32];
+        assert_eq!(
+            Module::decrypt(&module.encrypt(&key).unwrap(), &key),
+            Ok(module.clone())
+        );
+        let stripped = module.strip();
+        assert_eq!(stripped.source_map, None);
+        assert!(stripped.to_bytes().unwrap().len() < bytes.len());
+        assert_eq!(stripped.code, module.code);
+
+        let mut bytes = stripped.to_bytes().unwrap();
+        bytes.extend_from_slice(&[SECTION_DEBUG, 1, 0, 0, 0, 7]);
+        assert_eq!(
+            Module::from_bytes(&bytes),
+            Err(ModuleError::BadSection(SECTION_DEBUG))
+        );
+    }
+
+    #[test]
+    fn test_bad_modules() {
+        assert_eq!(Module::from_bytes(b"BOX"), Err(ModuleError::BadMagic));
+        assert_eq!(
+            Module::from_bytes(b"BBOX\x09"),
+            Err(ModuleError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            Module::from_bytes(b"BBOX\x01"),
+            Err(ModuleError::MissingCode)
+        );
+        let mut bytes = Module::new(vec![0, 0, 0, 0], Interface::new())
+            .to_bytes()
+            .unwrap();
+        bytes.pop();
+        assert_eq!(Module::from_bytes(&bytes), Err(ModuleError::Truncated));
+        //unknown sections are skipped over
+        let mut bytes = Module::new(vec![0, 0, 0, 0], Interface::new())
+            .to_bytes()
+            .unwrap();
+        bytes.extend_from_slice(&[99, 1, 0, 0, 0, 7]);
+        assert!(Module::from_bytes(&bytes).is_ok());
+    }
+
+    #[test]
+    fn test_encrypted_module() {
+        let mut interface = Interface::new();
+        interface.add_input("secret", ParamType::U32, None).unwrap();
+        let module = Module::new(vec![1, 0, 0x13, 0x37, 0, 0, 0, 0], interface);
+        let key = [42; 32];
+        let bytes = module.encrypt(&key).unwrap();
+        //the code isn't readable without the key
+        assert!(!bytes.windows(2).any(|w| w == [0x13, 0x37]));
+        assert_eq!(Module::from_bytes(&bytes), Err(ModuleError::Encrypted));
+        assert_eq!(
+            Module::decrypt(&bytes, &[1; 32]),
+            Err(ModuleError::DecryptionFailed)
+        );
+        assert_eq!(Module::decrypt(&bytes, &key), Ok(module.clone()));
+        //a changed byte anywhere in the ciphertext fails the tag, before decrypting
+        let mut changed = bytes.clone();
+        changed[20] ^= 0x80;
+        assert_eq!(
+            Module::decrypt(&changed, &key),
+            Err(ModuleError::DecryptionFailed)
+        );
+        assert_eq!(
+            Module::decrypt_sealed(&changed, &key).unwrap_err(),
+            ModuleError::DecryptionFailed
+        );
+        //and the same module never encrypts to the same bytes
+        assert_ne!(module.encrypt(&key).unwrap(), bytes);
+        //plain modules pass straight through
+        assert_eq!(
+            Module::decrypt(&module.to_bytes().unwrap(), &key),
+            Ok(module)
+        );
+    }
+
+    #[test]
+    fn test_permuted_module() {
+        let module = Module::new(
+            vec![1, 0, 0x13, 0x37, 2, 0, 1, 2, 210, 1, 2, 3],
+            Interface::new(),
+        );
+        let key = [42; 32];
+        let permuted = module.permute(5, &key);
+        assert_ne!(permuted.code, module.code);
+        //only the opcodes change, custom ones keep their byte
+        assert_eq!(&permuted.code[1..4], &module.code[1..4]);
+        assert_eq!(permuted.code[8], 210);
+
+        let bytes = permuted.to_bytes().unwrap();
+        assert_eq!(Module::from_bytes(&bytes), Err(ModuleError::Encrypted));
+        assert_eq!(
+            Module::decrypt(&bytes, &[1; 32]),
+            Err(ModuleError::DecryptionFailed)
+        );
+        assert_eq!(Module::decrypt(&bytes, &key), Ok(permuted.clone()));
+        //and together with encrypting the whole container
+        assert_eq!(
+            Module::decrypt(&permuted.encrypt(&key).unwrap(), &key),
+            Ok(permuted.clone())
+        );
+
+        //permuting again starts from the original opcodes
+        let twice = permuted.permute(6, &key);
+        assert_eq!(twice, module.permute(6, &key));
+    }
+
+    #[test]
+    fn test_masked_module() {
+        let code = vec![1, 0, 0x13, 0x37, 2, 0, 1, 2, 1, 3, 0x13, 0x37, 0, 0, 0, 0];
+        let module = Module::with_data(code, Interface::new(), b"secret".to_vec());
+        let masked = module.mask(8);
+        //only the loads change, each with a mask of its own
+        assert_eq!(masked.code[0], Opcode::LOADM as u8);
+        assert_eq!(&masked.code[4..8], &module.code[4..8]);
+        assert_ne!(&masked.code[2..4], &[0x13, 0x37]);
+        assert_ne!(&masked.code[2..4], &masked.code[10..12]);
+        assert!(!masked.data.windows(6).any(|w| w == b"secret"));
+        assert_eq!(masked.unmask(), module);
+        //remasking starts from the plain module
+        assert_eq!(masked.mask(9), module.mask(9));
+
+        let bytes = masked.to_bytes().unwrap();
+        assert_eq!(Module::from_bytes(&bytes), Ok(masked.clone()));
+        assert!(!bytes.windows(2).any(|w| w == [0x13, 0x37]));
+        //and under a permutation, which leaves the masks alone
+        let key = [42; 32];
+        let permuted = masked.permute(3, &key);
+        assert_eq!(
+            Module::decrypt(&permuted.to_bytes().unwrap(), &key),
+            Ok(permuted.clone())
+        );
+        assert_eq!(permuted.unmask(), module.permute(3, &key));
+    }
+
+    #[test]
+    fn test_sealed_module() {
+        let mut interface = Interface::new();
+        interface.add_input("secret", ParamType::U32, None).unwrap();
+        let key = [42; 32];
+        let module = Module::new(vec![1, 0, 0x13, 0x37, 0, 0, 0, 0], interface).permute(3, &key);
+        let unseal = |sealed: &SealedModule| {
+            let mut keystream = sealed.keystream().clone();
+            let code: Vec<u8> = (0..sealed.code.len())
+                .map(|i| sealed.code[i] ^ keystream.at(i))
+                .collect();
+            code
+        };
+
+        //the code of an encrypted container is never decrypted, only the rest of it
+        let sealed = Module::decrypt_sealed(&module.encrypt(&key).unwrap(), &key).unwrap();
+        assert!(!sealed.code.windows(2).any(|w| w == [0x13, 0x37]));
+        assert_eq!(unseal(&sealed), module.code);
+        assert_eq!(sealed.interface, module.interface);
+        assert_eq!(sealed.permutation, module.permutation);
+        assert_eq!(
+            Module::decrypt_sealed(&module.encrypt(&key).unwrap(), &[1; 32]).unwrap_err(),
+            ModuleError::DecryptionFailed
+        );
+
+        //anything else is sealed once it's loaded
+        let sealed = Module::decrypt_sealed(&module.to_bytes().unwrap(), &key).unwrap();
+        assert_ne!(sealed.code, module.code);
+        assert_eq!(unseal(&sealed), module.code);
+        assert_eq!(unseal(&module.seal(&key)), module.code);
+    }
+}
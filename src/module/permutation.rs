@@ -0,0 +1,201 @@
+// Per module opcode encodings. Without one every module uses the same byte for each
+// opcode, so decoding one module teaches you how to read all of them. A permutation
+// shuffles which byte stands for which opcode, drawn from the module key and a seed, and
+// the table the vm needs to undo it is stored sealed with the module key in its own section.
+//
+// The host extension range (CUSTOM_OPCODES) keeps its bytes, handlers are registered
+// on the raw byte.
+
+use crate::crypt::{self, Key, Nonce};
+use crate::instructions::CUSTOM_OPCODES;
+use zeroize::Zeroize;
+
+use std::fmt;
+
+// stream the shuffle is drawn from, keyed by the module key and the seed
+const SHUFFLE_NONCE: &Nonce = b"opcode-perm\0";
+
+/// How opcodes are encoded in a module's code. The key and the tables are wiped when
+/// it's dropped.
+#[derive(Clone)]
+pub struct Permutation {
+    // opcode byte to the byte in the code
+    encode: [u8; 256],
+    // byte in the code to the opcode byte, the table the vm runs with
+    decode: [u8; 256],
+    // what the table is encrypted with in the container
+    key: Key,
+}
+
+impl Permutation {
+    /// Shuffles every opcode byte outside the custom range, the same seed and key always
+    /// give the same permutation. Knowing the seed doesn't give it away without the key.
+    pub fn new(seed: u64, key: &Key) -> Permutation {
+        let plain: Vec<u8> = (0..=255).filter(|b| !CUSTOM_OPCODES.contains(b)).collect();
+        let mut shuffled = plain.clone();
+        let mut label = b"biobox opcodes".to_vec();
+        label.extend_from_slice(&seed.to_le_bytes());
+        let mut stream_key = crypt::derive_key(key, &label);
+        let mut stream = vec![0; shuffled.len() * 4];
+        crypt::apply_keystream(&stream_key, SHUFFLE_NONCE, 0, &mut stream);
+        stream_key.zeroize();
+        //fisher-yates
+        for i in (1..shuffled.len()).rev() {
+            let random = &stream[i * 4..i * 4 + 4];
+            let random = u32::from_le_bytes([random[0], random[1], random[2], random[3]]);
+            shuffled.swap(i, random as usize % (i + 1));
+        }
+
+        let mut encode = identity();
+        for (from, to) in plain.iter().zip(&shuffled) {
+            encode[usize::from(*from)] = *to;
+        }
+        Permutation::from_encode(encode, *key)
+    }
+
+    fn from_encode(encode: [u8; 256], key: Key) -> Permutation {
+        let mut decode = [0; 256];
+        for (from, to) in encode.iter().enumerate() {
+            decode[usize::from(*to)] = from as u8;
+        }
+        Permutation {
+            encode,
+            decode,
+            key,
+        }
+    }
+
+    /// The byte the opcode is written as
+    pub fn encode(&self, opcode: u8) -> u8 {
+        self.encode[usize::from(opcode)]
+    }
+
+    /// The opcode a byte in the code stands for
+    pub fn decode(&self, byte: u8) -> u8 {
+        self.decode[usize::from(byte)]
+    }
+
+    /// Indexed by the byte in the code
+    pub fn decode_table(&self) -> &[u8; 256] {
+        &self.decode
+    }
+
+    /// The encode table sealed with the key under a random nonce, the payload of the
+    /// opcode section, see crypt::seal
+    pub fn seal(&self) -> Vec<u8> {
+        crypt::seal(&self.key, &self.encode)
+    }
+
+    /// Opens a sealed table, None if the key is wrong, the payload was changed or it
+    /// isn't a table
+    pub fn open(payload: &[u8], key: &Key) -> Option<Permutation> {
+        let mut table = crypt::open(key, payload)?;
+        if table.len() != 256 {
+            return None;
+        }
+        let mut encode = [0; 256];
+        encode.copy_from_slice(&table);
+        table.zeroize();
+
+        //the table has to be one the shuffle could have made
+        let mut seen = [false; 256];
+        for (from, to) in encode.iter().enumerate() {
+            let custom = CUSTOM_OPCODES.contains(&(from as u8));
+            let stays = from as u8 == *to;
+            if seen[usize::from(*to)]
+                || (custom && !stays)
+                || (!custom && CUSTOM_OPCODES.contains(to))
+            {
+                return None;
+            }
+            seen[usize::from(*to)] = true;
+        }
+        Some(Permutation::from_encode(encode, *key))
+    }
+}
+
+// permutations are the same when their tables are, the key isn't compared
+impl PartialEq for Permutation {
+    fn eq(&self, other: &Permutation) -> bool {
+        self.encode[..] == other.encode[..]
+    }
+}
+
+impl Zeroize for Permutation {
+    fn zeroize(&mut self) {
+        self.encode.zeroize();
+        self.decode.zeroize();
+        self.key.zeroize();
+    }
+}
+
+impl Drop for Permutation {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+// the key stays out of debug output
+impl fmt::Debug for Permutation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Permutation")
+            .field("encode", &&self.encode[..])
+            .finish()
+    }
+}
+
+/// Every byte decoding to itself, the encoding of modules without a permutation
+pub fn identity() -> [u8; 256] {
+    let mut table = [0; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = i as u8;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permutation() {
+        let key = [7; 32];
+        let permutation = Permutation::new(1234, &key);
+        assert_eq!(permutation, Permutation::new(1234, &key));
+        assert_ne!(permutation, Permutation::new(1235, &key));
+        //the seed alone doesn't decide the shuffle
+        assert_ne!(
+            permutation.encode[..],
+            Permutation::new(1234, &[8; 32]).encode[..]
+        );
+        for byte in 0..=255 {
+            assert_eq!(permutation.decode(permutation.encode(byte)), byte);
+        }
+        assert_eq!(permutation.encode(210), 210);
+        //most opcodes move
+        let moved = (0..200).filter(|b| permutation.encode(*b) != *b).count();
+        assert!(moved > 150);
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = [7; 32];
+        let permutation = Permutation::new(99, &key);
+        let sealed = permutation.seal();
+        assert_eq!(Permutation::open(&sealed, &key), Some(permutation.clone()));
+        assert_eq!(Permutation::open(&sealed, &[8; 32]), None);
+        assert_eq!(Permutation::open(&sealed[1..], &key), None);
+        let mut changed = sealed.clone();
+        changed[20] ^= 1;
+        assert_eq!(Permutation::open(&changed, &key), None);
+        //sealing twice never gives the same bytes
+        assert_ne!(permutation.seal(), sealed);
+
+        let mut permutation = permutation;
+        permutation.zeroize();
+        assert_eq!(permutation.key, [0; 32]);
+        assert_eq!(permutation.decode[..], [0; 256][..]);
+        //the table isn't stored in the clear
+        assert!(!sealed.windows(8).any(|w| w == &permutation.encode[..8]));
+    }
+}
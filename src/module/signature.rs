@@ -0,0 +1,160 @@
+// Module signatures. Encrypting a module hides its code but anyone holding the key can
+// build one, so on its own it says nothing about where a module came from. A signature
+// section holds the signer's Ed25519 public key followed by a signature over every
+// container byte in front of the section, which makes it the last section. Loading can
+// then require a signature from one of a set of trusted keys.
+//
+// The container is signed as stored, so an encrypted module is signed and checked
+// without its key.
+
+use super::{ModuleError, Sections, MAGIC, SECTION_SIGNATURE, VERSION};
+
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+
+pub use ed25519_dalek::SigningKey;
+
+/// Public keys whose signatures are accepted
+#[derive(Debug, Default, Clone)]
+pub struct TrustedKeys {
+    keys: Vec<VerifyingKey>,
+}
+
+impl TrustedKeys {
+    pub fn new() -> TrustedKeys {
+        TrustedKeys { keys: vec![] }
+    }
+
+    /// Trusts a 32 byte Ed25519 public key
+    pub fn add(&mut self, public_key: &[u8; 32]) -> Result<(), &'static str> {
+        let key = VerifyingKey::from_bytes(public_key).map_err(|_| "not an ed25519 public key")?;
+        self.keys.push(key);
+        Ok(())
+    }
+
+    pub fn contains(&self, public_key: &[u8; 32]) -> bool {
+        self.keys.iter().any(|key| key.as_bytes() == public_key)
+    }
+}
+
+/// The public key to hand out for a signing key
+pub fn public_key(key: &SigningKey) -> [u8; 32] {
+    key.verifying_key().to_bytes()
+}
+
+/// Signs a module container, replacing any signature it already has
+pub fn sign(bytes: &[u8], key: &SigningKey) -> Result<Vec<u8>, ModuleError> {
+    let (signed, _) = split(bytes)?;
+    let signature = key.sign(signed);
+    let mut results = signed.to_vec();
+    results.push(SECTION_SIGNATURE);
+    results.extend_from_slice(&96u32.to_le_bytes());
+    results.extend_from_slice(&public_key(key));
+    results.extend_from_slice(&signature.to_bytes());
+    Ok(results)
+}
+
+/// Checks a container's signature, returning the public key it was signed with
+pub fn verify(bytes: &[u8], trusted: &TrustedKeys) -> Result<[u8; 32], ModuleError> {
+    let (signed, payload) = split(bytes)?;
+    let payload = payload.ok_or(ModuleError::Unsigned)?;
+    if payload.len() != 96 {
+        return Err(ModuleError::BadSignature);
+    }
+    let mut public_key = [0; 32];
+    public_key.copy_from_slice(&payload[..32]);
+    let mut signature = [0; 64];
+    signature.copy_from_slice(&payload[32..]);
+
+    let key = VerifyingKey::from_bytes(&public_key).map_err(|_| ModuleError::BadSignature)?;
+    key.verify_strict(signed, &Signature::from_bytes(&signature))
+        .map_err(|_| ModuleError::BadSignature)?;
+    if !trusted.contains(&public_key) {
+        return Err(ModuleError::UntrustedSigner);
+    }
+    Ok(public_key)
+}
+
+/// Whether a container has a signature section, valid or not
+pub fn is_signed(bytes: &[u8]) -> Result<bool, ModuleError> {
+    Ok(split(bytes)?.1.is_some())
+}
+
+// the signed part of a container and the signature section payload, if there is one.
+// Anything following the signature wasn't signed, so it fails the check.
+fn split(bytes: &[u8]) -> Result<(&[u8], Option<&[u8]>), ModuleError> {
+    if bytes.len() < 5 || &bytes[..4] != MAGIC {
+        return Err(ModuleError::BadMagic);
+    }
+    if bytes[4] != VERSION {
+        return Err(ModuleError::UnsupportedVersion(bytes[4]));
+    }
+    let mut position = 5;
+    for (id, payload) in Sections::new(&bytes[5..]) {
+        let payload = payload?;
+        if id == SECTION_SIGNATURE {
+            if position + 5 + payload.len() != bytes.len() {
+                return Err(ModuleError::BadSignature);
+            }
+            return Ok((&bytes[..position], Some(payload)));
+        }
+        position += 5 + payload.len();
+    }
+    Ok((bytes, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::interface::Interface;
+    use crate::module::Module;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let other = SigningKey::from_bytes(&[4; 32]);
+        let mut trusted = TrustedKeys::new();
+        trusted.add(&public_key(&key)).unwrap();
+        let module = Module::new(vec![1, 0, 0, 42, 0, 0, 0, 0], Interface::new());
+        let bytes = module.to_bytes().unwrap();
+
+        let signed = sign(&bytes, &key).unwrap();
+        assert_eq!(verify(&signed, &trusted), Ok(public_key(&key)));
+        assert_eq!(is_signed(&signed), Ok(true));
+        assert_eq!(is_signed(&bytes), Ok(false));
+        //the signature section is skipped when loading
+        assert_eq!(Module::from_bytes(&signed), Ok(module.clone()));
+        //signing again replaces the old signature
+        let resigned = sign(&signed, &other).unwrap();
+        assert_eq!(resigned.len(), signed.len());
+        assert_eq!(
+            verify(&resigned, &trusted),
+            Err(ModuleError::UntrustedSigner)
+        );
+
+        assert_eq!(verify(&bytes, &trusted), Err(ModuleError::Unsigned));
+        //any changed byte, before or inside the signature
+        for position in [10, 17, signed.len() - 1].iter() {
+            let mut tampered = signed.clone();
+            tampered[*position] ^= 1;
+            assert_eq!(verify(&tampered, &trusted), Err(ModuleError::BadSignature));
+        }
+        //and anything after it
+        let mut appended = signed.clone();
+        appended.extend_from_slice(&[99, 0, 0, 0, 0]);
+        assert_eq!(verify(&appended, &trusted), Err(ModuleError::BadSignature));
+
+        //encrypted containers are signed as they are
+        let encrypted = sign(&module.encrypt(&[9; 32]).unwrap(), &key).unwrap();
+        assert!(verify(&encrypted, &trusted).is_ok());
+        assert_eq!(Module::decrypt(&encrypted, &[9; 32]), Ok(module));
+    }
+
+    #[test]
+    fn test_trusted_keys() {
+        let mut trusted = TrustedKeys::new();
+        let key = public_key(&SigningKey::from_bytes(&[3; 32]));
+        assert!(!trusted.contains(&key));
+        assert!(trusted.add(&key).is_ok());
+        assert!(trusted.contains(&key));
+    }
+}
@@ -0,0 +1,237 @@
+// Where in the assembly source every instruction of the code came from, so debuggers,
+// fault reports and the disassembler can show lines instead of offsets. The assembler
+// builds one alongside the module, and can store it in the module's debug section.
+//
+// Serialized, all numbers are 4 byte little endian:
+//   file name length, file name (empty when it isn't known)
+//   instruction count, then pc, line and column of each instruction
+//   line count, then line number, text length and text of each line with code
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// A line and column in the source, both starting at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourceLocation {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Instruction offsets in the code mapped to their place in the source
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    // the source file, as named when it was assembled
+    file: Option<String>,
+    // sorted by offset, one entry per instruction
+    entries: Vec<(usize, SourceLocation)>,
+    // the source of the lines instructions came from, trimmed
+    text: BTreeMap<u32, String>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap {
+            file: None,
+            entries: vec![],
+            text: BTreeMap::new(),
+        }
+    }
+
+    /// Records the instruction at the offset, which has to come after the last one added
+    pub fn push(&mut self, pc: usize, line: u32, column: u32) {
+        debug_assert!(self.entries.last().is_none_or(|(last, _)| *last < pc));
+        self.entries.push((pc, SourceLocation { line, column }));
+    }
+
+    /// Keeps the text of a line, to be shown along with its location
+    pub fn set_text(&mut self, line: u32, text: &str) {
+        self.text.insert(line, text.to_string());
+    }
+
+    pub fn set_file(&mut self, file: &str) {
+        self.file = Some(file.to_string());
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// The source of the line, if it was kept
+    pub fn text(&self, line: u32) -> Option<&str> {
+        self.text.get(&line).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every instruction offset with its location, in order
+    pub fn entries(&self) -> &[(usize, SourceLocation)] {
+        &self.entries
+    }
+
+    /// Where the instruction holding the pc was written, pcs in the middle of one
+    /// included
+    pub fn lookup(&self, pc: usize) -> Option<SourceLocation> {
+        let index = match self.entries.binary_search_by_key(&pc, |(start, _)| *start) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let (start, location) = self.entries[index];
+        if pc < start + 4 {
+            Some(location)
+        } else {
+            None
+        }
+    }
+
+    /// The first instruction on the line, or on the closest line after it that has one
+    pub fn pc_for_line(&self, line: u32) -> Option<(usize, SourceLocation)> {
+        self.entries
+            .iter()
+            .filter(|(_, location)| location.line >= line)
+            .min_by_key(|(pc, location)| (location.line, *pc))
+            .cloned()
+    }
+
+    /// The pc's place in the source as `file:line:column: text`, the way fault reports
+    /// and the disassembler show it
+    pub fn describe(&self, pc: usize) -> Option<String> {
+        let location = self.lookup(pc)?;
+        let mut description = match &self.file {
+            Some(file) => format!("{}:{}:{}", file, location.line, location.column),
+            None => format!("line {}, column {}", location.line, location.column),
+        };
+        if let Some(text) = self.text(location.line) {
+            description.push_str(": ");
+            description.push_str(text);
+        }
+        Some(description)
+    }
+
+    /// Serializes the map into the payload of a module's debug section
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut results = vec![];
+        push_string(&mut results, self.file.as_deref().unwrap_or(""));
+        push_u32(&mut results, self.entries.len());
+        for (pc, location) in &self.entries {
+            push_u32(&mut results, *pc);
+            push_u32(&mut results, location.line as usize);
+            push_u32(&mut results, location.column as usize);
+        }
+        push_u32(&mut results, self.text.len());
+        for (line, text) in &self.text {
+            push_u32(&mut results, *line as usize);
+            push_string(&mut results, text);
+        }
+        results
+    }
+
+    /// Reads a map back out of its serialized form
+    pub fn from_bytes(bytes: &[u8]) -> Option<SourceMap> {
+        let mut reader = Reader { bytes };
+        let mut map = SourceMap::new();
+        let file = reader.string()?;
+        if !file.is_empty() {
+            map.file = Some(file);
+        }
+        for _ in 0..reader.u32()? {
+            let pc = reader.u32()? as usize;
+            if map.entries.last().is_some_and(|(last, _)| *last >= pc) {
+                return None;
+            }
+            let location = SourceLocation {
+                line: reader.u32()?,
+                column: reader.u32()?,
+            };
+            map.entries.push((pc, location));
+        }
+        for _ in 0..reader.u32()? {
+            let line = reader.u32()?;
+            let text = reader.string()?;
+            map.text.insert(line, text);
+        }
+        if !reader.bytes.is_empty() {
+            return None;
+        }
+        Some(map)
+    }
+}
+
+fn push_u32(results: &mut Vec<u8>, value: usize) {
+    results.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn push_string(results: &mut Vec<u8>, value: &str) {
+    push_u32(results, value.len());
+    results.extend_from_slice(value.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < count {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|bytes| u32::from_le_bytes(<[u8; 4]>::try_from(bytes).unwrap()))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_map() {
+        let mut map = SourceMap::new();
+        assert!(map.is_empty());
+        map.push(0, 2, 3);
+        map.push(4, 3, 1);
+        map.push(8, 6, 1);
+        let at = |line, column| SourceLocation { line, column };
+        assert_eq!(map.lookup(0), Some(at(2, 3)));
+        assert_eq!(map.lookup(6), Some(at(3, 1)));
+        assert_eq!(map.lookup(12), None);
+        assert_eq!(map.pc_for_line(3), Some((4, at(3, 1))));
+        //lines without code move down to the next one that has some
+        assert_eq!(map.pc_for_line(4), Some((8, at(6, 1))));
+        assert_eq!(map.pc_for_line(7), None);
+        assert_eq!(map.entries().len(), 3);
+    }
+
+    #[test]
+    fn test_describe_and_round_trip() {
+        let mut map = SourceMap::new();
+        map.push(0, 1, 1);
+        map.push(4, 2, 5);
+        map.set_text(2, "top: hlt");
+        assert_eq!(map.describe(0).unwrap(), "line 1, column 1");
+        map.set_file("main.asm");
+        assert_eq!(map.describe(6).unwrap(), "main.asm:2:5: top: hlt");
+        assert_eq!(map.describe(8), None);
+
+        let bytes = map.to_bytes();
+        assert_eq!(SourceMap::from_bytes(&bytes), Some(map));
+        assert_eq!(SourceMap::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(
+            SourceMap::from_bytes(&SourceMap::new().to_bytes()),
+            Some(SourceMap::new())
+        );
+    }
+}
@@ -0,0 +1,140 @@
+// Differential testing of transformed modules. Both modules are called with the same
+// random arguments and have to return the same outputs, or fail with the same error.
+// The arguments come from the original's interface, so programs that loop on an input
+// should bound it themselves.
+
+use super::Rng;
+use crate::module::interface::{Interface, ParamType, Value};
+use crate::module::Module;
+use crate::vm::{VmError, VM};
+
+use std::error::Error;
+use std::fmt;
+
+/// Arguments the two modules disagree on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub args: Vec<Value>,
+    pub expected: Result<Vec<Value>, VmError>,
+    pub found: Result<Vec<Value>, VmError>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "modules disagree on {:?}: expected {:?}, found {:?}",
+            self.args, self.expected, self.found
+        )
+    }
+}
+
+impl Error for Mismatch {}
+
+/// Calls both modules with `runs` sets of random arguments drawn from the seed
+pub fn check(
+    original: &Module,
+    transformed: &Module,
+    runs: usize,
+    seed: u64,
+) -> Result<(), Mismatch> {
+    let mut rng = Rng::new(seed);
+    let args: Vec<Vec<Value>> = (0..runs)
+        .map(|_| random_args(&original.interface, &mut rng))
+        .collect();
+    check_with(original, transformed, &args)
+}
+
+/// Calls both modules with each set of arguments
+pub fn check_with(
+    original: &Module,
+    transformed: &Module,
+    args: &[Vec<Value>],
+) -> Result<(), Mismatch> {
+    let mut expected_vm = VM::new();
+    expected_vm.load_module(original);
+    let mut found_vm = VM::new();
+    found_vm.load_module(transformed);
+    for args in args {
+        let expected = expected_vm.call(args);
+        let found = found_vm.call(args);
+        if !same(&expected, &found) {
+            return Err(Mismatch {
+                args: args.clone(),
+                expected,
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn random_args(interface: &Interface, rng: &mut Rng) -> Vec<Value> {
+    interface
+        .inputs
+        .iter()
+        .map(|param| {
+            //edge values turn up far more often than they would by chance
+            let bits = match rng.below(4) {
+                0 => [0, 1, u32::MAX, i32::MIN as u32, i32::MAX as u32][rng.below(5)],
+                1 => rng.below(256) as u32,
+                _ => rng.next() as u32,
+            };
+            match param.ty {
+                ParamType::I32 => Value::I32(bits as i32),
+                ParamType::U32 => Value::U32(bits),
+                ParamType::F32 => Value::F32(f32::from_bits(bits)),
+                ParamType::Bytes => {
+                    Value::Bytes((0..rng.below(32)).map(|_| rng.next() as u8).collect())
+                }
+            }
+        })
+        .collect()
+}
+
+// floats are compared by their bits, NaN has to come out as the same NaN
+fn same(a: &Result<Vec<Value>, VmError>, b: &Result<Vec<Value>, VmError>) -> bool {
+    match (a, b) {
+        (Ok(a), Ok(b)) => {
+            a.len() == b.len()
+                && a.iter().zip(b).all(|pair| match pair {
+                    (Value::F32(a), Value::F32(b)) => a.to_bits() == b.to_bits(),
+                    (a, b) => a == b,
+                })
+        }
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_check() {
+        let double = assemble(".input a i32\n.output b i32 $1\nadd $0 $0 $1\nhlt").unwrap();
+        let shifted =
+            assemble(".input a i32\n.output b i32 $1\nload $2 #1\nshl $0 $2 $1\nhlt").unwrap();
+        assert_eq!(check(&double, &shifted, 100, 1), Ok(()));
+
+        //wrong only for even inputs, which is still caught
+        let wrong =
+            assemble(".input a i32\n.output b i32 $1\nload $2 #1\nor $0 $2 $3\nadd $3 $0 $1\nhlt")
+                .unwrap();
+        let mismatch = check(&double, &wrong, 100, 1).unwrap_err();
+        match (&mismatch.args[0], &mismatch.expected) {
+            (Value::I32(a), Ok(outputs)) => {
+                assert_eq!(outputs, &vec![Value::I32(a.wrapping_mul(2))]);
+                assert_eq!(a & 1, 0);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        //errors have to match as well
+        let divide =
+            assemble(".input a i32\n.output b i32 $1\nload $2 #2\ndiv $2 $0 $1\nhlt").unwrap();
+        let mismatch = check_with(&divide, &double, &[vec![Value::I32(0)]]).unwrap_err();
+        assert_eq!(mismatch.expected, Err(VmError::DivideByZero));
+    }
+}
@@ -0,0 +1,341 @@
+// Control flow flattening. The program is cut into basic blocks, which are laid out in
+// a random order behind a dispatcher loop:
+//
+//   load $s #entry
+//   dispatch: load $j #k1; eq $s $j; load $j @block1; jeq $j   one per block, shuffled
+//             ...
+//             hlt
+//   block1:   ...body...
+//             load $s #k2; load $j @dispatch; jmp $j
+//
+// Code addresses turn into state numbers: `load $r @label` loads the state of the
+// label's block and jumps hand it to the dispatcher in $s. Every jump in the result
+// goes to the dispatcher or to a block it picked, so the layout says nothing about which
+// block follows which. Calls go through the dispatcher too and return to a stub that
+// dispatches to the block after the call.
+//
+// $s and $j are registers the program never touches. The dispatcher's compares clobber
+// the equal flag, so if a block reads the flag before setting it, a third one holds the
+// flag while control goes through the dispatcher.
+
+use super::lift::{self, Flow, Instr, Item, Label, FLAG_BIT};
+use super::{op, rewrite_module, ObfuscateError, Rng};
+use crate::instructions::Opcode;
+use crate::module::interface::Location;
+use crate::module::Module;
+
+use std::collections::{HashMap, HashSet};
+
+struct Block {
+    label: Label,
+    state: u16,
+    instrs: Vec<Instr>,
+    // the equal flag is read before it's written
+    reads_flag: bool,
+}
+
+/// Flattens plain (unpermuted) code. Registers in `reserved` are left alone, on top of
+/// the ones the program uses.
+pub fn flatten(code: &[u8], seed: u64, reserved: u32) -> Result<Vec<u8>, ObfuscateError> {
+    let lifted = lift::lift(code)?;
+    let live = lift::liveness(&lifted.items);
+    let mut rng = Rng::new(seed);
+
+    let mut used = u64::from(reserved);
+    let mut blocks: Vec<Block> = vec![];
+    let mut block_of: HashMap<Label, usize> = HashMap::new();
+    let mut pending = vec![];
+    let mut leader = true;
+    let mut index = 0;
+    for item in &lifted.items {
+        match item {
+            Item::Label(label) => {
+                pending.push(*label);
+                leader = true;
+            }
+            Item::Instr(instr) => {
+                if leader {
+                    for label in pending.drain(..) {
+                        block_of.insert(label, blocks.len());
+                    }
+                    blocks.push(Block {
+                        label: 0,
+                        state: 0,
+                        instrs: vec![],
+                        reads_flag: live[index].before & FLAG_BIT != 0,
+                    });
+                }
+                let effects = instr.effects();
+                used |= effects.reads | effects.writes;
+                blocks.last_mut().unwrap().instrs.push(instr.clone());
+                leader = instr.flow() != Flow::Next;
+                index += 1;
+            }
+        }
+    }
+    //a label at the very end names the halting block
+    for label in pending {
+        block_of.insert(label, blocks.len());
+    }
+    //the end of the program, where falling off the last block or jumping to the end goes
+    blocks.push(Block {
+        label: 0,
+        state: 0,
+        instrs: vec![Instr::new(Opcode::HLT, [0; 3])],
+        reads_flag: false,
+    });
+
+    let needs_flag = blocks.iter().any(|b| b.reads_flag);
+    let mut free = !used & 0xffff_ffff;
+    let mut pick = |rng: &mut Rng| {
+        let register = rng.register(free)?;
+        free &= !(1 << register);
+        Some(register)
+    };
+    let state = pick(&mut rng).ok_or(ObfuscateError::NoFreeRegisters)?;
+    let jump = pick(&mut rng).ok_or(ObfuscateError::NoFreeRegisters)?;
+    let flag = match needs_flag {
+        true => Some(pick(&mut rng).ok_or(ObfuscateError::NoFreeRegisters)?),
+        false => None,
+    };
+
+    let mut states = HashSet::new();
+    for (n, block) in blocks.iter_mut().enumerate() {
+        block.label = lifted.labels + n;
+        block.state = loop {
+            let candidate = rng.next() as u16;
+            if states.insert(candidate) {
+                break candidate;
+            }
+        };
+    }
+    let dispatch = lifted.labels + blocks.len();
+    let block_states: Vec<u16> = blocks.iter().map(|b| b.state).collect();
+    let state_of = |label: &Label| block_states[block_of[label]];
+
+    let mut items = vec![];
+    if let Some(flag) = flag {
+        items.push(op(Opcode::FLAG, flag, 0, 0));
+    }
+    items.push(Item::Instr(Instr::load(state, blocks[0].state)));
+    items.push(Item::Label(dispatch));
+    let mut order: Vec<usize> = (0..blocks.len()).collect();
+    rng.shuffle(&mut order);
+    for n in &order {
+        let block = &blocks[*n];
+        items.extend(vec![
+            Item::Instr(Instr::load(jump, block.state)),
+            op(Opcode::EQ, state, jump, 0),
+            Item::Instr(Instr::load_label(jump, block.label)),
+            Item::Instr(Instr::jump(Opcode::JEQ, jump, block.label)),
+        ]);
+    }
+    items.push(op(Opcode::HLT, 0, 0, 0));
+
+    //hands control to the dispatcher with the state already in $s
+    let to_dispatcher = |items: &mut Vec<Item>, opcode: Opcode| {
+        if let Some(flag) = flag {
+            items.push(op(Opcode::FLAG, flag, 0, 0));
+        }
+        items.push(Item::Instr(Instr::load_label(jump, dispatch)));
+        items.push(Item::Instr(Instr::jump(opcode, jump, dispatch)));
+    };
+    rng.shuffle(&mut order);
+    for n in order {
+        let block = &blocks[n];
+        let next = blocks.get(n + 1).map(|b| b.state);
+        items.push(Item::Label(block.label));
+        if let (true, Some(flag)) = (block.reads_flag, flag) {
+            items.push(Item::Instr(Instr::load(jump, 1)));
+            items.push(op(Opcode::EQ, flag, jump, 0));
+        }
+        let (last, body) = block.instrs.split_last().expect("blocks aren't empty");
+        for instr in body
+            .iter()
+            .chain(Some(last).filter(|l| l.flow() == Flow::Next))
+        {
+            match instr.target {
+                Some(label) => items.push(Item::Instr(Instr::load(
+                    instr.operands[0],
+                    state_of(&label),
+                ))),
+                None => items.push(Item::Instr(instr.clone())),
+            }
+        }
+        let target = last.operands[0];
+        match last.flow() {
+            Flow::Next => {
+                items.push(Item::Instr(Instr::load(
+                    state,
+                    next.expect("the halting block is last"),
+                )));
+                to_dispatcher(&mut items, Opcode::JMP);
+            }
+            Flow::Jump => {
+                items.push(op(Opcode::MOV, target, state, 0));
+                to_dispatcher(&mut items, Opcode::JMP);
+            }
+            //taken goes to the dispatcher with the target's state, not taken with the next one
+            Flow::Branch => {
+                items.push(op(Opcode::MOV, target, state, 0));
+                to_dispatcher(&mut items, last.code());
+                items.push(Item::Instr(Instr::load(
+                    state,
+                    next.expect("the halting block is last"),
+                )));
+                items.push(Item::Instr(Instr::jump(Opcode::JMP, jump, dispatch)));
+            }
+            //the callee starts at the dispatcher and returns right behind the call
+            Flow::Call => {
+                items.push(op(Opcode::MOV, target, state, 0));
+                to_dispatcher(&mut items, Opcode::CALL);
+                items.push(Item::Instr(Instr::load(
+                    state,
+                    next.expect("the halting block is last"),
+                )));
+                to_dispatcher(&mut items, Opcode::JMP);
+            }
+            Flow::Stop | Flow::Relative => items.push(Item::Instr(last.clone())),
+        }
+    }
+    lift::lower(&items)
+}
+
+/// Flattens a module's code, leaving the registers of its interface alone
+pub fn flatten_module(module: &Module, seed: u64) -> Result<Module, ObfuscateError> {
+    let reserved = module
+        .interface
+        .inputs
+        .iter()
+        .chain(&module.interface.outputs)
+        .fold(0, |mask, param| match param.location {
+            Location::Register(r) => mask | 1 << r,
+            Location::Buffer { .. } => mask,
+        });
+    rewrite_module(module, |code| flatten(code, seed, reserved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::compiler::compile;
+    use crate::obfuscate::{differential, obfuscate_module, Options};
+
+    fn check(module: &Module, seeds: u64) {
+        for seed in 0..seeds {
+            let flattened = flatten_module(module, seed).unwrap();
+            if let Err(mismatch) = differential::check(module, &flattened, 30, seed) {
+                panic!("seed {}: {}", seed, mismatch);
+            }
+        }
+    }
+
+    // the labels the jumps of the code go to
+    fn jump_targets(code: &[u8]) -> Vec<HashSet<Label>> {
+        let lifted = lift::lift(code).unwrap();
+        let mut targets = vec![HashSet::new(); 4];
+        for item in &lifted.items {
+            if let Item::Instr(instr) = item {
+                let kind = match instr.code() {
+                    Opcode::JMP => 0,
+                    Opcode::JEQ => 1,
+                    Opcode::JNEQ => 2,
+                    Opcode::CALL => 3,
+                    _ => continue,
+                };
+                targets[kind].extend(instr.jumps.iter().cloned());
+            }
+        }
+        targets
+    }
+
+    const SUM: &str = ".input n i32\n.output sum i32 $1\n\
+                       load $4 #255\nand $0 $4 $0\n\
+                       load $1 #0\nload $2 #1\nload $3 @top\n\
+                       top: add $1 $2 $1\ninc $2\nlteq $2 $0\njeq $3\nhlt\n";
+
+    #[test]
+    fn test_dispatcher() {
+        let module = assemble(SUM).unwrap();
+        let flattened = flatten_module(&module, 1).unwrap();
+        //every jump goes to the dispatcher, only the dispatcher branches to blocks
+        let targets = jump_targets(&flattened.code);
+        assert_eq!(targets[0].len(), 1);
+        assert!(targets[0].is_subset(&targets[1]));
+        //the entry, the loop, the hlt and the end of the program, plus the dispatcher
+        assert_eq!(targets[1].len(), 5);
+        //the loop address is gone, the state of the loop block took its place
+        assert!(!flattened.code.windows(4).any(|w| w == [1, 3, 0, 20]));
+        assert_ne!(flatten_module(&module, 2).unwrap(), flattened);
+        check(&module, 20);
+    }
+
+    #[test]
+    fn test_flag_across_blocks() {
+        //the flag set before the jump is read after it, and a caller's flag after a
+        //return comes from the callee
+        let module = assemble(
+            ".input a i32\n.input b i32\n.output c i32 $4\n\
+             gt $0 $1\nload $2 @next\njmp $2\n\
+             next: load $3 @yes\njeq $3\nload $4 #10\nload $3 @call\njmp $3\n\
+             yes: load $4 #20\n\
+             call: load $5 @callee\ncall $5\nload $3 @done\njneq $3\ninc $4\n\
+             done: hlt\n\
+             callee: lt $0 $1\nret\n",
+        )
+        .unwrap();
+        check(&module, 20);
+    }
+
+    #[test]
+    fn test_compiled_programs() {
+        let sources = [
+            "int gcd(int a, int b) { while (b != 0) { int t = b; b = a % b; a = t; } return a; }\n\
+             int main(int a, int b) { return gcd(a & 0xffff, b & 0xfff); }",
+            "int fact(int n) { if (n <= 1) { return 1; } return n * fact(n - 1); }\n\
+             int main(int n) { return fact(n & 15) - n / 3; }",
+            "float main(float x, int n) {\n\
+                 float total = 0;\n\
+                 for (int i = 0; i < (n & 31); i++) { total = total + x * (float)i; }\n\
+                 if (total > 100.0 && !(n == 7)) { total = -total; }\n\
+                 return total / 2;\n\
+             }",
+            "int main(int a, int b) { return a / (b & 3); }",
+        ];
+        for source in sources.iter() {
+            let module = compile(source).unwrap();
+            check(&module, 5);
+            //a call per level of recursion still goes through the dispatcher
+            let targets = jump_targets(&flatten_module(&module, 0).unwrap().code);
+            assert!(targets[3].is_subset(&targets[0]));
+        }
+    }
+
+    #[test]
+    fn test_stacked_passes() {
+        let module =
+            compile("int main(int a, int b) { if (a > b) { return a - b; } return b ^ a; }")
+                .unwrap()
+                .permute(5, &[3; 32]);
+        for seed in 0..5 {
+            let flattened = flatten_module(&module, seed).unwrap();
+            let obfuscated = obfuscate_module(&flattened, &Options::new(seed, 5)).unwrap();
+            differential::check(&module, &obfuscated, 30, seed).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_no_free_registers() {
+        let mut source = String::from("load $0 #1\n");
+        for r in 1..32 {
+            source.push_str(&format!("mov $0 ${}\n", r));
+        }
+        let code = assemble(&source).unwrap().code;
+        assert_eq!(flatten(&code, 1, 0), Err(ObfuscateError::NoFreeRegisters));
+        //the interface counts as used
+        let code = assemble("load $0 #1\nhlt").unwrap().code;
+        assert!(flatten(&code, 1, 0).is_ok());
+        assert_eq!(flatten(&code, 1, !1), Err(ObfuscateError::NoFreeRegisters));
+    }
+}
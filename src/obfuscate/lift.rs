@@ -0,0 +1,483 @@
+// Lifts bytecode back into instructions that can be moved around. Jumps are absolute and
+// go through registers, so the addresses a program jumps to are just LOAD immediates.
+// Lifting finds them with a reaching definitions analysis: every register a jump reads
+// has to come from LOADs, whose immediates then become labels. Anything it can't prove,
+// like a jump through a register holding an input, is an error rather than a guess.
+
+use super::ObfuscateError;
+use crate::instructions::{Opcode, CUSTOM_OPCODES};
+
+use std::collections::HashMap;
+
+pub type Label = usize;
+
+/// One instruction with its operand bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instr {
+    pub opcode: u8,
+    pub operands: [u8; 3],
+    /// for a LOAD of a code address, the label it loads
+    pub target: Option<Label>,
+    /// for jumps and calls, every label they can go to
+    pub jumps: Vec<Label>,
+}
+
+impl Instr {
+    pub fn new(opcode: Opcode, operands: [u8; 3]) -> Instr {
+        Instr {
+            opcode: opcode as u8,
+            operands,
+            target: None,
+            jumps: vec![],
+        }
+    }
+
+    /// `load $register #value`
+    pub fn load(register: u8, value: u16) -> Instr {
+        let [upper, lower] = value.to_be_bytes();
+        Instr::new(Opcode::LOAD, [register, upper, lower])
+    }
+
+    /// `load $register @label`
+    pub fn load_label(register: u8, label: Label) -> Instr {
+        let mut load = Instr::load(register, 0);
+        load.target = Some(label);
+        load
+    }
+
+    /// `jmp`, `jeq`, `jneq` or `call` through a register holding the label
+    pub fn jump(opcode: Opcode, register: u8, label: Label) -> Instr {
+        let mut jump = Instr::new(opcode, [register, 0, 0]);
+        jump.jumps = vec![label];
+        jump
+    }
+
+    pub fn code(&self) -> Opcode {
+        Opcode::from(self.opcode)
+    }
+
+    /// The 16 bit immediate of LOAD and LUI
+    pub fn immediate(&self) -> u16 {
+        u16::from_be_bytes([self.operands[1], self.operands[2]])
+    }
+
+    /// Registers and flag the instruction reads and writes
+    pub fn effects(&self) -> Effects {
+        use crate::instructions::Opcode::*;
+        let register = |i: usize| 1u64 << (self.operands[i] & 31);
+        let (reads, writes) = match self.code() {
+            HLT | NOP | RET | IGL => (0, 0),
+            LOAD | LOADM | LUI => (0, register(0)),
+            FLAG => (FLAG_BIT, register(0)),
+            ALOC | JMP | CALL | JMPF | JMPB => (register(0), 0),
+            JEQ | JNEQ => (register(0) | FLAG_BIT, 0),
+            INC | DEC => (register(0), register(0)),
+            MOV | LDB | MLEN | LDW | NEG | NOT | FNEG | ITOF | FTOI | UTOF | FTOU => {
+                (register(0), register(1))
+            }
+            STB | STW => (register(0) | register(1), 0),
+            ADD | SUB | MUL | DIV | MOD | DIVU | MODU | AND | OR | XOR | SHL | SHR | SHRU
+            | FADD | FSUB | FMUL | FDIV => (register(0) | register(1), register(2)),
+            EQ | NEQ | GT | LT | GTEQ | LTEQ | LTU | GTU | LTEQU | GTEQU | FLT | FGT | FLTEQ
+            | FGTEQ | FEQ | FNEQ => (register(0) | register(1), FLAG_BIT),
+            BETW => (register(0) | register(1) | register(2), FLAG_BIT),
+            //host code can do anything to the registers
+            SYSCALL => (ALL, ALL),
+        };
+        if CUSTOM_OPCODES.contains(&self.opcode) {
+            return Effects {
+                reads: ALL,
+                writes: ALL,
+            };
+        }
+        Effects { reads, writes }
+    }
+
+    /// Where control goes after the instruction
+    pub fn flow(&self) -> Flow {
+        match self.code() {
+            Opcode::JMP => Flow::Jump,
+            Opcode::JEQ | Opcode::JNEQ => Flow::Branch,
+            Opcode::CALL => Flow::Call,
+            Opcode::JMPF | Opcode::JMPB => Flow::Relative,
+            Opcode::HLT | Opcode::RET => Flow::Stop,
+            //unknown bytes stop the vm, unless the host handles them
+            Opcode::IGL if !CUSTOM_OPCODES.contains(&self.opcode) => Flow::Stop,
+            _ => Flow::Next,
+        }
+    }
+}
+
+/// Bit 32 of the masks is the equal flag, the low 32 are the registers
+pub const FLAG_BIT: u64 = 1 << 32;
+pub const ALL: u64 = (1 << 33) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Effects {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Next,
+    Jump,
+    Branch,
+    Call,
+    Relative,
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Label(Label),
+    Instr(Instr),
+}
+
+/// A lifted program, labels are placed in front of the instruction they name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lifted {
+    pub items: Vec<Item>,
+    pub labels: usize,
+    /// per instruction, the registers that may hold a code address in front of it.
+    /// Instructions that can't be reached have every bit set.
+    pub addresses: Vec<u32>,
+}
+
+// a definition that isn't a LOAD, or the unknown value a register starts with
+const OTHER: u32 = u32::MAX;
+
+/// Decodes plain (unpermuted) code into instructions with labels
+pub fn lift(code: &[u8]) -> Result<Lifted, ObfuscateError> {
+    if !code.len().is_multiple_of(4) {
+        return Err(ObfuscateError::Misaligned);
+    }
+    let instrs: Vec<Instr> = code
+        .chunks(4)
+        .map(|bytes| Instr {
+            opcode: bytes[0],
+            operands: [bytes[1], bytes[2], bytes[3]],
+            target: None,
+            jumps: vec![],
+        })
+        .collect();
+    if let Some(pc) = instrs.iter().position(|i| i.flow() == Flow::Relative) {
+        return Err(ObfuscateError::RelativeJump(pc * 4));
+    }
+
+    //jump targets are only known once the loads reaching the jumps are, and those depend
+    //on the edges the targets add, so go around until nothing new turns up
+    let mut targets: Vec<Vec<usize>> = vec![vec![]; instrs.len()];
+    let mut addresses: HashMap<usize, usize> = HashMap::new();
+    let mut reaching;
+    loop {
+        reaching = reaching_definitions(&instrs, &targets);
+        let mut changed = false;
+        for (index, instr) in instrs.iter().enumerate() {
+            let state = match &reaching[index] {
+                Some(state) => state,
+                None => continue,
+            };
+            let jump_register = match instr.flow() {
+                Flow::Jump | Flow::Branch | Flow::Call => Some(instr.operands[0] & 31),
+                _ => None,
+            };
+            let effects = instr.effects();
+            for register in 0..32u8 {
+                if effects.reads & (1 << register) == 0 || effects.reads == ALL {
+                    continue;
+                }
+                let definitions = &state[usize::from(register)];
+                if Some(register) != jump_register {
+                    //a code address used as data would break once code moves
+                    if definitions
+                        .iter()
+                        .any(|d| addresses.contains_key(&(*d as usize)))
+                    {
+                        return Err(ObfuscateError::AddressAsData(index * 4));
+                    }
+                    continue;
+                }
+                for definition in definitions {
+                    let load = match instrs.get(*definition as usize) {
+                        Some(load) if *definition != OTHER && load.code() == Opcode::LOAD => load,
+                        _ => return Err(ObfuscateError::UnresolvedJump(index * 4)),
+                    };
+                    let address = usize::from(load.immediate());
+                    if address % 4 != 0 || address / 4 > instrs.len() {
+                        return Err(ObfuscateError::UnresolvedJump(index * 4));
+                    }
+                    addresses.insert(*definition as usize, address / 4);
+                    if !targets[index].contains(&(address / 4)) {
+                        targets[index].push(address / 4);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let holding = reaching
+        .iter()
+        .map(|state| match state {
+            Some(state) => (0..32).fold(0, |mask, register| {
+                match state[register]
+                    .iter()
+                    .any(|d| addresses.contains_key(&(*d as usize)))
+                {
+                    true => mask | 1 << register,
+                    false => mask,
+                }
+            }),
+            None => u32::MAX,
+        })
+        .collect();
+
+    //one label per target, `labels[n]` is the end of the program
+    let mut labels: HashMap<usize, Label> = HashMap::new();
+    for target in addresses.values() {
+        let count = labels.len();
+        labels.entry(*target).or_insert(count);
+    }
+    let mut items = vec![];
+    for (index, mut instr) in instrs.into_iter().enumerate() {
+        if let Some(label) = labels.get(&index) {
+            items.push(Item::Label(*label));
+        }
+        if let Some(target) = addresses.get(&index) {
+            instr.target = Some(labels[target]);
+        }
+        instr.jumps = targets[index].iter().map(|t| labels[t]).collect();
+        items.push(Item::Instr(instr));
+    }
+    if let Some(label) = labels.get(&(code.len() / 4)) {
+        items.push(Item::Label(*label));
+    }
+    Ok(Lifted {
+        labels: labels.len(),
+        items,
+        addresses: holding,
+    })
+}
+
+/// For every instruction and register, the instructions whose value the register may
+/// hold. None for instructions that can't be reached.
+fn reaching_definitions(instrs: &[Instr], targets: &[Vec<usize>]) -> Vec<Option<Vec<Vec<u32>>>> {
+    let mut states: Vec<Option<Vec<Vec<u32>>>> = vec![None; instrs.len()];
+    if instrs.is_empty() {
+        return states;
+    }
+    states[0] = Some(vec![vec![OTHER]; 32]);
+    let mut work = vec![0];
+    while let Some(index) = work.pop() {
+        let instr = &instrs[index];
+        let mut state = states[index]
+            .clone()
+            .expect("only reached states are queued");
+        let writes = instr.effects().writes;
+        for (register, definitions) in state.iter_mut().enumerate() {
+            if writes & (1 << register) != 0 {
+                *definitions = match instr.code() {
+                    Opcode::LOAD if writes != ALL => vec![index as u32],
+                    _ => vec![OTHER],
+                };
+            }
+        }
+        let mut successors: Vec<(usize, bool)> =
+            targets[index].iter().map(|t| (*t, false)).collect();
+        match instr.flow() {
+            Flow::Next | Flow::Branch => successors.push((index + 1, false)),
+            //whatever the callee does to the registers shows up after the call
+            Flow::Call => successors.push((index + 1, true)),
+            _ => {}
+        }
+        for (successor, clobbered) in successors {
+            if successor >= instrs.len() {
+                continue;
+            }
+            let incoming = if clobbered {
+                vec![vec![OTHER]; 32]
+            } else {
+                state.clone()
+            };
+            let merged = match &states[successor] {
+                None => incoming,
+                Some(existing) => {
+                    let mut merged = existing.clone();
+                    for (set, new) in merged.iter_mut().zip(incoming) {
+                        for definition in new {
+                            if !set.contains(&definition) {
+                                set.push(definition);
+                            }
+                        }
+                    }
+                    merged
+                }
+            };
+            if states[successor].as_ref() != Some(&merged) {
+                states[successor] = Some(merged);
+                work.push(successor);
+            }
+        }
+    }
+    states
+}
+
+/// Lays the instructions out again and fills in the label addresses
+pub fn lower(items: &[Item]) -> Result<Vec<u8>, ObfuscateError> {
+    let mut addresses = HashMap::new();
+    let mut position = 0usize;
+    for item in items {
+        match item {
+            Item::Label(label) => {
+                addresses.insert(*label, position);
+            }
+            Item::Instr(_) => position += 4,
+        }
+    }
+    if position > usize::from(u16::MAX) {
+        return Err(ObfuscateError::TooLarge);
+    }
+    let mut code = Vec::with_capacity(position);
+    for item in items {
+        if let Item::Instr(instr) = item {
+            let mut operands = instr.operands;
+            if let Some(label) = instr.target {
+                let address = addresses[&label] as u16;
+                operands[1..].copy_from_slice(&address.to_be_bytes());
+            }
+            code.push(instr.opcode);
+            code.extend_from_slice(&operands);
+        }
+    }
+    Ok(code)
+}
+
+/// Registers and flag that may still be read before they are written again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Live {
+    pub before: u64,
+    pub after: u64,
+}
+
+/// What is live around every instruction in `items` (indexed by instruction, labels
+/// don't count)
+pub fn liveness(items: &[Item]) -> Vec<Live> {
+    let mut labels = HashMap::new();
+    let mut instrs = vec![];
+    for item in items {
+        match item {
+            Item::Label(label) => {
+                labels.insert(*label, instrs.len());
+            }
+            Item::Instr(instr) => instrs.push(instr),
+        }
+    }
+    //calls, returns and halts hand every register to code this can't see
+    let mut live = vec![
+        Live {
+            before: 0,
+            after: 0
+        };
+        instrs.len()
+    ];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..instrs.len()).rev() {
+            let instr = instrs[index];
+            let at = |i: usize| live.get(i).map(|l| l.before).unwrap_or(ALL);
+            let mut out: u64 = instr
+                .jumps
+                .iter()
+                .map(|l| at(labels[l]))
+                .fold(0, |a, b| a | b);
+            match instr.flow() {
+                Flow::Next | Flow::Branch => out |= at(index + 1),
+                Flow::Call | Flow::Stop | Flow::Relative => out = ALL,
+                Flow::Jump => {}
+            }
+            let effects = instr.effects();
+            let value = Live {
+                before: effects.reads | (out & !effects.writes),
+                after: out,
+            };
+            if value != live[index] {
+                live[index] = value;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const SUM: &str = "load $1 #0\nload $2 #1\nload $3 @top\n\
+                       top: add $1 $2 $1\ninc $2\nlteq $2 $0\njeq $3\nhlt\n";
+
+    #[test]
+    fn test_lift_and_lower() {
+        let code = assemble(SUM).unwrap().code;
+        let lifted = lift(&code).unwrap();
+        assert_eq!(lifted.labels, 1);
+        assert_eq!(lifted.items[3], Item::Label(0));
+        match &lifted.items[2] {
+            Item::Instr(load) => assert_eq!(load.target, Some(0)),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(lower(&lifted.items).unwrap(), code);
+
+        //moving code around moves the address with it
+        let mut items = lifted.items.clone();
+        items.insert(0, Item::Instr(Instr::new(Opcode::NOP, [0; 3])));
+        let moved = lower(&items).unwrap();
+        assert_eq!(&moved[12..16], &[1, 3, 0, 16]);
+    }
+
+    #[test]
+    fn test_lift_errors() {
+        let code = |source| assemble(source).unwrap().code;
+        assert_eq!(lift(&[0, 0, 0]), Err(ObfuscateError::Misaligned));
+        //the jump target comes from an input
+        assert_eq!(
+            lift(&code("jmp $0\nhlt")),
+            Err(ObfuscateError::UnresolvedJump(0))
+        );
+        assert_eq!(
+            lift(&code("load $0 #8\njmpf $0\nhlt")),
+            Err(ObfuscateError::RelativeJump(4))
+        );
+        assert_eq!(
+            lift(&code("load $0 @end\nadd $0 $0 $1\njmp $0\nend: hlt")),
+            Err(ObfuscateError::AddressAsData(4))
+        );
+        //constants that never reach a jump are plain data
+        let lifted = lift(&code("load $0 #8\nadd $0 $0 $1\nhlt")).unwrap();
+        assert_eq!(lifted.labels, 0);
+    }
+
+    #[test]
+    fn test_liveness() {
+        let lifted = lift(&assemble(SUM).unwrap().code).unwrap();
+        //$3 holds the loop address from the load on
+        assert_eq!(lifted.addresses[2], 0);
+        assert_eq!(lifted.addresses[3], 0b1000);
+        let live: Vec<u64> = liveness(&lifted.items).iter().map(|l| l.before).collect();
+        //each load kills its register, the loop reads $0 to $3
+        assert_eq!(live[0] & 0b10, 0);
+        assert_eq!(live[2] & 0b1000, 0);
+        assert_eq!(live[3] & 0b1111, 0b1111);
+        //the flag dies at every compare
+        assert_eq!(live[5] & FLAG_BIT, 0);
+        assert_ne!(live[6] & FLAG_BIT, 0);
+        //hlt keeps everything
+        assert_eq!(live[7], ALL);
+    }
+}
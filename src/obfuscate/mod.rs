@@ -0,0 +1,630 @@
+// Bytecode to bytecode obfuscation. Code is lifted into instructions with labels,
+// rewritten, and laid out again with the jump addresses fixed up. The result computes
+// exactly what the input does but reads like something else:
+//   junk          state preserving sequences between the real instructions
+//   substitution  instructions rewritten into equivalent sequences, ADD into a SUB of
+//                 the negated operand and so on
+//   predicates    branches on x*x+x being odd, which it never is, guarding fake blocks
+//                 made of copies of real instructions that never run
+// flatten.rs hides the control flow itself behind a dispatcher loop.
+// Inserted code only clobbers registers liveness proves dead, and never touches the
+// equal flag unless that is dead too. The same seed and intensity give the same code.
+
+pub mod differential;
+pub mod flatten;
+pub mod lift;
+
+use self::lift::{Flow, Instr, Item, Label, Lifted, Live, FLAG_BIT};
+use crate::instructions::Opcode;
+use crate::module::Module;
+
+use std::error::Error;
+use std::fmt;
+
+/// The highest intensity, every instruction that can be substituted is
+pub const MAX_INTENSITY: u8 = 10;
+
+/// How to obfuscate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub seed: u64,
+    /// 0 leaves the code alone, MAX_INTENSITY rewrites as much as it can
+    pub intensity: u8,
+}
+
+impl Options {
+    /// Intensities above MAX_INTENSITY count as MAX_INTENSITY
+    pub fn new(seed: u64, intensity: u8) -> Options {
+        Options {
+            seed,
+            intensity: intensity.min(MAX_INTENSITY),
+        }
+    }
+}
+
+/// Reasons code can't be obfuscated without changing what it does. Positions are byte
+/// offsets into the code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObfuscateError {
+    /// the code isn't made of whole 4 byte instructions
+    Misaligned,
+    /// JMPF and JMPB offsets would point somewhere else once code moves
+    RelativeJump(usize),
+    /// a jump whose target isn't always a LOAD of a code address
+    UnresolvedJump(usize),
+    /// a code address is used as data, like an offset added to a jump target
+    AddressAsData(usize),
+    /// the rewritten code has addresses past what LOAD can hold
+    TooLarge,
+    /// every register is used by the program, its interface or the host, so none is
+    /// left for the pass
+    NoFreeRegisters,
+}
+
+impl fmt::Display for ObfuscateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObfuscateError::Misaligned => write!(f, "code isn't made of 4 byte instructions"),
+            ObfuscateError::RelativeJump(pc) => {
+                write!(f, "relative jump at {} can't be moved", pc)
+            }
+            ObfuscateError::UnresolvedJump(pc) => {
+                write!(f, "unable to tell where the jump at {} goes", pc)
+            }
+            ObfuscateError::AddressAsData(pc) => {
+                write!(f, "instruction at {} uses a code address as data", pc)
+            }
+            ObfuscateError::TooLarge => write!(f, "obfuscated code is too large to address"),
+            ObfuscateError::NoFreeRegisters => write!(f, "no registers are free for the pass"),
+        }
+    }
+}
+
+impl Error for ObfuscateError {}
+
+/// Obfuscates plain (unpermuted) code
+pub fn obfuscate(code: &[u8], options: &Options) -> Result<Vec<u8>, ObfuscateError> {
+    if options.intensity == 0 {
+        return Ok(code.to_vec());
+    }
+    let lifted = lift::lift(code)?;
+    lift::lower(&Pass::new(&lifted, options).run())
+}
+
+/// Obfuscates a module's code, keeping its interface, opcode permutation, masks and data
+pub fn obfuscate_module(module: &Module, options: &Options) -> Result<Module, ObfuscateError> {
+    rewrite_module(module, |code| obfuscate(code, options))
+}
+
+// runs a pass over the plain code of a module, which may be permuted and masked. The
+// masks depend on where an instruction is, so they are taken off and put back after.
+fn rewrite_module<F>(module: &Module, pass: F) -> Result<Module, ObfuscateError>
+where
+    F: FnOnce(&[u8]) -> Result<Vec<u8>, ObfuscateError>,
+{
+    let plain = module.unmask();
+    let mut code = plain.code.clone();
+    if let Some(permutation) = &module.permutation {
+        for instruction in code.chunks_mut(4) {
+            instruction[0] = permutation.decode(instruction[0]);
+        }
+    }
+    let mut code = pass(&code)?;
+    if let Some(permutation) = &module.permutation {
+        for instruction in code.chunks_mut(4) {
+            instruction[0] = permutation.encode(instruction[0]);
+        }
+    }
+    //the pcs of the source map no longer match
+    let rewritten = Module {
+        code,
+        source_map: None,
+        ..plain
+    };
+    Ok(match &module.masks {
+        Some(masks) => rewritten.mask(masks.seed()),
+        None => rewritten,
+    })
+}
+
+/// xorshift64*, plenty for picking rewrites and test inputs
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng((seed ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in 0..n
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub(crate) fn chance(&mut self, percent: u32) -> bool {
+        self.below(100) < percent as usize
+    }
+
+    /// Fisher-Yates
+    pub(crate) fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+
+    /// A random register out of the low 32 bits of the mask
+    pub(crate) fn register(&mut self, mask: u64) -> Option<u8> {
+        let mask = mask & REGISTERS;
+        if mask == 0 {
+            return None;
+        }
+        let mut n = self.below(mask.count_ones() as usize);
+        for register in 0..32 {
+            if mask & 1 << register != 0 {
+                if n == 0 {
+                    return Some(register);
+                }
+                n -= 1;
+            }
+        }
+        None
+    }
+}
+
+const REGISTERS: u64 = 0xffff_ffff;
+
+// a label fake blocks may jump to, with what is live there
+struct Target {
+    label: Label,
+    live: u64,
+    addresses: u64,
+}
+
+struct Pass<'a> {
+    rng: Rng,
+    intensity: u32,
+    lifted: &'a Lifted,
+    live: Vec<Live>,
+    items: Vec<Item>,
+    // fake blocks only the always false predicates jump to, placed after the program
+    tail: Vec<Item>,
+    labels: usize,
+    end: Label,
+    targets: Vec<Target>,
+    // real instructions fake blocks are made of
+    copies: Vec<Instr>,
+}
+
+impl<'a> Pass<'a> {
+    fn new(lifted: &'a Lifted, options: &Options) -> Pass<'a> {
+        let live = lift::liveness(&lifted.items);
+        let mut targets = vec![];
+        let mut copies = vec![];
+        let mut index = 0;
+        for item in &lifted.items {
+            match item {
+                //only reachable places whose analysis a fake edge can't upset
+                Item::Label(label) => match (live.get(index), lifted.addresses.get(index)) {
+                    (Some(live), Some(addresses)) if *addresses != u32::MAX => {
+                        targets.push(Target {
+                            label: *label,
+                            live: live.before,
+                            addresses: u64::from(*addresses),
+                        })
+                    }
+                    _ => {}
+                },
+                Item::Instr(instr) => {
+                    if lifted.addresses[index] != u32::MAX
+                        && plain(instr)
+                        && instr.flow() == Flow::Next
+                        && instr.target.is_none()
+                    {
+                        copies.push(instr.clone());
+                    }
+                    index += 1;
+                }
+            }
+        }
+        Pass {
+            rng: Rng::new(options.seed),
+            intensity: u32::from(options.intensity),
+            lifted,
+            live,
+            items: vec![],
+            tail: vec![],
+            labels: lifted.labels + 1,
+            end: lifted.labels,
+            targets,
+            copies,
+        }
+    }
+
+    fn run(mut self) -> Vec<Item> {
+        let mut index = 0;
+        for item in &self.lifted.items {
+            let instr = match item {
+                Item::Label(_) => {
+                    self.items.push(item.clone());
+                    continue;
+                }
+                Item::Instr(instr) => instr,
+            };
+            if self.lifted.addresses[index] != u32::MAX {
+                //junk first, it could read the address a predicate leaves behind
+                if self.rng.chance(self.intensity * 6) {
+                    self.junk(index);
+                }
+                if self.rng.chance(self.intensity * 4) {
+                    self.predicate(index);
+                }
+                if plain(instr) && self.rng.chance(self.intensity * 10) {
+                    if let Some(mut replacement) = self.substitute(instr, index) {
+                        self.items.append(&mut replacement);
+                        index += 1;
+                        continue;
+                    }
+                }
+            }
+            self.items.push(item.clone());
+            index += 1;
+        }
+
+        self.items.push(Item::Label(self.end));
+        if !self.tail.is_empty() {
+            self.items.push(op(Opcode::HLT, 0, 0, 0));
+            self.items.append(&mut self.tail);
+        }
+        self.items
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    /// A state preserving sequence in front of the instruction
+    fn junk(&mut self, index: usize) {
+        let live = self.live[index].before;
+        let dead = !live & REGISTERS;
+        //dead registers may hold addresses left by predicates, and registers holding
+        //code addresses have to keep looking like them, so only live data is read
+        let data = live & REGISTERS & !u64::from(self.lifted.addresses[index]);
+        let r = match self.rng.register(data) {
+            Some(r) => r,
+            None => return,
+        };
+        let other = self.rng.register(data & !(1 << r));
+        let sequence = match (self.rng.below(8), other) {
+            (0, _) => vec![op(Opcode::INC, r, 0, 0), op(Opcode::DEC, r, 0, 0)],
+            (1, _) => vec![op(Opcode::NOT, r, r, 0), op(Opcode::NOT, r, r, 0)],
+            (2, _) => vec![op(Opcode::NEG, r, r, 0), op(Opcode::NEG, r, r, 0)],
+            (3, Some(b)) => vec![op(Opcode::ADD, r, b, r), op(Opcode::SUB, r, b, r)],
+            (4, Some(b)) => vec![op(Opcode::XOR, r, b, r), op(Opcode::XOR, r, b, r)],
+            (5, _) => vec![op(Opcode::MOV, r, r, 0)],
+            (7, Some(b)) => match self.rng.register(dead) {
+                Some(d) => {
+                    let opcodes = [
+                        Opcode::ADD,
+                        Opcode::SUB,
+                        Opcode::MUL,
+                        Opcode::AND,
+                        Opcode::OR,
+                        Opcode::XOR,
+                    ];
+                    let opcode = opcodes[self.rng.below(opcodes.len())];
+                    vec![
+                        Item::Instr(Instr::load(d, self.rng.next() as u16)),
+                        op(opcode, r, b, d),
+                    ]
+                }
+                None => vec![op(Opcode::NOP, 0, 0, 0)],
+            },
+            _ => vec![op(Opcode::NOP, 0, 0, 0)],
+        };
+        self.items.extend(sequence);
+    }
+
+    /// An equivalent sequence for the instruction, if there is one
+    fn substitute(&mut self, instr: &Instr, index: usize) -> Option<Vec<Item>> {
+        let [a, b, c] = instr.operands;
+        let effects = instr.effects();
+        let temporary = !(self.live[index].after | effects.reads | effects.writes);
+        let sequence = match instr.code() {
+            //a + b = a - -b
+            Opcode::ADD | Opcode::SUB if a != b => {
+                let inverse = match instr.code() {
+                    Opcode::ADD => Opcode::SUB,
+                    _ => Opcode::ADD,
+                };
+                let mut sequence = vec![op(Opcode::NEG, b, b, 0), op(inverse, a, b, c)];
+                if b != c {
+                    sequence.push(op(Opcode::NEG, b, b, 0));
+                }
+                sequence
+            }
+            //the destination may hold anything before, so it's cleared without reading it
+            Opcode::MOV if a != b => vec![Item::Instr(Instr::load(b, 0)), op(Opcode::OR, a, b, b)],
+            //-~r = r + 1 and ~-r = r - 1
+            Opcode::INC => vec![op(Opcode::NOT, a, a, 0), op(Opcode::NEG, a, a, 0)],
+            Opcode::DEC => vec![op(Opcode::NEG, a, a, 0), op(Opcode::NOT, a, a, 0)],
+            Opcode::NEG => vec![op(Opcode::NOT, a, b, 0), op(Opcode::INC, b, 0, 0)],
+            Opcode::NOT => vec![op(Opcode::NEG, a, b, 0), op(Opcode::DEC, b, 0, 0)],
+            //a ^ b = (a | b) - (a & b)
+            Opcode::XOR => {
+                let t = self.rng.register(temporary)?;
+                vec![
+                    op(Opcode::OR, a, b, t),
+                    op(Opcode::AND, a, b, c),
+                    op(Opcode::SUB, t, c, c),
+                ]
+            }
+            Opcode::LOAD if instr.target.is_none() => {
+                let t = self.rng.register(temporary)?;
+                let key = self.rng.next() as u16;
+                vec![
+                    Item::Instr(Instr::load(a, instr.immediate() ^ key)),
+                    Item::Instr(Instr::load(t, key)),
+                    op(Opcode::XOR, a, t, a),
+                ]
+            }
+            _ => return None,
+        };
+        Some(sequence)
+    }
+
+    /// A branch on a value that is always even, in front of the instruction. Half of
+    /// them are always taken and skip a fake block, the other half never are and guard
+    /// one placed after the program.
+    fn predicate(&mut self, index: usize) {
+        let live = self.live[index].before;
+        if live & FLAG_BIT != 0 {
+            return;
+        }
+        let addresses = u64::from(self.lifted.addresses[index]);
+        let dead = !live & REGISTERS;
+        let t = self.rng.register(dead);
+        let u = t.and_then(|t| self.rng.register(dead & !(1 << t)));
+        let (t, u) = match (t, u) {
+            (Some(t), Some(u)) => (t, u),
+            _ => return,
+        };
+        let x = match self.rng.register(live & !addresses) {
+            Some(x) => x,
+            None => return,
+        };
+        //t = (x * x + x) & 1, which is 0
+        self.items.extend(vec![
+            op(Opcode::MUL, x, x, t),
+            op(Opcode::ADD, t, x, t),
+            Item::Instr(Instr::load(u, 1)),
+            op(Opcode::AND, t, u, t),
+        ]);
+        let fake = self.label();
+        let block = self.fake_block(addresses | dead, fake);
+        if self.rng.chance(50) {
+            let real = self.label();
+            self.items.extend(vec![
+                Item::Instr(Instr::load(u, 0)),
+                op(Opcode::EQ, t, u, 0),
+                Item::Instr(Instr::load_label(u, real)),
+                Item::Instr(Instr::jump(Opcode::JEQ, u, real)),
+            ]);
+            self.items.extend(block);
+            self.items.push(Item::Label(real));
+        } else {
+            self.items.extend(vec![
+                op(Opcode::EQ, t, u, 0),
+                Item::Instr(Instr::load_label(u, fake)),
+                Item::Instr(Instr::jump(Opcode::JEQ, u, fake)),
+            ]);
+            self.tail.extend(block);
+        }
+    }
+
+    /// Copies of real instructions that don't read `avoid`, then a jump to somewhere
+    /// nothing in `avoid` is live. Fake blocks never run, but lifting the result has to
+    /// see them doing nothing with addresses a real instruction couldn't.
+    fn fake_block(&mut self, avoid: u64, label: Label) -> Vec<Item> {
+        let mut block = vec![Item::Label(label)];
+        if !self.copies.is_empty() {
+            for _ in 0..1 + self.rng.below(4) {
+                let copy = &self.copies[self.rng.below(self.copies.len())];
+                if copy.effects().reads & avoid == 0 {
+                    block.push(Item::Instr(copy.clone()));
+                }
+            }
+        }
+        let targets: Vec<&Target> = self
+            .targets
+            .iter()
+            .filter(|t| t.live & (avoid | t.addresses) == 0)
+            .collect();
+        let (target, live) = match self.rng.below(targets.len() + 1) {
+            0 => (self.end, 0),
+            n => (targets[n - 1].label, targets[n - 1].live),
+        };
+        let v = self.rng.register(!live).unwrap_or(0);
+        block.push(Item::Instr(Instr::load_label(v, target)));
+        block.push(Item::Instr(Instr::jump(Opcode::JMP, v, target)));
+        block
+    }
+}
+
+fn op(opcode: Opcode, a: u8, b: u8, c: u8) -> Item {
+    Item::Instr(Instr::new(opcode, [a, b, c]))
+}
+
+// instructions with known effects and only real registers, the ones that are safe to
+// rewrite and copy
+fn plain(instr: &Instr) -> bool {
+    match instr.code() {
+        Opcode::IGL | Opcode::SYSCALL => false,
+        Opcode::LOAD | Opcode::LUI => instr.operands[0] < 32,
+        _ => instr.operands.iter().all(|r| *r < 32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::compiler::compile;
+
+    fn obfuscated(module: &Module, seed: u64, intensity: u8) -> Module {
+        let module = obfuscate_module(module, &Options::new(seed, intensity)).unwrap();
+        //the output lifts again, so passes can be stacked
+        let mut code = module.unmask().code;
+        if let Some(permutation) = &module.permutation {
+            for instruction in code.chunks_mut(4) {
+                instruction[0] = permutation.decode(instruction[0]);
+            }
+        }
+        lift::lift(&code).unwrap();
+        module
+    }
+
+    fn check(module: &Module, seeds: u64) {
+        for seed in 0..seeds {
+            for intensity in [1, 5, MAX_INTENSITY].iter() {
+                let transformed = obfuscated(module, seed, *intensity);
+                if let Err(mismatch) = differential::check(module, &transformed, 30, seed) {
+                    panic!("seed {} intensity {}: {}", seed, intensity, mismatch);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_intensity() {
+        let module = compile("int main(int a, int b) { return a * b + (a ^ b); }").unwrap();
+        let options = Options::new(3, 0);
+        assert_eq!(obfuscate(&module.code, &options).unwrap(), module.code);
+        assert_eq!(Options::new(3, 200).intensity, MAX_INTENSITY);
+
+        let low = obfuscated(&module, 3, 1).code;
+        let high = obfuscated(&module, 3, MAX_INTENSITY).code;
+        assert!(high.len() > low.len());
+        assert!(high.len() > module.code.len() * 2);
+        //seeds are reproducible
+        assert_eq!(obfuscated(&module, 3, MAX_INTENSITY).code, high);
+        assert_ne!(obfuscated(&module, 4, MAX_INTENSITY).code, high);
+    }
+
+    #[test]
+    fn test_substitutions() {
+        let header = ".input a i32\n.input b i32\n.output c i32 $2\n";
+        for body in [
+            "add $0 $1 $2",
+            "add $0 $1 $1\nmov $1 $2",
+            "sub $0 $1 $2",
+            "sub $1 $0 $0\nmov $0 $2",
+            "xor $0 $1 $2",
+            "xor $0 $1 $0\nmov $0 $2",
+            "mov $1 $2",
+            "inc $0\nmov $0 $2",
+            "dec $1\nmov $1 $2",
+            "neg $0 $2",
+            "not $1 $2",
+            "load $2 #65535",
+        ]
+        .iter()
+        {
+            let module = assemble(&format!("{}{}\nhlt", header, body)).unwrap();
+            //every substitution at the highest intensity
+            let transformed = obfuscated(&module, 1, MAX_INTENSITY);
+            assert!(transformed.code.len() > module.code.len(), "{}", body);
+            for seed in 0..20 {
+                let transformed = obfuscated(&module, seed, MAX_INTENSITY);
+                if let Err(mismatch) = differential::check(&module, &transformed, 20, seed) {
+                    panic!("{}: {}", body, mismatch);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_assembled_loop() {
+        //sums 1..=n for n below 256, with the loop address held in $3 across iterations
+        let module = assemble(
+            ".input n i32\n.output sum i32 $1\n\
+             load $4 #255\nand $0 $4 $0\n\
+             load $1 #0\nload $2 #1\nload $3 @top\n\
+             top: add $1 $2 $1\ninc $2\nlteq $2 $0\njeq $3\nhlt\n",
+        )
+        .unwrap();
+        check(&module, 10);
+    }
+
+    #[test]
+    fn test_compiled_programs() {
+        let sources = [
+            "int gcd(int a, int b) { while (b != 0) { int t = b; b = a % b; a = t; } return a; }\n\
+             int main(int a, int b) { return gcd(a & 0xffff, b & 0xfff); }",
+            "int fact(int n) { if (n <= 1) { return 1; } return n * fact(n - 1); }\n\
+             int main(int n) { return fact(n & 15) - n / 3; }",
+            "uint main(uint a, uint b) { uint mixed = (a ^ b) | (a & 0xff00); return ~mixed / (b | 1) + a % (b | 1); }",
+            "float main(float x, int n) {\n\
+                 float total = 0;\n\
+                 for (int i = 0; i < (n & 31); i++) { total = total + x * (float)i; }\n\
+                 if (total > 100.0) { total = -total; }\n\
+                 return total / 2;\n\
+             }",
+            //divides by zero for some inputs, which has to stay an error
+            "int main(int a, int b) { return a / (b & 3); }",
+        ];
+        for source in sources.iter() {
+            check(&compile(source).unwrap(), 4);
+        }
+    }
+
+    #[test]
+    fn test_permuted_module() {
+        let module =
+            compile("int main(int a, int b) { if (a > b) { return a - b; } return b ^ a; }")
+                .unwrap()
+                .permute(77, &[5; 32]);
+        check(&module, 4);
+    }
+
+    #[test]
+    fn test_masked_module() {
+        let source = "int main(int a) { int s = 0; int n = a & 255; while (n > 0) { s = s + n; n = n - 3; } return s ^ 0x1234; }";
+        let module = compile(source).unwrap().mask(12).permute(77, &[5; 32]);
+        check(&module, 4);
+        //the rewritten code is masked again
+        let transformed = obfuscated(&module, 1, 5);
+        assert_eq!(transformed.masks, module.masks);
+        let permutation = transformed.permutation.as_ref().unwrap();
+        let opcodes: Vec<u8> = transformed
+            .code
+            .chunks(4)
+            .map(|i| permutation.decode(i[0]))
+            .collect();
+        assert!(opcodes.contains(&(Opcode::LOADM as u8)));
+        assert!(!opcodes.contains(&(Opcode::LOAD as u8)));
+    }
+
+    #[test]
+    fn test_unsupported_code() {
+        let options = Options::new(1, 5);
+        let relative = assemble("load $0 #8\njmpf $0\nhlt").unwrap();
+        assert_eq!(
+            obfuscate(&relative.code, &options),
+            Err(ObfuscateError::RelativeJump(4))
+        );
+        let computed = assemble(".input a i32\njmp $0\nhlt").unwrap();
+        assert_eq!(
+            obfuscate_module(&computed, &options),
+            Err(ObfuscateError::UnresolvedJump(0))
+        );
+    }
+}
@@ -1,5 +1,10 @@
+use crate::assembler::assemble_with_source_map;
 use crate::assembler::program_parsers::program;
+use crate::disassemble::disassemble;
 use crate::instructions::Opcode;
+use crate::module::interface::Interface;
+use crate::module::Module;
+use crate::vm::debugger::{Debugger, StopReason, Trigger, Watch};
 use crate::vm::VM;
 
 use std;
@@ -19,15 +24,32 @@ use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 #[derive(Default)]
 pub struct REPL {
     command_buffer: Vec<String>,
-    // The VM the REPL will use to execute code
-    vm: VM,
+    // The VM the REPL will use to execute code, inside the debugger stepping it
+    debugger: Debugger,
 }
 
+/// commands taking arguments that drive the debugger
+const DEBUG_COMMANDS: &[&str] = &[
+    ".break",
+    ".unbreak",
+    ".watch",
+    ".unwatch",
+    ".step",
+    ".next",
+    ".continue",
+    ".until",
+    ".restart",
+    ".points",
+];
+
 impl REPL {
     /// Creates and returns a new assembly REPL
     pub fn new() -> REPL {
+        let mut vm = VM::new();
+        //the repl is where the vm gets to talk
+        vm.set_verbose(true);
         REPL {
-            vm: VM::new(),
+            debugger: Debugger::new(vm),
             command_buffer: vec![],
         }
     }
@@ -55,6 +77,7 @@ impl REPL {
         .help | .usage    : "shows this message"
         .codes | .asm     : "shows a list of opcodes/instructions available"
         .program          : "prints the contents of the VM program instructions"
+        .disasm           : "prints the program as assembly, with source lines if loaded from a file"
         .registers        : "prints the contents of the VM Registers"
         .loadfile         : "loads a program file into the program bank"
         .run              : "starts the vm loop with the current program"
@@ -63,6 +86,20 @@ impl REPL {
         .history          : "prints out history of inputted commands"
         .quit             : "closes the shell process"
 
+    Debugger:
+
+        .break <pc>       : "stops before running the instruction at pc"
+        .unbreak <pc>     : "removes the breakpoint at pc"
+        .watch $<r> [= <value>] | .watch heap <offset> [= <value>]
+                          : "stops when a register or heap byte is written, or takes the value"
+        .unwatch <index>  : "removes a watchpoint by its index in .points"
+        .points           : "lists the breakpoints and watchpoints"
+        .step             : "runs one instruction"
+        .next             : "runs one instruction, a call runs until it returns"
+        .continue         : "runs until a breakpoint, watchpoint, halt or error"
+        .until <pc>       : "runs until the pc gets to the given offset"
+        .restart          : "goes back to the start of the program"
+
 ========================
             "#
         );
@@ -128,15 +165,23 @@ impl REPL {
                 ".program" => {
                     println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
                     println!("Listing VM program instructions contents:");
-                    for instruction in &self.vm.get_program() {
+                    for instruction in &self.debugger.vm_mut().get_program() {
                         println!("[{}]", instruction);
                     }
                     println!("~~~~~~~End of Program Instructions~~~~~~~");
                     println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
                 }
+                ".disasm" => {
+                    let vm = self.debugger.vm_mut();
+                    let module = Module {
+                        source_map: vm.source_map().cloned(),
+                        ..Module::new(vm.get_program(), Interface::new())
+                    };
+                    print!("{}", disassemble(&module));
+                }
                 ".registers" => {
                     println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.get_registers());
+                    println!("{:#?}", self.debugger.vm_mut().get_registers());
                     println!("End of registers listing.");
                 }
                 ".loadfile" => match REPL::get_file_path_prompt(&mut stdin) {
@@ -150,14 +195,14 @@ impl REPL {
                     }
                 },
                 ".run" => {
-                    self.vm.run();
+                    self.debugger.vm_mut().run();
                 }
                 ".clear_program" | ".clpro" => {
-                    self.vm.clear_program();
+                    self.debugger.vm_mut().clear_program();
                     println!("Cleared contents of the program bank!");
                 }
                 ".clear_registers" | ".clreg" => {
-                    self.vm.clear_registers();
+                    self.debugger.vm_mut().clear_registers();
                     println!("All registers re-initialized to 0!");
                 }
                 ".history" => {
@@ -177,20 +222,23 @@ impl REPL {
                     std::process::exit(0); //ends the process right away
                                            //break;//break out of the execution loop to reach the natural end of the process
                 }
+                line if DEBUG_COMMANDS.contains(&line.split_whitespace().next().unwrap_or("")) => {
+                    if let Err(message) = self.debug_command(line) {
+                        println!("{}", message);
+                    }
+                }
                 line => {
                     match &line[..2] {
                         "0x" => {
                             //hex input mode
-                            let results = match REPL::remove_first2(buffer) {
-                                Some(value) => Some(self.parse_hex(value)),
-                                None => None,
-                            };
+                            let results =
+                                REPL::remove_first2(buffer).map(|value| self.parse_hex(value));
                             match results {
                                 Some(Ok(bytes)) => {
                                     for byte in bytes {
-                                        self.vm.add_byte(byte);
+                                        self.debugger.vm_mut().add_byte(byte);
                                     }
-                                    self.vm.run_once();
+                                    self.run_once();
                                 }
                                 Some(Err(_)) | None => {
                                     println!("Unable to decode hex string. Please enter 4 groups (separated by spaces) of 2 hex characters each.");
@@ -203,12 +251,19 @@ impl REPL {
                             match program(buffer.into()) {
                                 Ok((_, program)) => {
                                     //check first if the opcodes are valid before running on system
-                                    if program.is_valid() {
-                                        self.vm.append_program_bytes(program.to_bytes());
-                                        self.vm.run_once();
-                                    } else {
-                                        println!("Invalid opcode or operands!");
-                                        REPL::print_help(&mut stdout)
+                                    match program.to_bytes() {
+                                        Ok(bytes) if program.is_valid() => {
+                                            self.debugger.vm_mut().append_program_bytes(bytes);
+                                            self.run_once();
+                                        }
+                                        Err(e) => {
+                                            println!("Invalid instruction: {}", e);
+                                            REPL::print_help(&mut stdout)
+                                        }
+                                        Ok(_) => {
+                                            println!("Invalid opcode or operands!");
+                                            REPL::print_help(&mut stdout)
+                                        }
                                     }
                                 }
                                 Err(_) => {
@@ -226,6 +281,115 @@ impl REPL {
     /// Helper functions
     ///
 
+    /// Runs the instruction just entered, reporting it if it failed
+    fn run_once(&mut self) {
+        let vm = self.debugger.vm_mut();
+        if let Err(e) = vm.run_once() {
+            println!("VM error: {}", vm.fault_report(&e));
+        }
+    }
+
+    /// Carries out one of the DEBUG_COMMANDS
+    fn debug_command(&mut self, line: &str) -> Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |index: usize| -> Result<usize, String> {
+            let word = words
+                .get(index)
+                .ok_or_else(|| format!("{} needs a number", words[0]))?;
+            word.parse()
+                .map_err(|_| format!("'{}' is not a number", word))
+        };
+        let stop = match words[0] {
+            ".break" => {
+                let pc = number(1)?;
+                if !self.debugger.add_breakpoint(pc) {
+                    return Err(format!("there already is a breakpoint at {}", pc));
+                }
+                return Ok(());
+            }
+            ".unbreak" => {
+                let pc = number(1)?;
+                if !self.debugger.remove_breakpoint(pc) {
+                    return Err(format!("there is no breakpoint at {}", pc));
+                }
+                return Ok(());
+            }
+            ".watch" => {
+                let (watch, rest) = match words.get(1) {
+                    Some(&"heap") => (Watch::Heap(number(2)?), 3),
+                    Some(word) if word.starts_with('$') => {
+                        let register = word[1..]
+                            .parse()
+                            .ok()
+                            .filter(|r| *r < 32)
+                            .ok_or_else(|| format!("'{}' is not a register", word))?;
+                        (Watch::Register(register), 2)
+                    }
+                    _ => return Err(".watch needs $<register> or heap <offset>".to_string()),
+                };
+                let trigger = match &words[rest..] {
+                    [] => Trigger::Write,
+                    ["=", value] => Trigger::Value(
+                        value
+                            .parse()
+                            .map_err(|_| format!("'{}' is not a number", value))?,
+                    ),
+                    _ => return Err("a watched value is given as = <value>".to_string()),
+                };
+                let index = self.debugger.add_watchpoint(watch, trigger);
+                println!("watchpoint {} on {}", index, watch);
+                return Ok(());
+            }
+            ".unwatch" => {
+                let index = number(1)?;
+                if self.debugger.remove_watchpoint(index).is_none() {
+                    return Err(format!("there is no watchpoint {}", index));
+                }
+                return Ok(());
+            }
+            ".points" => {
+                for pc in self.debugger.breakpoints() {
+                    println!("breakpoint at {}", pc);
+                }
+                for (index, point) in self.debugger.watchpoints().iter().enumerate() {
+                    match point.trigger {
+                        Trigger::Write => println!("{}: {} written", index, point.watch),
+                        Trigger::Value(value) => println!("{}: {} = {}", index, point.watch, value),
+                    }
+                }
+                return Ok(());
+            }
+            ".restart" => {
+                self.debugger.restart();
+                return Ok(());
+            }
+            ".step" => self.debugger.step(),
+            ".next" => self.debugger.step_over(),
+            ".continue" => self.debugger.resume_all(),
+            ".until" => self.debugger.run_to(number(1)?),
+            _ => unreachable!(),
+        };
+        //errors leave the pc inside the instruction that failed
+        let pc = match stop {
+            StopReason::Error(_) => self.debugger.vm().fault_pc(),
+            _ => Some(self.debugger.pc()),
+        };
+        match stop {
+            StopReason::Step => {}
+            stop => println!("{}", stop),
+        }
+        println!(
+            "pc {}, calls {:?}",
+            self.debugger.pc(),
+            self.debugger.call_stack()
+        );
+        let source_map = self.debugger.vm().source_map();
+        if let Some(source) = pc.and_then(|pc| source_map?.describe(pc)) {
+            println!("{}", source);
+        }
+        Ok(())
+    }
+
     /// File loading prompt
     ///
     fn get_file_path_prompt(stdin: &mut io::Stdin) -> Result<PathBuf, &str> {
@@ -248,14 +412,32 @@ impl REPL {
         let mut contents = String::new();
         f.read_to_string(&mut contents)
             .expect("There was an error reading from the file");
-        let program = match program(CompleteStr(&contents)) {
+        let mut program = match program(CompleteStr(&contents)) {
             Ok((_, program)) => program,
             Err(e) => {
                 println!("Unable to parse input: {:?}", e);
                 return false;
             }
         };
-        self.vm.append_program_bytes(program.to_bytes());
+        let bytes = match program.link().and_then(|_| program.to_bytes()) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("Unable to assemble input: {}", e);
+                return false;
+            }
+        };
+        let vm = self.debugger.vm_mut();
+        //lines are only known for a file loaded into an empty program bank
+        if vm.get_program().is_empty() {
+            let source_map = assemble_with_source_map(&contents)
+                .ok()
+                .map(|(_, mut map)| {
+                    map.set_file(&filename.to_string_lossy());
+                    map
+                });
+            vm.set_source_map(source_map);
+        }
+        vm.append_program_bytes(bytes);
         true
     }
 
@@ -265,7 +447,7 @@ impl REPL {
         let split = i.split(' ').collect::<Vec<&str>>();
         let mut results: Vec<u8> = vec![];
         for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
+            let byte = u8::from_str_radix(hex_string, 16);
             match byte {
                 Ok(result) => {
                     results.push(result);
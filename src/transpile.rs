@@ -0,0 +1,695 @@
+// Ahead of time translation of modules to Rust source, for hosts that can't run a JIT.
+// The generated file stands on its own: a `run` function over a `Memory` laid out like
+// the vm's address space, with the registers as locals and the basic blocks as the arms
+// of a `match` on the block index. Compiled into the host with optimisations the blocks
+// come out as straight line native code.
+//
+// Only verified code is translated. Jumps whose target the verifier knows are the only
+// block starts besides the instructions following jumps, so a program with a dynamic
+// jump gets a block per instruction. A jump into the middle of an instruction, which
+// the vm would decode from there, is a BadJump fault instead. SYSCALL and host opcodes
+// need the host's handlers, so modules using them can't be translated.
+//
+// The generated code holds the plain code and data, masks and permutations are gone.
+
+use crate::instructions::{Opcode, CUSTOM_OPCODES};
+use crate::module::interface::{Location, Param};
+use crate::module::Module;
+use crate::obfuscate::lift::{Flow, Instr};
+use crate::verify::{self, VerifiedProgram, VerifyError};
+use crate::vm::MAX_CALL_DEPTH;
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt::{self, Write};
+
+/// Why a module couldn't be translated
+#[derive(Debug, Clone, PartialEq)]
+pub enum TranspileError {
+    Verify(VerifyError),
+    /// an instruction that needs the host, SYSCALL or a host opcode
+    Unsupported {
+        pc: usize,
+        opcode: u8,
+    },
+}
+
+impl fmt::Display for TranspileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TranspileError::Verify(e) => write!(f, "{}", e),
+            TranspileError::Unsupported { pc, opcode } => write!(
+                f,
+                "opcode {} at {} needs the host and can't be translated",
+                opcode, pc
+            ),
+        }
+    }
+}
+
+impl Error for TranspileError {}
+
+impl From<VerifyError> for TranspileError {
+    fn from(e: VerifyError) -> TranspileError {
+        TranspileError::Verify(e)
+    }
+}
+
+// block index the generated code halts on
+const HALT: &str = "usize::MAX";
+
+/// Translates a module to the source of a standalone Rust module
+pub fn transpile(module: &Module) -> Result<String, TranspileError> {
+    let program = verify::verify_module(module)?;
+    let instructions: Vec<Instr> = program
+        .code()
+        .chunks(4)
+        .map(|bytes| Instr {
+            opcode: bytes[0],
+            operands: [bytes[1], bytes[2], bytes[3]],
+            target: None,
+            jumps: vec![],
+        })
+        .collect();
+    for (i, instruction) in instructions.iter().enumerate() {
+        if instruction.code() == Opcode::SYSCALL || CUSTOM_OPCODES.contains(&instruction.opcode) {
+            return Err(TranspileError::Unsupported {
+                pc: i * 4,
+                opcode: instruction.opcode,
+            });
+        }
+    }
+    let leaders = leaders(&program, &instructions);
+    let data = module.unmask().data;
+
+    let mut out = String::new();
+    header(&mut out, module, &data);
+    //where each block starts, block i runs from starts[i] to starts[i + 1]
+    let starts: Vec<usize> = leaders.iter().cloned().collect();
+    writeln!(out, "// the block starting at pc, halting past the end").unwrap();
+    writeln!(out, "fn block_at(pc: usize) -> Result<usize, Fault> {{").unwrap();
+    writeln!(out, "    match pc {{").unwrap();
+    for (block, start) in starts.iter().enumerate() {
+        writeln!(out, "        {} => Ok({}),", start * 4, block).unwrap();
+    }
+    writeln!(
+        out,
+        "        pc if pc >= {} => Ok({}),",
+        instructions.len() * 4,
+        HALT
+    )
+    .unwrap();
+    writeln!(out, "        pc => Err(Fault::BadJump(pc)),").unwrap();
+    writeln!(out, "    }}\n}}\n").unwrap();
+
+    let registers: Vec<String> = (0..32).map(|r| format!("mut r{}", r)).collect();
+    writeln!(
+        out,
+        "/// Runs the program from the start until it halts or faults. The registers hold\n\
+         /// the inputs going in and the outputs coming out, either way."
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "pub fn run(registers: &mut [i32; 32], memory: &mut Memory) -> Result<(), Fault> {{"
+    )
+    .unwrap();
+    writeln!(out, "    let [{}] = *registers;", registers.join(", ")).unwrap();
+    writeln!(out, "    let mut flag = false;").unwrap();
+    writeln!(out, "    let mut calls: Vec<usize> = Vec::new();").unwrap();
+    writeln!(out, "    let mut block = 0;").unwrap();
+    writeln!(out, "    let result = loop {{").unwrap();
+    writeln!(out, "        match block {{").unwrap();
+    for (block, start) in starts.iter().enumerate() {
+        let end = starts.get(block + 1).cloned().unwrap_or(instructions.len());
+        writeln!(out, "            {} => {{", block).unwrap();
+        let mut open = true;
+        for (i, instruction) in instructions.iter().enumerate().take(end).skip(*start) {
+            statement(&mut out, i * 4, instruction);
+            if instruction.flow() != Flow::Next {
+                open = false;
+            }
+        }
+        if open {
+            //falls into the next block
+            let next = if end < instructions.len() {
+                (block + 1).to_string()
+            } else {
+                HALT.to_string()
+            };
+            writeln!(out, "                block = {};", next).unwrap();
+        }
+        writeln!(out, "            }}").unwrap();
+    }
+    writeln!(out, "            _ => break Ok(()),").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }};").unwrap();
+    let registers: Vec<String> = (0..32).map(|r| format!("r{}", r)).collect();
+    writeln!(out, "    *registers = [{}];", registers.join(", ")).unwrap();
+    writeln!(out, "    result").unwrap();
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+// instructions starting a block: the entry, jump targets and whatever follows a jump
+fn leaders(program: &VerifiedProgram, instructions: &[Instr]) -> BTreeSet<usize> {
+    if !program.dynamic_jumps().is_empty() {
+        return (0..instructions.len()).collect();
+    }
+    let mut leaders: BTreeSet<usize> = program.jump_targets().iter().map(|pc| pc / 4).collect();
+    leaders.insert(0);
+    for (i, instruction) in instructions.iter().enumerate() {
+        if instruction.flow() != Flow::Next && i + 1 < instructions.len() {
+            leaders.insert(i + 1);
+        }
+    }
+    leaders.retain(|i| *i < instructions.len());
+    leaders
+}
+
+// one instruction as statements inside its block's arm
+fn statement(out: &mut String, pc: usize, instruction: &Instr) {
+    let [a, b, c] = instruction.operands;
+    let (a, b, c) = (format!("r{}", a), format!("r{}", b), format!("r{}", c));
+    let immediate = u16::from_be_bytes([instruction.operands[1], instruction.operands[2]]);
+    let line = match instruction.code() {
+        Opcode::HLT => "break Ok(());".to_string(),
+        Opcode::NOP => return,
+        Opcode::LOAD => format!("{} = {};", a, immediate),
+        Opcode::LUI => format!("{} = {:#x}u32 as i32;", a, u32::from(immediate) << 16),
+        Opcode::MOV => format!("{} = {};", b, a),
+        Opcode::FLAG => format!("{} = flag as i32;", a),
+        Opcode::ADD => format!("{} = {}.wrapping_add({});", c, a, b),
+        Opcode::SUB => format!("{} = {}.wrapping_sub({});", c, a, b),
+        Opcode::MUL => format!("{} = {}.wrapping_mul({});", c, a, b),
+        Opcode::DIV => divide(&b, &c, &format!("{}.wrapping_div({})", a, b)),
+        Opcode::MOD => divide(&b, &c, &format!("{}.wrapping_rem({})", a, b)),
+        Opcode::DIVU => divide(&b, &c, &format!("(({} as u32) / ({} as u32)) as i32", a, b)),
+        Opcode::MODU => divide(&b, &c, &format!("(({} as u32) % ({} as u32)) as i32", a, b)),
+        Opcode::AND => format!("{} = {} & {};", c, a, b),
+        Opcode::OR => format!("{} = {} | {};", c, a, b),
+        Opcode::XOR => format!("{} = {} ^ {};", c, a, b),
+        Opcode::SHL => format!("{} = {}.wrapping_shl({} as u32);", c, a, b),
+        Opcode::SHR => format!("{} = {}.wrapping_shr({} as u32);", c, a, b),
+        Opcode::SHRU => format!("{} = ({} as u32).wrapping_shr({} as u32) as i32;", c, a, b),
+        Opcode::NOT => format!("{} = !{};", b, a),
+        Opcode::NEG => format!("{} = {}.wrapping_neg();", b, a),
+        Opcode::INC => format!("{} = {}.wrapping_add(1);", a, a),
+        Opcode::DEC => format!("{} = {}.wrapping_sub(1);", a, a),
+        Opcode::FADD => format!("{} = bits(float({}) + float({}));", c, a, b),
+        Opcode::FSUB => format!("{} = bits(float({}) - float({}));", c, a, b),
+        Opcode::FMUL => format!("{} = bits(float({}) * float({}));", c, a, b),
+        Opcode::FDIV => format!("{} = bits(float({}) / float({}));", c, a, b),
+        Opcode::FNEG => format!("{} = bits(-float({}));", b, a),
+        Opcode::ITOF => format!("{} = bits({} as f32);", b, a),
+        Opcode::UTOF => format!("{} = bits({} as u32 as f32);", b, a),
+        Opcode::FTOI => format!("{} = float({}) as i32;", b, a),
+        Opcode::FTOU => format!("{} = float({}) as u32 as i32;", b, a),
+        Opcode::EQ => format!("flag = {} == {};", a, b),
+        Opcode::NEQ => format!("flag = {} != {};", a, b),
+        Opcode::GT => format!("flag = {} > {};", a, b),
+        Opcode::LT => format!("flag = {} < {};", a, b),
+        Opcode::GTEQ => format!("flag = {} >= {};", a, b),
+        Opcode::LTEQ => format!("flag = {} <= {};", a, b),
+        Opcode::LTU => format!("flag = ({} as u32) < ({} as u32);", a, b),
+        Opcode::GTU => format!("flag = ({} as u32) > ({} as u32);", a, b),
+        Opcode::LTEQU => format!("flag = ({} as u32) <= ({} as u32);", a, b),
+        Opcode::GTEQU => format!("flag = ({} as u32) >= ({} as u32);", a, b),
+        Opcode::BETW => format!("flag = {} > {} && {} < {};", a, b, a, c),
+        Opcode::FLT => format!("flag = float({}) < float({});", a, b),
+        Opcode::FGT => format!("flag = float({}) > float({});", a, b),
+        Opcode::FLTEQ => format!("flag = float({}) <= float({});", a, b),
+        Opcode::FGTEQ => format!("flag = float({}) >= float({});", a, b),
+        Opcode::FEQ => format!("flag = float({}) == float({});", a, b),
+        Opcode::FNEQ => format!("flag = float({}) != float({});", a, b),
+        Opcode::ALOC => format!("attempt!(memory.allocate({}));", a),
+        Opcode::LDB => format!("{} = i32::from(attempt!(memory.read({} as u32)));", b, a),
+        Opcode::STB => format!("attempt!(memory.write({} as u32, {} as u8));", b, a),
+        Opcode::MLEN => format!("{} = attempt!(memory.len({} as u32)) as i32;", b, a),
+        Opcode::LDW => format!("{} = attempt!(memory.read_word({} as u32));", b, a),
+        Opcode::STW => format!("attempt!(memory.write_word({} as u32, {}));", b, a),
+        Opcode::JMP => format!("block = attempt!(block_at({} as usize));", a),
+        Opcode::JEQ => format!(
+            "block = if flag {{ attempt!(block_at({} as usize)) }} else {{ {} }};",
+            a,
+            next_block(pc)
+        ),
+        Opcode::JNEQ => format!(
+            "block = if flag {{ {} }} else {{ attempt!(block_at({} as usize)) }};",
+            next_block(pc),
+            a
+        ),
+        //relative jumps past either end of usize halt, like the vm
+        Opcode::JMPF => format!(
+            "block = match {}usize.checked_add({} as usize) {{\n\
+                 Some(pc) => attempt!(block_at(pc)),\n\
+                 None => break Ok(()),\n\
+             }};",
+            pc + 2,
+            a
+        ),
+        Opcode::JMPB => format!(
+            "block = match {}usize.checked_sub({} as usize) {{\n\
+                 Some(pc) => attempt!(block_at(pc)),\n\
+                 None => break Ok(()),\n\
+             }};",
+            pc + 2,
+            a
+        ),
+        Opcode::CALL => format!(
+            "if calls.len() >= {} {{\n\
+                 break Err(Fault::CallStackOverflow);\n\
+             }}\n\
+             calls.push({});\n\
+             block = attempt!(block_at({} as usize));",
+            MAX_CALL_DEPTH,
+            pc + 4,
+            a
+        ),
+        Opcode::RET => "block = match calls.pop() {\n\
+                            Some(pc) => attempt!(block_at(pc)),\n\
+                            None => break Err(Fault::CallStackUnderflow),\n\
+                        };"
+        .to_string(),
+        //unmasking turned LOADM into LOAD, the verifier and the check above took the rest
+        Opcode::LOADM | Opcode::SYSCALL | Opcode::IGL => unreachable!(),
+    };
+    writeln!(out, "                // {}", describe(pc, instruction)).unwrap();
+    for statement in line.lines() {
+        writeln!(out, "                {}", statement.trim_start()).unwrap();
+    }
+}
+
+// the assembly an instruction was written as, for the comments
+fn describe(pc: usize, instruction: &Instr) -> String {
+    let mnemonic = format!("{:?}", instruction.code()).to_lowercase();
+    format!(
+        "{}: {} {} {} {}",
+        pc, mnemonic, instruction.operands[0], instruction.operands[1], instruction.operands[2]
+    )
+}
+
+// the block index of the instruction after a branch, which always starts one
+fn next_block(pc: usize) -> String {
+    format!("attempt!(block_at({}))", pc + 4)
+}
+
+fn divide(b: &str, c: &str, value: &str) -> String {
+    format!(
+        "if {} == 0 {{\n\
+             break Err(Fault::DivideByZero);\n\
+         }}\n\
+         {} = {};",
+        b, c, value
+    )
+}
+
+// everything ahead of block_at and run
+fn header(out: &mut String, module: &Module, data: &[u8]) {
+    writeln!(
+        out,
+        "// Generated by `biobox transpile`, edit the module it came from instead.\n\
+         //"
+    )
+    .unwrap();
+    for (kind, params) in [
+        ("input", &module.interface.inputs),
+        ("output", &module.interface.outputs),
+    ]
+    .iter()
+    {
+        for param in params.iter() {
+            writeln!(out, "// {} {}", kind, describe_param(param)).unwrap();
+        }
+    }
+    out.push_str(RUNTIME);
+    let bytes: Vec<String> = data.iter().map(|byte| byte.to_string()).collect();
+    writeln!(out, "// the module's data section, read only").unwrap();
+    writeln!(out, "const DATA: &[u8] = &[{}];\n", bytes.join(", ")).unwrap();
+}
+
+fn describe_param(param: &Param) -> String {
+    let location = match param.location {
+        Location::Register(r) => format!("in ${}", r),
+        Location::Buffer { index, capacity } => match capacity {
+            0 => format!("in buffer {}", index),
+            capacity => format!("in buffer {} of {} bytes", index, capacity),
+        },
+    };
+    format!("{}: {} {}", param.name, param.ty.name(), location)
+}
+
+// the part of every generated file that doesn't depend on the program
+const RUNTIME: &str = r#"
+#![allow(dead_code, unused_macros, unused_mut, unused_variables, unreachable_code)]
+#![allow(clippy::all)]
+
+/// Why a run stopped before halting, named like the vm's errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    DivideByZero,
+    CallStackOverflow,
+    CallStackUnderflow,
+    InvalidAddress(u32),
+    ReadOnlyAddress(u32),
+    /// a jump into the middle of an instruction
+    BadJump(usize),
+    /// ALOC past the heap's limits
+    BadAllocation(i32),
+}
+
+/// the most the heap grows to, past it nothing would be addressable
+const MAX_HEAP: usize = 1 << 24;
+
+/// The heap and the host's buffers, mapped into the same address space as in the vm
+#[derive(Debug, Default, Clone)]
+pub struct Memory<'a> {
+    pub heap: Vec<u8>,
+    pub inputs: Vec<&'a [u8]>,
+    pub outputs: Vec<Vec<u8>>,
+    /// how far the program wrote into each output
+    pub written: Vec<usize>,
+}
+
+impl<'a> Memory<'a> {
+    /// Maps the inputs in order and a zeroed output buffer for each capacity
+    pub fn new(inputs: Vec<&'a [u8]>, capacities: &[usize]) -> Memory<'a> {
+        Memory {
+            heap: Vec::new(),
+            inputs,
+            outputs: capacities.iter().map(|capacity| vec![0; *capacity]).collect(),
+            written: vec![0; capacities.len()],
+        }
+    }
+
+    /// The bytes the program wrote to output buffer `index`
+    pub fn output(&self, index: usize) -> &[u8] {
+        &self.outputs[index][..self.written[index]]
+    }
+
+    fn allocate(&mut self, bytes: i32) -> Result<(), Fault> {
+        let end = self.heap.len() as i64 + i64::from(bytes);
+        if end < 0 || end > MAX_HEAP as i64 {
+            return Err(Fault::BadAllocation(bytes));
+        }
+        self.heap.resize(end as usize, 0);
+        Ok(())
+    }
+
+    fn read(&self, address: u32) -> Result<u8, Fault> {
+        let offset = (address & 0x00ff_ffff) as usize;
+        let byte = match address >> 24 {
+            0 => self.heap.get(offset),
+            region @ 0x01..=0x0f => self
+                .inputs
+                .get(region as usize - 0x01)
+                .and_then(|input| input.get(offset)),
+            region @ 0x10..=0x1f => self
+                .outputs
+                .get(region as usize - 0x10)
+                .and_then(|output| output.get(offset)),
+            0x20 => DATA.get(offset),
+            _ => None,
+        };
+        byte.cloned().ok_or(Fault::InvalidAddress(address))
+    }
+
+    fn write(&mut self, address: u32, byte: u8) -> Result<(), Fault> {
+        let offset = (address & 0x00ff_ffff) as usize;
+        let slot = match address >> 24 {
+            0 => self.heap.get_mut(offset),
+            0x01..=0x0f | 0x20 => return Err(Fault::ReadOnlyAddress(address)),
+            region @ 0x10..=0x1f => {
+                let index = region as usize - 0x10;
+                match self.outputs.get_mut(index) {
+                    Some(output) if offset < output.len() => {
+                        self.written[index] = self.written[index].max(offset + 1);
+                        output.get_mut(offset)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match slot {
+            Some(slot) => {
+                *slot = byte;
+                Ok(())
+            }
+            None => Err(Fault::InvalidAddress(address)),
+        }
+    }
+
+    fn read_word(&self, address: u32) -> Result<i32, Fault> {
+        let mut word = [0; 4];
+        for (i, byte) in word.iter_mut().enumerate() {
+            *byte = self.read(address.wrapping_add(i as u32))?;
+        }
+        Ok(i32::from_le_bytes(word))
+    }
+
+    fn write_word(&mut self, address: u32, value: i32) -> Result<(), Fault> {
+        for (i, byte) in value.to_le_bytes().iter().enumerate() {
+            self.write(address.wrapping_add(i as u32), *byte)?;
+        }
+        Ok(())
+    }
+
+    fn len(&self, address: u32) -> Result<usize, Fault> {
+        let length = match address >> 24 {
+            0 => Some(self.heap.len()),
+            region @ 0x01..=0x0f => self.inputs.get(region as usize - 0x01).map(|i| i.len()),
+            region @ 0x10..=0x1f => self.outputs.get(region as usize - 0x10).map(|o| o.len()),
+            0x20 => Some(DATA.len()),
+            _ => None,
+        };
+        length.ok_or(Fault::InvalidAddress(address))
+    }
+}
+
+fn float(bits: i32) -> f32 {
+    f32::from_bits(bits as u32)
+}
+
+fn bits(value: f32) -> i32 {
+    value.to_bits() as i32
+}
+
+// the value of an Ok, or out of the run loop with the fault
+macro_rules! attempt {
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(fault) => break Err(fault),
+        }
+    };
+}
+
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::compiler::compile;
+    use crate::module::interface::Value;
+    use crate::vm::VM;
+
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    // what a run left behind, printed the same way on both sides
+    fn interpreted(module: &Module, args: &[Value]) -> String {
+        let mut vm = VM::new();
+        vm.load_module(module);
+        let result = match vm.call(args) {
+            Ok(_) => "Ok(())".to_string(),
+            Err(e) => format!("Err({:?})", e),
+        };
+        let outputs: Vec<Vec<u8>> = (0..module.interface.outputs.len())
+            .filter_map(|index| vm.output(index).map(<[u8]>::to_vec))
+            .collect();
+        format!("{} {:?} {:?}", result, vm.get_registers().to_vec(), outputs)
+    }
+
+    // the statements running the translated module `name` with the same arguments
+    fn harness(name: &str, module: &Module, args: &[Value]) -> String {
+        let mut out = String::from("{\nlet mut registers = [0i32; 32];\n");
+        let mut inputs = vec![];
+        for (param, arg) in module.interface.inputs.iter().zip(args) {
+            match (param.location, arg) {
+                (Location::Register(r), _) => writeln!(
+                    out,
+                    "registers[{}] = {};",
+                    r,
+                    arg.to_register().unwrap_or(0)
+                )
+                .unwrap(),
+                (Location::Buffer { .. }, Value::Bytes(bytes)) => {
+                    inputs.push(format!("&{:?}[..]", bytes))
+                }
+                (Location::Buffer { .. }, _) => {}
+            }
+        }
+        let capacities: Vec<String> = module
+            .interface
+            .outputs
+            .iter()
+            .filter_map(|param| match param.location {
+                Location::Buffer { capacity, .. } => Some(capacity.to_string()),
+                Location::Register(_) => None,
+            })
+            .collect();
+        writeln!(
+            out,
+            "let mut memory = {}::Memory::new(vec![{}], &[{}]);",
+            name,
+            inputs.join(", "),
+            capacities.join(", ")
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "let result = {}::run(&mut registers, &mut memory);\n\
+             let outputs: Vec<Vec<u8>> = (0..memory.outputs.len()).map(|i| memory.output(i).to_vec()).collect();\n\
+             println!(\"{{:?}} {{:?}} {{:?}}\", result, registers.to_vec(), outputs);\n}}",
+            name
+        )
+        .unwrap();
+        out
+    }
+
+    // translates every module, builds them into one optimised binary and checks each
+    // run prints what the interpreter ended with
+    fn differential(cases: &[(Module, Vec<Vec<Value>>)]) {
+        let dir = env::temp_dir().join(format!("biobox-transpile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut main = String::new();
+        let mut expected = vec![];
+        let mut body = String::new();
+        for (i, (module, runs)) in cases.iter().enumerate() {
+            let name = format!("p{}", i);
+            fs::write(dir.join(format!("{}.rs", name)), transpile(module).unwrap()).unwrap();
+            writeln!(main, "#[path = \"{}.rs\"]\nmod {};", name, name).unwrap();
+            for args in runs {
+                body.push_str(&harness(&name, module, args));
+                expected.push(interpreted(module, args));
+            }
+        }
+        writeln!(main, "fn main() {{\n{}\n}}", body).unwrap();
+        fs::write(dir.join("main.rs"), main).unwrap();
+
+        let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let binary = dir.join("transpiled");
+        let build = Command::new(rustc)
+            .args(["-O", "--edition", "2018", "-o"])
+            .arg(&binary)
+            .arg(dir.join("main.rs"))
+            .output()
+            .unwrap();
+        assert!(
+            build.status.success(),
+            "{}",
+            String::from_utf8_lossy(&build.stderr)
+        );
+        let run = Command::new(&binary).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let printed = String::from_utf8(run.stdout).unwrap();
+        let printed: Vec<&str> = printed.lines().collect();
+        assert_eq!(printed, expected);
+    }
+
+    fn ints(values: &[i32]) -> Vec<Value> {
+        values.iter().map(|v| Value::I32(*v)).collect()
+    }
+
+    #[test]
+    fn test_transpiled_matches_vm() {
+        let mut cases = vec![];
+        //recursion and calls
+        let source = "int fact(int n) { if (n <= 1) { return 1; } return n * fact(n - 1); }\n\
+                      int main(int n) { return fact(n) + fact(3) * 100; }";
+        cases.push((
+            compile(source).unwrap(),
+            vec![ints(&[0]), ints(&[5]), ints(&[12])],
+        ));
+        //loops, unsigned and floats
+        let source = "int main(int n) { int total = 0;\n\
+                      for (int k = 0; k < n; k++) { total = total * 31 + k % 7; }\n\
+                      return total; }";
+        cases.push((compile(source).unwrap(), vec![ints(&[0]), ints(&[1000])]));
+        let source = "uint main(uint a, uint b) { return a / b + a % b + (a >> 3); }";
+        let args = vec![
+            vec![Value::U32(4_000_000_000), Value::U32(7)],
+            vec![Value::U32(1), Value::U32(0)],
+        ];
+        cases.push((compile(source).unwrap(), args));
+        let source = "float main(float x, int n) { float y = x;\n\
+                      for (int k = 0; k < n; k++) { y = y * x - (float)k / 3.0; }\n\
+                      return -y; }";
+        let args = vec![
+            vec![Value::F32(1.5), Value::I32(6)],
+            vec![Value::F32(0.0), Value::I32(2)],
+        ];
+        cases.push((compile(source).unwrap(), args));
+        //faults stop both the same way
+        let source = "int main(int a) { return 10 / a; }";
+        cases.push((compile(source).unwrap(), vec![ints(&[0]), ints(&[-3])]));
+        let source = "int down(int n) { return down(n + 1); } int main() { return down(0); }";
+        cases.push((compile(source).unwrap(), vec![vec![]]));
+
+        //buffers, the heap and the data section: writes the input reversed, then the data
+        let source = ".input text bytes\n.output reversed bytes #16\n.output length i32 $10\n\
+                      .data tail \"!?\"\n\
+                      lui $0 #256\nmlen $0 $10\nlui $1 #4096\nload $2 #1\n\
+                      load $3 @loop\nload $4 @copy\nload $5 @heap\n\
+                      loop: eq $10 $9\njeq $4\n\
+                      add $0 $9 $6\nldb $6 $7\n\
+                      sub $10 $9 $8\nsub $8 $2 $8\nadd $1 $8 $8\nstb $7 $8\n\
+                      inc $9\njmp $3\n\
+                      copy: lui $6 #8192\nldb $6 $7\nadd $1 $10 $8\nstb $7 $8\n\
+                      load $11 #8\naloc $11\nstw $10 $12\nldw $12 $13\njmp $5\n\
+                      heap: hlt\n";
+        let args = vec![
+            vec![Value::Bytes(b"hello".to_vec())],
+            vec![Value::Bytes(vec![])],
+            //longer than the output, so the stores run off its end
+            vec![Value::Bytes(vec![7; 20])],
+        ];
+        cases.push((assemble(source).unwrap(), args));
+        //ALOC can't take the heap below empty
+        let source = ".input n i32\naloc $0\nhlt\n";
+        cases.push((assemble(source).unwrap(), vec![ints(&[8]), ints(&[-1])]));
+        //stores into an input are refused
+        let source = ".input text bytes\nlui $0 #256\nstb $1 $0\nhlt\n";
+        cases.push((assemble(source).unwrap(), vec![vec![Value::Bytes(vec![1])]]));
+        //a jump through a register the verifier can't follow gets a block per instruction
+        let source = ".input n i32\n.output r i32 $1\n\
+                      load $2 #4\nmul $0 $2 $3\nload $4 @table\nadd $3 $4 $3\njmp $3\n\
+                      table: load $1 #10\nload $1 #20\ninc $1\nhlt\n";
+        cases.push((
+            assemble(source).unwrap(),
+            vec![ints(&[0]), ints(&[1]), ints(&[2])],
+        ));
+
+        differential(&cases);
+    }
+
+    #[test]
+    fn test_transpile_errors() {
+        let module = assemble("load $0 #1\nsyscall #1\nhlt\n").unwrap();
+        assert_eq!(
+            transpile(&module),
+            Err(TranspileError::Unsupported {
+                pc: 4,
+                opcode: Opcode::SYSCALL as u8
+            })
+        );
+        let module = assemble("load $0 #6\njmp $0\n").unwrap();
+        assert!(matches!(
+            transpile(&module),
+            Err(TranspileError::Verify(VerifyError::BadJumpTarget { .. }))
+        ));
+    }
+}
@@ -0,0 +1,397 @@
+// Static checks of bytecode before it runs. The interpreter trusts what it's handed: a
+// register operand past $31 or a jump into the middle of an instruction is only noticed
+// when it's hit, if at all. Verifying once up front checks that
+//   - the code is made of whole 4 byte instructions
+//   - every opcode is known (or in the host extension range)
+//   - every register operand is below 32
+//   - every jump whose target is known statically lands on an instruction boundary
+//
+// Jump targets come from a constant propagation over the registers. A jump through a
+// register that doesn't hold a constant can go anywhere, which also means it can land
+// between any LOAD and the jump using it, so once there is one, no target is known.
+// Those jumps are listed in the VerifiedProgram and still need checking at runtime.
+
+use crate::instructions::{Opcode, CUSTOM_OPCODES};
+use crate::module::Module;
+use crate::obfuscate::lift::{Flow, Instr};
+
+use std::error::Error;
+use std::fmt;
+
+/// Why bytecode failed verification, pc is the byte offset of the instruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    /// the last instruction is shorter than 4 bytes
+    Truncated {
+        pc: usize,
+    },
+    IllegalOpcode {
+        pc: usize,
+        opcode: u8,
+    },
+    InvalidRegister {
+        pc: usize,
+        register: u8,
+    },
+    /// a jump goes into the middle of an instruction or past the end of the code
+    BadJumpTarget {
+        pc: usize,
+        target: i64,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Truncated { pc } => write!(f, "instruction at {} is truncated", pc),
+            VerifyError::IllegalOpcode { pc, opcode } => {
+                write!(f, "illegal opcode {} at {}", opcode, pc)
+            }
+            VerifyError::InvalidRegister { pc, register } => {
+                write!(f, "instruction at {} uses register ${}", pc, register)
+            }
+            VerifyError::BadJumpTarget { pc, target } => {
+                write!(
+                    f,
+                    "jump at {} goes to {}, which isn't an instruction",
+                    pc, target
+                )
+            }
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
+/// Code that passed verification
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedProgram {
+    code: Vec<u8>,
+    dynamic_jumps: Vec<usize>,
+    jump_targets: Vec<usize>,
+}
+
+impl VerifiedProgram {
+    /// The plain code, without any opcode permutation or masks
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Offsets of the jumps whose target isn't known until they run
+    pub fn dynamic_jumps(&self) -> &[usize] {
+        &self.dynamic_jumps
+    }
+
+    /// Offsets of the instructions the statically known jumps go to, in order. Only
+    /// complete when there are no dynamic jumps.
+    pub fn jump_targets(&self) -> &[usize] {
+        &self.jump_targets
+    }
+}
+
+/// Verifies plain (unpermuted, unmasked) code
+pub fn verify(code: &[u8]) -> Result<VerifiedProgram, VerifyError> {
+    if !code.len().is_multiple_of(4) {
+        return Err(VerifyError::Truncated {
+            pc: code.len() / 4 * 4,
+        });
+    }
+    let instructions: Vec<Instr> = code
+        .chunks(4)
+        .map(|bytes| Instr {
+            opcode: bytes[0],
+            operands: [bytes[1], bytes[2], bytes[3]],
+            target: None,
+            jumps: vec![],
+        })
+        .collect();
+    for (i, instruction) in instructions.iter().enumerate() {
+        check_operands(i * 4, instruction)?;
+    }
+    let (dynamic_jumps, jump_targets) = check_jumps(&instructions)?;
+    Ok(VerifiedProgram {
+        code: code.to_vec(),
+        dynamic_jumps,
+        jump_targets,
+    })
+}
+
+/// Verifies a module's code, taking off its opcode permutation and masks first
+pub fn verify_module(module: &Module) -> Result<VerifiedProgram, VerifyError> {
+    let mut code = module.unmask().code;
+    if let Some(permutation) = &module.permutation {
+        for instruction in code.chunks_mut(4) {
+            instruction[0] = permutation.decode(instruction[0]);
+        }
+    }
+    verify(&code)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Operand {
+    Register,
+    // the two bytes of a 16 bit immediate
+    Immediate,
+    Unused,
+}
+
+// what each operand byte of an opcode is
+pub(crate) fn layout(opcode: Opcode) -> [Operand; 3] {
+    use self::Operand::*;
+    use crate::instructions::Opcode::*;
+    match opcode {
+        HLT | NOP | RET | IGL => [Unused, Unused, Unused],
+        LOAD | LOADM | LUI => [Register, Immediate, Immediate],
+        SYSCALL => [Immediate, Immediate, Unused],
+        FLAG | ALOC | JMP | JMPF | JMPB | JEQ | JNEQ | CALL | INC | DEC => {
+            [Register, Unused, Unused]
+        }
+        MOV | LDB | STB | MLEN | LDW | STW | NEG | NOT | FNEG | ITOF | FTOI | UTOF | FTOU | EQ
+        | NEQ | GT | LT | GTEQ | LTEQ | LTU | GTU | LTEQU | GTEQU | FLT | FGT | FLTEQ | FGTEQ
+        | FEQ | FNEQ => [Register, Register, Unused],
+        ADD | SUB | MUL | DIV | MOD | DIVU | MODU | AND | OR | XOR | SHL | SHR | SHRU | FADD
+        | FSUB | FMUL | FDIV | BETW => [Register, Register, Register],
+    }
+}
+
+fn check_operands(pc: usize, instruction: &Instr) -> Result<(), VerifyError> {
+    //host opcodes get the registers through a checked context
+    if CUSTOM_OPCODES.contains(&instruction.opcode) {
+        return Ok(());
+    }
+    let opcode = instruction.code();
+    if opcode == Opcode::IGL {
+        return Err(VerifyError::IllegalOpcode {
+            pc,
+            opcode: instruction.opcode,
+        });
+    }
+    for (kind, register) in layout(opcode).iter().zip(&instruction.operands) {
+        if *kind == Operand::Register && *register >= 32 {
+            return Err(VerifyError::InvalidRegister {
+                pc,
+                register: *register,
+            });
+        }
+    }
+    Ok(())
+}
+
+// registers holding a known value at an instruction, None for ones that don't
+type Constants = [Option<u32>; 32];
+
+// Propagates constants until nothing changes, then checks the jumps. Returns the jumps
+// whose target isn't known and the targets that are.
+fn check_jumps(instructions: &[Instr]) -> Result<(Vec<usize>, Vec<usize>), VerifyError> {
+    let mut states: Vec<Option<Constants>> = vec![None; instructions.len()];
+    let mut dynamic = vec![];
+    let mut targets = vec![];
+    if let Some(entry) = states.first_mut() {
+        //inputs are mapped into the registers before a run
+        *entry = Some([None; 32]);
+    }
+    let mut work: Vec<usize> = vec![0];
+    while let Some(i) = work.pop() {
+        let state = match states.get(i) {
+            Some(Some(state)) => *state,
+            _ => continue,
+        };
+        let instruction = &instructions[i];
+        let after = transfer(instruction, &state);
+        let mut successors = vec![];
+        match instruction.flow() {
+            Flow::Next => successors.push((i + 1, after)),
+            Flow::Stop => {}
+            Flow::Jump | Flow::Branch | Flow::Call | Flow::Relative => {
+                if let Some(target) = target(i * 4, instruction, &state) {
+                    if target == instructions.len() as i64 * 4 {
+                        //going to the end halts, like falling off it
+                    } else if target >= 0
+                        && target % 4 == 0
+                        && target / 4 < instructions.len() as i64
+                    {
+                        targets.push(target as usize);
+                        successors.push(((target / 4) as usize, after));
+                    } else {
+                        return Err(VerifyError::BadJumpTarget { pc: i * 4, target });
+                    }
+                } else if !dynamic.contains(&(i * 4)) {
+                    dynamic.push(i * 4);
+                }
+                match instruction.flow() {
+                    Flow::Branch => successors.push((i + 1, after)),
+                    //the callee can change any register before it returns
+                    Flow::Call => successors.push((i + 1, [None; 32])),
+                    _ => {}
+                }
+            }
+        }
+        for (next, incoming) in successors {
+            if next >= instructions.len() {
+                continue;
+            }
+            let merged = match &states[next] {
+                None => incoming,
+                Some(current) => meet(current, &incoming),
+            };
+            if states[next] != Some(merged) {
+                states[next] = Some(merged);
+                work.push(next);
+            }
+        }
+    }
+
+    targets.sort_unstable();
+    targets.dedup();
+    if dynamic.is_empty() {
+        return Ok((dynamic, targets));
+    }
+    //a dynamic jump can land right before any other jump, so none of them is known
+    let mut jumps = vec![];
+    for (i, instruction) in instructions.iter().enumerate() {
+        if instruction.flow() != Flow::Next && instruction.flow() != Flow::Stop {
+            jumps.push(i * 4);
+        }
+    }
+    Ok((jumps, targets))
+}
+
+// the register constants after an instruction runs
+fn transfer(instruction: &Instr, state: &Constants) -> Constants {
+    let [register, upper, lower] = instruction.operands;
+    let register = usize::from(register & 31);
+    let immediate = u32::from(u16::from_be_bytes([upper, lower]));
+    let mut after = *state;
+    match instruction.code() {
+        Opcode::LOAD => after[register] = Some(immediate),
+        Opcode::LUI => after[register] = Some(immediate << 16),
+        Opcode::MOV => after[usize::from(upper & 31)] = state[register],
+        _ => {
+            let writes = instruction.effects().writes;
+            for (r, value) in after.iter_mut().enumerate() {
+                if writes & (1 << r) != 0 {
+                    *value = None;
+                }
+            }
+        }
+    }
+    after
+}
+
+// where a jump goes with the registers it sees, None if that isn't known
+fn target(pc: usize, instruction: &Instr, state: &Constants) -> Option<i64> {
+    let value = i64::from(state[usize::from(instruction.operands[0] & 31)]? as i32);
+    match instruction.code() {
+        //relative jumps count from just after the register operand
+        Opcode::JMPF => Some(pc as i64 + 2 + value),
+        Opcode::JMPB => Some(pc as i64 + 2 - value),
+        //absolute jumps take the register as an unsigned address
+        _ => Some(i64::from(value as u32)),
+    }
+}
+
+fn meet(a: &Constants, b: &Constants) -> Constants {
+    let mut merged = *a;
+    for (value, other) in merged.iter_mut().zip(b) {
+        if *value != *other {
+            *value = None;
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::compiler::compile;
+
+    #[test]
+    fn test_verify() {
+        let module = assemble(
+            ".input n i32\n.output sum i32 $1\n\
+             load $1 #0\nload $2 #1\nload $3 @top\n\
+             top: add $1 $2 $1\ninc $2\nlteq $2 $0\njeq $3\nhlt\n",
+        )
+        .unwrap();
+        let verified = verify(&module.code).unwrap();
+        assert_eq!(verified.code(), &module.code[..]);
+        assert!(verified.dynamic_jumps().is_empty());
+        assert_eq!(verified.jump_targets(), &[12]);
+
+        //compiled code with calls, and the same through a permutation and masks
+        let module = compile(
+            "int fact(int n) { if (n <= 1) { return 1; } return n * fact(n - 1); }\n\
+             int main(int n) { return fact(n & 15); }",
+        )
+        .unwrap();
+        assert!(verify(&module.code).unwrap().dynamic_jumps().is_empty());
+        let hidden = module.mask(3).permute(4, &[1; 32]);
+        assert!(verify(&hidden.code).is_err());
+        assert_eq!(verify_module(&hidden).unwrap().code(), &module.code[..]);
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        assert_eq!(
+            verify(&[1, 0, 0, 1, 0, 0]),
+            Err(VerifyError::Truncated { pc: 4 })
+        );
+        assert_eq!(
+            verify(&[0, 0, 0, 0, 120, 0, 0, 0]),
+            Err(VerifyError::IllegalOpcode { pc: 4, opcode: 120 })
+        );
+        //host opcodes are left to the host
+        assert!(verify(&[210, 40, 0, 0]).is_ok());
+        assert_eq!(
+            verify(&[Opcode::ADD as u8, 1, 32, 3]),
+            Err(VerifyError::InvalidRegister {
+                pc: 0,
+                register: 32
+            })
+        );
+        //immediates aren't registers
+        assert!(verify(&[Opcode::LOAD as u8, 1, 200, 200]).is_ok());
+
+        let misaligned = assemble("load $0 #6\njmp $0\nhlt").unwrap();
+        assert_eq!(
+            verify(&misaligned.code),
+            Err(VerifyError::BadJumpTarget { pc: 4, target: 6 })
+        );
+        let past_end = assemble("load $0 #20\nload $1 #1\neq $0 $1\njeq $0\n").unwrap();
+        assert_eq!(
+            verify(&past_end.code),
+            Err(VerifyError::BadJumpTarget { pc: 12, target: 20 })
+        );
+        //jumping to the very end halts, same as falling off it
+        assert!(verify(&assemble("load $0 #8\njmp $0").unwrap().code).is_ok());
+        //a relative jump counts from its register operand
+        let relative = assemble("load $0 #6\njmpf $0\nhlt\nhlt").unwrap();
+        assert!(verify(&relative.code).is_ok());
+        let relative = assemble("load $0 #4\njmpf $0\nhlt\nhlt").unwrap();
+        assert_eq!(
+            verify(&relative.code),
+            Err(VerifyError::BadJumpTarget { pc: 4, target: 10 })
+        );
+    }
+
+    #[test]
+    fn test_dynamic_jumps() {
+        //a jump through an input could land anywhere, so the other jump isn't known either
+        let module =
+            assemble(".input a i32\nload $1 @end\nload $2 #1\neq $0 $2\njeq $1\njmp $0\nend: hlt")
+                .unwrap();
+        assert_eq!(verify(&module.code).unwrap().dynamic_jumps(), &[12, 16]);
+        //unreachable jumps don't count
+        let module = assemble(".input a i32\nload $1 @end\njmp $1\njmp $0\nend: hlt").unwrap();
+        assert!(verify(&module.code).unwrap().dynamic_jumps().is_empty());
+        assert!(verify(&[]).unwrap().dynamic_jumps().is_empty());
+        //a value from two paths that disagree isn't known
+        let module = assemble(
+            ".input a i32\nload $1 @one\nload $2 #0\neq $0 $2\njeq $1\nload $1 @two\n\
+             one: jmp $1\ntwo: hlt",
+        )
+        .unwrap();
+        assert_eq!(verify(&module.code).unwrap().dynamic_jumps(), &[12, 20]);
+    }
+}
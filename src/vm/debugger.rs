@@ -0,0 +1,496 @@
+// Interactive control over a vm: breakpoints on the pc, watchpoints on registers and
+// heap bytes, and stepping one instruction, over calls, to an address or until a stop.
+// The debugger always interprets one instruction at a time, the jit never runs under it.
+
+use super::memory::Region;
+use super::{VmError, VM};
+use crate::instructions::Opcode;
+use crate::obfuscate::lift::{Instr, ALL};
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// A location a watchpoint keeps an eye on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watch {
+    Register(u8),
+    /// a byte of the heap, by offset
+    Heap(usize),
+}
+
+/// When a watchpoint stops the run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// any instruction storing to the location, even the value it already holds
+    Write,
+    /// the location changing to this value
+    Value(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub watch: Watch,
+    pub trigger: Trigger,
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// a step or step over finished
+    Step,
+    /// the pc reached a breakpoint, which hasn't run yet
+    Breakpoint(usize),
+    /// an instruction fired a watchpoint. Heap bytes past the end of the heap read as 0.
+    Watchpoint { watch: Watch, old: i32, new: i32 },
+    /// run to address got there
+    Reached(usize),
+    /// the program halted or ran off the end
+    Halted,
+    /// an instruction failed, the pc is left where the vm stopped
+    Error(VmError),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "stepped"),
+            StopReason::Breakpoint(pc) => write!(f, "breakpoint at {}", pc),
+            StopReason::Watchpoint { watch, old, new } => {
+                write!(f, "watchpoint on {}: {} -> {}", watch, old, new)
+            }
+            StopReason::Reached(pc) => write!(f, "reached {}", pc),
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::Error(e) => write!(f, "VM error: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Register(r) => write!(f, "${}", r),
+            Watch::Heap(offset) => write!(f, "heap {}", offset),
+        }
+    }
+}
+
+/// Steps a vm it owns, stopping at breakpoints and watchpoints
+#[derive(Default)]
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    pub fn into_vm(self) -> VM {
+        self.vm
+    }
+
+    /// Offset of the next instruction to run
+    pub fn pc(&self) -> usize {
+        self.vm.pc
+    }
+
+    /// Return addresses of the calls in progress, innermost last
+    pub fn call_stack(&self) -> &[usize] {
+        &self.vm.call_stack
+    }
+
+    /// Moves the pc, the next step runs from there
+    pub fn set_pc(&mut self, pc: usize) {
+        self.vm.pc = pc;
+    }
+
+    pub fn registers(&self) -> &[i32; 32] {
+        &self.vm.registers
+    }
+
+    /// Returns false for a register past the 32 there are
+    pub fn set_register(&mut self, register: usize, value: i32) -> bool {
+        match self.vm.registers.get_mut(register) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.vm.equal_flag
+    }
+
+    pub fn set_equal_flag(&mut self, flag: bool) {
+        self.vm.equal_flag = flag;
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.vm.heap
+    }
+
+    /// Length of the program bank
+    pub fn code_len(&self) -> usize {
+        self.vm.program.len()
+    }
+
+    /// A byte of the program bank as it runs: decrypted, and with the opcode permutation
+    /// taken off the first byte of every instruction
+    pub fn read_code(&mut self, offset: usize) -> Option<u8> {
+        if offset >= self.vm.program.len() {
+            return None;
+        }
+        let byte = self.vm.fetch(offset).ok()?;
+        if offset.is_multiple_of(4) {
+            Some(self.vm.opcodes[usize::from(byte)])
+        } else {
+            Some(byte)
+        }
+    }
+
+    /// A byte of the address space LDB sees
+    pub fn read_memory(&mut self, address: u32) -> Result<u8, VmError> {
+        self.vm.read_byte(address)
+    }
+
+    /// Stores a byte the way STB does, so inputs and the data section stay read only
+    pub fn write_memory(&mut self, address: u32, byte: u8) -> Result<(), VmError> {
+        self.vm.write_byte(address, byte)
+    }
+
+    /// Goes back to the start of the program with no calls in progress, the registers
+    /// and memory are kept
+    pub fn restart(&mut self) {
+        self.vm.pc = 0;
+        self.vm.call_stack.clear();
+    }
+
+    /// Returns false if there already was a breakpoint at the pc
+    pub fn add_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.insert(pc)
+    }
+
+    /// Returns false if there was no breakpoint at the pc
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().cloned()
+    }
+
+    /// Adds a watchpoint and returns its index
+    pub fn add_watchpoint(&mut self, watch: Watch, trigger: Trigger) -> usize {
+        self.watchpoints.push(Watchpoint { watch, trigger });
+        self.watchpoints.len() - 1
+    }
+
+    /// Removes the watchpoint at the index, later ones move down one
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.watchpoints.len() {
+            Some(self.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Runs the instruction at the pc
+    pub fn step(&mut self) -> StopReason {
+        self.resume(|_| Some(StopReason::Step))
+    }
+
+    /// Like step, but a CALL runs until it returns
+    pub fn step_over(&mut self) -> StopReason {
+        let call = self.instruction().map(|instruction| instruction.code());
+        if call != Some(Opcode::CALL) {
+            return self.step();
+        }
+        let depth = self.vm.call_stack.len();
+        self.resume(|vm| {
+            if vm.call_stack.len() <= depth {
+                Some(StopReason::Step)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Runs until the call in progress returns. Outside of any call that is the end.
+    pub fn step_out(&mut self) -> StopReason {
+        let depth = self.vm.call_stack.len();
+        self.resume(|vm| {
+            if vm.call_stack.len() < depth {
+                Some(StopReason::Step)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Runs until a breakpoint, a watchpoint, a halt or an error
+    pub fn resume_all(&mut self) -> StopReason {
+        self.resume(|_| None)
+    }
+
+    /// Runs until the pc gets to the address, or something else stops it first
+    pub fn run_to(&mut self, address: usize) -> StopReason {
+        self.resume(|vm| {
+            if vm.pc == address {
+                Some(StopReason::Reached(address))
+            } else {
+                None
+            }
+        })
+    }
+
+    // runs instructions until one stops or `done` says the run is over. A breakpoint on
+    // the first instruction doesn't count, so resuming from one gets past it.
+    fn resume<F>(&mut self, done: F) -> StopReason
+    where
+        F: Fn(&VM) -> Option<StopReason>,
+    {
+        let mut first = true;
+        loop {
+            if !first && self.breakpoints.contains(&self.vm.pc) {
+                return StopReason::Breakpoint(self.vm.pc);
+            }
+            first = false;
+            if let Some(stop) = self.execute() {
+                return stop;
+            }
+            if let Some(stop) = done(&self.vm) {
+                return stop;
+            }
+        }
+    }
+
+    // one instruction, and whatever watchpoint it fired
+    fn execute(&mut self) -> Option<StopReason> {
+        let instruction = self.instruction();
+        //halts are reported as a stop instead of the vm's notice on the console, which
+        //could be a debug protocol's stream. The pc ends up where the vm leaves it.
+        if let Some(instruction) = &instruction {
+            let halts = match instruction.code() {
+                Opcode::HLT => true,
+                Opcode::IGL => !self.vm.custom_opcodes.contains_key(&instruction.opcode),
+                _ => false,
+            };
+            if halts {
+                self.vm.pc += 1;
+                return Some(StopReason::Halted);
+            }
+        }
+        let before: Vec<i32> = self
+            .watchpoints
+            .iter()
+            .map(|point| self.value(point.watch))
+            .collect();
+        let written = instruction.map_or(vec![], |instruction| self.written(&instruction));
+        match self.vm.execute_instruction() {
+            Ok(true) => {}
+            Ok(false) => return Some(StopReason::Halted),
+            Err(e) => return Some(StopReason::Error(e)),
+        }
+        for (point, old) in self.watchpoints.iter().zip(before) {
+            let new = self.value(point.watch);
+            let fired = match point.trigger {
+                Trigger::Write => old != new || written.contains(&point.watch),
+                Trigger::Value(value) => old != new && new == value,
+            };
+            if fired {
+                return Some(StopReason::Watchpoint {
+                    watch: point.watch,
+                    old,
+                    new,
+                });
+            }
+        }
+        None
+    }
+
+    fn value(&self, watch: Watch) -> i32 {
+        match watch {
+            Watch::Register(r) => self.vm.registers[usize::from(r & 31)],
+            Watch::Heap(offset) => i32::from(self.vm.heap.get(offset).cloned().unwrap_or(0)),
+        }
+    }
+
+    // the watched locations the instruction at the pc is about to store to
+    fn written(&self, instruction: &Instr) -> Vec<Watch> {
+        let writes = instruction.effects().writes;
+        let mut written: Vec<Watch> = (0..32)
+            .filter(|r| writes != ALL && writes & (1 << r) != 0)
+            .map(Watch::Register)
+            .collect();
+        let width = match instruction.code() {
+            Opcode::STB => 1,
+            Opcode::STW => 4,
+            _ => 0,
+        };
+        let address = self.vm.registers[usize::from(instruction.operands[1] & 31)] as u32;
+        for i in 0..width {
+            if let Region::Heap(offset) = Region::decode(address.wrapping_add(i)) {
+                written.push(Watch::Heap(offset));
+            }
+        }
+        written
+    }
+
+    // the plain instruction at the pc, None when there isn't a whole one left
+    fn instruction(&mut self) -> Option<Instr> {
+        let pc = self.vm.pc;
+        if pc + 4 > self.vm.program.len() {
+            return None;
+        }
+        Some(Instr {
+            opcode: self.vm.opcodes[usize::from(self.vm.fetch(pc).ok()?)],
+            operands: [
+                self.vm.fetch(pc + 1).ok()?,
+                self.vm.fetch(pc + 2).ok()?,
+                self.vm.fetch(pc + 3).ok()?,
+            ],
+            target: None,
+            jumps: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn debug(source: &str) -> Debugger {
+        let mut vm = VM::new();
+        vm.load_module(&assemble(source).unwrap());
+        Debugger::new(vm)
+    }
+
+    // sums 1..=5 in $1, calling a function that doubles $2 into $4 on every round
+    const LOOP: &str = "load $0 #5\nload $3 @top\nload $5 @double\n\
+                        top: add $1 $2 $1\ncall $5\ninc $2\nlteq $2 $0\njeq $3\nhlt\n\
+                        double: add $2 $2 $4\nret\n";
+
+    #[test]
+    fn test_breakpoints_and_steps() {
+        let mut debugger = debug(LOOP);
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!(debugger.pc(), 4);
+        assert!(debugger.add_breakpoint(16));
+        assert!(!debugger.add_breakpoint(16));
+        assert_eq!(debugger.resume_all(), StopReason::Breakpoint(16));
+        //stepping into the call, then back out
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!((debugger.pc(), debugger.call_stack()), (36, &[20][..]));
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!((debugger.pc(), debugger.call_stack()), (20, &[][..]));
+        //resuming from a breakpoint gets past it, and it stops there the next round
+        assert_eq!(debugger.resume_all(), StopReason::Breakpoint(16));
+        assert_eq!(debugger.vm().registers[2], 1);
+        //stepping over the call runs the whole function
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!(debugger.pc(), 20);
+        assert_eq!(debugger.vm().registers[4], 2);
+
+        assert!(debugger.remove_breakpoint(16));
+        assert!(!debugger.remove_breakpoint(16));
+        //stepping out of one finishes it
+        assert_eq!(debugger.run_to(36), StopReason::Reached(36));
+        assert_eq!(debugger.step_out(), StopReason::Step);
+        assert_eq!((debugger.pc(), debugger.vm().registers[4]), (20, 4));
+        assert_eq!(debugger.run_to(32), StopReason::Reached(32));
+        assert_eq!(debugger.resume_all(), StopReason::Halted);
+        assert_eq!(debugger.vm().registers[1], 15);
+
+        debugger.restart();
+        assert_eq!(debugger.pc(), 0);
+        assert_eq!(debugger.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debug(LOOP);
+        debugger.add_watchpoint(Watch::Register(1), Trigger::Value(6));
+        let stop = debugger.resume_all();
+        assert_eq!(
+            stop,
+            StopReason::Watchpoint {
+                watch: Watch::Register(1),
+                old: 3,
+                new: 6
+            }
+        );
+        assert_eq!(stop.to_string(), "watchpoint on $1: 3 -> 6");
+        assert_eq!(debugger.pc(), 16);
+        assert_eq!(
+            debugger.remove_watchpoint(0).unwrap().trigger,
+            Trigger::Value(6)
+        );
+        assert_eq!(debugger.remove_watchpoint(0), None);
+
+        //writes fire even when the value stays the same
+        let mut debugger = debug("load $1 #0\nload $1 #0\nload $1 #3\nhlt\n");
+        debugger.add_watchpoint(Watch::Register(1), Trigger::Write);
+        for pc in [4, 8, 12].iter() {
+            assert!(matches!(
+                debugger.resume_all(),
+                StopReason::Watchpoint { .. }
+            ));
+            assert_eq!(debugger.pc(), *pc);
+        }
+        assert_eq!(debugger.resume_all(), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_heap_watchpoints_and_errors() {
+        let source = "load $0 #8\naloc $0\nload $1 #4\nlui $2 #65280\nstw $2 $1\n\
+                      load $3 #0\nstb $3 $3\ndiv $2 $3 $4\nhlt\n";
+        let mut debugger = debug(source);
+        let index = debugger.add_watchpoint(Watch::Heap(7), Trigger::Write);
+        debugger.add_watchpoint(Watch::Heap(0), Trigger::Write);
+        assert_eq!(debugger.watchpoints()[index].watch, Watch::Heap(7));
+        let stop = debugger.resume_all();
+        assert_eq!(
+            stop,
+            StopReason::Watchpoint {
+                watch: Watch::Heap(7),
+                old: 0,
+                new: 255
+            }
+        );
+        //storing the zero already there still counts as a write
+        assert!(matches!(
+            debugger.resume_all(),
+            StopReason::Watchpoint {
+                watch: Watch::Heap(0),
+                old: 0,
+                new: 0
+            }
+        ));
+        assert_eq!(
+            debugger.resume_all(),
+            StopReason::Error(VmError::DivideByZero)
+        );
+    }
+}
@@ -0,0 +1,311 @@
+// Pre-decoded programs. The byte interpreter looks every opcode up through the
+// permutation table and Opcode::from and bounds checks each operand byte on every step.
+// Loading a plain module decodes its code once into one DecodedInstr per 4 byte
+// instruction so the hot loop only indexes a Vec.
+//
+// The decoded copy is only kept for code the vm holds in the clear. Sealed modules and
+// vms checking their integrity run the bytes themselves, and anything that changes the
+// program bank after loading drops it. A pc that isn't on an instruction boundary or
+// lands on a trailing partial instruction falls back to the byte interpreter, and so does
+// everything from the first instruction naming a register past $31, which the byte
+// interpreter fails with InvalidRegister.
+
+use super::{float, VmError, MAX_CALL_DEPTH, VM};
+use crate::instructions::Opcode;
+use crate::verify::{layout, Operand};
+
+/// One instruction with its opcode resolved and its operand bytes split out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodedInstr {
+    pub opcode: Opcode,
+    // the byte as stored, host opcodes are looked up by it
+    pub raw: u8,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    // the 16 bit operand the opcode reads, bytes 1-2 for SYSCALL and 2-3 otherwise
+    pub immediate: u16,
+}
+
+/// Decodes the whole instructions of the program through the opcode table, up to the
+/// first one with a register that doesn't exist
+pub fn decode(program: &[u8], opcodes: &[u8; 256]) -> Vec<DecodedInstr> {
+    program
+        .chunks_exact(4)
+        .map(|bytes| {
+            let opcode = Opcode::from(opcodes[usize::from(bytes[0])]);
+            let immediate = match opcode {
+                Opcode::SYSCALL => u16::from_be_bytes([bytes[1], bytes[2]]),
+                _ => u16::from_be_bytes([bytes[2], bytes[3]]),
+            };
+            DecodedInstr {
+                opcode,
+                raw: bytes[0],
+                a: bytes[1],
+                b: bytes[2],
+                c: bytes[3],
+                immediate,
+            }
+        })
+        .take_while(registers_exist)
+        .collect()
+}
+
+// whether every register operand of the instruction is one of the 32
+fn registers_exist(instr: &DecodedInstr) -> bool {
+    let operands = [instr.a, instr.b, instr.c];
+    layout(instr.opcode)
+        .iter()
+        .zip(operands.iter())
+        .all(|(kind, operand)| *kind != Operand::Register || *operand < 32)
+}
+
+impl VM {
+    /// runs the decoded instruction at pc, the same way execute_instruction runs its bytes
+    pub(super) fn execute_decoded(&mut self, instr: DecodedInstr) -> Result<bool, VmError> {
+        let start = self.pc;
+        let result = self.run_decoded(instr, start);
+        if result.is_err() {
+            //the byte interpreter stops right after the last operand it read
+            self.pc = start + fault_offset(instr.opcode);
+        }
+        result
+    }
+
+    fn run_decoded(&mut self, instr: DecodedInstr, start: usize) -> Result<bool, VmError> {
+        // where every instruction that doesn't jump carries on from
+        self.pc = start + 4;
+        let a = usize::from(instr.a);
+        let b = usize::from(instr.b);
+        let c = usize::from(instr.c);
+        match instr.opcode {
+            Opcode::HLT => {
+                self.pc = start + 1;
+                if self.verbose {
+                    println!("\n\nHLT Encountered\n");
+                }
+                return Ok(false);
+            }
+            Opcode::NOP => {}
+            Opcode::LOAD => self.registers[a] = i32::from(instr.immediate),
+            Opcode::LOADM => {
+                let mask = match &mut self.masks {
+                    Some(masks) => masks.immediate(start),
+                    None => 0,
+                };
+                self.registers[a] = i32::from(instr.immediate ^ mask);
+            }
+            Opcode::LUI => self.registers[a] = (u32::from(instr.immediate) << 16) as i32,
+            Opcode::ADD => self.registers[c] = self.registers[a].wrapping_add(self.registers[b]),
+            Opcode::SUB => self.registers[c] = self.registers[a].wrapping_sub(self.registers[b]),
+            Opcode::MUL => self.registers[c] = self.registers[a].wrapping_mul(self.registers[b]),
+            Opcode::DIV => {
+                let (register1, register2) = (self.registers[a], self.registers[b]);
+                if register2 == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                self.registers[c] = register1.wrapping_div(register2);
+                self.remainder = register1.wrapping_rem(register2) as u32;
+            }
+            Opcode::JMP => self.pc = self.registers[a] as usize,
+            Opcode::JMPF => {
+                let target = self.registers[a];
+                match (start + 2).checked_add(target as usize) {
+                    Some(pc) => self.pc = pc,
+                    None => {
+                        if self.verbose {
+                            println!("\n\nPROGRAM COUNTER OVERFLOWED! (JMPF went above usize::MAX) at index: {} args: {}\n", start + 2, target);
+                        }
+                        return Ok(false);
+                    }
+                }
+            }
+            Opcode::JMPB => {
+                let target = self.registers[a];
+                match (start + 2).checked_sub(target as usize) {
+                    Some(pc) => self.pc = pc,
+                    None => {
+                        if self.verbose {
+                            println!("\n\nPROGRAM COUNTER OVERFLOWED! (JMPB went below 0) at index: {} args: {}\n", start + 2, target);
+                        }
+                        return Ok(false);
+                    }
+                }
+            }
+            Opcode::EQ => self.equal_flag = self.registers[a] == self.registers[b],
+            Opcode::NEQ => self.equal_flag = self.registers[a] != self.registers[b],
+            Opcode::GT => self.equal_flag = self.registers[a] > self.registers[b],
+            Opcode::LT => self.equal_flag = self.registers[a] < self.registers[b],
+            Opcode::GTEQ => self.equal_flag = self.registers[a] >= self.registers[b],
+            Opcode::LTEQ => self.equal_flag = self.registers[a] <= self.registers[b],
+            Opcode::BETW => {
+                let value = self.registers[a];
+                self.equal_flag = value > self.registers[b] && value < self.registers[c];
+            }
+            Opcode::ALOC => self.allocate(self.registers[a])?,
+            Opcode::JEQ => {
+                if self.equal_flag {
+                    self.pc = self.registers[a] as usize;
+                }
+            }
+            Opcode::JNEQ => {
+                if !self.equal_flag {
+                    self.pc = self.registers[a] as usize;
+                }
+            }
+            Opcode::INC => self.registers[a] = self.registers[a].wrapping_add(1),
+            Opcode::DEC => self.registers[a] = self.registers[a].wrapping_sub(1),
+            Opcode::LDB => {
+                let byte = self.read_byte(self.registers[a] as u32)?;
+                self.registers[b] = i32::from(byte);
+            }
+            Opcode::STB => self.write_byte(self.registers[b] as u32, self.registers[a] as u8)?,
+            Opcode::MLEN => self.registers[b] = self.region_len(self.registers[a] as u32)? as i32,
+            Opcode::MOV => self.registers[b] = self.registers[a],
+            Opcode::FLAG => self.registers[a] = self.equal_flag as i32,
+            Opcode::LDW => {
+                let address = self.registers[a] as u32;
+                let mut word = [0; 4];
+                for (i, byte) in word.iter_mut().enumerate() {
+                    *byte = self.read_byte(address.wrapping_add(i as u32))?;
+                }
+                self.registers[b] = i32::from_le_bytes(word);
+            }
+            Opcode::STW => {
+                let address = self.registers[b] as u32;
+                for (i, byte) in self.registers[a].to_le_bytes().iter().enumerate() {
+                    self.write_byte(address.wrapping_add(i as u32), *byte)?;
+                }
+            }
+            Opcode::CALL => {
+                if self.call_stack.len() >= MAX_CALL_DEPTH {
+                    return Err(VmError::CallStackOverflow);
+                }
+                self.call_stack.push(start + 4);
+                self.pc = self.registers[a] as usize;
+            }
+            Opcode::RET => match self.call_stack.pop() {
+                Some(pc) => self.pc = pc,
+                None => return Err(VmError::CallStackUnderflow),
+            },
+            Opcode::AND => self.registers[c] = self.registers[a] & self.registers[b],
+            Opcode::OR => self.registers[c] = self.registers[a] | self.registers[b],
+            Opcode::XOR => self.registers[c] = self.registers[a] ^ self.registers[b],
+            Opcode::SHL => {
+                self.registers[c] = self.registers[a].wrapping_shl(self.registers[b] as u32)
+            }
+            Opcode::SHR => {
+                self.registers[c] = self.registers[a].wrapping_shr(self.registers[b] as u32)
+            }
+            Opcode::SHRU => {
+                let value = self.registers[a] as u32;
+                self.registers[c] = value.wrapping_shr(self.registers[b] as u32) as i32;
+            }
+            Opcode::MOD => {
+                self.registers[c] = match self.registers[b] {
+                    0 => return Err(VmError::DivideByZero),
+                    divisor => self.registers[a].wrapping_rem(divisor),
+                }
+            }
+            Opcode::DIVU => {
+                self.registers[c] = match self.registers[b] as u32 {
+                    0 => return Err(VmError::DivideByZero),
+                    divisor => (self.registers[a] as u32 / divisor) as i32,
+                }
+            }
+            Opcode::MODU => {
+                self.registers[c] = match self.registers[b] as u32 {
+                    0 => return Err(VmError::DivideByZero),
+                    divisor => (self.registers[a] as u32 % divisor) as i32,
+                }
+            }
+            Opcode::FADD => {
+                self.float_result(c, float(self.registers[a]) + float(self.registers[b]))
+            }
+            Opcode::FSUB => {
+                self.float_result(c, float(self.registers[a]) - float(self.registers[b]))
+            }
+            Opcode::FMUL => {
+                self.float_result(c, float(self.registers[a]) * float(self.registers[b]))
+            }
+            Opcode::FDIV => {
+                self.float_result(c, float(self.registers[a]) / float(self.registers[b]))
+            }
+            Opcode::NOT => self.registers[b] = !self.registers[a],
+            Opcode::NEG => self.registers[b] = self.registers[a].wrapping_neg(),
+            Opcode::FNEG => self.float_result(b, -float(self.registers[a])),
+            Opcode::ITOF => self.float_result(b, self.registers[a] as f32),
+            Opcode::UTOF => self.float_result(b, self.registers[a] as u32 as f32),
+            Opcode::FTOI => self.registers[b] = float(self.registers[a]) as i32,
+            Opcode::FTOU => self.registers[b] = float(self.registers[a]) as u32 as i32,
+            Opcode::LTU => {
+                self.equal_flag = (self.registers[a] as u32) < (self.registers[b] as u32)
+            }
+            Opcode::GTU => {
+                self.equal_flag = (self.registers[a] as u32) > (self.registers[b] as u32)
+            }
+            Opcode::LTEQU => {
+                self.equal_flag = (self.registers[a] as u32) <= (self.registers[b] as u32)
+            }
+            Opcode::GTEQU => {
+                self.equal_flag = (self.registers[a] as u32) >= (self.registers[b] as u32)
+            }
+            Opcode::FLT => self.equal_flag = float(self.registers[a]) < float(self.registers[b]),
+            Opcode::FGT => self.equal_flag = float(self.registers[a]) > float(self.registers[b]),
+            Opcode::FLTEQ => self.equal_flag = float(self.registers[a]) <= float(self.registers[b]),
+            Opcode::FGTEQ => self.equal_flag = float(self.registers[a]) >= float(self.registers[b]),
+            Opcode::FEQ => self.equal_flag = float(self.registers[a]) == float(self.registers[b]),
+            Opcode::FNEQ => self.equal_flag = float(self.registers[a]) != float(self.registers[b]),
+            Opcode::SYSCALL => self.execute_syscall(instr.immediate)?,
+            Opcode::IGL => {
+                if self.custom_opcodes.contains_key(&instr.raw) {
+                    self.execute_custom(instr.raw, [instr.a, instr.b, instr.c])?;
+                    return Ok(true);
+                }
+                self.pc = start + 1;
+                if self.verbose {
+                    println!("\n\nUnrecognized opcode found! Terminating!\n");
+                }
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // stores the bits of a float result
+    fn float_result(&mut self, register: usize, value: f32) {
+        self.registers[register] = value.to_bits() as i32;
+    }
+}
+
+// bytes of an instruction the byte interpreter has read when it faults
+fn fault_offset(opcode: Opcode) -> usize {
+    match opcode {
+        Opcode::RET => 1,
+        Opcode::ALOC | Opcode::LDB | Opcode::MLEN | Opcode::LDW => 2,
+        Opcode::DIV | Opcode::MOD | Opcode::DIVU | Opcode::MODU | Opcode::STB | Opcode::STW => 3,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::permutation;
+
+    #[test]
+    fn test_decode() {
+        let mut program = vec![];
+        program.extend_from_slice(&[Opcode::LOAD as u8, 1, 0x13, 0x37]);
+        program.extend_from_slice(&[Opcode::SYSCALL as u8, 0, 7, 0]);
+        program.extend_from_slice(&[Opcode::ADD as u8, 1, 2, 3]);
+        //a trailing partial instruction is left to the byte interpreter
+        program.push(Opcode::HLT as u8);
+        let decoded = decode(&program, &permutation::identity());
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0].opcode, Opcode::LOAD);
+        assert_eq!((decoded[0].a, decoded[0].immediate), (1, 0x1337));
+        assert_eq!(decoded[1].immediate, 7);
+        assert_eq!((decoded[2].a, decoded[2].b, decoded[2].c), (1, 2, 3));
+    }
+}
@@ -0,0 +1,97 @@
+// Integrity checks of the loaded program. A host running one vm for a long time can't
+// tell if something patched the program bank between calls, so the vm keeps a keyed
+// MAC (HMAC-SHA256) of it taken at load time and compares the bank against it before a
+// run, every so many instructions, or whenever the host asks.
+//
+// The MAC covers the code as stored (still encrypted for sealed modules), the opcode
+// table it is decoded with and the data section.
+
+use super::VmError;
+use crate::crypt::Key;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+/// The MAC of the program bank and when to check it
+pub struct Integrity {
+    key: Key,
+    mac: [u8; 32],
+    before_run: bool,
+    // instructions between checks while running, 0 for none
+    interval: u64,
+    countdown: u64,
+}
+
+impl Integrity {
+    pub fn new(key: &Key) -> Integrity {
+        Integrity {
+            key: *key,
+            mac: [0; 32],
+            before_run: false,
+            interval: 0,
+            countdown: 0,
+        }
+    }
+
+    /// Takes the MAC of a freshly loaded bank
+    pub fn update(&mut self, program: &[u8], opcodes: &[u8], data: &[u8]) {
+        self.mac = self
+            .hasher(program, opcodes, data)
+            .finalize()
+            .into_bytes()
+            .into();
+    }
+
+    /// Compares the bank against the MAC taken at load, in constant time
+    pub fn verify(&self, program: &[u8], opcodes: &[u8], data: &[u8]) -> Result<(), VmError> {
+        self.hasher(program, opcodes, data)
+            .verify_slice(&self.mac)
+            .map_err(|_| VmError::IntegrityViolation)
+    }
+
+    pub fn set_before_run(&mut self, enabled: bool) {
+        self.before_run = enabled;
+    }
+
+    pub fn before_run(&self) -> bool {
+        self.before_run
+    }
+
+    pub fn set_interval(&mut self, instructions: u64) {
+        self.interval = instructions;
+        self.countdown = instructions;
+    }
+
+    /// Counts an executed instruction, true when a periodic check is due
+    pub fn tick(&mut self) -> bool {
+        if self.interval == 0 {
+            return false;
+        }
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.interval;
+            return true;
+        }
+        false
+    }
+
+    // lengths go in first so moving bytes from one part to the next changes the MAC
+    fn hasher(&self, program: &[u8], opcodes: &[u8], data: &[u8]) -> Hmac<Sha256> {
+        let mut hasher =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("hmac takes any key size");
+        for part in [program, opcodes, data].iter() {
+            hasher.update(&(part.len() as u64).to_le_bytes());
+        }
+        for part in [program, opcodes, data].iter() {
+            hasher.update(part);
+        }
+        hasher
+    }
+}
+
+impl Drop for Integrity {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
@@ -0,0 +1,882 @@
+// Native code for verified programs through Cranelift, behind the jit feature. The
+// whole program becomes one function with a block per instruction. Jumps go through a
+// dispatch block that turns the target pc into a block (through a jump table), and
+// anything that isn't an instruction boundary leaves the native code.
+//
+// Only register work, comparisons, jumps, CALL and RET are compiled. Everything that
+// touches memory, the host or the masks (LDB, STB, MLEN, LDW, STW, ALOC, SYSCALL, host
+// opcodes, LOADM), the relative jumps and HLT exit to the interpreter, which runs that
+// one instruction before the native code is entered again. Faults are handled the same
+// way: a zero divisor, a full call stack or a RET without a CALL exits right before the
+// instruction, so the interpreter raises the error exactly as it would have anyway.
+//
+// Registers, the flag, the remainder and the call stack are copied into a State on
+// entry and back on exit, the native code keeps them in machine registers in between.
+
+use super::MAX_CALL_DEPTH;
+use crate::instructions::Opcode;
+use crate::module::Module;
+use crate::verify::{self, VerifyError};
+
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Type, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Switch, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module as _};
+
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+use zeroize::Zeroize;
+
+/// Why a module couldn't be compiled to native code
+#[derive(Debug, Clone, PartialEq)]
+pub enum JitError {
+    /// native code indexes registers without checks, so only verified code is compiled
+    Verify(VerifyError),
+    /// Cranelift couldn't target the host or build the function
+    Codegen(String),
+    /// integrity checks are on, and the MAC covers the bytes rather than native code
+    Integrity,
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JitError::Verify(e) => write!(f, "{}", e),
+            JitError::Codegen(msg) => write!(f, "code generation failed: {}", msg),
+            JitError::Integrity => write!(f, "integrity checked programs aren't compiled"),
+        }
+    }
+}
+
+impl Error for JitError {}
+
+impl From<VerifyError> for JitError {
+    fn from(e: VerifyError) -> JitError {
+        JitError::Verify(e)
+    }
+}
+
+// what the native code reads on entry and writes back on exit
+#[repr(C)]
+struct State {
+    registers: [i32; 32],
+    remainder: u32,
+    equal_flag: u32,
+    call_depth: u64,
+    call_stack: [u64; MAX_CALL_DEPTH],
+}
+
+const REMAINDER: i32 = 128;
+const EQUAL_FLAG: i32 = 132;
+const CALL_DEPTH: i32 = 136;
+const CALL_STACK: i32 = 144;
+
+// (state, pc to start at) -> pc the interpreter carries on from
+type Entry = unsafe extern "C" fn(*mut State, u64) -> u64;
+
+/// A module compiled to native code
+pub struct JitProgram {
+    // owns the code entry points into, freed on drop
+    module: Option<JITModule>,
+    entry: Entry,
+    state: Box<State>,
+}
+
+impl JitProgram {
+    /// Verifies the module's code and compiles it. LOADM stays masked, it's left to the
+    /// interpreter like the other instructions native code doesn't handle.
+    pub fn compile(module: &Module) -> Result<JitProgram, JitError> {
+        verify::verify_module(module)?;
+        let mut code = module.code.clone();
+        if let Some(permutation) = &module.permutation {
+            for instruction in code.chunks_mut(4) {
+                instruction[0] = permutation.decode(instruction[0]);
+            }
+        }
+
+        let codegen = |e: &dyn fmt::Display| JitError::Codegen(e.to_string());
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|e| codegen(&e))?;
+        let isa = cranelift_native::builder()
+            .map_err(|e| codegen(&e))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| codegen(&e))?;
+        let mut jit = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let pointer = jit.target_config().pointer_type();
+        let mut context = jit.make_context();
+        context.func.signature.params.push(AbiParam::new(pointer));
+        context
+            .func
+            .signature
+            .params
+            .push(AbiParam::new(types::I64));
+        context
+            .func
+            .signature
+            .returns
+            .push(AbiParam::new(types::I64));
+        let mut builder_context = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        Translator::new(builder, &code).translate();
+
+        let id = jit
+            .declare_function("biobox", Linkage::Export, &context.func.signature)
+            .map_err(|e| codegen(&e))?;
+        jit.define_function(id, &mut context)
+            .map_err(|e| codegen(&e))?;
+        jit.clear_context(&mut context);
+        jit.finalize_definitions().map_err(|e| codegen(&e))?;
+        let code = jit.get_finalized_function(id);
+        // the function was built with exactly the Entry signature above
+        let entry = unsafe { mem::transmute::<*const u8, Entry>(code) };
+        Ok(JitProgram {
+            module: Some(jit),
+            entry,
+            state: Box::new(State {
+                registers: [0; 32],
+                remainder: 0,
+                equal_flag: 0,
+                call_depth: 0,
+                call_stack: [0; MAX_CALL_DEPTH],
+            }),
+        })
+    }
+
+    /// Runs native code from pc until it reaches something it leaves to the
+    /// interpreter, returning the pc to interpret next
+    pub fn run(
+        &mut self,
+        pc: usize,
+        registers: &mut [i32; 32],
+        remainder: &mut u32,
+        equal_flag: &mut bool,
+        call_stack: &mut Vec<usize>,
+    ) -> usize {
+        let state = &mut *self.state;
+        state.registers = *registers;
+        state.remainder = *remainder;
+        state.equal_flag = u32::from(*equal_flag);
+        state.call_depth = call_stack.len() as u64;
+        for (slot, pc) in state.call_stack.iter_mut().zip(call_stack.iter()) {
+            *slot = *pc as u64;
+        }
+        // the code was verified, so every register it touches is inside the state,
+        // and the call stack is bounds checked against MAX_CALL_DEPTH
+        let pc = unsafe { (self.entry)(state, pc as u64) } as usize;
+        *registers = state.registers;
+        *remainder = state.remainder;
+        *equal_flag = state.equal_flag != 0;
+        let depth = state.call_depth as usize;
+        call_stack.clear();
+        call_stack.extend(state.call_stack[..depth].iter().map(|pc| *pc as usize));
+        pc
+    }
+}
+
+impl Drop for JitProgram {
+    fn drop(&mut self) {
+        self.state.registers.zeroize();
+        self.state.call_stack.zeroize();
+        if let Some(module) = self.module.take() {
+            // nothing can call into the code once the program is dropped
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+// variables past the 32 registers
+const REMAINDER_VAR: usize = 32;
+const FLAG_VAR: usize = 33;
+const DEPTH_VAR: usize = 34;
+// the pc dispatch jumps to and the exit hands back
+const TARGET_VAR: usize = 35;
+
+// builds the function, one block per 4 byte instruction
+struct Translator<'a, 'b> {
+    builder: FunctionBuilder<'b>,
+    code: &'a [u8],
+    state: Value,
+    blocks: Vec<Block>,
+    dispatch: Block,
+    exit: Block,
+}
+
+impl<'a, 'b> Translator<'a, 'b> {
+    fn new(mut builder: FunctionBuilder<'b>, code: &'a [u8]) -> Translator<'a, 'b> {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let state = builder.block_params(entry)[0];
+        let start = builder.block_params(entry)[1];
+        let blocks = code.chunks(4).map(|_| builder.create_block()).collect();
+        let dispatch = builder.create_block();
+        let exit = builder.create_block();
+
+        let mut translator = Translator {
+            builder,
+            code,
+            state,
+            blocks,
+            dispatch,
+            exit,
+        };
+        for register in 0..32 {
+            translator.declare(register, types::I32);
+            let value = translator.load(types::I32, register as i32 * 4);
+            translator.set(register, value);
+        }
+        translator.declare(REMAINDER_VAR, types::I32);
+        let remainder = translator.load(types::I32, REMAINDER);
+        translator.set(REMAINDER_VAR, remainder);
+        translator.declare(FLAG_VAR, types::I8);
+        let flag = translator.load(types::I32, EQUAL_FLAG);
+        let flag = translator.builder.ins().icmp_imm(IntCC::NotEqual, flag, 0);
+        translator.set(FLAG_VAR, flag);
+        translator.declare(DEPTH_VAR, types::I64);
+        let depth = translator.load(types::I64, CALL_DEPTH);
+        translator.set(DEPTH_VAR, depth);
+        translator.declare(TARGET_VAR, types::I64);
+        translator.set(TARGET_VAR, start);
+        translator.builder.ins().jump(dispatch, &[]);
+        translator
+    }
+
+    fn translate(mut self) {
+        for i in 0..self.blocks.len() {
+            self.builder.switch_to_block(self.blocks[i]);
+            self.instruction(i);
+        }
+        self.emit_dispatch();
+        self.emit_exit();
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    // instruction i, which starts at pc i * 4
+    fn instruction(&mut self, i: usize) {
+        let pc = i * 4;
+        let bytes = &self.code[pc..pc + 4];
+        let (a, b, c) = (bytes[1], bytes[2], bytes[3]);
+        let immediate = i64::from(u16::from_be_bytes([b, c]));
+        match Opcode::from(bytes[0]) {
+            Opcode::NOP => {}
+            Opcode::LOAD => {
+                let value = self.builder.ins().iconst(types::I32, immediate);
+                self.set(a as usize, value);
+            }
+            Opcode::LUI => {
+                let value = (immediate << 16) as u32 as i32;
+                let value = self.builder.ins().iconst(types::I32, i64::from(value));
+                self.set(a as usize, value);
+            }
+            Opcode::MOV => {
+                let value = self.reg(a);
+                self.set(b as usize, value);
+            }
+            Opcode::FLAG => {
+                let flag = self.get(FLAG_VAR);
+                let value = self.builder.ins().uextend(types::I32, flag);
+                self.set(a as usize, value);
+            }
+            Opcode::ADD => self.binary(a, b, c, |ins, x, y| ins.iadd(x, y)),
+            Opcode::SUB => self.binary(a, b, c, |ins, x, y| ins.isub(x, y)),
+            Opcode::MUL => self.binary(a, b, c, |ins, x, y| ins.imul(x, y)),
+            Opcode::AND => self.binary(a, b, c, |ins, x, y| ins.band(x, y)),
+            Opcode::OR => self.binary(a, b, c, |ins, x, y| ins.bor(x, y)),
+            Opcode::XOR => self.binary(a, b, c, |ins, x, y| ins.bxor(x, y)),
+            //shift amounts wrap at 32 like wrapping_shl and wrapping_shr
+            Opcode::SHL => self.binary(a, b, c, |ins, x, y| ins.ishl(x, y)),
+            Opcode::SHR => self.binary(a, b, c, |ins, x, y| ins.sshr(x, y)),
+            Opcode::SHRU => self.binary(a, b, c, |ins, x, y| ins.ushr(x, y)),
+            Opcode::INC => {
+                let value = self.reg(a);
+                let value = self.builder.ins().iadd_imm(value, 1);
+                self.set(a as usize, value);
+            }
+            Opcode::DEC => {
+                let value = self.reg(a);
+                let value = self.builder.ins().iadd_imm(value, -1);
+                self.set(a as usize, value);
+            }
+            Opcode::NOT => self.unary(a, b, |ins, x| ins.bnot(x)),
+            Opcode::NEG => self.unary(a, b, |ins, x| ins.ineg(x)),
+            Opcode::DIV => {
+                //-1 is left to the interpreter too, i32::MIN / -1 traps in native code
+                self.guard_signed_divisor(pc, b);
+                let (x, y) = (self.reg(a), self.reg(b));
+                let quotient = self.builder.ins().sdiv(x, y);
+                let remainder = self.builder.ins().srem(x, y);
+                self.set(c as usize, quotient);
+                self.set(REMAINDER_VAR, remainder);
+            }
+            Opcode::MOD => {
+                self.guard_signed_divisor(pc, b);
+                self.binary(a, b, c, |ins, x, y| ins.srem(x, y));
+            }
+            Opcode::DIVU => {
+                self.guard(pc, b, IntCC::Equal, 0);
+                self.binary(a, b, c, |ins, x, y| ins.udiv(x, y));
+            }
+            Opcode::MODU => {
+                self.guard(pc, b, IntCC::Equal, 0);
+                self.binary(a, b, c, |ins, x, y| ins.urem(x, y));
+            }
+            Opcode::FADD => self.float_binary(a, b, c, |ins, x, y| ins.fadd(x, y)),
+            Opcode::FSUB => self.float_binary(a, b, c, |ins, x, y| ins.fsub(x, y)),
+            Opcode::FMUL => self.float_binary(a, b, c, |ins, x, y| ins.fmul(x, y)),
+            Opcode::FDIV => self.float_binary(a, b, c, |ins, x, y| ins.fdiv(x, y)),
+            Opcode::FNEG => {
+                let value = self.float(a);
+                let value = self.builder.ins().fneg(value);
+                self.set_float(b, value);
+            }
+            Opcode::ITOF => {
+                let value = self.reg(a);
+                let value = self.builder.ins().fcvt_from_sint(types::F32, value);
+                self.set_float(b, value);
+            }
+            Opcode::UTOF => {
+                let value = self.reg(a);
+                let value = self.builder.ins().fcvt_from_uint(types::F32, value);
+                self.set_float(b, value);
+            }
+            //saturating with NaN as 0, the same as an `as` cast
+            Opcode::FTOI => {
+                let value = self.float(a);
+                let value = self.builder.ins().fcvt_to_sint_sat(types::I32, value);
+                self.set(b as usize, value);
+            }
+            Opcode::FTOU => {
+                let value = self.float(a);
+                let value = self.builder.ins().fcvt_to_uint_sat(types::I32, value);
+                self.set(b as usize, value);
+            }
+            Opcode::EQ => self.compare(a, b, IntCC::Equal),
+            Opcode::NEQ => self.compare(a, b, IntCC::NotEqual),
+            Opcode::GT => self.compare(a, b, IntCC::SignedGreaterThan),
+            Opcode::LT => self.compare(a, b, IntCC::SignedLessThan),
+            Opcode::GTEQ => self.compare(a, b, IntCC::SignedGreaterThanOrEqual),
+            Opcode::LTEQ => self.compare(a, b, IntCC::SignedLessThanOrEqual),
+            Opcode::LTU => self.compare(a, b, IntCC::UnsignedLessThan),
+            Opcode::GTU => self.compare(a, b, IntCC::UnsignedGreaterThan),
+            Opcode::LTEQU => self.compare(a, b, IntCC::UnsignedLessThanOrEqual),
+            Opcode::GTEQU => self.compare(a, b, IntCC::UnsignedGreaterThanOrEqual),
+            Opcode::BETW => {
+                let (value, lower, upper) = (self.reg(a), self.reg(b), self.reg(c));
+                let above = self
+                    .builder
+                    .ins()
+                    .icmp(IntCC::SignedGreaterThan, value, lower);
+                let below = self.builder.ins().icmp(IntCC::SignedLessThan, value, upper);
+                let flag = self.builder.ins().band(above, below);
+                self.set(FLAG_VAR, flag);
+            }
+            Opcode::FLT => self.float_compare(a, b, FloatCC::LessThan),
+            Opcode::FGT => self.float_compare(a, b, FloatCC::GreaterThan),
+            Opcode::FLTEQ => self.float_compare(a, b, FloatCC::LessThanOrEqual),
+            Opcode::FGTEQ => self.float_compare(a, b, FloatCC::GreaterThanOrEqual),
+            Opcode::FEQ => self.float_compare(a, b, FloatCC::Equal),
+            Opcode::FNEQ => self.float_compare(a, b, FloatCC::NotEqual),
+            Opcode::JMP => {
+                let target = self.reg(a);
+                self.jump_to(target);
+                return;
+            }
+            Opcode::JEQ | Opcode::JNEQ => {
+                let taken = self.builder.create_block();
+                let next = self.builder.create_block();
+                let flag = self.get(FLAG_VAR);
+                if Opcode::from(bytes[0]) == Opcode::JEQ {
+                    self.builder.ins().brif(flag, taken, &[], next, &[]);
+                } else {
+                    self.builder.ins().brif(flag, next, &[], taken, &[]);
+                }
+                self.builder.switch_to_block(taken);
+                let target = self.reg(a);
+                self.jump_to(target);
+                self.builder.switch_to_block(next);
+            }
+            Opcode::CALL => {
+                self.guard_var(
+                    pc,
+                    DEPTH_VAR,
+                    IntCC::UnsignedGreaterThanOrEqual,
+                    MAX_CALL_DEPTH,
+                );
+                let depth = self.get(DEPTH_VAR);
+                let address = self.stack_slot(depth);
+                let back = self.builder.ins().iconst(types::I64, pc as i64 + 4);
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), back, address, CALL_STACK);
+                let depth = self.builder.ins().iadd_imm(depth, 1);
+                self.set(DEPTH_VAR, depth);
+                let target = self.reg(a);
+                self.jump_to(target);
+                return;
+            }
+            Opcode::RET => {
+                self.guard_var(pc, DEPTH_VAR, IntCC::Equal, 0);
+                let depth = self.get(DEPTH_VAR);
+                let depth = self.builder.ins().iadd_imm(depth, -1);
+                self.set(DEPTH_VAR, depth);
+                let address = self.stack_slot(depth);
+                let target =
+                    self.builder
+                        .ins()
+                        .load(types::I64, MemFlags::trusted(), address, CALL_STACK);
+                self.set(TARGET_VAR, target);
+                self.builder.ins().jump(self.dispatch, &[]);
+                return;
+            }
+            //memory, the host, masked immediates, relative jumps, HLT and anything unknown
+            _ => {
+                self.exit_at(pc);
+                return;
+            }
+        }
+        self.fall_through(i);
+    }
+
+    // carries on with the next instruction, or leaves at the end of the code
+    fn fall_through(&mut self, i: usize) {
+        match self.blocks.get(i + 1) {
+            Some(next) => {
+                self.builder.ins().jump(*next, &[]);
+            }
+            None => self.exit_at((i + 1) * 4),
+        }
+    }
+
+    // hands the instruction at pc to the interpreter
+    fn exit_at(&mut self, pc: usize) {
+        let pc = self.builder.ins().iconst(types::I64, pc as i64);
+        self.set(TARGET_VAR, pc);
+        self.builder.ins().jump(self.exit, &[]);
+    }
+
+    // jumps to the pc in a register, negative ones become huge like `as usize`
+    fn jump_to(&mut self, target: Value) {
+        let target = self.builder.ins().sextend(types::I64, target);
+        self.set(TARGET_VAR, target);
+        self.builder.ins().jump(self.dispatch, &[]);
+    }
+
+    // exits to the interpreter at pc when the register compares true against value
+    fn guard(&mut self, pc: usize, register: u8, cc: IntCC, value: i64) {
+        let operand = self.reg(register);
+        let fault = self.builder.ins().icmp_imm(cc, operand, value);
+        self.exit_if(pc, fault);
+    }
+
+    fn guard_var(&mut self, pc: usize, var: usize, cc: IntCC, value: usize) {
+        let operand = self.get(var);
+        let fault = self.builder.ins().icmp_imm(cc, operand, value as i64);
+        self.exit_if(pc, fault);
+    }
+
+    // divisors of 0 and -1
+    fn guard_signed_divisor(&mut self, pc: usize, register: u8) {
+        let divisor = self.reg(register);
+        let adjusted = self.builder.ins().iadd_imm(divisor, 1);
+        let fault = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::UnsignedLessThanOrEqual, adjusted, 1);
+        self.exit_if(pc, fault);
+    }
+
+    fn exit_if(&mut self, pc: usize, condition: Value) {
+        let fault = self.builder.create_block();
+        let ok = self.builder.create_block();
+        self.builder.set_cold_block(fault);
+        self.builder.ins().brif(condition, fault, &[], ok, &[]);
+        self.builder.switch_to_block(fault);
+        self.exit_at(pc);
+        self.builder.switch_to_block(ok);
+    }
+
+    // the pc in TARGET_VAR to its block, or out to the interpreter if it isn't one
+    fn emit_dispatch(&mut self) {
+        self.builder.switch_to_block(self.dispatch);
+        let target = self.get(TARGET_VAR);
+        let aligned = self.builder.create_block();
+        let misaligned = self.builder.ins().band_imm(target, 3);
+        self.builder
+            .ins()
+            .brif(misaligned, self.exit, &[], aligned, &[]);
+        self.builder.switch_to_block(aligned);
+        let index = self.builder.ins().ushr_imm(target, 2);
+        let mut switch = Switch::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            switch.set_entry(i as u128, *block);
+        }
+        switch.emit(&mut self.builder, index, self.exit);
+    }
+
+    // writes everything back to the state and returns the pc in TARGET_VAR
+    fn emit_exit(&mut self) {
+        self.builder.switch_to_block(self.exit);
+        for register in 0..32 {
+            let value = self.get(register);
+            self.store(value, register as i32 * 4);
+        }
+        let remainder = self.get(REMAINDER_VAR);
+        self.store(remainder, REMAINDER);
+        let flag = self.get(FLAG_VAR);
+        let flag = self.builder.ins().uextend(types::I32, flag);
+        self.store(flag, EQUAL_FLAG);
+        let depth = self.get(DEPTH_VAR);
+        self.store(depth, CALL_DEPTH);
+        let target = self.get(TARGET_VAR);
+        self.builder.ins().return_(&[target]);
+    }
+
+    fn binary<F>(&mut self, a: u8, b: u8, dst: u8, op: F)
+    where
+        F: Fn(cranelift_frontend::FuncInstBuilder, Value, Value) -> Value,
+    {
+        let (x, y) = (self.reg(a), self.reg(b));
+        let value = op(self.builder.ins(), x, y);
+        self.set(dst as usize, value);
+    }
+
+    fn unary<F>(&mut self, src: u8, dst: u8, op: F)
+    where
+        F: Fn(cranelift_frontend::FuncInstBuilder, Value) -> Value,
+    {
+        let x = self.reg(src);
+        let value = op(self.builder.ins(), x);
+        self.set(dst as usize, value);
+    }
+
+    fn float_binary<F>(&mut self, a: u8, b: u8, dst: u8, op: F)
+    where
+        F: Fn(cranelift_frontend::FuncInstBuilder, Value, Value) -> Value,
+    {
+        let (x, y) = (self.float(a), self.float(b));
+        let value = op(self.builder.ins(), x, y);
+        self.set_float(dst, value);
+    }
+
+    fn compare(&mut self, a: u8, b: u8, cc: IntCC) {
+        let (x, y) = (self.reg(a), self.reg(b));
+        let flag = self.builder.ins().icmp(cc, x, y);
+        self.set(FLAG_VAR, flag);
+    }
+
+    fn float_compare(&mut self, a: u8, b: u8, cc: FloatCC) {
+        let (x, y) = (self.float(a), self.float(b));
+        let flag = self.builder.ins().fcmp(cc, x, y);
+        self.set(FLAG_VAR, flag);
+    }
+
+    // address of the call stack entry at depth, less CALL_STACK
+    fn stack_slot(&mut self, depth: Value) -> Value {
+        let offset = self.builder.ins().imul_imm(depth, 8);
+        self.builder.ins().iadd(self.state, offset)
+    }
+
+    fn reg(&mut self, register: u8) -> Value {
+        self.get(usize::from(register))
+    }
+
+    // the f32 stored in a register's bits
+    fn float(&mut self, register: u8) -> Value {
+        let bits = self.reg(register);
+        self.builder
+            .ins()
+            .bitcast(types::F32, MemFlags::new(), bits)
+    }
+
+    fn set_float(&mut self, register: u8, value: Value) {
+        let bits = self
+            .builder
+            .ins()
+            .bitcast(types::I32, MemFlags::new(), value);
+        self.set(usize::from(register), bits);
+    }
+
+    fn declare(&mut self, var: usize, ty: Type) {
+        self.builder.declare_var(Variable::from_u32(var as u32), ty);
+    }
+
+    fn get(&mut self, var: usize) -> Value {
+        self.builder.use_var(Variable::from_u32(var as u32))
+    }
+
+    fn set(&mut self, var: usize, value: Value) {
+        self.builder.def_var(Variable::from_u32(var as u32), value);
+    }
+
+    fn load(&mut self, ty: Type, offset: i32) -> Value {
+        self.builder
+            .ins()
+            .load(ty, MemFlags::trusted(), self.state, offset)
+    }
+
+    fn store(&mut self, value: Value, offset: i32) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, self.state, offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{
+        assemble, assemble_masked, assemble_permuted, assemble_with_extensions, OpcodeExtensions,
+    };
+    use crate::module::interface::{Interface, Value};
+    use crate::vm::{Capabilities, VmError, VM};
+
+    // runs the module interpreted and natively, everything the program can observe has
+    // to come out the same
+    fn differential(module: &Module, args: &[Value]) -> Result<Vec<Value>, VmError> {
+        let mut runs = vec![];
+        for native in [false, true].iter() {
+            let mut vm = VM::new();
+            vm.register_opcode(210, |context| {
+                let register = context.register_operand(0)?;
+                context.registers[register] *= 3;
+                Ok(())
+            })
+            .unwrap();
+            vm.register_syscall(1, "double", |context| {
+                context.registers[0] *= 2;
+                Ok(())
+            });
+            if *native {
+                vm.load_jit(module).unwrap();
+                assert!(vm.jit.is_some());
+            } else {
+                vm.predecode(false);
+                vm.load_module(module);
+            }
+            vm.set_capabilities(Capabilities::new().grant("double"));
+            let result = vm.call(args);
+            //NaN results compare by their bits
+            let bits = result.as_ref().ok().map(|values| {
+                let bits: Vec<_> = values.iter().map(Value::to_register).collect();
+                bits
+            });
+            runs.push((
+                result.clone(),
+                bits,
+                vm.registers,
+                vm.remainder,
+                vm.equal_flag,
+                vm.heap.clone(),
+                vm.pc,
+                vm.call_stack.clone(),
+            ));
+        }
+        let (interpreted, native) = (&runs[0], &runs[1]);
+        assert_eq!(interpreted.0.is_ok(), native.0.is_ok());
+        assert_eq!(interpreted.0.as_ref().err(), native.0.as_ref().err());
+        assert_eq!(interpreted.1, native.1);
+        assert_eq!(&interpreted.2[..], &native.2[..]);
+        assert_eq!(
+            (
+                interpreted.3,
+                interpreted.4,
+                &interpreted.5,
+                interpreted.6,
+                &interpreted.7
+            ),
+            (native.3, native.4, &native.5, native.6, &native.7)
+        );
+        runs.remove(0).0
+    }
+
+    fn run(source: &str, args: &[Value]) -> Result<Vec<Value>, VmError> {
+        differential(&assemble(source).unwrap(), args)
+    }
+
+    #[test]
+    fn test_integer_opcodes() {
+        let source = ".input a i32\n.input b i32\n.output sum i32 $2\n\
+                      add $0 $1 $2\nsub $0 $1 $3\nmul $0 $1 $4\ndiv $0 $1 $5\n\
+                      mod $0 $1 $6\ndivu $0 $1 $7\nmodu $0 $1 $8\nand $0 $1 $9\n\
+                      or $0 $1 $10\nxor $0 $1 $11\nshl $0 $1 $12\nshr $0 $1 $13\n\
+                      shru $0 $1 $14\nnot $0 $15\nneg $1 $16\nmov $0 $17\ninc $17\ndec $16\n\
+                      lui $18 #65535\nload $19 #65535\nor $18 $19 $18\n\
+                      lt $0 $1\nflag $20\ngtu $0 $1\nflag $21\nbetw $0 $1 $18\nflag $22\n\
+                      lteq $1 $0\nflag $23\ngtequ $1 $0\nflag $24\nneq $0 $1\nflag $25\nhlt\n";
+        for (a, b) in [
+            (100, 7),
+            (-100, 7),
+            (7, -100),
+            (i32::MIN, 3),
+            (5, 33),
+            (0, 1),
+        ]
+        .iter()
+        {
+            let result = run(source, &[Value::I32(*a), Value::I32(*b)]);
+            assert_eq!(result, Ok(vec![Value::I32(a.wrapping_add(*b))]));
+        }
+    }
+
+    #[test]
+    fn test_division_faults() {
+        let source = ".input a i32\n.input b i32\n.output quotient i32 $2\n\
+                      div $0 $1 $2\nmod $0 $1 $3\nhlt\n";
+        let args = |a, b| [Value::I32(a), Value::I32(b)];
+        assert_eq!(run(source, &args(7, 0)), Err(VmError::DivideByZero));
+        //i32::MIN / -1 wraps instead of trapping
+        assert_eq!(
+            run(source, &args(i32::MIN, -1)),
+            Ok(vec![Value::I32(i32::MIN)])
+        );
+        assert_eq!(run(source, &args(-9, -1)), Ok(vec![Value::I32(9)]));
+        let unsigned = ".input a i32\n.input b i32\n.output quotient i32 $2\n\
+                        divu $0 $1 $2\nhlt\n";
+        assert_eq!(run(unsigned, &args(7, 0)), Err(VmError::DivideByZero));
+        assert_eq!(run(unsigned, &args(-1, 2)), Ok(vec![Value::I32(i32::MAX)]));
+    }
+
+    #[test]
+    fn test_float_opcodes() {
+        let source = ".input x f32\n.input y f32\n.output sum f32 $2\n\
+                      fadd $0 $1 $2\nfsub $0 $1 $3\nfmul $0 $1 $4\nfdiv $0 $1 $5\n\
+                      fneg $0 $6\nftoi $0 $7\nftou $0 $8\nitof $7 $9\nutof $8 $10\n\
+                      flt $0 $1\nflag $11\nfgt $0 $1\nflag $12\nflteq $0 $1\nflag $13\n\
+                      fgteq $0 $1\nflag $14\nfeq $0 $1\nflag $15\nfneq $0 $1\nflag $16\nhlt\n";
+        let values = [
+            (1.5, 2.25),
+            (-3.75, 0.0),
+            (1e20, -1e20),
+            (-1.0, f32::INFINITY),
+            (f32::NAN, 1.0),
+            (4e9, 2.0),
+        ];
+        for (x, y) in values.iter() {
+            let result = run(source, &[Value::F32(*x), Value::F32(*y)]).unwrap();
+            assert_eq!(result[0].to_register(), Value::F32(x + y).to_register());
+        }
+    }
+
+    #[test]
+    fn test_calls_and_jumps() {
+        //sum of 1..n by recursion
+        let source = ".input n i32\n.output sum i32 $1\n\
+                      load $1 #0\nload $5 @sum\nload $6 @end\ncall $5\njmp $6\n\
+                      sum: load $7 #0\neq $0 $7\njeq $6\nadd $1 $0 $1\ndec $0\ncall $5\nret\n\
+                      end: hlt\n";
+        assert_eq!(run(source, &[Value::I32(100)]), Ok(vec![Value::I32(5050)]));
+        //one call too many
+        assert_eq!(
+            run(source, &[Value::I32(2000)]),
+            Err(VmError::CallStackOverflow)
+        );
+        assert_eq!(run("ret\n", &[]), Err(VmError::CallStackUnderflow));
+
+        //relative, misaligned, negative and past the end targets go to the interpreter
+        let source = ".input target i32\n.output value i32 $1\n\
+                      load $2 #6\njmpf $2\nhlt\nload $1 #7\njmp $0\nload $1 #9\nhlt\n";
+        for target in [20, 21, 22, -4, 400, 28].iter() {
+            differential(&assemble(source).unwrap(), &[Value::I32(*target)]).ok();
+        }
+    }
+
+    #[test]
+    fn test_host_and_memory() {
+        let source = ".input n i32\n.output total i32 $3\n\
+                      load $1 #16\naloc $1\nload $2 #0\nload $3 #0\nload $4 @fill\n\
+                      fill: stb $2 $2\ninc $2\nlt $2 $1\njeq $4\n\
+                      load $2 #4\nldw $2 $3\nmlen $2 $5\nadd $3 $5 $3\nstw $3 $2\nldb $2 $6\n\
+                      add $3 $6 $3\nmov $3 $0\nsyscall #1\nmov $0 $3\ntriple $3\nhlt\n";
+        let mut extensions = OpcodeExtensions::new();
+        extensions.register("triple", 210).unwrap();
+        let module = assemble_with_extensions(source, &extensions).unwrap();
+        assert!(differential(&module, &[Value::I32(1)]).is_ok());
+        //unmapped addresses fault the same
+        let source = "lui $1 #65535\nldb $1 $2\nhlt\n";
+        assert_eq!(run(source, &[]), Err(VmError::InvalidAddress(0xffff_0000)));
+    }
+
+    #[test]
+    fn test_masked_and_permuted() {
+        let source = ".input n i32\n.output sum i32 $1\n\
+                      load $1 #0\nload $2 #1\nload $3 @top\n\
+                      top: add $1 $2 $1\ninc $2\nlteq $2 $0\njeq $3\nload $4 #4919\nhlt\n";
+        let expected = Ok(vec![Value::I32(5050)]);
+        let masked = assemble_masked(source, 5).unwrap();
+        assert_eq!(differential(&masked, &[Value::I32(100)]), expected);
+        let permuted = assemble_permuted(source, 9, &[2; 32]).unwrap();
+        assert_eq!(differential(&permuted, &[Value::I32(100)]), expected);
+    }
+
+    #[test]
+    fn test_random_programs() {
+        use crate::instructions::Opcode::*;
+
+        let opcodes = [
+            ADD, SUB, MUL, DIV, MOD, DIVU, MODU, AND, OR, XOR, SHL, SHR, SHRU, NOT, NEG, MOV, INC,
+            DEC, FLAG, EQ, NEQ, GT, LT, GTEQ, LTEQ, BETW, LTU, GTU, LTEQU, GTEQU, FADD, FSUB, FMUL,
+            FDIV, FNEG, FLT, FGT, FLTEQ, FGTEQ, FEQ, FNEQ, ITOF, UTOF, FTOI, FTOU, LOAD, LUI, NOP,
+        ];
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for _ in 0..16 {
+            let mut code = vec![];
+            for register in 0..32 {
+                let value = random();
+                let [high, upper, lower, low] = (value as u32).to_be_bytes();
+                code.extend_from_slice(&[LUI as u8, register, high, upper]);
+                code.extend_from_slice(&[LOAD as u8, 31, lower, low]);
+                code.extend_from_slice(&[OR as u8, register, 31, register]);
+            }
+            for _ in 0..64 {
+                let value = random();
+                let opcode = opcodes[value as usize % opcodes.len()];
+                let registers = [(value >> 8) as u8 % 32, (value >> 16) as u8 % 32];
+                code.extend_from_slice(&[
+                    opcode as u8,
+                    registers[0],
+                    registers[1],
+                    (value >> 24) as u8 % 32,
+                ]);
+            }
+            code.extend_from_slice(&[HLT as u8, 0, 0, 0]);
+            differential(&Module::new(code, Interface::new()), &[]).ok();
+        }
+    }
+
+    #[test]
+    fn test_unverified_code() {
+        let module = Module::new(vec![Opcode::INC as u8, 40, 0, 0], Interface::new());
+        assert_eq!(
+            JitProgram::compile(&module).err(),
+            Some(JitError::Verify(VerifyError::InvalidRegister {
+                pc: 0,
+                register: 40
+            }))
+        );
+    }
+
+    #[test]
+    fn test_integrity_refuses_jit() {
+        let module = assemble("load $0 #1\nhlt\n").unwrap();
+        let mut vm = VM::new();
+        vm.load_jit(&module).unwrap();
+        assert!(vm.jit.is_some());
+        //turning integrity on drops the native code, and none is compiled after that
+        vm.enable_integrity(&[4; 32]);
+        assert!(vm.jit.is_none());
+        assert_eq!(vm.load_jit(&module), Err(JitError::Integrity));
+        assert!(vm.jit.is_none());
+    }
+}
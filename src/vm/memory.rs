@@ -0,0 +1,162 @@
+// Layout of the vm address space that LDB/STB/MLEN work on.
+// The top 8 bits of an address pick the region and the low 24 bits are the offset into it:
+//   0x00xxxxxx  the heap grown with ALOC
+//   0x01xxxxxx - 0x0Fxxxxxx  read only input buffers 0-14 supplied by the host
+//   0x10xxxxxx - 0x1Fxxxxxx  writable output buffers 0-15 supplied by the host
+//   0x20xxxxxx  the module's read only data section
+// `lui $r #0x0100` puts the base address of input buffer 0 into a register.
+
+use zeroize::Zeroize;
+
+/// how far the region number is shifted up inside an address
+pub const REGION_SHIFT: u32 = 24;
+/// region number of the first input buffer
+pub const INPUT_REGION: u32 = 0x01;
+/// region number of the first output buffer
+pub const OUTPUT_REGION: u32 = 0x10;
+/// region number of the data section
+pub const DATA_REGION: u32 = 0x20;
+pub const MAX_INPUTS: usize = (OUTPUT_REGION - INPUT_REGION) as usize;
+pub const MAX_OUTPUTS: usize = 16;
+/// the most ALOC can grow the heap to, past it nothing would be addressable
+pub const MAX_HEAP: usize = 1 << REGION_SHIFT;
+/// the largest output buffer, the offset part of an address can't reach further
+pub const MAX_BUFFER: usize = 1 << REGION_SHIFT;
+
+const OFFSET_MASK: u32 = (1 << REGION_SHIFT) - 1;
+
+/// Which piece of memory an address lands in
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Region {
+    Heap(usize),
+    Input { index: usize, offset: usize },
+    Output { index: usize, offset: usize },
+    Data(usize),
+    Unmapped,
+}
+
+impl Region {
+    /// Splits an address up into its region and offset
+    pub fn decode(address: u32) -> Region {
+        let region = address >> REGION_SHIFT;
+        let offset = (address & OFFSET_MASK) as usize;
+        if region == 0 {
+            Region::Heap(offset)
+        } else if region < OUTPUT_REGION {
+            Region::Input {
+                index: (region - INPUT_REGION) as usize,
+                offset,
+            }
+        } else if region < OUTPUT_REGION + MAX_OUTPUTS as u32 {
+            Region::Output {
+                index: (region - OUTPUT_REGION) as usize,
+                offset,
+            }
+        } else if region == DATA_REGION {
+            Region::Data(offset)
+        } else {
+            Region::Unmapped
+        }
+    }
+}
+
+/// base address of input buffer `index`
+pub fn input_address(index: usize) -> u32 {
+    (INPUT_REGION + index as u32) << REGION_SHIFT
+}
+
+/// base address of output buffer `index`
+pub fn output_address(index: usize) -> u32 {
+    (OUTPUT_REGION + index as u32) << REGION_SHIFT
+}
+
+/// A writable buffer the host hands to the vm, remembering how far the program wrote into it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OutputBuffer {
+    bytes: Vec<u8>,
+    written: usize,
+}
+
+impl OutputBuffer {
+    pub fn new(capacity: usize) -> OutputBuffer {
+        OutputBuffer {
+            bytes: vec![0; capacity],
+            written: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// stores a byte, returning false when the offset is past the capacity
+    pub fn write(&mut self, offset: usize, byte: u8) -> bool {
+        match self.bytes.get_mut(offset) {
+            Some(slot) => {
+                *slot = byte;
+                self.written = self.written.max(offset + 1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn read(&self, offset: usize) -> Option<u8> {
+        self.bytes.get(offset).cloned()
+    }
+
+    /// everything up to the furthest byte the program wrote
+    pub fn written(&self) -> &[u8] {
+        &self.bytes[..self.written]
+    }
+}
+
+impl Zeroize for OutputBuffer {
+    fn zeroize(&mut self) {
+        self.bytes.zeroize();
+        self.written = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_regions() {
+        assert_eq!(Region::decode(12), Region::Heap(12));
+        assert_eq!(
+            Region::decode(input_address(0) + 3),
+            Region::Input {
+                index: 0,
+                offset: 3
+            }
+        );
+        assert_eq!(
+            Region::decode(input_address(14)),
+            Region::Input {
+                index: 14,
+                offset: 0
+            }
+        );
+        assert_eq!(
+            Region::decode(output_address(15) + 1),
+            Region::Output {
+                index: 15,
+                offset: 1
+            }
+        );
+        assert_eq!(Region::decode(0x2000_0005), Region::Data(5));
+        assert_eq!(Region::decode(0x2100_0000), Region::Unmapped);
+    }
+
+    #[test]
+    fn test_output_buffer_written() {
+        let mut buffer = OutputBuffer::new(4);
+        assert!(buffer.write(2, 7));
+        assert!(!buffer.write(4, 1));
+        //bytes before the furthest write are included even if never set
+        assert_eq!(buffer.written(), &[0, 0, 7]);
+        assert_eq!(buffer.capacity(), 4);
+    }
+}
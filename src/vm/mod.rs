@@ -0,0 +1,2718 @@
+use crate::crypt::{Key, Keystream};
+use crate::instructions::{Opcode, CUSTOM_OPCODES};
+use crate::module::interface::{Interface, Location, ParamType, Value};
+use crate::module::masks::Masks;
+use crate::module::permutation;
+use crate::module::signature::{self, TrustedKeys};
+use crate::module::source_map::SourceMap;
+use crate::module::{Module, ModuleError, SealedModule};
+use crate::verify::{self, VerifyError};
+
+pub mod debugger;
+mod decoded;
+mod integrity;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod memory;
+pub mod trace;
+
+use self::decoded::DecodedInstr;
+use self::integrity::Integrity;
+#[cfg(feature = "jit")]
+use self::jit::{JitError, JitProgram};
+use self::memory::{OutputBuffer, Region, MAX_BUFFER, MAX_HEAP, MAX_INPUTS, MAX_OUTPUTS};
+use self::trace::{Trace, TraceOptions};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use zeroize::Zeroize;
+
+/// Errors that stop the vm from executing any further
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    /// tried to bind a handler to an opcode outside of the 200-249 host range
+    ReservedOpcode(u8),
+    /// an instruction referenced a register index past the 32 available
+    InvalidRegister(u8),
+    /// a host opcode handler gave up with its own message
+    Custom(String),
+    /// SYSCALL asked for a number the host never registered
+    UnknownSyscall(u16),
+    /// SYSCALL asked for a host function this program was not granted
+    CapabilityDenied(String),
+    /// a load or store went outside of every mapped region
+    InvalidAddress(u32),
+    /// a store targeted one of the read only input buffers
+    ReadOnlyAddress(u32),
+    /// the host tried to map more buffers than the address space has regions for
+    TooManyBuffers,
+    /// a call passed a different number of arguments than the module declares
+    ArityMismatch { expected: usize, found: usize },
+    /// DIV, MOD, DIVU or MODU with a zero divisor
+    DivideByZero,
+    /// CALL nested deeper than MAX_CALL_DEPTH
+    CallStackOverflow,
+    /// RET without a matching CALL
+    CallStackUnderflow,
+    /// a call argument doesn't have the type the module declares for it
+    TypeMismatch {
+        name: String,
+        expected: ParamType,
+        found: ParamType,
+    },
+    /// the program bank no longer matches the MAC taken when it was loaded
+    IntegrityViolation,
+    /// the program ends part way through an instruction, at the byte it needed next
+    Truncated(usize),
+    /// ALOC by that many bytes would take the heap below empty or past MAX_HEAP
+    BadAllocation(i32),
+    /// an output buffer of that many bytes is bigger than memory::MAX_BUFFER
+    BufferTooLarge(usize),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::ReservedOpcode(code) => write!(
+                f,
+                "opcode {} is not in the host extension range {}-{}",
+                code,
+                CUSTOM_OPCODES.start(),
+                CUSTOM_OPCODES.end()
+            ),
+            VmError::InvalidRegister(reg) => write!(f, "register ${} does not exist", reg),
+            VmError::Custom(msg) => write!(f, "{}", msg),
+            VmError::UnknownSyscall(number) => write!(f, "no syscall registered as #{}", number),
+            VmError::CapabilityDenied(name) => {
+                write!(f, "program was not granted the '{}' syscall", name)
+            }
+            VmError::InvalidAddress(address) => {
+                write!(f, "address {:#010x} is not mapped", address)
+            }
+            VmError::ReadOnlyAddress(address) => {
+                write!(
+                    f,
+                    "address {:#010x} is in a read only input buffer",
+                    address
+                )
+            }
+            VmError::TooManyBuffers => write!(f, "no free region left to map the buffer into"),
+            VmError::DivideByZero => write!(f, "division by zero"),
+            VmError::CallStackOverflow => {
+                write!(f, "calls nested deeper than {}", MAX_CALL_DEPTH)
+            }
+            VmError::CallStackUnderflow => write!(f, "RET without a CALL to return to"),
+            VmError::ArityMismatch { expected, found } => {
+                write!(
+                    f,
+                    "module takes {} inputs but {} were given",
+                    expected, found
+                )
+            }
+            VmError::TypeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "input '{}' is declared as {} but a {} was given",
+                name,
+                expected.name(),
+                found.name()
+            ),
+            VmError::IntegrityViolation => {
+                write!(f, "program was modified after it was loaded")
+            }
+            VmError::BadAllocation(bytes) => write!(
+                f,
+                "can't grow the heap by {} bytes, it holds 0 to {}",
+                bytes, MAX_HEAP
+            ),
+            VmError::BufferTooLarge(capacity) => write!(
+                f,
+                "an output buffer of {} bytes is bigger than {}",
+                capacity, MAX_BUFFER
+            ),
+            VmError::Truncated(position) => {
+                write!(
+                    f,
+                    "program ends at {:#06x} part way through an instruction",
+                    position
+                )
+            }
+        }
+    }
+}
+
+impl Error for VmError {}
+
+/// What a host opcode handler gets to work with while its instruction runs
+pub struct OpcodeContext<'a> {
+    /// the three operand bytes following the opcode byte
+    pub operands: [u8; 3],
+    pub registers: &'a mut [i32; 32],
+    pub heap: &'a mut Vec<u8>,
+}
+
+impl<'a> OpcodeContext<'a> {
+    /// Resolves operand `index` (0-2) as a register number, checking it is in range
+    pub fn register_operand(&self, index: usize) -> Result<usize, VmError> {
+        let reg = self.operands[index];
+        if usize::from(reg) < self.registers.len() {
+            Ok(usize::from(reg))
+        } else {
+            Err(VmError::InvalidRegister(reg))
+        }
+    }
+
+    /// The last two operand bytes read as one 16 bit integer, the same way LOAD reads them
+    pub fn operand_16(&self) -> u16 {
+        (u16::from(self.operands[1]) << 8) | u16::from(self.operands[2])
+    }
+}
+
+/// How many CALLs can be waiting on a RET at once
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+/// A host supplied implementation of a custom opcode or syscall
+pub type OpcodeHandler = Box<dyn FnMut(&mut OpcodeContext) -> Result<(), VmError>>;
+
+/// A host function bytecode can reach with `SYSCALL #n`
+struct Syscall {
+    name: String,
+    handler: OpcodeHandler,
+}
+
+/// The set of syscalls (by name) the host lets the loaded program use.
+/// Nothing is granted by default.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Capabilities {
+    granted: HashSet<String>,
+}
+
+impl Capabilities {
+    pub fn new() -> Capabilities {
+        Capabilities {
+            granted: HashSet::new(),
+        }
+    }
+
+    /// Allows the syscall registered under `name`
+    pub fn grant(mut self, name: &str) -> Capabilities {
+        self.granted.insert(name.to_string());
+        self
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        self.granted.contains(name)
+    }
+}
+
+/// this is the definition of our vm
+pub struct VM {
+    // the vm has 32bit wide registers
+    registers: [i32; 32],
+    // program counter
+    pc: usize,
+    // program bytecode stored as a vector of bytes
+    program: Vec<u8>,
+    // for sealed modules, what the program is encrypted with. Bytes are decrypted one
+    // at a time as they are fetched.
+    keystream: Option<Keystream>,
+    // opcode byte in the program to the opcode it stands for, see module::permutation
+    opcodes: [u8; 256],
+    // the module's data section, still masked. Bytes are unmasked as they are read.
+    data: Vec<u8>,
+    // what LOADM immediates and the data are masked with, see module::masks
+    masks: Option<Masks>,
+    // the program decoded once at load, one entry per 4 byte instruction. Empty when
+    // the bytes are interpreted directly, see vm::decoded
+    decoded: Vec<DecodedInstr>,
+    // whether loading a plain module pre-decodes it
+    predecode: bool,
+    // native code for the program, see VM::load_jit
+    #[cfg(feature = "jit")]
+    jit: Option<JitProgram>,
+    //our heap allocated pretend MEMORY for the vm.
+    heap: Vec<u8>,
+    // read only byte buffers the host mapped in as inputs
+    inputs: Vec<Vec<u8>>,
+    // writable byte buffers the host mapped in for the results
+    outputs: Vec<OutputBuffer>,
+    // the remainder attribute left over from division ops
+    remainder: u32,
+    // Dedicated flag register for the result of the last comparison operation
+    equal_flag: bool,
+    // host handlers for opcodes in the 200-249 range keyed by opcode byte
+    custom_opcodes: HashMap<u8, OpcodeHandler>,
+    // host functions reachable through SYSCALL keyed by syscall number
+    syscalls: HashMap<u16, Syscall>,
+    // which of the syscalls the loaded module may actually call, reset on every load
+    capabilities: Capabilities,
+    // declared inputs and outputs of the loaded module
+    interface: Interface,
+    // signers the container loads accept modules from, None to load unsigned modules
+    trusted_keys: Option<TrustedKeys>,
+    // MAC of the program bank for detecting patches, see VM::enable_integrity
+    integrity: Option<Integrity>,
+    // integrity check settings, kept here so they apply whenever integrity gets enabled
+    integrity_before_run: bool,
+    integrity_interval: u64,
+    // return addresses of the CALLs that haven't hit their RET yet
+    call_stack: Vec<usize>,
+    // the loaded module's source map, for fault reports
+    source_map: Option<SourceMap>,
+    // where the instruction that last failed starts
+    fault_pc: Option<usize>,
+    // the trace being recorded, see vm::trace
+    trace: Option<Trace>,
+    // whether halting, faults and pc overflows are printed to stdout, see VM::set_verbose
+    verbose: bool,
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
+}
+
+/// implementation of the vm
+impl VM {
+    /// Default values for a new VM
+    pub fn new() -> VM {
+        VM {
+            //fill the default values for the registers, program bytecode, and program counter
+            registers: [0; 32],
+            program: vec![],
+            keystream: None,
+            opcodes: permutation::identity(),
+            data: vec![],
+            masks: None,
+            decoded: vec![],
+            predecode: true,
+            #[cfg(feature = "jit")]
+            jit: None,
+            heap: vec![],
+            inputs: vec![],
+            outputs: vec![],
+            pc: 0,
+            remainder: 0,
+            equal_flag: false,
+            custom_opcodes: HashMap::new(),
+            syscalls: HashMap::new(),
+            capabilities: Capabilities::new(),
+            interface: Interface::new(),
+            trusted_keys: None,
+            integrity: None,
+            integrity_before_run: false,
+            integrity_interval: 0,
+            call_stack: vec![],
+            source_map: None,
+            fault_pc: None,
+            trace: None,
+            verbose: false,
+        }
+    }
+
+    /// Replaces the program bank with the module's code and remembers its interface
+    pub fn load_module(&mut self, module: &Module) {
+        self.program = module.code.clone();
+        self.keystream = None;
+        self.opcodes = match &module.permutation {
+            Some(permutation) => *permutation.decode_table(),
+            None => permutation::identity(),
+        };
+        self.data = module.data.clone();
+        self.masks = module.masks.clone();
+        self.interface = module.interface.clone();
+        self.source_map = module.source_map.clone();
+        //grants were for the module that was loaded before
+        self.capabilities = Capabilities::new();
+        self.pc = 0;
+        self.update_integrity();
+        self.update_decoded();
+        self.drop_jit();
+    }
+
+    /// Verifies the module's code (see verify) and loads it if it passes, otherwise the
+    /// loaded program is left alone. Unverified code is still checked as it runs, a bad
+    /// register or operand fails the run with a VmError, but only once it's reached and
+    /// after everything before it has run. Verifying finds it before anything runs.
+    pub fn load_verified(&mut self, module: &Module) -> Result<(), VerifyError> {
+        verify::verify_module(module)?;
+        self.load_module(module);
+        Ok(())
+    }
+
+    /// Verifies the module and compiles it to native code (see vm::jit) on top of loading
+    /// it. run, try_run and call then go through the native code wherever they can.
+    /// Fails with JitError::Integrity when integrity checks are on, see enable_integrity.
+    #[cfg(feature = "jit")]
+    pub fn load_jit(&mut self, module: &Module) -> Result<(), JitError> {
+        if self.integrity.is_some() {
+            return Err(JitError::Integrity);
+        }
+        let program = JitProgram::compile(module)?;
+        self.load_module(module);
+        self.jit = Some(program);
+        Ok(())
+    }
+
+    /// Loads a module whose code stays encrypted, each byte is decrypted only when it's
+    /// fetched. Slower than load_module but the plain code is never in memory as a whole.
+    pub fn load_sealed(&mut self, module: &SealedModule) {
+        self.program = module.code.clone();
+        self.keystream = Some(module.keystream().clone());
+        self.opcodes = match &module.permutation {
+            Some(permutation) => *permutation.decode_table(),
+            None => permutation::identity(),
+        };
+        self.data = module.data.clone();
+        self.masks = module.masks.clone();
+        self.interface = module.interface.clone();
+        self.source_map = None;
+        self.capabilities = Capabilities::new();
+        self.pc = 0;
+        self.update_integrity();
+        self.update_decoded();
+        self.drop_jit();
+    }
+
+    /// Parses a module container (like the bytes biobox_asm! produces) and loads it
+    pub fn load_module_bytes(&mut self, bytes: &[u8]) -> Result<(), ModuleError> {
+        self.check_signature(bytes)?;
+        let module = Module::from_bytes(bytes)?;
+        self.load_module(&module);
+        Ok(())
+    }
+
+    /// Opens a container made with Module::encrypt or a permutation with the key and
+    /// loads it, the signature is checked first like load_module_bytes does
+    pub fn load_encrypted_bytes(&mut self, bytes: &[u8], key: &Key) -> Result<(), ModuleError> {
+        self.check_signature(bytes)?;
+        let module = Module::decrypt(bytes, key)?;
+        self.load_module(&module);
+        Ok(())
+    }
+
+    /// load_sealed for a container, see Module::decrypt_sealed. The signature is checked
+    /// first like load_module_bytes does.
+    pub fn load_sealed_bytes(&mut self, bytes: &[u8], key: &Key) -> Result<(), ModuleError> {
+        self.check_signature(bytes)?;
+        let module = Module::decrypt_sealed(bytes, key)?;
+        self.load_sealed(&module);
+        Ok(())
+    }
+
+    /// Makes load_module_bytes, load_encrypted_bytes and load_sealed_bytes refuse
+    /// containers that aren't signed by one of the keys. Modules handed to load_module
+    /// and load_sealed are taken as already checked, so a container opened with
+    /// Module::decrypt by hand skips the check.
+    pub fn require_signature(&mut self, trusted_keys: TrustedKeys) {
+        self.trusted_keys = Some(trusted_keys);
+    }
+
+    // the container has to be signed by a trusted key once signatures are required
+    fn check_signature(&self, bytes: &[u8]) -> Result<(), ModuleError> {
+        if let Some(trusted_keys) = &self.trusted_keys {
+            signature::verify(bytes, trusted_keys)?;
+        }
+        Ok(())
+    }
+
+    /// Keeps a MAC of the program bank under the key, taken now and whenever a program
+    /// is loaded, so patches to the bank can be caught with verify_integrity.
+    ///
+    /// The MAC only covers the bytes, so from now on the bytes are what runs: the program
+    /// isn't pre-decoded anymore (see predecode) and native code from load_jit is dropped,
+    /// with later load_jit calls failing. Expect byte interpreter speed.
+    pub fn enable_integrity(&mut self, key: &Key) {
+        let mut integrity = Integrity::new(key);
+        integrity.set_before_run(self.integrity_before_run);
+        integrity.set_interval(self.integrity_interval);
+        self.integrity = Some(integrity);
+        self.update_integrity();
+        //the MAC covers the bytes, so those are what runs from now on
+        self.update_decoded();
+        self.drop_jit();
+    }
+
+    /// Turns pre-decoding plain programs on or off (it's on by default), re-decoding or
+    /// dropping the loaded program to match. Off leaves every step to the byte interpreter.
+    /// Sealed programs and integrity checked ones are never pre-decoded.
+    pub fn predecode(&mut self, enabled: bool) {
+        self.predecode = enabled;
+        self.update_decoded();
+    }
+
+    /// Checks the bank before every run once enable_integrity has been called, before or
+    /// after this
+    pub fn verify_before_run(&mut self, enabled: bool) {
+        self.integrity_before_run = enabled;
+        if let Some(integrity) = &mut self.integrity {
+            integrity.set_before_run(enabled);
+        }
+    }
+
+    /// Checks the bank every so many executed instructions, 0 turns it off. Like
+    /// verify_before_run it takes effect once enable_integrity has been called.
+    pub fn verify_every(&mut self, instructions: u64) {
+        self.integrity_interval = instructions;
+        if let Some(integrity) = &mut self.integrity {
+            integrity.set_interval(instructions);
+        }
+    }
+
+    /// Compares the program bank against the MAC taken when it was loaded, Ok if
+    /// integrity checks aren't enabled
+    pub fn verify_integrity(&self) -> Result<(), VmError> {
+        match &self.integrity {
+            Some(integrity) => integrity.verify(&self.program, &self.opcodes, &self.data),
+            None => Ok(()),
+        }
+    }
+
+    // retakes the MAC after the bank was changed through the vm
+    fn update_integrity(&mut self) {
+        if let Some(integrity) = &mut self.integrity {
+            integrity.update(&self.program, &self.opcodes, &self.data);
+        }
+    }
+
+    // decodes the program bank again, unless it's sealed or its integrity is checked
+    fn update_decoded(&mut self) {
+        self.decoded = if self.predecode && self.keystream.is_none() && self.integrity.is_none() {
+            decoded::decode(&self.program, &self.opcodes)
+        } else {
+            vec![]
+        };
+    }
+
+    // native code was compiled for a program that isn't loaded anymore
+    fn drop_jit(&mut self) {
+        #[cfg(feature = "jit")]
+        {
+            self.jit = None;
+        }
+    }
+
+    // the check verify_before_run turns on
+    fn verify_before_run_check(&self) -> Result<(), VmError> {
+        match &self.integrity {
+            Some(integrity) if integrity.before_run() => self.verify_integrity(),
+            _ => Ok(()),
+        }
+    }
+
+    /// The declared interface of the loaded module
+    pub fn interface(&self) -> &Interface {
+        &self.interface
+    }
+
+    /// Calls the loaded module with one argument per declared input, in declaration order.
+    /// The vm state is reset first and the declared outputs are returned in order.
+    pub fn call(&mut self, args: &[Value]) -> Result<Vec<Value>, VmError> {
+        self.fault_pc = None;
+        if args.len() != self.interface.inputs.len() {
+            return Err(VmError::ArityMismatch {
+                expected: self.interface.inputs.len(),
+                found: args.len(),
+            });
+        }
+        for (param, arg) in self.interface.inputs.iter().zip(args) {
+            if param.ty != arg.ty() {
+                return Err(VmError::TypeMismatch {
+                    name: param.name.clone(),
+                    expected: param.ty,
+                    found: arg.ty(),
+                });
+            }
+        }
+
+        //interfaces read from a container are checked, ones built by hand may not be
+        let registers = self.interface.inputs.iter().chain(&self.interface.outputs);
+        for param in registers {
+            if let Location::Register(r) = param.location {
+                if usize::from(r) >= self.registers.len() {
+                    return Err(VmError::InvalidRegister(r));
+                }
+            }
+        }
+
+        self.clear_registers();
+        self.clear_buffers();
+        self.heap.clear();
+        self.remainder = 0;
+        self.equal_flag = false;
+        let interface = self.interface.clone();
+        for (param, arg) in interface.inputs.iter().zip(args) {
+            match (param.location, arg) {
+                (Location::Register(r), _) => {
+                    self.registers[usize::from(r)] = arg.to_register().unwrap_or(0)
+                }
+                (Location::Buffer { .. }, Value::Bytes(bytes)) => {
+                    self.map_input(bytes)?;
+                }
+                (Location::Buffer { .. }, _) => {}
+            }
+        }
+        for param in &interface.outputs {
+            if let Location::Buffer { capacity, .. } = param.location {
+                self.map_output(capacity as usize)?;
+            }
+        }
+
+        self.execute()?;
+        Ok(interface
+            .outputs
+            .iter()
+            .map(|param| match param.location {
+                Location::Register(r) => {
+                    Value::from_register(param.ty, self.registers[usize::from(r)])
+                }
+                Location::Buffer { index, .. } => {
+                    Value::Bytes(self.output(usize::from(index)).unwrap_or(&[]).to_vec())
+                }
+            })
+            .collect())
+    }
+
+    /// Binds a host closure to one of the free opcodes in the 200-249 range.
+    /// Registering the same code twice replaces the previous handler.
+    pub fn register_opcode<F>(&mut self, code: u8, handler: F) -> Result<(), VmError>
+    where
+        F: FnMut(&mut OpcodeContext) -> Result<(), VmError> + 'static,
+    {
+        if !CUSTOM_OPCODES.contains(&code) {
+            return Err(VmError::ReservedOpcode(code));
+        }
+        self.custom_opcodes.insert(code, Box::new(handler));
+        Ok(())
+    }
+
+    /// Binds a host function to `SYSCALL #number`. Arguments and results are passed in
+    /// the registers, and the program still needs the `name` capability to call it.
+    pub fn register_syscall<F>(&mut self, number: u16, name: &str, handler: F)
+    where
+        F: FnMut(&mut OpcodeContext) -> Result<(), VmError> + 'static,
+    {
+        self.syscalls.insert(
+            number,
+            Syscall {
+                name: name.to_string(),
+                handler: Box::new(handler),
+            },
+        );
+    }
+
+    /// Looks up the number a syscall was registered under by its name
+    pub fn syscall_number(&self, name: &str) -> Option<u16> {
+        self.syscalls
+            .iter()
+            .find(|(_, syscall)| syscall.name == name)
+            .map(|(number, _)| *number)
+    }
+
+    /// Replaces the capabilities granted to the program that is loaded. They only last
+    /// until the next module is loaded, which starts out with nothing granted.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Maps a read only copy of `bytes` into the address space, returning its base address
+    pub fn map_input(&mut self, bytes: &[u8]) -> Result<u32, VmError> {
+        if self.inputs.len() >= MAX_INPUTS {
+            return Err(VmError::TooManyBuffers);
+        }
+        self.inputs.push(bytes.to_vec());
+        Ok(memory::input_address(self.inputs.len() - 1))
+    }
+
+    /// Maps a zeroed writable buffer of `capacity` bytes, returning its base address.
+    /// At most memory::MAX_BUFFER bytes, the most an address can reach into.
+    pub fn map_output(&mut self, capacity: usize) -> Result<u32, VmError> {
+        if self.outputs.len() >= MAX_OUTPUTS {
+            return Err(VmError::TooManyBuffers);
+        }
+        if capacity > MAX_BUFFER {
+            return Err(VmError::BufferTooLarge(capacity));
+        }
+        self.outputs.push(OutputBuffer::new(capacity));
+        Ok(memory::output_address(self.outputs.len() - 1))
+    }
+
+    /// The bytes the program has written to output buffer `index` so far
+    pub fn output(&self, index: usize) -> Option<&[u8]> {
+        self.outputs.get(index).map(|buffer| buffer.written())
+    }
+
+    /// Unmaps every input and output buffer
+    pub fn clear_buffers(&mut self) {
+        self.inputs.clear();
+        self.outputs.clear();
+    }
+
+    /// Runs the loaded program from the start until it halts and returns the bytes it
+    /// wrote to each output buffer, in the order they were mapped
+    pub fn execute(&mut self) -> Result<Vec<Vec<u8>>, VmError> {
+        self.pc = 0;
+        self.call_stack.clear();
+        self.try_run()?;
+        Ok(self
+            .outputs
+            .iter()
+            .map(|buffer| buffer.written().to_vec())
+            .collect())
+    }
+
+    /// Loops as long as instructions can be executed, printing any error when verbose
+    pub fn run(&mut self) {
+        self.fault_pc = None;
+        if let Err(e) = self.verify_before_run_check() {
+            if self.verbose {
+                println!("\n\nVM error: {}\n", e);
+            }
+            return;
+        }
+        loop {
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    if self.verbose {
+                        println!("\n\nVM error: {}\n", self.fault_report(&e));
+                    }
+                    break;
+                }
+            }
+        }
+
+        if self.verbose {
+            println!("\n\nReached end of execution.");
+        }
+    }
+
+    /// Prints halting, errors, pc overflows and unknown opcodes to stdout as they happen,
+    /// the way the REPL shows them. Off by default, stdout belongs to the host and to the
+    /// debug adapter when it talks over stdio.
+    pub fn set_verbose(&mut self, enabled: bool) {
+        self.verbose = enabled;
+    }
+
+    /// Same as run but hands any error back to the caller instead of printing it
+    pub fn try_run(&mut self) -> Result<(), VmError> {
+        self.fault_pc = None;
+        self.verify_before_run_check()?;
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Runs only one instruction at current program counter (usually 0) then exits.
+    /// Returns false once the program has halted, like the run loop.
+    pub fn run_once(&mut self) -> Result<bool, VmError> {
+        self.execute_instruction()
+    }
+
+    // runs native code as far as it goes, if there is any, then one instruction
+    fn step(&mut self) -> Result<bool, VmError> {
+        #[cfg(feature = "jit")]
+        {
+            //traces need every instruction interpreted
+            if let (Some(jit), None) = (&mut self.jit, &self.trace) {
+                self.pc = jit.run(
+                    self.pc,
+                    &mut self.registers,
+                    &mut self.remainder,
+                    &mut self.equal_flag,
+                    &mut self.call_stack,
+                );
+            }
+        }
+        self.execute_instruction()
+    }
+
+    /// The offset of the instruction behind the last error, None if the error didn't
+    /// come from running one
+    pub fn fault_pc(&self) -> Option<usize> {
+        self.fault_pc
+    }
+
+    /// The loaded module's source map, if it kept one (see Module::source_map)
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    /// Replaces the source map of the loaded program
+    pub fn set_source_map(&mut self, source_map: Option<SourceMap>) {
+        self.source_map = source_map;
+    }
+
+    /// The error along with the offset of the instruction that failed, and its source
+    /// line when the module has a source map
+    pub fn fault_report(&self, error: &VmError) -> String {
+        let pc = match self.fault_pc {
+            Some(pc) => pc,
+            None => return error.to_string(),
+        };
+        let source = self
+            .source_map
+            .as_ref()
+            .and_then(|source_map| source_map.describe(pc));
+        match source {
+            Some(source) => format!("{} at {:#06x}, {}", error, pc, source),
+            None => format!("{} at {:#06x}", error, pc),
+        }
+    }
+
+    /// Starts recording every instruction that runs in a trace, replacing the trace
+    /// being recorded if there is one. The jit is skipped while tracing.
+    pub fn start_trace(&mut self, options: TraceOptions) {
+        self.trace = Some(Trace::new(options));
+    }
+
+    /// The trace recorded so far
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    /// Stops tracing and hands back the trace
+    pub fn stop_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    // executes the next instruction, remembering where it started if it fails
+    fn execute_instruction(&mut self) -> Result<bool, VmError> {
+        let pc = self.pc;
+        let result = if self.trace.is_some() {
+            self.execute_traced()
+        } else {
+            self.execute_next()
+        };
+        result.inspect_err(|_| self.fault_pc = Some(pc))
+    }
+
+    /// this is run every time we need to execute the next instruction
+    fn execute_next(&mut self) -> Result<bool, VmError> {
+        // if program counter has exceeded length of the program itself, something is wrong
+        if self.pc >= self.program.len() {
+            return Ok(false);
+        }
+        if self.pc.is_multiple_of(4) {
+            if let Some(instr) = self.decoded.get(self.pc / 4) {
+                return self.execute_decoded(*instr);
+            }
+        }
+        if let Some(integrity) = &mut self.integrity {
+            if integrity.tick() {
+                integrity.verify(&self.program, &self.opcodes, &self.data)?;
+            }
+        }
+        //decode_opcode is the first 8 bits (pc +1)
+        match self.decode_opcode()? {
+            Opcode::HLT => {
+                if self.verbose {
+                    println!("\n\nHLT Encountered\n");
+                }
+                return Ok(false); //cancels out of loop to halt running
+            }
+            Opcode::NOP => {
+                //do nothing and advance to next instruction for the next loop
+                //move up 3 bytes (24 bits)
+                self.pc += 3;
+            }
+            Opcode::LOAD => {
+                let register = self.next_register()?;
+                let number = u32::from(self.next_16_bits()?);
+                self.registers[register] = number as i32; // the registers are i32s
+            }
+            Opcode::ADD => {
+                //addition opcode. stores result in the register //TODO: Maybe an overflow attribute could be stored if an overflow is detected
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1.wrapping_add(register2);
+            }
+            Opcode::SUB => {
+                //subtraction opcode. stores result in the register
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1.wrapping_sub(register2);
+            }
+            Opcode::MUL => {
+                //multiply opcode. stores result in the register
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1.wrapping_mul(register2);
+            }
+            Opcode::DIV => {
+                //divide opcode. Special Type of OPCODE. Leaves result in provided register and the remainder in the VM remainder attribute
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                if register2 == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                self.registers[self.next_register()?] = register1.wrapping_div(register2);
+                self.remainder = register1.wrapping_rem(register2) as u32;
+            }
+            Opcode::JMP => {
+                // litteral jump opcode. Jumps to the exact instruction program counter location
+                let target = self.registers[self.next_register()?];
+                self.pc = target as usize;
+            }
+            Opcode::JMPF => {
+                //relative jump opcodes (from current position) jump forward
+                let target = self.registers[self.next_register()?];
+                let result = self.pc.overflowing_add(target as usize);
+                if result.1 {
+                    //panic!("PROGRAM COUNTER OVERFLOWED! (JMPF went above usize::MAX)");
+                    //panic if program counter overflows. (It should never overflow) and print debug info
+                    if self.verbose {
+                        println!("\n\nPROGRAM COUNTER OVERFLOWED! (JMPF went above usize::MAX) at index: {} args: {}\n", self.pc, target);
+                    }
+                    return Ok(false);
+                }
+                self.pc = result.0;
+            }
+            Opcode::JMPB => {
+                //relative jump opcodes (from current position) jump back
+                let target = self.registers[self.next_register()?];
+                let result = self.pc.overflowing_sub(target as usize);
+                //second item in the tuple is a bool of wether or not the program overflowed
+                if result.1 {
+                    //panic!("PROGRAM COUNTER OVERFLOWED! (JMPB went below 0)");
+                    //panic if program counter overflows. (It should never overflow) and print debug info
+                    if self.verbose {
+                        println!("\n\nPROGRAM COUNTER OVERFLOWED! (JMPB went below 0) at index: {} args: {}\n", self.pc, target);
+                    }
+                    return Ok(false);
+                }
+                self.pc = result.0;
+            }
+            Opcode::EQ => {
+                //equal comparison operator
+                //get the contents of the first two registers (16 bits total)
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                //set the equal flag to the result of comparison
+                self.equal_flag = register1 == register2;
+                //advance the last 8 bits of the instruction row
+                self.next_8_bits()?;
+            }
+            Opcode::NEQ => {
+                //not equal comparison operator
+                //get the contents of the first two registers (16 bits total)
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                //set the equal flag to the result of comparison
+                self.equal_flag = register1 != register2;
+                //advance the last 8 bits of the instruction row
+                self.next_8_bits()?;
+            }
+            Opcode::GT => {
+                //greater than comparison operator
+                //get the contents of the first two registers (16 bits total)
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                //set the equal flag to the result of comparison
+                self.equal_flag = register1 > register2;
+                //advance the last 8 bits of the instruction row
+                self.next_8_bits()?;
+            }
+            Opcode::LT => {
+                //less than comparison operator
+                //get the contents of the first two registers (16 bits total)
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                //set the equal flag to the result of comparison
+                self.equal_flag = register1 < register2;
+                //advance the last 8 bits of the instruction row
+                self.next_8_bits()?;
+            }
+            Opcode::GTEQ => {
+                //greater than or equal comparison operator
+                //get the contents of the first two registers (16 bits total)
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                //set the equal flag to the result of comparison
+                self.equal_flag = register1 >= register2;
+                //advance the last 8 bits of the instruction row
+                self.next_8_bits()?;
+            }
+            Opcode::LTEQ => {
+                //less than or equal comparison operator
+                //get the contents of the first two registers (16 bits total)
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                //set the equal flag to the result of comparison
+                self.equal_flag = register1 <= register2;
+                //advance the last 8 bits of the instruction row
+                self.next_8_bits()?;
+            }
+            Opcode::BETW => {
+                //BETWEEN COMPARISON OPERATOR BTW $VALUE $LOWERBOUND $UPPERBOUND
+                //Combines less than and greater than into only one instruction
+                let value = self.registers[self.next_register()?];
+                let lower = self.registers[self.next_register()?];
+                let upper = self.registers[self.next_register()?];
+                self.equal_flag = value > lower && value < upper;
+            }
+            Opcode::ALOC => {
+                //heap memory allocation system opcode for the simulated heap memory
+                let register = self.next_register()?;
+                self.allocate(self.registers[register])?;
+                //move the final 16 bits of the instruction line
+                self.next_16_bits()?;
+            }
+            Opcode::JEQ => {
+                //jump if equal. Jumps to provided PC index if the previous comparison resulted in true
+                let register = self.next_register()?;
+                let target = self.registers[register];
+                if self.equal_flag {
+                    self.pc = target as usize;
+                } else {
+                    //skip the unused 16 bits like every other instruction does, otherwise
+                    //the padding after the register is fetched as a HLT
+                    self.next_16_bits()?;
+                }
+            }
+            Opcode::INC => {
+                //increment the value at register
+                let register = self.next_register()?;
+                self.registers[register] = self.registers[register].wrapping_add(1);
+                //advance the final 16 bits
+                self.next_16_bits()?;
+            }
+            Opcode::DEC => {
+                //decrement the value at register
+                let register = self.next_register()?;
+                self.registers[register] = self.registers[register].wrapping_sub(1);
+                //advance the final 16 bits
+                self.next_16_bits()?;
+            }
+            Opcode::LOADM => {
+                //LOAD with the immediate unmasked by this instruction's key
+                let position = self.pc - 1;
+                let register = self.next_register()?;
+                let number = self.next_16_bits()?;
+                let mask = match &mut self.masks {
+                    Some(masks) => masks.immediate(position),
+                    None => 0,
+                };
+                self.registers[register] = i32::from(number ^ mask);
+            }
+            Opcode::LUI => {
+                //load upper immediate. Puts the 16 bit number in the top half of the register
+                let register = self.next_register()?;
+                let number = u32::from(self.next_16_bits()?) << 16;
+                self.registers[register] = number as i32;
+            }
+            Opcode::LDB => {
+                //load the byte at the address in the first register into the second
+                let address = self.registers[self.next_register()?] as u32;
+                let byte = self.read_byte(address)?;
+                self.registers[self.next_register()?] = i32::from(byte);
+                //advance the final 8 bits
+                self.next_8_bits()?;
+            }
+            Opcode::STB => {
+                //store the low byte of the first register at the address in the second
+                let value = self.registers[self.next_register()?];
+                let address = self.registers[self.next_register()?] as u32;
+                self.write_byte(address, value as u8)?;
+                //advance the final 8 bits
+                self.next_8_bits()?;
+            }
+            Opcode::MLEN => {
+                //length of the region the address in the first register belongs to
+                let address = self.registers[self.next_register()?] as u32;
+                let length = self.region_len(address)?;
+                self.registers[self.next_register()?] = length as i32;
+                //advance the final 8 bits
+                self.next_8_bits()?;
+            }
+            Opcode::MOV => {
+                //copy the first register into the second
+                let value = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = value;
+                self.next_8_bits()?;
+            }
+            Opcode::FLAG => {
+                //store the equal flag in a register as 1 or 0
+                self.registers[self.next_register()?] = self.equal_flag as i32;
+                self.next_16_bits()?;
+            }
+            Opcode::LDW => {
+                //load the little endian 32 bit word at the address in the first register into the second
+                let address = self.registers[self.next_register()?] as u32;
+                let mut word = [0; 4];
+                for (i, byte) in word.iter_mut().enumerate() {
+                    *byte = self.read_byte(address.wrapping_add(i as u32))?;
+                }
+                self.registers[self.next_register()?] = i32::from_le_bytes(word);
+                self.next_8_bits()?;
+            }
+            Opcode::STW => {
+                //store the first register as a little endian word at the address in the second
+                let value = self.registers[self.next_register()?];
+                let address = self.registers[self.next_register()?] as u32;
+                for (i, byte) in value.to_le_bytes().iter().enumerate() {
+                    self.write_byte(address.wrapping_add(i as u32), *byte)?;
+                }
+                self.next_8_bits()?;
+            }
+            Opcode::JNEQ => {
+                //jump if not equal. The opposite of JEQ
+                let target = self.registers[self.next_register()?];
+                if self.equal_flag {
+                    self.next_16_bits()?;
+                } else {
+                    self.pc = target as usize;
+                }
+            }
+            Opcode::CALL => {
+                //jump to the address in the register, RET comes back to the next instruction
+                let target = self.registers[self.next_register()?];
+                self.next_16_bits()?;
+                if self.call_stack.len() >= MAX_CALL_DEPTH {
+                    return Err(VmError::CallStackOverflow);
+                }
+                self.call_stack.push(self.pc);
+                self.pc = target as usize;
+            }
+            Opcode::RET => match self.call_stack.pop() {
+                Some(pc) => self.pc = pc,
+                None => return Err(VmError::CallStackUnderflow),
+            },
+            Opcode::AND => self.binary_op(|a, b| Ok(a & b))?,
+            Opcode::OR => self.binary_op(|a, b| Ok(a | b))?,
+            Opcode::XOR => self.binary_op(|a, b| Ok(a ^ b))?,
+            Opcode::SHL => self.binary_op(|a, b| Ok(a.wrapping_shl(b as u32)))?,
+            Opcode::SHR => self.binary_op(|a, b| Ok(a.wrapping_shr(b as u32)))?,
+            Opcode::SHRU => self.binary_op(|a, b| Ok((a as u32).wrapping_shr(b as u32) as i32))?,
+            Opcode::MOD => self.binary_op(|a, b| match b {
+                0 => Err(VmError::DivideByZero),
+                _ => Ok(a.wrapping_rem(b)),
+            })?,
+            Opcode::DIVU => self.binary_op(|a, b| match b {
+                0 => Err(VmError::DivideByZero),
+                _ => Ok(((a as u32) / (b as u32)) as i32),
+            })?,
+            Opcode::MODU => self.binary_op(|a, b| match b {
+                0 => Err(VmError::DivideByZero),
+                _ => Ok(((a as u32) % (b as u32)) as i32),
+            })?,
+            Opcode::FADD => self.float_op(|a, b| a + b)?,
+            Opcode::FSUB => self.float_op(|a, b| a - b)?,
+            Opcode::FMUL => self.float_op(|a, b| a * b)?,
+            Opcode::FDIV => self.float_op(|a, b| a / b)?,
+            Opcode::NOT => self.unary_op(|a| !a)?,
+            Opcode::NEG => self.unary_op(|a| a.wrapping_neg())?,
+            Opcode::FNEG => self.unary_op(|a| (-f32::from_bits(a as u32)).to_bits() as i32)?,
+            Opcode::ITOF => self.unary_op(|a| (a as f32).to_bits() as i32)?,
+            Opcode::UTOF => self.unary_op(|a| (a as u32 as f32).to_bits() as i32)?,
+            Opcode::FTOI => self.unary_op(|a| f32::from_bits(a as u32) as i32)?,
+            Opcode::FTOU => self.unary_op(|a| f32::from_bits(a as u32) as u32 as i32)?,
+            Opcode::LTU => self.compare_op(|a, b| (a as u32) < (b as u32))?,
+            Opcode::GTU => self.compare_op(|a, b| (a as u32) > (b as u32))?,
+            Opcode::LTEQU => self.compare_op(|a, b| (a as u32) <= (b as u32))?,
+            Opcode::GTEQU => self.compare_op(|a, b| (a as u32) >= (b as u32))?,
+            Opcode::FLT => self.compare_op(|a, b| float(a) < float(b))?,
+            Opcode::FGT => self.compare_op(|a, b| float(a) > float(b))?,
+            Opcode::FLTEQ => self.compare_op(|a, b| float(a) <= float(b))?,
+            Opcode::FGTEQ => self.compare_op(|a, b| float(a) >= float(b))?,
+            Opcode::FEQ => self.compare_op(|a, b| float(a) == float(b))?,
+            Opcode::FNEQ => self.compare_op(|a, b| float(a) != float(b))?,
+            Opcode::SYSCALL => {
+                //call into the host function registered under the 16 bit number
+                let number = self.next_16_bits()?;
+                //advance the final 8 bits
+                self.next_8_bits()?;
+                self.execute_syscall(number)?;
+            }
+            _ => {
+                //opcodes the host plugged in are looked up by their raw byte
+                let code = self.fetch(self.pc - 1)?;
+                if self.custom_opcodes.contains_key(&code) {
+                    let operands = [
+                        self.next_8_bits()?,
+                        self.next_8_bits()?,
+                        self.next_8_bits()?,
+                    ];
+                    return self.execute_custom(code, operands).map(|_| true);
+                }
+                if self.verbose {
+                    println!("\n\nUnrecognized opcode found! Terminating!\n");
+                }
+                return Ok(false);
+            }
+        }
+        Ok(true) // continue to the next itteration of the loop by default. The next 8 bits waiting to be read should be an opcode.
+    }
+
+    /// runs the host handler for a custom opcode with the three bytes following it
+    fn execute_custom(&mut self, code: u8, operands: [u8; 3]) -> Result<(), VmError> {
+        let mut context = OpcodeContext {
+            operands,
+            registers: &mut self.registers,
+            heap: &mut self.heap,
+        };
+        match self.custom_opcodes.get_mut(&code) {
+            Some(handler) => handler(&mut context),
+            None => Ok(()),
+        }
+    }
+
+    /// runs a host syscall after checking the program was granted it
+    fn execute_syscall(&mut self, number: u16) -> Result<(), VmError> {
+        let syscall = match self.syscalls.get_mut(&number) {
+            Some(syscall) => syscall,
+            None => return Err(VmError::UnknownSyscall(number)),
+        };
+        if !self.capabilities.allows(&syscall.name) {
+            return Err(VmError::CapabilityDenied(syscall.name.clone()));
+        }
+        let mut context = OpcodeContext {
+            operands: [0, (number >> 8) as u8, number as u8],
+            registers: &mut self.registers,
+            heap: &mut self.heap,
+        };
+        (syscall.handler)(&mut context)
+    }
+
+    /// reads a byte from whichever region the address is mapped to
+    fn read_byte(&mut self, address: u32) -> Result<u8, VmError> {
+        let byte = match Region::decode(address) {
+            Region::Heap(offset) => self.heap.get(offset).cloned(),
+            Region::Input { index, offset } => self
+                .inputs
+                .get(index)
+                .and_then(|input| input.get(offset).cloned()),
+            Region::Output { index, offset } => self
+                .outputs
+                .get(index)
+                .and_then(|output| output.read(offset)),
+            //unmasked one byte at a time, the plain section is never held as a whole
+            Region::Data(offset) => match (self.data.get(offset), &mut self.masks) {
+                (Some(byte), Some(masks)) => Some(byte ^ masks.data(offset)),
+                (byte, _) => byte.cloned(),
+            },
+            Region::Unmapped => None,
+        };
+        byte.ok_or(VmError::InvalidAddress(address))
+    }
+
+    /// writes a byte to the heap or an output buffer, inputs are read only
+    fn write_byte(&mut self, address: u32, byte: u8) -> Result<(), VmError> {
+        let stored = match Region::decode(address) {
+            Region::Heap(offset) => match self.heap.get_mut(offset) {
+                Some(slot) => {
+                    *slot = byte;
+                    true
+                }
+                None => false,
+            },
+            Region::Input { .. } | Region::Data(_) => {
+                return Err(VmError::ReadOnlyAddress(address))
+            }
+            Region::Output { index, offset } => match self.outputs.get_mut(index) {
+                Some(output) => output.write(offset, byte),
+                None => false,
+            },
+            Region::Unmapped => false,
+        };
+        if stored {
+            Ok(())
+        } else {
+            Err(VmError::InvalidAddress(address))
+        }
+    }
+
+    // grows the heap by ALOC's operand, or shrinks it when that's negative
+    fn allocate(&mut self, bytes: i32) -> Result<(), VmError> {
+        let new_end = i64::try_from(self.heap.len())
+            .ok()
+            .and_then(|length| length.checked_add(i64::from(bytes)))
+            .and_then(|end| usize::try_from(end).ok())
+            .filter(|end| *end <= MAX_HEAP)
+            .ok_or(VmError::BadAllocation(bytes))?;
+        self.heap.resize(new_end, 0);
+        Ok(())
+    }
+
+    /// length of the mapped region holding the address
+    fn region_len(&self, address: u32) -> Result<usize, VmError> {
+        let length = match Region::decode(address) {
+            Region::Heap(_) => Some(self.heap.len()),
+            Region::Input { index, .. } => self.inputs.get(index).map(|input| input.len()),
+            Region::Output { index, .. } => self.outputs.get(index).map(|output| output.capacity()),
+            Region::Data(_) => Some(self.data.len()),
+            Region::Unmapped => None,
+        };
+        length.ok_or(VmError::InvalidAddress(address))
+    }
+
+    /// OP $a $b $dst with the result computed from the two source registers
+    fn binary_op<F>(&mut self, op: F) -> Result<(), VmError>
+    where
+        F: Fn(i32, i32) -> Result<i32, VmError>,
+    {
+        let register1 = self.registers[self.next_register()?];
+        let register2 = self.registers[self.next_register()?];
+        self.registers[self.next_register()?] = op(register1, register2)?;
+        Ok(())
+    }
+
+    /// binary_op on the f32 bits stored in the registers
+    fn float_op<F>(&mut self, op: F) -> Result<(), VmError>
+    where
+        F: Fn(f32, f32) -> f32,
+    {
+        let register1 = float(self.registers[self.next_register()?]);
+        let register2 = float(self.registers[self.next_register()?]);
+        self.registers[self.next_register()?] = op(register1, register2).to_bits() as i32;
+        Ok(())
+    }
+
+    /// OP $src $dst
+    fn unary_op<F>(&mut self, op: F) -> Result<(), VmError>
+    where
+        F: Fn(i32) -> i32,
+    {
+        let value = self.registers[self.next_register()?];
+        self.registers[self.next_register()?] = op(value);
+        //advance the last 8 bits of the instruction row
+        self.next_8_bits()?;
+        Ok(())
+    }
+
+    /// OP $a $b setting the equal flag to the result of the comparison
+    fn compare_op<F>(&mut self, op: F) -> Result<(), VmError>
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        let register1 = self.registers[self.next_register()?];
+        let register2 = self.registers[self.next_register()?];
+        self.equal_flag = op(register1, register2);
+        //advance the last 8 bits of the instruction row
+        self.next_8_bits()?;
+        Ok(())
+    }
+
+    //
+    // Helpers
+    //
+
+    //opcode decoder helper
+
+    fn decode_opcode(&mut self) -> Result<Opcode, VmError> {
+        let opcode = Opcode::from(self.opcodes[usize::from(self.fetch(self.pc)?)]);
+        self.pc += 1;
+        Ok(opcode)
+    }
+
+    //bit helpers
+
+    fn next_8_bits(&mut self) -> Result<u8, VmError> {
+        let result = self.fetch(self.pc)?;
+        self.pc += 1;
+        Ok(result)
+    }
+
+    // the next byte as a register number, an error past $31 since unverified code can
+    // name any register
+    fn next_register(&mut self) -> Result<usize, VmError> {
+        let register = self.next_8_bits()?;
+        if usize::from(register) < self.registers.len() {
+            Ok(usize::from(register))
+        } else {
+            Err(VmError::InvalidRegister(register))
+        }
+    }
+
+    fn next_16_bits(&mut self) -> Result<u16, VmError> {
+        let result = (u16::from(self.fetch(self.pc)?) << 8) | u16::from(self.fetch(self.pc + 1)?);
+        self.pc += 2;
+        Ok(result)
+    }
+
+    //the program byte at position, decrypted if the program is sealed. Unverified code
+    //can end part way through an instruction.
+    fn fetch(&mut self, position: usize) -> Result<u8, VmError> {
+        let byte = *self
+            .program
+            .get(position)
+            .ok_or(VmError::Truncated(position))?;
+        Ok(match &mut self.keystream {
+            None => byte,
+            Some(keystream) => byte ^ keystream.at(position),
+        })
+    }
+
+    //
+    // Setters and Getters
+    //
+
+    /// The program as stored, still encrypted for sealed modules
+    pub fn get_program(&mut self) -> Vec<u8> {
+        //return a copy of the program contents vector
+        self.program.to_vec()
+    }
+
+    pub fn clear_program(&mut self) {
+        //clear the entire program memory
+        self.program.clear();
+        self.keystream = None;
+        self.opcodes = permutation::identity();
+        self.data.clear();
+        self.masks = None;
+        self.source_map = None;
+        self.update_integrity();
+        self.update_decoded();
+        self.drop_jit();
+    }
+
+    pub fn get_registers(&mut self) -> [i32; 32] {
+        //return the data in the VM registers
+        self.registers
+    }
+
+    pub fn clear_registers(&mut self) {
+        //clears out the register memory (resets all back to 0)
+        self.registers = [0; 32];
+    }
+
+    pub fn add_byte(&mut self, byte: u8) {
+        self.program.push(byte);
+        self.update_integrity();
+        self.decoded.clear();
+        self.drop_jit();
+    }
+
+    pub fn append_program_bytes(&mut self, mut bytes: Vec<u8>) {
+        self.program.append(&mut bytes);
+        self.update_integrity();
+        self.decoded.clear();
+        self.drop_jit();
+    }
+}
+
+/// Wipes the program, its key, the registers and all memory. The vm is left as if
+/// nothing had been loaded, host handlers and capabilities stay registered.
+impl Zeroize for VM {
+    fn zeroize(&mut self) {
+        self.program.zeroize();
+        if let Some(keystream) = &mut self.keystream {
+            keystream.zeroize();
+        }
+        self.keystream = None;
+        self.opcodes = permutation::identity();
+        self.data.zeroize();
+        self.masks = None;
+        self.decoded.clear();
+        self.drop_jit();
+        self.integrity = None;
+        self.registers.zeroize();
+        self.heap.zeroize();
+        for input in &mut self.inputs {
+            input.zeroize();
+        }
+        self.inputs.clear();
+        for output in &mut self.outputs {
+            output.zeroize();
+        }
+        self.outputs.clear();
+        self.remainder = 0;
+        self.equal_flag = false;
+        self.call_stack.zeroize();
+        self.pc = 0;
+    }
+}
+
+// nothing the program computed outlives the vm
+impl Drop for VM {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+/// reinterprets register bits as an f32
+fn float(bits: i32) -> f32 {
+    f32::from_bits(bits as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_vm() {
+        let test_vm = VM::new();
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![Opcode::HLT as u8, 0, 0, 0];
+        test_vm.program = test_bytes;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_opcode_igl() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![200, 0, 0, 0];
+        test_vm.program = test_bytes;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_load_opcode() {
+        let mut test_vm = VM::new();
+        //load opcode = 1
+        test_vm.program = vec![Opcode::LOAD as u8, 0, 1, 244]; //this is how we represent 500 using two u8s in little endian format
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_add_opcode() {
+        let mut test_vm = VM::new();
+        //registers 0 and 1 are values 500 and 24
+        test_vm.registers[0] = 500;
+        test_vm.registers[1] = 24;
+        //add(2) the values of register 0 and 1 then store the result into register 3
+        test_vm.program = vec![Opcode::ADD as u8, 0, 1, 3];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], 524);
+    }
+
+    #[test]
+    fn test_inc_opcode() {
+        let mut test_vm = VM::new();
+        //register 1 is 41
+        test_vm.registers[1] = 41;
+        //add(2) the values of register 0 and 1 then store the result into register 3
+        test_vm.program = vec![Opcode::INC as u8, 0, 0, 0, Opcode::INC as u8, 1, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        //both registers 0, and 1 should have been incremented once
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.registers[1], 42);
+    }
+
+    #[test]
+    fn test_dec_opcode() {
+        let mut test_vm = VM::new();
+        //register 1 is 45 register 0 is 999
+        test_vm.registers[0] = 999;
+        test_vm.registers[1] = 45;
+        //add(2) the values of register 0 and 1 then store the result into register 3
+        test_vm.program = vec![Opcode::DEC as u8, 0, 0, 0, Opcode::DEC as u8, 1, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        //both registers 0, and 1 should have been decremented once
+        assert_eq!(test_vm.registers[0], 998);
+        assert_eq!(test_vm.registers[1], 44);
+    }
+
+    #[test]
+    fn test_jmp_opcode() {
+        let mut test_vm = VM::new();
+        //jump to pc 1
+        test_vm.registers[0] = 1;
+        test_vm.program = vec![Opcode::JMP as u8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_jumpf_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 2;
+        //7, 0 is jump forward amount in reg0 (which is 2) which skips the last 2 zeros of line1 (the remaining 16 bits on the jmpf instruction line)
+        // into line2 which is a normal jmp at index 4bytes (32 bits, the second instruction row)
+        test_vm.program = vec![Opcode::JMPF as u8, 0, 0, 0, Opcode::JMP as u8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_jumpb_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 2;
+        //goes forward 2 bytes to read instruction and register 0, register 0 is 2 which means go back 2
+        test_vm.program = vec![Opcode::JMPB as u8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        //going back to from pc 2 is 0
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_eq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 10;
+        //eq opcode(9) testing against registers 0 and 1 should result in true
+        test_vm.program = vec![Opcode::EQ as u8, 0, 1, 0, Opcode::EQ as u8, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //with register 1 on a different value it should now result in false
+        test_vm.registers[1] = 11;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_neq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 12;
+        //neq opcode(10) testing against registers 0 and 1 should result in true
+        test_vm.program = vec![Opcode::NEQ as u8, 0, 1, 0, Opcode::NEQ as u8, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //with register 1 on the same value now it should now result in false
+        test_vm.registers[1] = 10;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_gt_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 9;
+        //gt opcode(11) testing against registers 0 and 1 should result in true
+        test_vm.program = vec![Opcode::GT as u8, 0, 1, 0, Opcode::GT as u8, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //with register 1 on a different value it should now result in false
+        test_vm.registers[1] = 11;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_lt_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 11;
+        //lt opcode(12) testing against registers 0 and 1 should result in true
+        test_vm.program = vec![Opcode::LT as u8, 0, 1, 0, Opcode::LT as u8, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //with register 1 on a different value it should now result in false
+        test_vm.registers[1] = 9;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_gtq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 9;
+        //gtq opcode(13) testing against registers 0 and 1 should result in true
+        test_vm.program = vec![
+            Opcode::GTEQ as u8,
+            0,
+            1,
+            0,
+            Opcode::GTEQ as u8,
+            0,
+            1,
+            0,
+            Opcode::GTEQ as u8,
+            0,
+            1,
+            0,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //with register 1 as same value it should still result in true
+        test_vm.registers[1] = 10;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //with register 1 as higher value it should now result in false
+        test_vm.registers[1] = 11;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_ltq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 9;
+        test_vm.registers[1] = 10;
+        //ltq opcode(14) testing against registers 0 and 1 should result in true
+        test_vm.program = vec![
+            Opcode::LTEQ as u8,
+            0,
+            1,
+            0,
+            Opcode::LTEQ as u8,
+            0,
+            1,
+            0,
+            Opcode::LTEQ as u8,
+            0,
+            1,
+            0,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //with register 1 as same value it should still result in true
+        test_vm.registers[1] = 9;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //with register 1 as lower value it should now result in false
+        test_vm.registers[1] = 8;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_btw_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 9;
+        test_vm.registers[1] = 5; //lower
+        test_vm.registers[2] = 12; //upper
+                                   //btw opcode(15) 9 should be between 5 and 12
+        test_vm.program = vec![
+            Opcode::BETW as u8,
+            0,
+            1,
+            2,
+            Opcode::BETW as u8,
+            0,
+            1,
+            2,
+            Opcode::BETW as u8,
+            0,
+            1,
+            2,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        //should return false since 4 is below lower bound of 5
+        test_vm.registers[0] = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+        //should return false with 13 above upper bound of 12
+        test_vm.registers[0] = 13;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_jeq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.equal_flag = true;
+        //JEQ opcode 15 to the location in register 0 (7) if equal_flag is true (it is)
+        test_vm.program = vec![Opcode::JEQ as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 7);
+    }
+
+    #[test]
+    fn test_jeq_opcode_not_taken() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.equal_flag = false;
+        //without the equal flag JEQ just moves on to the next instruction row
+        test_vm.program = vec![Opcode::JEQ as u8, 0, 0, 0, Opcode::INC as u8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.run_once(), Ok(true));
+        assert_eq!(test_vm.registers[0], 8);
+    }
+
+    #[test]
+    fn test_jeq_falls_through() {
+        use crate::assembler::assemble;
+        use crate::verify::verify_module;
+
+        //the JEQ is never taken, everything after it has to run in both interpreters
+        let source = ".input n i32\n.output sum i32 $1\n\
+                      load $1 #0\nload $3 @end\neq $0 $1\njeq $3\n\
+                      inc $1\nadd $1 $0 $1\nload $4 #3\nmul $1 $4 $1\n\
+                      end: hlt\n";
+        let module = assemble(source).unwrap();
+        assert!(verify_module(&module).is_ok());
+        for predecode in [false, true].iter() {
+            let mut test_vm = VM::new();
+            test_vm.predecode(*predecode);
+            test_vm.load_module(&module);
+            assert_eq!(test_vm.call(&[Value::I32(5)]), Ok(vec![Value::I32(18)]));
+            //taken it skips straight to the end
+            assert_eq!(test_vm.call(&[Value::I32(0)]), Ok(vec![Value::I32(0)]));
+        }
+    }
+
+    #[test]
+    fn test_nop_opcode() {
+        let mut test_vm = VM::new();
+        //nop opcode 17 should do nothing and simply increase pc to next row
+        test_vm.program = vec![Opcode::NOP as u8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_aloc_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1024;
+        //aloc opcode 18
+        test_vm.program = vec![Opcode::ALOC as u8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        //heap should be aloc'd to 1024
+        assert_eq!(test_vm.heap.len(), 1024);
+        //program counter should be next row after running
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_aloc_limits() {
+        for predecode in [true, false].iter() {
+            let mut test_vm = VM::new();
+            test_vm.predecode(*predecode);
+            test_vm.load_module(&Module::new(
+                vec![Opcode::ALOC as u8, 0, 0, 0, Opcode::ALOC as u8, 1, 0, 0],
+                Interface::new(),
+            ));
+            //shrinking below empty, overflowing and growing past MAX_HEAP all fail
+            for bytes in [-1, i32::MIN, i32::MAX, MAX_HEAP as i32 + 1].iter() {
+                test_vm.heap = vec![];
+                test_vm.pc = 0;
+                test_vm.registers[0] = *bytes;
+                assert_eq!(test_vm.run_once(), Err(VmError::BadAllocation(*bytes)));
+                assert_eq!(test_vm.pc, 2);
+                assert!(test_vm.heap.is_empty());
+            }
+            //up to the limit and back down is fine
+            test_vm.pc = 0;
+            test_vm.registers[0] = MAX_HEAP as i32;
+            test_vm.registers[1] = -(MAX_HEAP as i32);
+            assert_eq!(test_vm.try_run(), Ok(()));
+            assert!(test_vm.heap.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_register_opcode_range() {
+        let mut test_vm = VM::new();
+        //only the 200-249 range is open to hosts
+        assert_eq!(
+            test_vm.register_opcode(Opcode::ADD as u8, |_| Ok(())),
+            Err(VmError::ReservedOpcode(2))
+        );
+        assert_eq!(
+            test_vm.register_opcode(250, |_| Ok(())),
+            Err(VmError::ReservedOpcode(250))
+        );
+        assert!(test_vm.register_opcode(200, |_| Ok(())).is_ok());
+        assert!(test_vm.register_opcode(249, |_| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn test_custom_opcode() {
+        let mut test_vm = VM::new();
+        //opcode 200 squares the first register into the second and writes the low byte to the heap
+        test_vm
+            .register_opcode(200, |ctx| {
+                let src = ctx.register_operand(0)?;
+                let dst = ctx.register_operand(1)?;
+                ctx.registers[dst] = ctx.registers[src] * ctx.registers[src];
+                ctx.heap.push(ctx.registers[dst] as u8);
+                Ok(())
+            })
+            .unwrap();
+        test_vm.registers[1] = 12;
+        test_vm.program = vec![200, 1, 2, 0, Opcode::HLT as u8, 0, 0, 0];
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(test_vm.registers[2], 144);
+        assert_eq!(test_vm.heap, vec![144]);
+        //the pc lands on the following HLT instruction
+        assert_eq!(test_vm.pc, 5);
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let run = |code: Vec<u8>, predecode: bool| {
+            let mut test_vm = VM::new();
+            test_vm.register_opcode(210, |_| Ok(())).unwrap();
+            test_vm.predecode(predecode);
+            test_vm.load_module(&Module::new(code, Interface::new()));
+            //jumps can land back on the cut off instruction, so only so many steps
+            for _ in 0..16 {
+                match test_vm.run_once() {
+                    Ok(true) => {}
+                    Ok(false) => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        };
+        //every prefix of every opcode after a whole instruction, which gets decoded
+        for byte in 0..=255u8 {
+            for length in 1..4 {
+                for predecode in [true, false].iter() {
+                    let mut code = vec![Opcode::LOAD as u8, 0, 0, 4];
+                    code.extend_from_slice(&[byte, 1, 2][..length]);
+                    run(code, *predecode).ok();
+                }
+            }
+        }
+
+        for predecode in [true, false].iter() {
+            let truncated = |code: Vec<u8>| run(code, *predecode);
+            assert_eq!(truncated(vec![1, 0]), Err(VmError::Truncated(2)));
+            assert_eq!(truncated(vec![2, 0, 1]), Err(VmError::Truncated(3)));
+            let jeq = vec![Opcode::JEQ as u8, 0];
+            assert_eq!(truncated(jeq), Err(VmError::Truncated(2)));
+            assert_eq!(truncated(vec![210, 1]), Err(VmError::Truncated(2)));
+            //NOP and HLT don't read past their opcode
+            assert_eq!(truncated(vec![Opcode::NOP as u8]), Ok(()));
+            assert_eq!(truncated(vec![Opcode::HLT as u8]), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        //unverified code fails the run instead of panicking, decoded or not
+        let bad = [
+            [Opcode::INC as u8, 40, 0, 0],
+            [Opcode::ADD as u8, 1, 2, 40],
+            [Opcode::FADD as u8, 40, 2, 3],
+            [Opcode::NOT as u8, 1, 40, 0],
+            [Opcode::LTU as u8, 40, 1, 0],
+            [Opcode::JEQ as u8, 40, 0, 0],
+        ];
+        for instruction in bad.iter() {
+            for predecode in [true, false].iter() {
+                let mut code = vec![Opcode::LOAD as u8, 0, 0, 7];
+                code.extend_from_slice(instruction);
+                let mut test_vm = VM::new();
+                test_vm.predecode(*predecode);
+                test_vm.load_module(&Module::new(code, Interface::new()));
+                assert_eq!(test_vm.decoded.len(), if *predecode { 1 } else { 0 });
+                assert_eq!(test_vm.try_run(), Err(VmError::InvalidRegister(40)));
+                assert_eq!(test_vm.registers[0], 7);
+                assert_eq!(test_vm.fault_pc, Some(4));
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_opcode_error() {
+        let mut test_vm = VM::new();
+        test_vm
+            .register_opcode(201, |ctx| {
+                ctx.register_operand(0)?;
+                Ok(())
+            })
+            .unwrap();
+        //register 40 is out of range so the handler fails the run
+        test_vm.program = vec![201, 40, 0, 0];
+        assert_eq!(test_vm.try_run(), Err(VmError::InvalidRegister(40)));
+    }
+
+    #[test]
+    fn test_syscall_opcode() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut test_vm = VM::new();
+        let logged = Rc::new(RefCell::new(vec![]));
+        let log = logged.clone();
+        //syscall 1 logs register 0, syscall 2 would read a clock into register 0
+        test_vm.register_syscall(1, "log", move |ctx| {
+            log.borrow_mut().push(ctx.registers[0]);
+            Ok(())
+        });
+        test_vm.register_syscall(2, "clock", |ctx| {
+            ctx.registers[0] = 1234;
+            Ok(())
+        });
+        assert_eq!(test_vm.syscall_number("clock"), Some(2));
+        test_vm.set_capabilities(Capabilities::new().grant("log"));
+        test_vm.registers[0] = 42;
+        test_vm.program = vec![
+            Opcode::SYSCALL as u8,
+            0,
+            1,
+            0,
+            Opcode::SYSCALL as u8,
+            0,
+            2,
+            0,
+        ];
+        //logging is granted but reading the clock is not
+        assert_eq!(
+            test_vm.try_run(),
+            Err(VmError::CapabilityDenied("clock".to_string()))
+        );
+        assert_eq!(*logged.borrow(), vec![42]);
+        assert_eq!(test_vm.registers[0], 42);
+    }
+
+    #[test]
+    fn test_unknown_syscall() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Opcode::SYSCALL as u8, 1, 0, 0];
+        assert_eq!(test_vm.try_run(), Err(VmError::UnknownSyscall(256)));
+    }
+
+    #[test]
+    fn test_capabilities_reset_on_load() {
+        let key = [3; 32];
+        let module = Module::new(vec![Opcode::SYSCALL as u8, 0, 1, 0], Interface::new());
+        let mut test_vm = VM::new();
+        test_vm.register_syscall(1, "log", |_| Ok(()));
+        test_vm.load_module(&module);
+        test_vm.set_capabilities(Capabilities::new().grant("log"));
+        assert_eq!(test_vm.try_run(), Ok(()));
+
+        //the next module wasn't granted anything, whichever way it is loaded
+        let denied = Err(VmError::CapabilityDenied("log".to_string()));
+        test_vm.load_module(&module);
+        assert_eq!(test_vm.try_run(), denied);
+        test_vm.set_capabilities(Capabilities::new().grant("log"));
+        test_vm
+            .load_module_bytes(&module.to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(test_vm.try_run(), denied);
+        test_vm.set_capabilities(Capabilities::new().grant("log"));
+        test_vm.load_sealed(&Module::decrypt_sealed(&module.encrypt(&key).unwrap(), &key).unwrap());
+        assert_eq!(test_vm.try_run(), denied);
+    }
+
+    #[test]
+    fn test_lui_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Opcode::LUI as u8, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 0x0100_0000);
+    }
+
+    #[test]
+    fn test_map_buffers() {
+        let mut test_vm = VM::new();
+        assert_eq!(test_vm.map_input(b"key"), Ok(memory::input_address(0)));
+        assert_eq!(test_vm.map_output(8), Ok(memory::output_address(0)));
+        assert_eq!(
+            test_vm.map_output(MAX_BUFFER + 1),
+            Err(VmError::BufferTooLarge(MAX_BUFFER + 1))
+        );
+        for _ in 1..MAX_INPUTS {
+            test_vm.map_input(&[]).unwrap();
+        }
+        assert_eq!(test_vm.map_input(&[]), Err(VmError::TooManyBuffers));
+        assert_eq!(test_vm.output(0), Some(&[][..]));
+        assert_eq!(test_vm.output(1), None);
+    }
+
+    #[test]
+    fn test_ldb_stb_mlen_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = test_vm.map_input(&[5, 6, 7]).unwrap() as i32 + 1;
+        test_vm.registers[1] = test_vm.map_output(4).unwrap() as i32 + 2;
+        test_vm.program = vec![
+            Opcode::LDB as u8,
+            0,
+            2,
+            0,
+            Opcode::STB as u8,
+            2,
+            1,
+            0,
+            Opcode::MLEN as u8,
+            0,
+            3,
+            0,
+            Opcode::MLEN as u8,
+            1,
+            4,
+            0,
+        ];
+        assert!(test_vm.try_run().is_ok());
+        //byte 1 of the input was copied to byte 2 of the output
+        assert_eq!(test_vm.registers[2], 6);
+        assert_eq!(test_vm.output(0), Some(&[0, 0, 6][..]));
+        assert_eq!(test_vm.registers[3], 3);
+        assert_eq!(test_vm.registers[4], 4);
+    }
+
+    #[test]
+    fn test_memory_faults() {
+        let mut test_vm = VM::new();
+        let input = test_vm.map_input(&[1]).unwrap();
+        test_vm.registers[0] = input as i32;
+        //inputs can't be written to
+        test_vm.program = vec![Opcode::STB as u8, 1, 0, 0];
+        assert_eq!(test_vm.try_run(), Err(VmError::ReadOnlyAddress(input)));
+        //and reading past the end of the input faults
+        test_vm.registers[0] = input as i32 + 1;
+        test_vm.program = vec![Opcode::LDB as u8, 0, 1, 0];
+        assert_eq!(test_vm.execute(), Err(VmError::InvalidAddress(input + 1)));
+        //the heap is only usable once ALOC has grown it
+        test_vm.registers[0] = 0;
+        assert_eq!(test_vm.execute(), Err(VmError::InvalidAddress(0)));
+    }
+
+    #[test]
+    fn test_execute_returns_outputs() {
+        use crate::assembler::program_parsers::program;
+        use nom::types::CompleteStr;
+
+        //copies the input buffer backwards into the output buffer
+        let source = "lui $0 #256\n\
+                      lui $1 #4096\n\
+                      mlen $0 $2\n\
+                      load $5 #1\n\
+                      load $6 #0\n\
+                      load $7 #24\n\
+                      dec $2\n\
+                      add $0 $2 $3\n\
+                      ldb $3 $4\n\
+                      stb $4 $1\n\
+                      inc $1\n\
+                      gt $2 $6\n\
+                      jeq $7\n\
+                      hlt\n";
+        let (_, program) = program(CompleteStr(source)).unwrap();
+        assert!(program.is_valid());
+        let mut test_vm = VM::new();
+        test_vm.append_program_bytes(program.to_bytes().unwrap());
+        test_vm.map_input(b"biobox").unwrap();
+        test_vm.map_output(16).unwrap();
+        assert_eq!(test_vm.execute(), Ok(vec![b"xoboib".to_vec()]));
+    }
+
+    #[test]
+    fn test_call_module() {
+        use crate::assembler::program_parsers::program;
+        use nom::types::CompleteStr;
+
+        //sums the bytes of the key, adds the offset and writes the low byte out twice
+        let source = ".input key bytes\n\
+                      .input offset i32\n\
+                      .output sum i32 $21\n\
+                      .output tag bytes #4\n\
+                      lui $1 #256\n\
+                      mlen $1 $2\n\
+                      lui $3 #4096\n\
+                      load $4 #0\n\
+                      add $0 $4 $21\n\
+                      lt $4 $2\n\
+                      load $7 #48\n\
+                      jeq $7\n\
+                      stb $21 $3\n\
+                      inc $3\n\
+                      stb $21 $3\n\
+                      hlt\n\
+                      add $1 $4 $8\n\
+                      ldb $8 $9\n\
+                      add $21 $9 $21\n\
+                      inc $4\n\
+                      load $6 #20\n\
+                      jmp $6\n";
+        let (_, program) = program(CompleteStr(source)).unwrap();
+        let module = program.to_module().unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&module);
+        let results = test_vm
+            .call(&[Value::Bytes(vec![1, 2, 3]), Value::I32(10)])
+            .unwrap();
+        assert_eq!(results, vec![Value::I32(16), Value::Bytes(vec![16, 16])]);
+        //calls start from a clean state each time
+        let results = test_vm
+            .call(&[Value::Bytes(vec![]), Value::I32(2)])
+            .unwrap();
+        assert_eq!(results, vec![Value::I32(2), Value::Bytes(vec![2, 2])]);
+    }
+
+    #[test]
+    fn test_call_validation() {
+        let mut interface = Interface::new();
+        interface.add_input("a", ParamType::I32, None).unwrap();
+        interface.add_input("b", ParamType::U32, None).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&Module::new(vec![Opcode::HLT as u8, 0, 0, 0], interface));
+        assert_eq!(
+            test_vm.call(&[Value::I32(1)]),
+            Err(VmError::ArityMismatch {
+                expected: 2,
+                found: 1
+            })
+        );
+        assert_eq!(
+            test_vm.call(&[Value::I32(1), Value::F32(2.0)]),
+            Err(VmError::TypeMismatch {
+                name: "b".to_string(),
+                expected: ParamType::U32,
+                found: ParamType::F32
+            })
+        );
+        assert_eq!(test_vm.call(&[Value::I32(1), Value::U32(2)]), Ok(vec![]));
+
+        //an interface put together by hand can name registers that don't exist
+        let mut interface = Interface::new();
+        interface.add_output("x", ParamType::I32, None, 0).unwrap();
+        interface.outputs[0].location = Location::Register(40);
+        test_vm.load_module(&Module::new(vec![Opcode::HLT as u8, 0, 0, 0], interface));
+        assert_eq!(test_vm.call(&[]), Err(VmError::InvalidRegister(40)));
+    }
+
+    #[test]
+    fn test_mov_flag_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.equal_flag = true;
+        test_vm.program = vec![Opcode::MOV as u8, 0, 1, 0, Opcode::FLAG as u8, 2, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], -7);
+        assert_eq!(test_vm.registers[2], 1);
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_ldw_stw_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = -123456;
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![Opcode::STW as u8, 0, 1, 0, Opcode::LDW as u8, 1, 2, 0];
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(&test_vm.heap[4..], &(-123456i32).to_le_bytes());
+        assert_eq!(test_vm.registers[2], -123456);
+        //a word hanging off the end of the heap faults
+        test_vm.registers[1] = 6;
+        assert_eq!(test_vm.execute(), Err(VmError::InvalidAddress(8)));
+    }
+
+    #[test]
+    fn test_jneq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 12;
+        test_vm.program = vec![Opcode::JNEQ as u8, 0, 0, 0, Opcode::JNEQ as u8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 12);
+        test_vm.pc = 4;
+        test_vm.equal_flag = true;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_call_ret_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 12;
+        //call the inc at 12 which returns to the hlt at 4
+        test_vm.program = vec![
+            Opcode::CALL as u8,
+            0,
+            0,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+            Opcode::NOP as u8,
+            0,
+            0,
+            0,
+            Opcode::INC as u8,
+            1,
+            0,
+            0,
+            Opcode::RET as u8,
+            0,
+            0,
+            0,
+        ];
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(test_vm.registers[1], 1);
+        assert_eq!(test_vm.pc, 5);
+        assert!(test_vm.call_stack.is_empty());
+        //a RET with nothing to return to is an error
+        test_vm.pc = 16;
+        assert_eq!(test_vm.try_run(), Err(VmError::CallStackUnderflow));
+        //and so is recursing forever
+        test_vm.program = vec![Opcode::CALL as u8, 1, 0, 0];
+        test_vm.registers[1] = 0;
+        assert_eq!(test_vm.execute(), Err(VmError::CallStackOverflow));
+    }
+
+    #[test]
+    fn test_bitwise_opcodes() {
+        let cases = [
+            (Opcode::AND, 0b1100, 0b1010, 0b1000),
+            (Opcode::OR, 0b1100, 0b1010, 0b1110),
+            (Opcode::XOR, 0b1100, 0b1010, 0b0110),
+            (Opcode::SHL, 3, 4, 48),
+            (Opcode::SHR, -16, 2, -4),
+            (Opcode::SHRU, -16, 28, 15),
+            (Opcode::MOD, -7, 3, -1),
+            (Opcode::DIVU, -2, 2, 0x7fff_ffff),
+            (Opcode::MODU, -1, 10, 5),
+        ];
+        for (opcode, a, b, expected) in cases.iter() {
+            let mut test_vm = VM::new();
+            test_vm.registers[0] = *a;
+            test_vm.registers[1] = *b;
+            test_vm.program = vec![*opcode as u8, 0, 1, 2];
+            assert!(test_vm.try_run().is_ok());
+            assert_eq!(test_vm.registers[2], *expected, "{:?}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_unary_opcodes() {
+        let cases = [
+            (Opcode::NOT, 0, -1),
+            (Opcode::NEG, 5, -5),
+            (Opcode::ITOF, -3, (-3.0f32).to_bits() as i32),
+            (Opcode::UTOF, -1, (4294967295.0f32).to_bits() as i32),
+            (Opcode::FTOI, (-2.75f32).to_bits() as i32, -2),
+            (Opcode::FTOU, (3.5f32).to_bits() as i32, 3),
+            (
+                Opcode::FNEG,
+                (1.5f32).to_bits() as i32,
+                (-1.5f32).to_bits() as i32,
+            ),
+        ];
+        for (opcode, a, expected) in cases.iter() {
+            let mut test_vm = VM::new();
+            test_vm.registers[0] = *a;
+            test_vm.program = vec![*opcode as u8, 0, 1, 0];
+            assert!(test_vm.try_run().is_ok());
+            assert_eq!(test_vm.registers[1], *expected, "{:?}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_float_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1.5f32.to_bits() as i32;
+        test_vm.registers[1] = 0.25f32.to_bits() as i32;
+        test_vm.program = vec![
+            Opcode::FADD as u8,
+            0,
+            1,
+            2,
+            Opcode::FSUB as u8,
+            0,
+            1,
+            3,
+            Opcode::FMUL as u8,
+            0,
+            1,
+            4,
+            Opcode::FDIV as u8,
+            0,
+            1,
+            5,
+            Opcode::FGT as u8,
+            0,
+            1,
+            0,
+        ];
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(float(test_vm.registers[2]), 1.75);
+        assert_eq!(float(test_vm.registers[3]), 1.25);
+        assert_eq!(float(test_vm.registers[4]), 0.375);
+        assert_eq!(float(test_vm.registers[5]), 6.0);
+        assert!(test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_unsigned_compare_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.registers[1] = 1;
+        //-1 is the largest unsigned value
+        test_vm.program = vec![Opcode::GTU as u8, 0, 1, 0, Opcode::LTEQU as u8, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_math_wraps_and_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![Opcode::MUL as u8, 0, 1, 2, Opcode::INC as u8, 0, 0, 0];
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(test_vm.registers[2], -2);
+        assert_eq!(test_vm.registers[0], i32::MIN);
+        test_vm.program = vec![Opcode::DIV as u8, 0, 3, 2];
+        assert_eq!(test_vm.execute(), Err(VmError::DivideByZero));
+        test_vm.program = vec![Opcode::MODU as u8, 0, 3, 2];
+        assert_eq!(test_vm.execute(), Err(VmError::DivideByZero));
+    }
+
+    #[test]
+    fn test_masked_constants() {
+        use crate::assembler::{assemble, assemble_masked};
+
+        //copies the greeting out of the data section and loads a magic number
+        let source = ".output msg bytes #5\n.output magic i32 $9\n\
+                      .data pad \"xy\"\n.data greeting \"hello\"\n\
+                      lui $0 #8192\nload $1 @greeting\nor $0 $1 $0\n\
+                      lui $2 #4096\nload $3 #5\nload $5 #0\nload $6 @top\n\
+                      top: ldb $0 $4\nstb $4 $2\ninc $0\ninc $2\ndec $3\ngt $3 $5\njeq $6\n\
+                      load $9 #4919\nhlt\n";
+        let expected = vec![Value::Bytes(b"hello".to_vec()), Value::I32(4919)];
+        let plain = assemble(source).unwrap();
+        assert_eq!(plain.data, b"xyhello".to_vec());
+        let masked = assemble_masked(source, 31).unwrap();
+        assert!(!masked.data.windows(5).any(|w| w == b"hello"));
+        assert!(!masked.code.windows(2).any(|w| w == [0x13, 0x37]));
+        for module in [plain, masked.clone()].iter() {
+            let mut test_vm = VM::new();
+            test_vm.load_module(module);
+            assert_eq!(test_vm.call(&[]).unwrap(), expected);
+        }
+
+        //the data is unmasked as it's read, never in place
+        let mut test_vm = VM::new();
+        test_vm.load_module(&masked);
+        test_vm.call(&[]).unwrap();
+        assert_eq!(test_vm.data, masked.data);
+        //and it's read only
+        test_vm.registers[0] = 0x2000_0000;
+        test_vm.registers[1] = 0x2000_0000;
+        assert_eq!(test_vm.region_len(0x2000_0000), Ok(7));
+        test_vm.program = vec![Opcode::STB as u8, 0, 1, 0];
+        test_vm.decoded.clear();
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.try_run(),
+            Err(VmError::ReadOnlyAddress(0x2000_0000))
+        );
+    }
+
+    #[test]
+    fn test_require_signature() {
+        use crate::module::signature::{public_key, sign, SigningKey};
+
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.add(&public_key(&key)).unwrap();
+        let bytes = Module::new(vec![Opcode::LOAD as u8, 0, 0, 7], Interface::new())
+            .to_bytes()
+            .unwrap();
+        let signed = sign(&bytes, &key).unwrap();
+
+        let mut test_vm = VM::new();
+        //anything loads until signatures are required
+        assert_eq!(test_vm.load_module_bytes(&bytes), Ok(()));
+        test_vm.require_signature(trusted_keys);
+        assert_eq!(
+            test_vm.load_module_bytes(&bytes),
+            Err(ModuleError::Unsigned)
+        );
+        let mut tampered = signed.clone();
+        tampered[13] = 8;
+        assert_eq!(
+            test_vm.load_module_bytes(&tampered),
+            Err(ModuleError::BadSignature)
+        );
+        let untrusted = sign(&bytes, &SigningKey::from_bytes(&[6; 32])).unwrap();
+        assert_eq!(
+            test_vm.load_module_bytes(&untrusted),
+            Err(ModuleError::UntrustedSigner)
+        );
+        assert_eq!(test_vm.load_module_bytes(&signed), Ok(()));
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(test_vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_require_signature_encrypted() {
+        use crate::module::signature::{public_key, sign, SigningKey};
+
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.add(&public_key(&key)).unwrap();
+        let module_key = [9; 32];
+        let encrypted = Module::new(vec![Opcode::LOAD as u8, 0, 0, 7], Interface::new())
+            .permute(3, &module_key)
+            .encrypt(&module_key)
+            .unwrap();
+        let signed = sign(&encrypted, &key).unwrap();
+
+        let mut test_vm = VM::new();
+        test_vm.require_signature(trusted_keys);
+        //the key opens the container but doesn't stand in for a signature
+        assert_eq!(
+            test_vm.load_encrypted_bytes(&encrypted, &module_key),
+            Err(ModuleError::Unsigned)
+        );
+        assert_eq!(
+            test_vm.load_sealed_bytes(&encrypted, &module_key),
+            Err(ModuleError::Unsigned)
+        );
+        let mut tampered = signed.clone();
+        tampered[20] ^= 1;
+        assert_eq!(
+            test_vm.load_sealed_bytes(&tampered, &module_key),
+            Err(ModuleError::BadSignature)
+        );
+        assert_eq!(
+            test_vm.load_encrypted_bytes(&signed, &[1; 32]),
+            Err(ModuleError::DecryptionFailed)
+        );
+
+        assert_eq!(test_vm.load_encrypted_bytes(&signed, &module_key), Ok(()));
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(test_vm.registers[0], 7);
+        test_vm.registers[0] = 0;
+        assert_eq!(test_vm.load_sealed_bytes(&signed, &module_key), Ok(()));
+        assert!(test_vm.keystream.is_some());
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(test_vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_load_verified() {
+        let mut test_vm = VM::new();
+        let good = Module::new(vec![Opcode::LOAD as u8, 0, 0, 7], Interface::new());
+        assert_eq!(test_vm.load_verified(&good), Ok(()));
+        //a register past $31 would only have failed the run once it was reached
+        let bad = Module::new(vec![Opcode::INC as u8, 40, 0, 0], Interface::new());
+        assert_eq!(
+            test_vm.load_verified(&bad),
+            Err(VerifyError::InvalidRegister {
+                pc: 0,
+                register: 40
+            })
+        );
+        assert_eq!(test_vm.get_program(), good.code);
+    }
+
+    #[test]
+    fn test_integrity() {
+        use crate::assembler::assemble;
+
+        let source = ".input n i32\n.output sum i32 $1\n\
+                      load $1 #0\nload $2 #1\nload $3 @top\n\
+                      top: add $1 $2 $1\ninc $2\nlteq $2 $0\njeq $3\nhlt\n";
+        let module = assemble(source).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&module);
+        //without a key nothing is checked
+        test_vm.program[6] = 1;
+        assert_eq!(test_vm.verify_integrity(), Ok(()));
+
+        test_vm.enable_integrity(&[4; 32]);
+        test_vm.load_module(&module);
+        test_vm.verify_before_run(true);
+        assert_eq!(test_vm.call(&[Value::I32(10)]), Ok(vec![Value::I32(55)]));
+        //patching a byte between calls is caught before anything runs
+        test_vm.program[6] = 1;
+        assert_eq!(test_vm.verify_integrity(), Err(VmError::IntegrityViolation));
+        assert_eq!(
+            test_vm.call(&[Value::I32(10)]),
+            Err(VmError::IntegrityViolation)
+        );
+        //and so are the opcode table and the data
+        test_vm.program[6] = 0;
+        assert_eq!(test_vm.verify_integrity(), Ok(()));
+        test_vm.opcodes[2] = 3;
+        assert_eq!(test_vm.verify_integrity(), Err(VmError::IntegrityViolation));
+        test_vm.opcodes[2] = 2;
+        test_vm.data.push(0);
+        assert_eq!(test_vm.verify_integrity(), Err(VmError::IntegrityViolation));
+        //loading again takes a new MAC
+        test_vm.load_module(&module);
+        assert_eq!(test_vm.verify_integrity(), Ok(()));
+
+        //patched while running, caught by the periodic check
+        test_vm.verify_before_run(false);
+        test_vm.verify_every(16);
+        test_vm.registers[0] = 1000;
+        test_vm.pc = 12;
+        for _ in 0..8 {
+            assert_eq!(test_vm.execute_instruction(), Ok(true));
+        }
+        test_vm.program[13] = 5;
+        let result = (0..8).try_for_each(|_| test_vm.execute_instruction().map(|_| ()));
+        assert_eq!(result, Err(VmError::IntegrityViolation));
+
+        //settings made before enable_integrity aren't lost
+        let mut test_vm = VM::new();
+        test_vm.verify_before_run(true);
+        test_vm.enable_integrity(&[4; 32]);
+        test_vm.load_module(&module);
+        test_vm.program[6] = 1;
+        assert_eq!(
+            test_vm.call(&[Value::I32(10)]),
+            Err(VmError::IntegrityViolation)
+        );
+        let mut test_vm = VM::new();
+        test_vm.verify_every(16);
+        test_vm.load_module(&module);
+        test_vm.enable_integrity(&[4; 32]);
+        test_vm.registers[0] = 1000;
+        test_vm.pc = 12;
+        test_vm.program[13] = 5;
+        let result = (0..16).try_for_each(|_| test_vm.execute_instruction().map(|_| ()));
+        assert_eq!(result, Err(VmError::IntegrityViolation));
+    }
+
+    #[test]
+    fn test_sealed_module() {
+        use crate::assembler::assemble;
+
+        let source = ".input n i32\n.output sum i32 $1\n\
+                      load $1 #0\nload $2 #1\nload $3 @top\n\
+                      top: add $1 $2 $1\ninc $2\nlteq $2 $0\njeq $3\nhlt\n";
+        let key = [11; 32];
+        let mut module = assemble(source).unwrap();
+        //custom opcodes are looked up on the decrypted byte too
+        let end = module.code.len() - 4;
+        module.code.splice(end..end, vec![210, 1, 0, 0]);
+        let mut plain = VM::new();
+        plain.load_module(&module);
+        plain
+            .register_opcode(210, |context| {
+                context.registers[1] *= 2;
+                Ok(())
+            })
+            .unwrap();
+        let expected = plain.call(&[Value::I32(100)]).unwrap();
+        assert_eq!(expected, vec![Value::I32(10100)]);
+
+        for sealed in [
+            module.seal(&key),
+            Module::decrypt_sealed(&module.permute(4, &key).encrypt(&key).unwrap(), &key).unwrap(),
+        ]
+        .iter()
+        {
+            let mut test_vm = VM::new();
+            test_vm.load_sealed(sealed);
+            test_vm
+                .register_opcode(210, |context| {
+                    context.registers[1] *= 2;
+                    Ok(())
+                })
+                .unwrap();
+            assert_ne!(test_vm.get_program(), module.code);
+            assert_eq!(test_vm.call(&[Value::I32(100)]).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_fault_report() {
+        use crate::assembler::assemble_with_debug_info;
+
+        let source = ".input d i32\nload $1 #7\n  div $1 $0 $2\nhlt\n";
+        let module = assemble_with_debug_info(source, "div.asm").unwrap();
+        for predecode in [true, false].iter() {
+            let mut test_vm = VM::new();
+            test_vm.predecode(*predecode);
+            test_vm.load_module(&module);
+            let error = test_vm.call(&[Value::I32(0)]).unwrap_err();
+            assert_eq!(test_vm.fault_pc(), Some(4));
+            assert_eq!(
+                test_vm.fault_report(&error),
+                "division by zero at 0x0004, div.asm:3:3: div $1 $0 $2"
+            );
+            //errors that don't come from an instruction have no place
+            let error = test_vm.call(&[]).unwrap_err();
+            assert_eq!(test_vm.fault_pc(), None);
+            assert_eq!(test_vm.fault_report(&error), error.to_string());
+        }
+        //without the debug section there is only the offset
+        let mut test_vm = VM::new();
+        test_vm.load_module(&module.strip());
+        let error = test_vm.call(&[Value::I32(0)]).unwrap_err();
+        assert_eq!(test_vm.fault_report(&error), "division by zero at 0x0004");
+    }
+
+    #[test]
+    fn test_predecoded_matches_bytes() {
+        use crate::assembler::{assemble, assemble_masked};
+        use crate::compiler::compile;
+
+        let compiled = compile(
+            "int fact(int n) { if (n <= 1) { return 1; } return n * fact(n - 1); }\n\
+             float main(float x, int n) {\n\
+                 float total = 0;\n\
+                 for (int i = 0; i < n; i++) { total = total + x * (float)(fact(i % 6) % 7); }\n\
+                 return total / 2;\n\
+             }",
+        )
+        .unwrap();
+        //relative jumps count from 2 bytes into their instruction
+        let source = ".input n i32\n.output sum i32 $1\n\
+                      load $1 #0\nload $2 #1\nload $3 @top\nload $4 #6\nload $5 #10\n\
+                      top: add $1 $2 $1\ninc $2\nlteq $2 $0\njmpf $4\n\
+                      hlt\njeq $3\nload $6 #4919\nxor $1 $6 $1\nhlt\n";
+        let mut modules = vec![compiled, assemble(source).unwrap()];
+        modules.push(assemble_masked(source, 77).unwrap());
+        let args = [
+            vec![Value::F32(1.5), Value::I32(20)],
+            vec![Value::I32(100)],
+            vec![Value::I32(100)],
+        ];
+        for (module, args) in modules.iter().zip(args.iter()) {
+            let mut results = vec![];
+            for predecode in [false, true].iter() {
+                let mut test_vm = VM::new();
+                test_vm.predecode(*predecode);
+                test_vm.load_module(module);
+                assert_eq!(test_vm.decoded.is_empty(), !*predecode);
+                results.push((test_vm.call(args), test_vm.registers));
+            }
+            assert_eq!(results[0], results[1]);
+        }
+
+        //a trailing partial instruction runs from the bytes
+        let mut test_vm = VM::new();
+        let code = vec![Opcode::INC as u8, 0, 0, 0, Opcode::HLT as u8];
+        test_vm.load_module(&Module::new(code, Interface::new()));
+        assert_eq!(test_vm.decoded.len(), 1);
+        assert_eq!(test_vm.try_run(), Ok(()));
+        assert_eq!((test_vm.registers[0], test_vm.pc), (1, 5));
+
+        //sealed code, integrity checks and changes to the bank all run the bytes
+        let mut test_vm = VM::new();
+        test_vm.load_module(&modules[1]);
+        assert!(!test_vm.decoded.is_empty());
+        test_vm.add_byte(0);
+        assert!(test_vm.decoded.is_empty());
+        test_vm.load_sealed(&modules[1].seal(&[3; 32]));
+        assert!(test_vm.decoded.is_empty());
+        test_vm.load_module(&modules[1]);
+        test_vm.enable_integrity(&[4; 32]);
+        assert!(test_vm.decoded.is_empty());
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut test_vm = VM::new();
+        test_vm.load_sealed(
+            &Module::new(vec![Opcode::INC as u8, 0, 0, 0], Interface::new()).seal(&[1; 32]),
+        );
+        test_vm.heap = vec![9; 16];
+        test_vm.map_input(&[1, 2, 3]).unwrap();
+        assert!(test_vm.try_run().is_ok());
+        assert_eq!(test_vm.registers[0], 1);
+        test_vm.zeroize();
+        assert_eq!(test_vm.registers, [0; 32]);
+        assert!(test_vm.program.is_empty() && test_vm.heap.is_empty() && test_vm.inputs.is_empty());
+        assert!(test_vm.keystream.is_none());
+    }
+}
@@ -0,0 +1,638 @@
+// Execution traces, for finding where a program goes wrong without stepping through it
+// by hand. While tracing (see VM::start_trace) every instruction the vm runs adds a
+// record of
+//   - its pc and the instruction, decoded
+//   - the registers it reads, with their values
+//   - the registers it writes, with their values before and after
+//   - the equal flag, when it changes
+//   - the bytes its stores write, heap and output buffers, with before and after
+// to an in memory trace, as JSON Lines or the compact binary form below. Only
+// instructions starting inside the pc range are recorded, and once the next record
+// doesn't fit under the size cap recording stops, so a trace is always a prefix.
+//
+// Host opcodes and syscalls can do anything, their reads aren't known and their
+// writes are the registers and heap bytes that changed.
+//
+// Binary traces start with "BBTR" and a version byte, followed by the records. All
+// numbers are little endian:
+//   pc u32, the 4 plain instruction bytes
+//   bits u8: 1 the flag changed, 2 the flag after, 4 the instruction failed
+//   read count u8, then register u8 and value i32 of each
+//   write count u8, then register u8, before i32 and after i32 of each
+//   memory write count u16, then address u32, before u8 and after u8 of each
+//   when it failed, message length u16 and the message
+
+use super::{VmError, VM};
+use crate::disassemble;
+use crate::instructions::Opcode;
+use crate::obfuscate::lift::{Instr, ALL};
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::ops::Range;
+
+pub const BINARY_MAGIC: &[u8; 4] = b"BBTR";
+pub const BINARY_VERSION: u8 = 1;
+
+/// How the records of a trace are written out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// one JSON object per line
+    JsonLines,
+    Binary,
+}
+
+/// What a trace records
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceOptions {
+    pub format: TraceFormat,
+    /// only instructions starting in the range are recorded
+    pub pcs: Range<usize>,
+    /// the most bytes the trace may take, records that would go over are dropped
+    pub max_bytes: usize,
+}
+
+impl TraceOptions {
+    /// Every instruction, without a size cap
+    pub fn new(format: TraceFormat) -> TraceOptions {
+        TraceOptions {
+            format,
+            pcs: 0..usize::MAX,
+            max_bytes: usize::MAX,
+        }
+    }
+}
+
+/// A register an instruction wrote
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterWrite {
+    pub register: u8,
+    pub before: i32,
+    pub after: i32,
+}
+
+/// A byte a store wrote, by its vm address (see vm::memory)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryWrite {
+    pub address: u32,
+    pub before: u8,
+    pub after: u8,
+}
+
+/// Everything one instruction did
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub pc: usize,
+    /// the plain opcode and its operand bytes
+    pub instruction: [u8; 4],
+    /// registers read and their values
+    pub reads: Vec<(u8, i32)>,
+    pub writes: Vec<RegisterWrite>,
+    /// the equal flag before and after, when it changed
+    pub flag: Option<(bool, bool)>,
+    pub memory: Vec<MemoryWrite>,
+    /// why the instruction failed, if it did
+    pub error: Option<String>,
+}
+
+impl TraceRecord {
+    /// The record as one line of JSON, without the newline
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"pc\":{},\"instruction\":\"{}\",\"reads\":[",
+            self.pc,
+            disassemble::instruction(&self.instruction)
+        );
+        for (i, (register, value)) in self.reads.iter().enumerate() {
+            let comma = if i == 0 { "" } else { "," };
+            write!(
+                json,
+                "{}{{\"register\":{},\"value\":{}}}",
+                comma, register, value
+            )
+            .unwrap();
+        }
+        json.push_str("],\"writes\":[");
+        for (i, write) in self.writes.iter().enumerate() {
+            write!(
+                json,
+                "{}{{\"register\":{},\"before\":{},\"after\":{}}}",
+                if i == 0 { "" } else { "," },
+                write.register,
+                write.before,
+                write.after
+            )
+            .unwrap();
+        }
+        json.push(']');
+        if let Some((before, after)) = self.flag {
+            write!(
+                json,
+                ",\"flag\":{{\"before\":{},\"after\":{}}}",
+                before, after
+            )
+            .unwrap();
+        }
+        json.push_str(",\"memory\":[");
+        for (i, write) in self.memory.iter().enumerate() {
+            write!(
+                json,
+                "{}{{\"address\":{},\"before\":{},\"after\":{}}}",
+                if i == 0 { "" } else { "," },
+                write.address,
+                write.before,
+                write.after
+            )
+            .unwrap();
+        }
+        json.push(']');
+        if let Some(error) = &self.error {
+            write!(json, ",\"error\":\"{}\"", escape(error)).unwrap();
+        }
+        json.push('}');
+        json
+    }
+
+    /// Appends the record in the binary form
+    pub fn write_binary(&self, results: &mut Vec<u8>) {
+        results.extend_from_slice(&(self.pc as u32).to_le_bytes());
+        results.extend_from_slice(&self.instruction);
+        let (changed, after) = match self.flag {
+            Some((_, after)) => (1, if after { 2 } else { 0 }),
+            None => (0, 0),
+        };
+        let failed = if self.error.is_some() { 4 } else { 0 };
+        results.push(changed | after | failed);
+        results.push(self.reads.len() as u8);
+        for (register, value) in &self.reads {
+            results.push(*register);
+            results.extend_from_slice(&value.to_le_bytes());
+        }
+        results.push(self.writes.len() as u8);
+        for write in &self.writes {
+            results.push(write.register);
+            results.extend_from_slice(&write.before.to_le_bytes());
+            results.extend_from_slice(&write.after.to_le_bytes());
+        }
+        //counts and lengths that don't fit their field are cut short
+        let memory = &self.memory[..self.memory.len().min(usize::from(u16::MAX))];
+        results.extend_from_slice(&(memory.len() as u16).to_le_bytes());
+        for write in memory {
+            results.extend_from_slice(&write.address.to_le_bytes());
+            results.push(write.before);
+            results.push(write.after);
+        }
+        if let Some(error) = &self.error {
+            let mut len = error.len().min(usize::from(u16::MAX));
+            while !error.is_char_boundary(len) {
+                len -= 1;
+            }
+            let message = &error.as_bytes()[..len];
+            results.extend_from_slice(&(message.len() as u16).to_le_bytes());
+            results.extend_from_slice(message);
+        }
+    }
+}
+
+/// Reads the records back out of a binary trace, None if it is malformed
+pub fn parse_binary(bytes: &[u8]) -> Option<Vec<TraceRecord>> {
+    let mut reader = Reader { bytes };
+    if reader.take(4)? != BINARY_MAGIC || reader.u8()? != BINARY_VERSION {
+        return None;
+    }
+    let mut records = vec![];
+    while !reader.bytes.is_empty() {
+        let pc = u32::from_le_bytes(reader.array()?) as usize;
+        let instruction = reader.array()?;
+        let bits = reader.u8()?;
+        let mut reads = vec![];
+        for _ in 0..reader.u8()? {
+            reads.push((reader.u8()?, i32::from_le_bytes(reader.array()?)));
+        }
+        let mut writes = vec![];
+        for _ in 0..reader.u8()? {
+            writes.push(RegisterWrite {
+                register: reader.u8()?,
+                before: i32::from_le_bytes(reader.array()?),
+                after: i32::from_le_bytes(reader.array()?),
+            });
+        }
+        let mut memory = vec![];
+        for _ in 0..u16::from_le_bytes(reader.array()?) {
+            memory.push(MemoryWrite {
+                address: u32::from_le_bytes(reader.array()?),
+                before: reader.u8()?,
+                after: reader.u8()?,
+            });
+        }
+        let flag = if bits & 1 != 0 {
+            let after = bits & 2 != 0;
+            Some((!after, after))
+        } else {
+            None
+        };
+        let error = if bits & 4 != 0 {
+            let len = u16::from_le_bytes(reader.array()?);
+            let message = reader.take(usize::from(len))?;
+            Some(String::from_utf8(message.to_vec()).ok()?)
+        } else {
+            None
+        };
+        records.push(TraceRecord {
+            pc,
+            instruction,
+            reads,
+            writes,
+            flag,
+            memory,
+            error,
+        });
+    }
+    Some(records)
+}
+
+/// The records of a run so far, encoded as the options ask
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    options: TraceOptions,
+    bytes: Vec<u8>,
+    records: usize,
+    dropped: usize,
+}
+
+impl Trace {
+    pub fn new(options: TraceOptions) -> Trace {
+        let bytes = match options.format {
+            TraceFormat::JsonLines => vec![],
+            TraceFormat::Binary => {
+                let mut bytes = BINARY_MAGIC.to_vec();
+                bytes.push(BINARY_VERSION);
+                bytes
+            }
+        };
+        Trace {
+            options,
+            bytes,
+            records: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn options(&self) -> &TraceOptions {
+        &self.options
+    }
+
+    /// The encoded records, JSON Lines text or a binary trace
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// How many records the trace holds
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// How many records were left out because of the size cap
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Adds a record, unless the trace is full
+    pub fn push(&mut self, record: &TraceRecord) {
+        if self.dropped > 0 {
+            self.dropped += 1;
+            return;
+        }
+        let start = self.bytes.len();
+        match self.options.format {
+            TraceFormat::JsonLines => {
+                self.bytes.extend_from_slice(record.to_json().as_bytes());
+                self.bytes.push(b'\n');
+            }
+            TraceFormat::Binary => record.write_binary(&mut self.bytes),
+        }
+        if self.bytes.len() > self.options.max_bytes {
+            self.bytes.truncate(start);
+            self.dropped += 1;
+        } else {
+            self.records += 1;
+        }
+    }
+
+    // whether the instruction at the pc gets a record, once the trace is full it is
+    // counted as dropped instead
+    fn wants(&mut self, pc: usize) -> bool {
+        if !self.options.pcs.contains(&pc) {
+            return false;
+        }
+        if self.dropped > 0 {
+            self.dropped += 1;
+            return false;
+        }
+        true
+    }
+}
+
+impl VM {
+    /// Runs the next instruction, recording it in the trace
+    pub(super) fn execute_traced(&mut self) -> Result<bool, VmError> {
+        let pc = self.pc;
+        let whole = pc + 4 <= self.program.len();
+        if !whole || !self.trace.as_mut().is_some_and(|trace| trace.wants(pc)) {
+            return self.execute_next();
+        }
+        let instruction = [
+            self.opcodes[usize::from(self.fetch(pc)?)],
+            self.fetch(pc + 1)?,
+            self.fetch(pc + 2)?,
+            self.fetch(pc + 3)?,
+        ];
+        let effects = Instr {
+            opcode: instruction[0],
+            operands: [instruction[1], instruction[2], instruction[3]],
+            target: None,
+            jumps: vec![],
+        }
+        .effects();
+        let registers = self.registers;
+        let flag = self.equal_flag;
+        //host code gets at the whole heap, so it is compared as a whole
+        let heap = if effects.writes == ALL {
+            Some(self.heap.clone())
+        } else {
+            None
+        };
+        let width = match Opcode::from(instruction[0]) {
+            Opcode::STB => 1,
+            Opcode::STW => 4,
+            _ => 0,
+        };
+        let address = registers[usize::from(instruction[2] & 31)] as u32;
+        let stored: Vec<(u32, u8)> = (0..width)
+            .map(|i| address.wrapping_add(i))
+            .map(|address| (address, self.read_byte(address).unwrap_or(0)))
+            .collect();
+
+        let result = self.execute_next();
+
+        let failed = result.is_err();
+        let register_bits =
+            (0..32u8).filter(|r| effects.reads != ALL && effects.reads & 1 << r != 0);
+        let reads = register_bits
+            .map(|r| (r, registers[usize::from(r)]))
+            .collect();
+        let writes = (0..32u8)
+            .filter(|r| {
+                let i = usize::from(*r);
+                let changed = registers[i] != self.registers[i];
+                match effects.writes {
+                    ALL => changed,
+                    //a failed instruction may not have got to its write
+                    writes => writes & 1 << r != 0 && (!failed || changed),
+                }
+            })
+            .map(|r| RegisterWrite {
+                register: r,
+                before: registers[usize::from(r)],
+                after: self.registers[usize::from(r)],
+            })
+            .collect();
+        let mut memory = vec![];
+        for (address, before) in stored {
+            if let Ok(after) = self.read_byte(address) {
+                if !failed || after != before {
+                    memory.push(MemoryWrite {
+                        address,
+                        before,
+                        after,
+                    });
+                }
+            }
+        }
+        if let Some(heap) = heap {
+            //the heap starts at address 0
+            for (offset, after) in self.heap.iter().enumerate() {
+                let before = heap.get(offset).cloned();
+                if before != Some(*after) {
+                    memory.push(MemoryWrite {
+                        address: offset as u32,
+                        before: before.unwrap_or(0),
+                        after: *after,
+                    });
+                }
+            }
+        }
+        let record = TraceRecord {
+            pc,
+            instruction,
+            reads,
+            writes,
+            flag: Some((flag, self.equal_flag)).filter(|(before, after)| before != after),
+            memory,
+            error: result.as_ref().err().map(VmError::to_string),
+        };
+        if let Some(trace) = &mut self.trace {
+            trace.push(&record);
+        }
+        result
+    }
+}
+
+// a string as the inside of a JSON string
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < count {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)
+            .map(|bytes| <[u8; N]>::try_from(bytes).unwrap())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // stores into the heap, then sets the flag
+    const STORE: &str = "load $0 #2\naloc $0\nload $1 #300\nload $2 #0\nstb $1 $2\n\
+                         load $3 #3\ngt $1 $3\nhlt\n";
+
+    fn traced(source: &str, options: TraceOptions) -> (Trace, Result<(), VmError>) {
+        let mut test_vm = VM::new();
+        test_vm.load_module(&assemble(source).unwrap());
+        test_vm.start_trace(options);
+        let result = test_vm.try_run();
+        (test_vm.stop_trace().unwrap(), result)
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let (trace, result) = traced(STORE, TraceOptions::new(TraceFormat::JsonLines));
+        assert_eq!(result, Ok(()));
+        assert_eq!(trace.records(), 8);
+        let text = String::from_utf8(trace.into_bytes()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(
+            lines[0],
+            "{\"pc\":0,\"instruction\":\"load $0 #2\",\"reads\":[],\
+             \"writes\":[{\"register\":0,\"before\":0,\"after\":2}],\"memory\":[]}"
+        );
+        assert_eq!(
+            lines[4],
+            "{\"pc\":16,\"instruction\":\"stb $1 $2\",\
+             \"reads\":[{\"register\":1,\"value\":300},{\"register\":2,\"value\":0}],\
+             \"writes\":[],\"memory\":[{\"address\":0,\"before\":0,\"after\":44}]}"
+        );
+        assert_eq!(
+            lines[6],
+            "{\"pc\":24,\"instruction\":\"gt $1 $3\",\
+             \"reads\":[{\"register\":1,\"value\":300},{\"register\":3,\"value\":3}],\
+             \"writes\":[],\"flag\":{\"before\":false,\"after\":true},\"memory\":[]}"
+        );
+    }
+
+    #[test]
+    fn test_binary_filters_and_cap() {
+        let (trace, _) = traced(STORE, TraceOptions::new(TraceFormat::Binary));
+        let records = parse_binary(trace.bytes()).unwrap();
+        assert_eq!(records.len(), 8);
+        //writing the value a register already holds is still a write
+        assert_eq!(
+            records[3].writes,
+            vec![RegisterWrite {
+                register: 2,
+                before: 0,
+                after: 0
+            }]
+        );
+        assert_eq!(records[6].flag, Some((false, true)));
+        assert_eq!(records[7].instruction, [0, 0, 0, 0]);
+        assert_eq!(
+            parse_binary(&trace.bytes()[..trace.bytes().len() - 1]),
+            None
+        );
+
+        let (filtered, _) = traced(
+            STORE,
+            TraceOptions {
+                pcs: 16..28,
+                ..TraceOptions::new(TraceFormat::Binary)
+            },
+        );
+        let pcs: Vec<usize> = parse_binary(filtered.bytes())
+            .unwrap()
+            .iter()
+            .map(|record| record.pc)
+            .collect();
+        assert_eq!(pcs, vec![16, 20, 24]);
+        assert_eq!(filtered.records(), 3);
+
+        //the cap keeps whole records from the start
+        let mut first = vec![];
+        records[0].write_binary(&mut first);
+        let (capped, result) = traced(
+            STORE,
+            TraceOptions {
+                max_bytes: 5 + first.len() + 4,
+                ..TraceOptions::new(TraceFormat::Binary)
+            },
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!((capped.records(), capped.dropped()), (1, 7));
+        assert_eq!(parse_binary(capped.bytes()).unwrap(), records[..1].to_vec());
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn test_jit_is_skipped() {
+        let mut test_vm = VM::new();
+        test_vm.load_jit(&assemble(STORE).unwrap()).unwrap();
+        test_vm.start_trace(TraceOptions::new(TraceFormat::Binary));
+        test_vm.try_run().unwrap();
+        assert_eq!(test_vm.trace().unwrap().records(), 8);
+    }
+
+    #[test]
+    fn test_errors_and_host_code() {
+        let (trace, result) = traced(
+            "load $1 #7\ndiv $1 $3 $4\n",
+            TraceOptions::new(TraceFormat::JsonLines),
+        );
+        assert_eq!(result, Err(VmError::DivideByZero));
+        let text = String::from_utf8(trace.into_bytes()).unwrap();
+        assert!(text
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with("\"writes\":[],\"memory\":[],\"error\":\"division by zero\"}"));
+
+        let mut module = assemble("load $0 #1\naloc $0\nnop\nhlt\n").unwrap();
+        module.code[8] = 210;
+        let mut test_vm = VM::new();
+        test_vm.load_module(&module);
+        test_vm
+            .register_opcode(210, |context| {
+                context.heap[0] = 9;
+                context.registers[5] = -1;
+                Ok(())
+            })
+            .unwrap();
+        test_vm.start_trace(TraceOptions::new(TraceFormat::Binary));
+        test_vm.try_run().unwrap();
+        let records = parse_binary(test_vm.trace().unwrap().bytes()).unwrap();
+        assert_eq!(
+            records[2],
+            TraceRecord {
+                pc: 8,
+                instruction: [210, 0, 0, 0],
+                reads: vec![],
+                writes: vec![RegisterWrite {
+                    register: 5,
+                    before: 0,
+                    after: -1
+                }],
+                flag: None,
+                memory: vec![MemoryWrite {
+                    address: 0,
+                    before: 0,
+                    after: 9
+                }],
+                error: None,
+            }
+        );
+        assert_eq!(escape("a\"b\\\n"), "a\\\"b\\\\\\u000a");
+    }
+}